@@ -8,7 +8,7 @@ use std::fs::File;
 
 use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct BattleParamTables {
     ep1: Vec<BattleParam>,
     ep2: Vec<BattleParam>,
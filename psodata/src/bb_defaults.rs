@@ -1,3 +1,10 @@
+/// Length of a Blue Burst keyboard configuration blob.
+pub const KEY_CONFIG_LEN: usize = 364;
+/// Length of a Blue Burst joystick configuration blob.
+pub const JOY_CONFIG_LEN: usize = 56;
+/// Length of a Blue Burst chat shortcut blob.
+pub const SHORTCUTS_LEN: usize = 0x0A40;
+
 pub static DEFAULT_KEYS: &'static [u8] = &[0u8, 0, 0, 0, 38, 0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 0, 0,
 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
 0, 0, 97, 0, 0, 0, 0, 0, 0, 0, 80, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 89, 0, 0, 0, 0, 0,
@@ -108,6 +108,57 @@ impl Default for ItemData {
     }
 }
 
+impl ItemData {
+    /// Meseta is held as a number on the character, not in a slot.
+    pub fn is_meseta(&self) -> bool {
+        self.data[0] == 4
+    }
+
+    /// Tools, other than technique disks, stack in a single slot.
+    pub fn is_stackable(&self) -> bool {
+        self.data[0] == 3 && self.data[1] != 2
+    }
+
+    /// How many items this is. Always 1 for items that don't stack.
+    pub fn stack_count(&self) -> u8 {
+        if self.is_stackable() && self.data[5] > 0 { self.data[5] } else { 1 }
+    }
+
+    /// The amount of a meseta item.
+    pub fn meseta_amount(&self) -> u32 {
+        let mut d = &self.data2[..];
+        d.read_u32::<LE>().unwrap_or(0)
+    }
+
+    /// Weapons can drop unidentified. They have to be taken to the tekker
+    /// before they can be equipped or sold.
+    pub fn is_unidentified(&self) -> bool {
+        self.data[0] == 0 && self.data[4] & 0x80 != 0
+    }
+
+    pub fn identify(&mut self) {
+        if self.data[0] == 0 {
+            self.data[4] &= 0x7F;
+        }
+    }
+
+    fn same_kind(&self, other: &ItemData) -> bool {
+        self.data[0..3] == other.data[0..3]
+    }
+}
+
+/// The most slots a Blue Burst inventory has.
+pub const INVENTORY_SLOTS: usize = 30;
+
+/// Where a picked up item would go in an inventory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InventoryFit {
+    /// Into an empty slot.
+    NewSlot,
+    /// Onto the existing stack at this index.
+    Stack(usize)
+}
+
 #[derive(Clone, Debug)]
 pub struct Inventory {
     pub hp_mats: u8,
@@ -142,6 +193,92 @@ impl Serial for Inventory {
         })
     }
 }
+impl Inventory {
+    /// Where `item` would go, given at most `max_slots` slots and
+    /// `max_stack` items per stack. `None` if it won't fit. A stack that
+    /// would overflow isn't split into a new slot; the client can't do that
+    /// either. Meseta always returns `None` since it doesn't use a slot.
+    pub fn fit_for(&self, item: &ItemData, max_slots: usize, max_stack: u8) -> Option<InventoryFit> {
+        if item.is_meseta() {
+            return None
+        }
+        if item.is_stackable() {
+            if let Some(i) = self.items.iter().position(|i| i.data.same_kind(item)) {
+                let have = self.items[i].data.stack_count() as u32;
+                if have + item.stack_count() as u32 <= max_stack as u32 {
+                    return Some(InventoryFit::Stack(i))
+                } else {
+                    return None
+                }
+            }
+            if item.stack_count() > max_stack {
+                return None
+            }
+        }
+        if self.items.len() < max_slots && self.items.len() < INVENTORY_SLOTS {
+            Some(InventoryFit::NewSlot)
+        } else {
+            None
+        }
+    }
+
+    /// Put `item` in the inventory if it fits. Returns whether it did.
+    pub fn add_item(&mut self, item: ItemData, max_slots: usize, max_stack: u8) -> bool {
+        match self.fit_for(&item, max_slots, max_stack) {
+            Some(InventoryFit::Stack(i)) => {
+                let count = self.items[i].data.stack_count() + item.stack_count();
+                self.items[i].data.data[5] = count;
+                true
+            },
+            Some(InventoryFit::NewSlot) => {
+                self.items.push(InvItem {
+                    exists: 1,
+                    tech: 0,
+                    flags: 0,
+                    data: item
+                });
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The item with this ID.
+    pub fn find(&self, item_id: u32) -> Option<&InvItem> {
+        self.items.iter().find(|i| i.data.item_id == item_id)
+    }
+
+    /// Take the item with this ID out of the inventory.
+    pub fn remove_item(&mut self, item_id: u32) -> Option<InvItem> {
+        match self.items.iter().position(|i| i.data.item_id == item_id) {
+            Some(i) => Some(self.items.remove(i)),
+            None => None
+        }
+    }
+
+    /// Take `amount` of the item with this ID out of the inventory. Part of
+    /// a stack is split off and given `split_id`; otherwise the item keeps
+    /// its ID. `None` if the item isn't here or there aren't enough.
+    pub fn take(&mut self, item_id: u32, amount: u8, split_id: u32) -> Option<ItemData> {
+        let i = match self.items.iter().position(|i| i.data.item_id == item_id) {
+            Some(i) => i,
+            None => return None
+        };
+        let have = self.items[i].data.stack_count();
+        if !self.items[i].data.is_stackable() || amount == 0 || amount >= have {
+            if amount > have {
+                return None
+            }
+            return Some(self.items.remove(i).data)
+        }
+        self.items[i].data.data[5] = have - amount;
+        let mut split = self.items[i].data.clone();
+        split.data[5] = amount;
+        split.item_id = split_id;
+        Some(split)
+    }
+}
+
 impl Default for Inventory {
     fn default() -> Self {
         Inventory {
@@ -178,6 +315,92 @@ impl Serial for ItemBank {
         })
     }
 }
+/// The most entries a bank has.
+pub const BANK_SLOTS: usize = 200;
+
+impl ItemBank {
+    /// The entries in use. The rest of `items` is padding.
+    pub fn live_items(&self) -> &[BankItem] {
+        &self.items[..self.item_count as usize]
+    }
+
+    pub fn live_items_mut(&mut self) -> &mut [BankItem] {
+        let n = self.item_count as usize;
+        &mut self.items[..n]
+    }
+
+    /// Put `item` in the bank, stacking it with one of the same kind when
+    /// the stack stays within `max_stack`. Returns whether it fit.
+    pub fn deposit(&mut self, item: ItemData, max_items: usize, max_stack: u8) -> bool {
+        if item.is_stackable() {
+            let count = item.stack_count();
+            if let Some(b) = self.live_items_mut().iter_mut().find(|b| b.data.same_kind(&item)) {
+                let total = b.data.stack_count() as u32 + count as u32;
+                if total > max_stack as u32 {
+                    return false
+                }
+                b.data.data[5] = total as u8;
+                b.amount = total as u16;
+                return true
+            }
+            if count > max_stack {
+                return false
+            }
+        }
+        let n = self.item_count as usize;
+        if n >= max_items || n >= BANK_SLOTS {
+            return false
+        }
+        if self.items.len() < BANK_SLOTS {
+            self.items.resize(BANK_SLOTS, Default::default());
+        }
+        self.items[n] = BankItem {
+            amount: item.stack_count() as u16,
+            data: item,
+            flags: 1
+        };
+        self.item_count += 1;
+        true
+    }
+
+    /// Take `amount` of the item with this ID out of the bank, leaving the
+    /// rest of a stack behind. `None` if it isn't here or there aren't
+    /// enough.
+    pub fn withdraw(&mut self, item_id: u32, amount: u8) -> Option<ItemData> {
+        let i = match self.live_items().iter().position(|b| b.data.item_id == item_id) {
+            Some(i) => i,
+            None => return None
+        };
+        let have = self.items[i].data.stack_count();
+        if self.items[i].data.is_stackable() && amount > 0 && amount < have {
+            self.items[i].data.data[5] = have - amount;
+            self.items[i].amount = (have - amount) as u16;
+            let mut part = self.items[i].data.clone();
+            part.data[5] = amount;
+            return Some(part)
+        }
+        if amount > have {
+            return None
+        }
+        let b = self.items.remove(i);
+        self.items.push(Default::default());
+        self.item_count -= 1;
+        Some(b.data)
+    }
+
+    /// Throw away every item `f` matches. Returns how many entries went.
+    pub fn remove_matching<F: Fn(&ItemData) -> bool>(&mut self, f: F) -> usize {
+        let n = self.item_count as usize;
+        let mut kept: Vec<BankItem> = self.items.drain(..n).filter(|b| !f(&b.data)).collect();
+        let removed = n - kept.len();
+        self.item_count = kept.len() as u32;
+        kept.extend(self.items.drain(..));
+        kept.resize(BANK_SLOTS, Default::default());
+        self.items = kept;
+        removed
+    }
+}
+
 impl Default for ItemBank {
     fn default() -> Self {
         ItemBank {
@@ -548,6 +771,14 @@ impl Serial for BbFullCharData {
         })
     }
 }
+impl BbFullCharData {
+    /// Items this character holds that count against an account's storage:
+    /// one per inventory slot and one per bank entry. Meseta doesn't count.
+    pub fn stored_items(&self) -> u32 {
+        self.inv.items.len() as u32 + self.bank.item_count
+    }
+}
+
 impl Default for BbFullCharData {
     fn default() -> Self {
         BbFullCharData {
@@ -857,6 +1088,118 @@ mod test {
         assert_eq!(cursor.position(), 24);
     }
 
+    #[test]
+    fn test_identify() {
+        let mut w = ItemData::default();
+        w.data[0] = 0;
+        w.data[1] = 1;
+        w.data[4] = 0x85;
+        assert!(w.is_unidentified());
+        w.identify();
+        assert!(!w.is_unidentified());
+        assert_eq!(w.data[4], 0x05);
+
+        // Only weapons carry the flag.
+        let mut t = ItemData::default();
+        t.data[0] = 3;
+        t.data[4] = 0x80;
+        assert!(!t.is_unidentified());
+        t.identify();
+        assert_eq!(t.data[4], 0x80);
+    }
+
+    fn tool(kind: u8, count: u8, id: u32) -> ItemData {
+        let mut d = ItemData::default();
+        d.data[0] = 3;
+        d.data[1] = kind;
+        d.data[5] = count;
+        d.item_id = id;
+        d
+    }
+
+    fn weapon(id: u32) -> ItemData {
+        let mut d = ItemData::default();
+        d.data[1] = 1;
+        d.item_id = id;
+        d
+    }
+
+    #[test]
+    fn test_inventory_stacks_tools() {
+        let mut inv = Inventory::default();
+        assert!(inv.add_item(tool(0, 3, 1), 30, 10));
+        assert_eq!(inv.fit_for(&tool(0, 7, 2), 30, 10), Some(InventoryFit::Stack(0)));
+        assert!(inv.add_item(tool(0, 7, 2), 30, 10));
+        assert_eq!(inv.items.len(), 1);
+        assert_eq!(inv.items[0].data.stack_count(), 10);
+        // A full stack doesn't spill into a new slot.
+        assert_eq!(inv.fit_for(&tool(0, 1, 3), 30, 10), None);
+        // Nor does a new stack start over the limit.
+        assert_eq!(inv.fit_for(&tool(1, 11, 6), 30, 10), None);
+        // Technique disks don't stack.
+        assert!(inv.add_item(tool(2, 0, 4), 30, 10));
+        assert!(inv.add_item(tool(2, 0, 5), 30, 10));
+        assert_eq!(inv.items.len(), 3);
+    }
+
+    #[test]
+    fn test_inventory_slot_limit() {
+        let mut inv = Inventory::default();
+        for i in 0..4 {
+            assert!(inv.add_item(weapon(i), 4, 10));
+        }
+        assert!(!inv.add_item(weapon(4), 4, 10));
+        assert_eq!(inv.items.len(), 4);
+        assert_eq!(inv.remove_item(2).map(|i| i.data.item_id), Some(2));
+        assert!(inv.add_item(weapon(4), 4, 10));
+    }
+
+    #[test]
+    fn test_bank_deposit_and_withdraw() {
+        let mut bank = ItemBank::default();
+        assert!(bank.deposit(weapon(1), 2, 10));
+        assert!(bank.deposit(tool(0, 4, 2), 2, 10));
+        assert!(bank.deposit(tool(0, 5, 3), 2, 10));
+        assert_eq!(bank.item_count, 2);
+        assert_eq!(bank.live_items()[1].amount, 9);
+        // Full on entries, and the stack would overflow.
+        assert!(!bank.deposit(weapon(4), 2, 10));
+        assert!(!bank.deposit(tool(0, 2, 5), 2, 10));
+        assert!(!bank.deposit(tool(1, 11, 6), 3, 10));
+
+        let part = bank.withdraw(2, 3).unwrap();
+        assert_eq!(part.stack_count(), 3);
+        assert_eq!(bank.live_items()[1].data.stack_count(), 6);
+        assert!(bank.withdraw(2, 7).is_none());
+        assert_eq!(bank.withdraw(1, 0).map(|i| i.item_id), Some(1));
+        assert_eq!(bank.item_count, 1);
+        assert_eq!(bank.items.len(), BANK_SLOTS);
+    }
+
+    #[test]
+    fn test_bank_remove_matching() {
+        let mut bank = ItemBank::default();
+        assert!(bank.deposit(weapon(1), 10, 10));
+        assert!(bank.deposit(tool(0, 4, 2), 10, 10));
+        assert!(bank.deposit(weapon(3), 10, 10));
+        assert_eq!(bank.remove_matching(|i| i.data[0] == 0), 2);
+        assert_eq!(bank.item_count, 1);
+        assert_eq!(bank.live_items()[0].data.item_id, 2);
+        assert_eq!(bank.items.len(), BANK_SLOTS);
+    }
+
+    #[test]
+    fn test_inventory_take_splits_stacks() {
+        let mut inv = Inventory::default();
+        inv.add_item(tool(0, 5, 1), 30, 10);
+        let part = inv.take(1, 2, 99).unwrap();
+        assert_eq!((part.item_id, part.stack_count()), (99, 2));
+        assert_eq!(inv.items[0].data.stack_count(), 3);
+        assert!(inv.take(1, 4, 100).is_none());
+        assert_eq!(inv.take(1, 3, 100).map(|i| i.item_id), Some(1));
+        assert!(inv.items.is_empty());
+    }
+
     #[test]
     fn test_inventory_size() {
         let mut cursor = Cursor::new(Vec::new());
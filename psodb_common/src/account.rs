@@ -1,7 +1,9 @@
 //! Structs related to accounts.
 
+use crypto::bcrypt::bcrypt;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
 
 use rand::random;
 
@@ -17,30 +19,30 @@ pub struct Account {
 }
 
 impl Account {
-    pub fn new<U, P, S>(username: U, password: P, salt: S) -> Account
-        where U: Into<String>, P: Into<String>, S: Into<String> {
-        let un = username.into();
-        let pw = password.into();
-        let s = salt.into();
+    pub fn new<U, P>(username: U, password: P) -> Account
+        where U: Into<String>, P: Into<String> {
         Account {
             id: None,
-            password_hash: hash_password(&un, &pw, &s),
-            username: un,
+            username: username.into(),
+            password_hash: hash_password(&password.into()),
             password_invalidated: false,
             banned: false
         }
     }
 
-    /// Set the username for this account. This will invalidate the current password,
-    /// because password hashes are salted by username and a salt.
+    /// Set the username for this account. A legacy password hash is salted
+    /// by username, so if the account still has one its password is
+    /// invalidated.
     pub fn set_username<U: Into<String>>(&mut self, un: U) -> () {
-        self.password_invalidated = true;
+        if self.needs_rehash() {
+            self.password_invalidated = true;
+        }
         self.username = un.into();
     }
 
     /// Set the password for this account.
-    pub fn set_password<P: Into<String>, S: Into<String>>(&mut self, pw: P, salt: S) -> () {
-        self.password_hash = hash_password(&self.username, &pw.into(), &salt.into())
+    pub fn set_password<P: Into<String>>(&mut self, pw: P) -> () {
+        self.password_hash = hash_password(&pw.into())
     }
 
     /// Get the database ID of this account.
@@ -48,9 +50,16 @@ impl Account {
         self.id
     }
 
-    pub fn cmp_password(&self, pw: &str, salt: &str) -> bool {
-        let hashed = hash_password(&self.username, pw, salt);
-        hashed == self.password_hash
+    /// Check a password against the account's. `legacy_salt` is only used
+    /// if the account still has a legacy hash.
+    pub fn cmp_password(&self, pw: &str, legacy_salt: &str) -> bool {
+        verify_password(&self.username, pw, legacy_salt, &self.password_hash)
+    }
+
+    /// Whether the password is stored in the legacy format, and should be
+    /// hashed again with `set_password` once the player has logged in.
+    pub fn needs_rehash(&self) -> bool {
+        !self.password_hash.starts_with(BCRYPT_PREFIX)
     }
 }
 
@@ -89,10 +98,83 @@ impl Default for BbAccountInfo {
     }
 }
 
-/// Generate a password hash string.
+/// A ban on a guild card, checked as the player reaches a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BbBan {
+    pub guildcard: u32,
+    /// Shown to the player as they're turned away.
+    pub reason: String,
+    /// Unix time at which the ban lifts, if it does.
+    pub expires: Option<u64>
+}
+
+/// A chat message kept for moderation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    pub guildcard: u32,
+    /// The guild card whispered to, or 0 for lobby chat.
+    pub target: u32,
+    pub block: u16,
+    /// The sender's lobby, from 1; 0 if they weren't in one.
+    pub lobby: u8,
+    /// Unix time it was said.
+    pub time: u64,
+    pub text: String
+}
+
+/// bcrypt work factor for new password hashes.
+pub const BCRYPT_COST: u32 = 10;
+
+const BCRYPT_PREFIX: &'static str = "bcrypt$";
+
+/// Hash a password with bcrypt and a random salt, as
+/// "bcrypt$cost$salt$hash" with the salt and hash in hex.
+pub fn hash_password(pw: &str) -> String {
+    hash_password_with(pw, BCRYPT_COST, random())
+}
+
+fn hash_password_with(pw: &str, cost: u32, salt: [u8; 16]) -> String {
+    format!("{}{}${}${}", BCRYPT_PREFIX, cost, to_hex(&salt), to_hex(&bcrypt_raw(pw, cost, &salt)))
+}
+
+fn bcrypt_raw(pw: &str, cost: u32, salt: &[u8]) -> [u8; 24] {
+    // bcrypt takes 1 to 72 bytes. As OpenBSD's does, hash the password with
+    // a terminating NUL, so an empty one is fine too.
+    let mut input: Vec<u8> = pw.as_bytes().iter().cloned().take(71).collect();
+    input.push(0);
+    let mut out = [0; 24];
+    bcrypt(cost, salt, &input, &mut out);
+    out
+}
+
+/// Check a password against a stored hash, in time that doesn't depend on
+/// where they differ. Legacy hashes are checked with `legacy_hash_password`.
+/// A hash that can't be read never matches.
+pub fn verify_password(un: &str, pw: &str, legacy_salt: &str, hash: &str) -> bool {
+    if !hash.starts_with(BCRYPT_PREFIX) {
+        let legacy = legacy_hash_password(un, pw, legacy_salt);
+        return fixed_time_eq(legacy.as_bytes(), hash.as_bytes())
+    }
+    let parts: Vec<&str> = hash[BCRYPT_PREFIX.len()..].split('$').collect();
+    if parts.len() != 3 {
+        return false
+    }
+    let cost = match parts[0].parse::<u32>() {
+        Ok(c) if c >= 4 && c < 32 => c,
+        _ => return false
+    };
+    match (from_hex(parts[1]), from_hex(parts[2])) {
+        (Some(ref salt), Some(ref expected)) if salt.len() == 16 && expected.len() == 24 => {
+            fixed_time_eq(&bcrypt_raw(pw, cost, salt), expected)
+        },
+        _ => false
+    }
+}
+
+/// Generate a legacy password hash string, as older versions stored them.
 ///
 /// The hash algorithm is Sha256 over the string "un:pw:salt".
-pub fn hash_password(un: &str, pw: &str, salt: &str) -> String {
+pub fn legacy_hash_password(un: &str, pw: &str, salt: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.input_str(un);
     hasher.input_str(":");
@@ -101,3 +183,62 @@ pub fn hash_password(un: &str, pw: &str, salt: &str) -> String {
     hasher.input_str(salt);
     hasher.result_str()
 }
+
+fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None
+    }
+    let mut out = Vec::with_capacity(s.len() / 2);
+    for pair in s.as_bytes().chunks(2) {
+        match ((pair[0] as char).to_digit(16), (pair[1] as char).to_digit(16)) {
+            (Some(h), Some(l)) => out.push((h * 16 + l) as u8),
+            _ => return None
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The lowest cost bcrypt allows, to keep the tests quick.
+    fn quick_hash(pw: &str) -> String {
+        hash_password_with(pw, 4, [7; 16])
+    }
+
+    #[test]
+    fn test_hash_verify() {
+        let h = quick_hash("hunter2");
+        assert!(h.starts_with("bcrypt$4$"));
+        assert!(verify_password("rico", "hunter2", "", &h));
+        assert!(verify_password("rico", "", "", &quick_hash("")));
+        // Salts are per hash, so the same password hashes differently.
+        assert!(hash_password_with("hunter2", 4, [8; 16]) != h);
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let h = quick_hash("hunter2");
+        assert!(!verify_password("rico", "hunter3", "", &h));
+        assert!(!verify_password("rico", "", "", &h));
+        assert!(!verify_password("rico", "hunter2", "", "bcrypt$4$00$00"));
+        assert!(!verify_password("rico", "hunter2", "", "bcrypt$99$zz$zz"));
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let mut a = Account::new("rico", "x");
+        a.password_hash = legacy_hash_password("rico", "hunter2", "salt");
+        assert!(a.needs_rehash());
+        assert!(a.cmp_password("hunter2", "salt"));
+        assert!(!a.cmp_password("hunter2", ""));
+        a.set_password("hunter2");
+        assert!(!a.needs_rehash());
+        assert!(a.cmp_password("hunter2", ""));
+    }
+}
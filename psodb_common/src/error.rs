@@ -2,22 +2,60 @@
 
 use std::error;
 use std::io;
+use std::result;
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
+/// Wrapper around the standard result that yields the database error type for Err.
+pub type Result<T> = result::Result<T, Error>;
+
 #[derive(Debug)]
 pub enum Error {
     BackendError(Option<Box<error::Error>>),
     IoError(io::Error),
+    /// The connection to the database was lost. The pool reconnects on the
+    /// next operation, so this is worth retrying.
+    ConnectionLost(Option<Box<error::Error>>),
+    /// Something that was expected to exist doesn't. Carries a description
+    /// of what was looked up, e.g. "bb account info for account 5".
+    NotFound(String),
     Other(String, Option<Box<error::Error>>)
 }
 
+impl Error {
+    /// Whether the operation might succeed if tried again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            &Error::ConnectionLost(_) => true,
+            _ => false
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(s: String) -> Error {
+        Error::Other(s, None)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(s: &'a str) -> Error {
+        Error::Other(s.to_string(), None)
+    }
+}
+
 unsafe impl Send for Error {}
 unsafe impl Sync for Error {}
 
 impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         use std::error::Error;
         write!(f, "database error: {}\ncaused by: {:?}",
             self.description(),
@@ -32,6 +70,8 @@ impl error::Error for Error {
             &BackendError(Some(ref e)) => e.description(),
             &BackendError(None) => "",
             &IoError(ref e) => e.description(),
+            &ConnectionLost(_) => "database connection lost",
+            &NotFound(ref s) => &s,
             &Other(ref s, _) => &s,
         }
     }
@@ -41,8 +81,53 @@ impl error::Error for Error {
         match self {
             &BackendError(Some(ref o)) => Some(o.as_ref()),
             &IoError(ref e) => Some(e),
+            &ConnectionLost(Some(ref o)) => Some(o.as_ref()),
             &Other(_, Some(ref o)) => Some(o.as_ref()),
             _ => None
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as StdError;
+    use std::io;
+
+    use super::{Error, Result};
+
+    #[test]
+    fn test_not_found() {
+        let e = Error::NotFound("account 5".to_string());
+        assert_eq!(e.description(), "account 5");
+        assert!(e.cause().is_none());
+        assert_eq!(format!("{}", e), "database error: account 5\ncaused by: None");
+        assert!(!e.is_retryable());
+    }
+
+    fn read_config() -> Result<()> {
+        try!(Err(io::Error::new(io::ErrorKind::NotFound, "no such file")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let e = read_config().unwrap_err();
+        match e {
+            Error::IoError(_) => (),
+            ref e => panic!("expected an IoError, got {:?}", e)
+        }
+        assert_eq!(e.description(), "no such file");
+        let cause = e.cause().unwrap();
+        assert_eq!(cause.description(), "no such file");
+        assert_eq!(cause.to_string(), "no such file");
+    }
+
+    #[test]
+    fn test_from_message() {
+        let e: Error = "bad row".into();
+        assert_eq!(e.description(), "bad row");
+        assert!(e.cause().is_none());
+        let e = Error::from("bad row".to_string());
+        assert_eq!(e.description(), "bad row");
+    }
+}
@@ -10,17 +10,14 @@ pub mod pool;
 
 pub mod account;
 
-pub use self::error::Error;
+pub use self::error::{Error, Result};
 pub use self::account::Account;
 pub use self::account::BbAccountInfo;
+pub use self::account::BbBan;
+pub use self::account::ChatLine;
 pub use self::pool::Pool;
 
-use psodata::chara::BbFullCharData;
-
-use std::result;
-
-/// Wrapper around the standard result that yields the database error type for Err.
-pub type Result<T> = result::Result<T, Error>;
+use psodata::chara::{BbFullCharData, ItemBank};
 
 /// A backend implementation for the database.
 ///
@@ -31,6 +28,17 @@ pub trait Backend {
     /// A boxed Backend trait object is yielded so the trait doesn't have a Sized constraint.
     fn try_clone(&mut self) -> Result<Box<Backend>>;
 
+    /// Check that the connection still works, with `Error::ConnectionLost` if
+    /// it doesn't.
+    fn ping(&self) -> Result<()>;
+
+    /// Throw away the current connection and open a new one.
+    fn reconnect(&mut self) -> Result<()>;
+
+    /// Compact the database and refresh the query planner's statistics.
+    /// Returns the database's size in bytes before and after.
+    fn maintain(&self) -> Result<(u64, u64)>;
+
     /// Retrieve an account by its ID.
     fn get_account_by_id(&self, id: u32) -> Result<Option<Account>>;
 
@@ -57,7 +65,49 @@ pub trait Backend {
     /// whether or not to save the account-global data from the character info.
     fn put_bb_character(&self, account_id: u32, slot: u8, chara: BbFullCharData, save_acct_data: bool) -> Result<()>;
 
+    /// Delete the character in the slot, along with its name and play time.
+    /// `Ok(false)` means the slot was already empty.
+    fn delete_bb_character(&self, account_id: u32, slot: u8) -> Result<bool>;
+
+    /// Fetch the account-wide shared bank. An account that has never used
+    /// it has an empty one.
+    fn fetch_bb_shared_bank(&self, account_id: u32) -> Result<ItemBank>;
+
+    /// Save a character and the account's shared bank together. Either both
+    /// are written or neither is, so items moved between them can't be
+    /// duplicated or lost.
+    fn put_bb_character_and_shared_bank(&self, account_id: u32, slot: u8, chara: BbFullCharData, bank: &ItemBank) -> Result<()>;
+
+    /// Reserve a character name for the character in the slot, releasing
+    /// whatever name that slot had before. Names are compared ignoring case.
+    /// `Ok(false)` means another character already has it.
+    fn claim_bb_character_name(&self, account_id: u32, slot: u8, name: &str) -> Result<bool>;
+
+    /// The version of the server rules the account last accepted, 0 if it
+    /// never has.
+    fn fetch_bb_rules_version(&self, account_id: u32) -> Result<u32>;
+
+    /// Record that the account accepted this version of the server rules.
+    fn put_bb_rules_version(&self, account_id: u32, version: u32) -> Result<()>;
+
+    /// Add to the time played on the character in the slot.
+    fn add_bb_playtime(&self, account_id: u32, slot: u8, seconds: u32) -> Result<()>;
+
+    /// `add_bb_playtime` for each (account, slot, seconds), in one
+    /// transaction.
+    fn add_bb_playtimes(&self, entries: &[(u32, u8, u32)]) -> Result<()>;
+
+    /// Seconds played on the whole account, and on the character in the
+    /// slot.
+    fn fetch_bb_playtime(&self, account_id: u32, slot: u8) -> Result<(u64, u64)>;
+
     fn set_bb_login_flags(&self, account_id: u32, flags: u32) -> Result<()>;
 
     fn get_bb_login_flags(&self, account_id: u32) -> Result<u32>;
+
+    /// The ban on the guild card, if there is one, expired or not.
+    fn fetch_bb_ban(&self, guildcard: u32) -> Result<Option<BbBan>>;
+
+    /// Keep chat messages for moderation, in one transaction.
+    fn put_bb_chat_logs(&self, lines: &[ChatLine]) -> Result<()>;
 }
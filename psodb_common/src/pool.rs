@@ -5,13 +5,17 @@ use super::Result;
 use super::error::Error;
 
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 /// A connection pool of Backend instances that can be safely moved over thread boundaries and
 /// called asynchronously.
 pub struct Pool {
     backends: Vec<Arc<Mutex<Box<Backend>>>>,
-    counter: AtomicUsize
+    counter: AtomicUsize,
+    /// Check connections before handing them out and reconnect lost ones.
+    reconnect: bool,
+    /// Which connections were last seen lost, so recovery is logged once.
+    lost: Vec<AtomicBool>
 }
 
 impl Pool {
@@ -19,18 +23,84 @@ impl Pool {
     /// for blocking over the returned Backend, but does not completely eliminate it. Holders of
     /// a reference to the Backend should try to minimize time spent holding on the Mutex guard.
     pub fn get_connection(&self) -> Result<Arc<Mutex<Box<Backend>>>> {
-        let i = self.counter.fetch_add(1, Ordering::SeqCst);
-        match self.backends.get(i % self.backends.len()) {
-            Some(b) => Ok(b.clone()),
-            None => Err(Error::Other("unknown".to_string(), None))
+        let i = self.counter.fetch_add(1, Ordering::SeqCst) % self.backends.len();
+        let b = match self.backends.get(i) {
+            Some(b) => b.clone(),
+            None => return Err(Error::Other("unknown".to_string(), None))
+        };
+        if self.reconnect {
+            try!(self.check_connection(i, &b));
         }
+        Ok(b)
+    }
+
+    /// Run `f` on a connection. If it fails with an error that's worth
+    /// retrying, like the connection going away partway through, it's run
+    /// once more on the next connection, which gets checked first.
+    pub fn run<T, F>(&self, mut f: F) -> Result<T> where F: FnMut(&Backend) -> Result<T> {
+        let first = self.run_on_next(&mut f);
+        match first {
+            Err(ref e) if e.is_retryable() => warn!("Retrying database operation: {}", e),
+            r => return r
+        }
+        self.run_on_next(&mut f)
+    }
+
+    /// Run `f` on a connection without retrying, for operations that aren't
+    /// safe to repeat if the first attempt might have gone through, like
+    /// adding to a running total.
+    pub fn run_once<T, F>(&self, mut f: F) -> Result<T> where F: FnMut(&Backend) -> Result<T> {
+        self.run_on_next(&mut f)
+    }
+
+    fn run_on_next<T, F>(&self, f: &mut F) -> Result<T> where F: FnMut(&Backend) -> Result<T> {
+        let b = try!(self.get_connection());
+        let handle = match b.lock() {
+            Ok(h) => h,
+            Err(_) => return Err(Error::Other("connection mutex poisoned".to_string(), None))
+        };
+        f(&**handle)
+    }
+
+    /// Make sure connection `i` works, reconnecting it if it doesn't. If it
+    /// can't be brought back, `Error::ConnectionLost` is returned and the
+    /// next checkout tries again.
+    fn check_connection(&self, i: usize, b: &Arc<Mutex<Box<Backend>>>) -> Result<()> {
+        let mut handle = match b.lock() {
+            Ok(h) => h,
+            Err(_) => return Err(Error::Other("connection mutex poisoned".to_string(), None))
+        };
+        if let Err(e) = handle.ping() {
+            if !self.lost[i].swap(true, Ordering::SeqCst) {
+                warn!("Database connection {} lost: {}", i, e);
+            }
+            try!(handle.reconnect());
+            try!(handle.ping());
+        }
+        if self.lost[i].swap(false, Ordering::SeqCst) {
+            info!("Database connection {} recovered", i);
+        }
+        Ok(())
+    }
+
+    /// The number of connections in the pool.
+    pub fn size(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// Set whether connections are checked and reconnected on checkout. On
+    /// by default.
+    pub fn set_reconnect(&mut self, reconnect: bool) {
+        self.reconnect = reconnect;
     }
 
     /// Creates a new connection pool, making the given number of clones of the base Backend.
     /// An error is returned if it fails to clone as many as requested.
     pub fn new(connections: usize, base: &mut Backend) -> Result<Pool> {
         let mut be = Vec::with_capacity(connections);
+        let mut lost = Vec::with_capacity(connections);
         for _ in 0..connections {
+            lost.push(AtomicBool::new(false));
             let c = match base.try_clone() {
                 Ok(b) => b,
                 Err(e) => return Err(e)
@@ -40,7 +110,9 @@ impl Pool {
 
         Ok(Pool {
             backends: be,
-            counter: AtomicUsize::new(0)
+            counter: AtomicUsize::new(0),
+            reconnect: true,
+            lost: lost
         })
     }
 }
@@ -0,0 +1,569 @@
+//! MySQL database backend, for sharing one account database between several
+//! machines.
+
+#[macro_use] extern crate log;
+extern crate mysql;
+extern crate psodb_common;
+extern crate psodata;
+extern crate psoserial;
+
+use std::cell::RefCell;
+use std::io::Cursor;
+
+use mysql::{Conn, Opts, OptsBuilder, from_row};
+use mysql::Error as MyError;
+use mysql::DriverError;
+
+use psoserial::Serial;
+
+use psodb_common::Result;
+use psodb_common::Backend;
+use psodb_common::error::Error;
+
+use psodb_common::account::Account;
+use psodb_common::account::BbAccountInfo;
+use psodb_common::account::BbBan;
+use psodb_common::account::ChatLine;
+
+use psodata::chara::{BbFullCharData, BbTeamAndKeyData, BbChar, ItemBank};
+
+mod schema;
+use self::schema::{SCHEMA, TABLES};
+
+/// MySQL's error code for a duplicate key.
+const ER_DUP_ENTRY: u16 = 1062;
+
+/// Errors the backend can run into, turned into the Backend's error. Losing
+/// the server partway through is `ConnectionLost`, so it's retried on a new
+/// connection.
+trait IntoDbError {
+    fn into_db_error(self) -> Error;
+}
+
+impl IntoDbError for MyError {
+    fn into_db_error(self) -> Error {
+        match self {
+            MyError::IoError(_) |
+            MyError::DriverError(DriverError::CouldNotConnect(_)) |
+            MyError::DriverError(DriverError::PacketOutOfSync) |
+            MyError::DriverError(DriverError::Timeout) => Error::ConnectionLost(Some(Box::new(self))),
+            _ => Error::BackendError(Some(Box::new(self)))
+        }
+    }
+}
+
+impl IntoDbError for std::io::Error {
+    fn into_db_error(self) -> Error {
+        Error::BackendError(Some(Box::new(self)))
+    }
+}
+
+impl IntoDbError for Error {
+    fn into_db_error(self) -> Error {
+        self
+    }
+}
+
+macro_rules! try_db {
+    ($e:expr) => {
+        match $e {
+            Ok(s) => s,
+            Err(e) => return Err(e.into_db_error())
+        }
+    }
+}
+
+/// A connection to a MySQL server, to implement Backend.
+pub struct MySql {
+    opts: Opts,
+    /// The driver wants the connection mutable for every query.
+    conn: RefCell<Conn>
+}
+
+impl MySql {
+    /// Connect to the database, creating any tables it doesn't have yet.
+    pub fn new(host: &str, port: u16, user: &str, password: &str, database: &str) -> Result<MySql> {
+        let mut builder = OptsBuilder::new();
+        builder.ip_or_hostname(Some(host))
+            .tcp_port(port)
+            .user(Some(user))
+            .pass(Some(password))
+            .db_name(Some(database));
+        let opts: Opts = builder.into();
+        let conn = try_db!(Conn::new(opts.clone()));
+        let m = MySql {
+            opts: opts,
+            conn: RefCell::new(conn)
+        };
+        for stmt in SCHEMA {
+            try!(m.run(stmt));
+        }
+        Ok(m)
+    }
+
+    /// Run a statement that takes no parameters, discarding any rows.
+    fn run(&self, query: &str) -> Result<()> {
+        try_db!(self.conn.borrow_mut().query(query));
+        Ok(())
+    }
+
+    fn put_bb_shared_bank(&self, account_id: u32, bank: &ItemBank) -> Result<()> {
+        let b = serial_to_vec(bank);
+        try_db!(self.conn.borrow_mut().prep_exec(
+            "INSERT INTO bb_shared_bank (account_id, bank) VALUES (?, ?) ON DUPLICATE KEY UPDATE bank=VALUES(bank)",
+            (account_id, b)));
+        Ok(())
+    }
+
+    /// Size of the database's tables and indexes in bytes.
+    fn size(&self) -> Result<u64> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.query(
+            "SELECT CAST(COALESCE(SUM(data_length + index_length), 0) AS UNSIGNED) FROM information_schema.tables WHERE table_schema = DATABASE()"));
+        match results.next() {
+            Some(Ok(row)) => Ok(from_row::<u64>(row)),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(0)
+        }
+    }
+
+    /// Run `f` between `START TRANSACTION` and `COMMIT`, rolling back if it
+    /// fails.
+    fn transaction<F: FnOnce() -> Result<()>>(&self, what: &str, f: F) -> Result<()> {
+        try!(self.run("START TRANSACTION"));
+        match f() {
+            Ok(_) => self.run("COMMIT"),
+            Err(e) => {
+                if let Err(re) = self.run("ROLLBACK") {
+                    error!("Couldn't roll back {}: {}", what, re);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[inline(always)] fn b2i(a: bool) -> i64 { match a { true => 1, false => 0 }}
+#[inline(always)] fn i2b(a: i64) -> bool { match a { 0 => false, _ => true }}
+
+impl Backend for MySql {
+    fn try_clone(&mut self) -> Result<Box<Backend>> {
+        let c = try_db!(Conn::new(self.opts.clone()));
+        Ok(Box::new(MySql {
+            opts: self.opts.clone(),
+            conn: RefCell::new(c)
+        }))
+    }
+
+    fn ping(&self) -> Result<()> {
+        if self.conn.borrow_mut().ping() {
+            Ok(())
+        } else {
+            Err(Error::ConnectionLost(None))
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let c = match Conn::new(self.opts.clone()) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::ConnectionLost(Some(Box::new(e))))
+        };
+        self.conn = RefCell::new(c);
+        Ok(())
+    }
+
+    fn maintain(&self) -> Result<(u64, u64)> {
+        let before = try!(self.size());
+        try!(self.run(&format!("OPTIMIZE TABLE {}", TABLES)));
+        try!(self.run(&format!("ANALYZE TABLE {}", TABLES)));
+        let after = try!(self.size());
+        Ok((before, after))
+    }
+
+    fn get_account_by_id(&self, id: u32) -> Result<Option<Account>> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec(
+            "SELECT username,password_hash,password_invalidated,banned FROM accounts WHERE id=? LIMIT 1", (id,)));
+        match results.next() {
+            Some(Ok(row)) => {
+                let (username, password_hash, invalidated, banned) = from_row::<(String, String, i64, i64)>(row);
+                Ok(Some(Account {
+                    id: Some(id),
+                    username: username,
+                    password_hash: password_hash,
+                    password_invalidated: i2b(invalidated),
+                    banned: i2b(banned)
+                }))
+            },
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(None)
+        }
+    }
+
+    fn get_account_by_username(&self, username: &str) -> Result<Option<Account>> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec(
+            "SELECT id,password_hash,password_invalidated,banned FROM accounts WHERE username=? LIMIT 1", (username,)));
+        match results.next() {
+            Some(Ok(row)) => {
+                let (id, password_hash, invalidated, banned) = from_row::<(u32, String, i64, i64)>(row);
+                Ok(Some(Account {
+                    id: Some(id),
+                    username: username.to_owned(),
+                    password_hash: password_hash,
+                    password_invalidated: i2b(invalidated),
+                    banned: i2b(banned)
+                }))
+            },
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(None)
+        }
+    }
+
+    fn put_account(&self, account: &mut Account) -> Result<()> {
+        let mut conn = self.conn.borrow_mut();
+        match account.id {
+            Some(id) => {
+                try_db!(conn.prep_exec("UPDATE accounts SET username=?,password_hash=?,password_invalidated=?,banned=? WHERE id=?",
+                    (&account.username, &account.password_hash, b2i(account.password_invalidated), b2i(account.banned), id)));
+                Ok(())
+            },
+            None => {
+                let id = {
+                    let r = try_db!(conn.prep_exec("INSERT INTO accounts (username,password_hash,password_invalidated,banned) VALUES (?,?,?,?)",
+                        (&account.username, &account.password_hash, b2i(account.password_invalidated), b2i(account.banned))));
+                    r.last_insert_id()
+                };
+                account.id = Some(id as u32);
+                Ok(())
+            }
+        }
+    }
+
+    fn reset_account_passwords(&self) -> Result<()> {
+        self.run("UPDATE accounts SET password_invalidated=1")
+    }
+
+    fn fetch_bb_account_info(&self, account_id: u32) -> Result<Option<BbAccountInfo>> {
+        let found = {
+            let mut conn = self.conn.borrow_mut();
+            let mut results = try_db!(conn.prep_exec(
+                "SELECT id,team_id,options,key_config,joy_config,shortcuts,symbol_chats FROM bb_guildcard WHERE account_id=? LIMIT 1",
+                (account_id,)));
+            match results.next() {
+                Some(Ok(row)) => {
+                    let (gc, team, options, key, joy, shortcuts, symbols) =
+                        from_row::<(u32, u32, u32, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)>(row);
+                    Some(BbAccountInfo {
+                        account_id: account_id,
+                        guildcard_num: gc,
+                        team_id: team,
+                        options: options,
+                        key_config: key,
+                        joy_config: joy,
+                        shortcuts: shortcuts,
+                        symbol_chats: symbols
+                    })
+                },
+                Some(Err(e)) => return Err(e.into_db_error()),
+                None => None
+            }
+        };
+
+        match found {
+            Some(a) => Ok(Some(a)),
+            None => {
+                // create defaults and push them to the database
+                let mut a = BbAccountInfo::new();
+                a.account_id = account_id;
+                try!(self.put_bb_account_info(&a));
+                Ok(Some(a))
+            }
+        }
+    }
+
+    fn put_bb_account_info(&self, info: &BbAccountInfo) -> Result<()> {
+        try_db!(self.conn.borrow_mut().prep_exec("INSERT INTO bb_guildcard (id,account_id,team_id,options,key_config,joy_config,shortcuts,symbol_chats) VALUES (?,?,?,?,?,?,?,?)
+            ON DUPLICATE KEY UPDATE
+                team_id=VALUES(team_id),
+                options=VALUES(options),
+                key_config=VALUES(key_config),
+                joy_config=VALUES(joy_config),
+                shortcuts=VALUES(shortcuts),
+                symbol_chats=VALUES(symbol_chats)",
+            (info.guildcard_num, info.account_id, info.team_id, info.options, &info.key_config, &info.joy_config, &info.shortcuts, &info.symbol_chats)));
+        Ok(())
+    }
+
+    fn fetch_bb_character(&self, account_id: u32, slot: u8) -> Result<Option<BbFullCharData>> {
+        // First, fetch their BB account data.
+        let acc_info = match self.fetch_bb_account_info(account_id) {
+            Ok(Some(info)) => info,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e)
+        };
+
+        debug!("Account info for {} retrieved", account_id);
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec("SELECT
+            inventory,
+            char_data,
+            quest_data1,
+            bank,
+            guildcard_desc,
+            autoreply,
+            infoboard,
+            challenge_data,
+            tech_menu,
+            quest_data2 FROM bb_character WHERE account_id=? AND slot=?", (account_id, slot)));
+        let row = match results.next() {
+            Some(Ok(row)) => row,
+            Some(Err(e)) => return Err(e.into_db_error()),
+            None => return Ok(None)
+        };
+        let (inventory, char_data, quest_data1, bank, guildcard_desc, autoreply, infoboard, challenge_data, tech_menu, quest_data2) =
+            from_row::<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, String, String, String, Vec<u8>, Vec<u8>, Vec<u8>)>(row);
+
+        let chara: BbChar = try_db!(Serial::deserialize(&mut Cursor::new(char_data)));
+        let key_config = BbTeamAndKeyData {
+            unk: vec![0; 276],
+            key_config: acc_info.key_config.clone(),
+            joy_config: acc_info.joy_config.clone(),
+            guildcard: 0, // TODO no teams yet
+            team_id: 0,
+            team_info: (0, 0),
+            team_priv: 0,
+            team_name: "".to_string(),
+            team_flag: vec![0; 2048],
+            team_rewards: 0
+        };
+        Ok(Some(BbFullCharData {
+            inv: try_db!(Serial::deserialize(&mut Cursor::new(inventory))),
+            chara: chara.clone(),
+            unk: vec![0; 0x0010],
+            option_flags: acc_info.options,
+            quest_data1: quest_data1,
+            bank: try_db!(Serial::deserialize(&mut Cursor::new(bank))),
+            guildcard: acc_info.guildcard_num,
+            name: chara.name.clone(),
+            team_name: "".to_string(), // TODO no teams yet
+            guildcard_desc: guildcard_desc,
+            reserved1: 1,
+            reserved2: 1,
+            section: chara.section,
+            class: chara.class,
+            unk2: 0,
+            symbol_chats: acc_info.symbol_chats.clone(),
+            shortcuts: acc_info.shortcuts.clone(),
+            autoreply: autoreply,
+            infoboard: infoboard,
+            unk3: vec![0; 0x001C],
+            challenge_data: challenge_data,
+            tech_menu: tech_menu,
+            unk4: vec![0; 0x002C],
+            quest_data2: quest_data2,
+            key_config: key_config
+        }))
+    }
+
+    fn put_bb_character(&self, account_id: u32, slot: u8, chara: BbFullCharData, save_acct_data: bool) -> Result<()> {
+        // Save the options, key, joy, shortcuts, symbols, to account data
+        if save_acct_data {
+            let mut acc_info = match try!(self.fetch_bb_account_info(account_id)) {
+                Some(a) => a,
+                None => return Err(Error::NotFound(format!("bb account info for account {}", account_id)))
+            };
+            acc_info.key_config = chara.key_config.key_config.clone();
+            acc_info.joy_config = chara.key_config.joy_config.clone();
+            acc_info.symbol_chats = chara.symbol_chats.clone();
+            acc_info.shortcuts = chara.shortcuts.clone();
+            try!(self.put_bb_account_info(&acc_info));
+        }
+
+        let inventory = serial_to_vec(&chara.inv);
+        let char_data = serial_to_vec(&chara.chara);
+        let bank = serial_to_vec(&chara.bank);
+
+        // (account_id, slot) is unique, so this overwrites any character
+        // already in the slot.
+        try_db!(self.conn.borrow_mut().prep_exec("INSERT INTO bb_character (
+                account_id,
+                slot,
+                inventory,
+                char_data,
+                quest_data1,
+                bank,
+                guildcard_desc,
+                autoreply,
+                infoboard,
+                challenge_data,
+                tech_menu,
+                quest_data2
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE
+                inventory = VALUES(inventory),
+                char_data = VALUES(char_data),
+                quest_data1 = VALUES(quest_data1),
+                bank = VALUES(bank),
+                guildcard_desc = VALUES(guildcard_desc),
+                autoreply = VALUES(autoreply),
+                infoboard = VALUES(infoboard),
+                challenge_data = VALUES(challenge_data),
+                tech_menu = VALUES(tech_menu),
+                quest_data2 = VALUES(quest_data2)",
+            (account_id, slot, inventory, char_data, &chara.quest_data1, bank, &chara.guildcard_desc, &chara.autoreply,
+                &chara.infoboard, &chara.challenge_data, &chara.tech_menu, &chara.quest_data2)));
+        Ok(())
+    }
+
+    fn delete_bb_character(&self, account_id: u32, slot: u8) -> Result<bool> {
+        let mut deleted = false;
+        try!(self.transaction("character deletion", || {
+            let mut conn = self.conn.borrow_mut();
+            deleted = try_db!(conn.prep_exec("DELETE FROM bb_character WHERE account_id=? AND slot=?", (account_id, slot))).affected_rows() > 0;
+            try_db!(conn.prep_exec("DELETE FROM bb_character_name WHERE account_id=? AND slot=?", (account_id, slot)));
+            try_db!(conn.prep_exec("DELETE FROM bb_playtime WHERE account_id=? AND slot=?", (account_id, slot)));
+            Ok(())
+        }));
+        Ok(deleted)
+    }
+
+    fn fetch_bb_shared_bank(&self, account_id: u32) -> Result<ItemBank> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec("SELECT bank FROM bb_shared_bank WHERE account_id=?", (account_id,)));
+        match results.next() {
+            Some(Ok(row)) => Ok(try_db!(Serial::deserialize(&mut Cursor::new(from_row::<Vec<u8>>(row))))),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(ItemBank::default())
+        }
+    }
+
+    fn put_bb_character_and_shared_bank(&self, account_id: u32, slot: u8, chara: BbFullCharData, bank: &ItemBank) -> Result<()> {
+        self.transaction("shared bank transfer", || {
+            self.put_bb_character(account_id, slot, chara, false)
+                .and_then(|_| self.put_bb_shared_bank(account_id, bank))
+        })
+    }
+
+    fn claim_bb_character_name(&self, account_id: u32, slot: u8, name: &str) -> Result<bool> {
+        try!(self.run("START TRANSACTION"));
+        let r = {
+            let mut conn = self.conn.borrow_mut();
+            let deleted = conn.prep_exec("DELETE FROM bb_character_name WHERE account_id=? AND slot=?", (account_id, slot)).map(|_| ());
+            match deleted {
+                Ok(_) => conn.prep_exec("INSERT INTO bb_character_name (name, account_id, slot) VALUES (?, ?, ?)", (name, account_id, slot)).map(|_| ()),
+                Err(e) => Err(e)
+            }
+        };
+        let claimed = match r {
+            Ok(_) => true,
+            // The name's primary key is what settles two characters taking
+            // the same name at once.
+            Err(MyError::MySqlError(ref e)) if e.code == ER_DUP_ENTRY => false,
+            Err(e) => {
+                if let Err(re) = self.run("ROLLBACK") {
+                    error!("Couldn't roll back name claim for account {}: {}", account_id, re);
+                }
+                return Err(e.into_db_error())
+            }
+        };
+        try!(self.run(if claimed { "COMMIT" } else { "ROLLBACK" }));
+        Ok(claimed)
+    }
+
+    fn fetch_bb_rules_version(&self, account_id: u32) -> Result<u32> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec("SELECT version FROM bb_accepted_rules WHERE account_id=?", (account_id,)));
+        match results.next() {
+            Some(Ok(row)) => Ok(from_row::<u32>(row)),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(0)
+        }
+    }
+
+    fn put_bb_rules_version(&self, account_id: u32, version: u32) -> Result<()> {
+        try_db!(self.conn.borrow_mut().prep_exec(
+            "INSERT INTO bb_accepted_rules (account_id, version) VALUES (?, ?) ON DUPLICATE KEY UPDATE version=VALUES(version)",
+            (account_id, version)));
+        Ok(())
+    }
+
+    fn add_bb_playtime(&self, account_id: u32, slot: u8, seconds: u32) -> Result<()> {
+        try_db!(self.conn.borrow_mut().prep_exec(
+            "INSERT INTO bb_playtime (account_id, slot, seconds) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE seconds=seconds+VALUES(seconds)",
+            (account_id, slot, seconds)));
+        Ok(())
+    }
+
+    fn add_bb_playtimes(&self, entries: &[(u32, u8, u32)]) -> Result<()> {
+        self.transaction("batched playtime", || {
+            for &(account_id, slot, seconds) in entries {
+                try!(self.add_bb_playtime(account_id, slot, seconds));
+            }
+            Ok(())
+        })
+    }
+
+    fn fetch_bb_playtime(&self, account_id: u32, slot: u8) -> Result<(u64, u64)> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec(
+            "SELECT CAST(COALESCE(SUM(seconds), 0) AS UNSIGNED), CAST(COALESCE(SUM(CASE WHEN slot=? THEN seconds ELSE 0 END), 0) AS UNSIGNED) FROM bb_playtime WHERE account_id=?",
+            (slot, account_id)));
+        match results.next() {
+            Some(Ok(row)) => Ok(from_row::<(u64, u64)>(row)),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok((0, 0))
+        }
+    }
+
+    fn set_bb_login_flags(&self, account_id: u32, flags: u32) -> Result<()> {
+        try_db!(self.conn.borrow_mut().prep_exec(
+            "INSERT INTO bb_account_flags (account_id, login_flags) VALUES (?, ?) ON DUPLICATE KEY UPDATE login_flags=VALUES(login_flags)",
+            (account_id, flags)));
+        Ok(())
+    }
+
+    fn get_bb_login_flags(&self, account_id: u32) -> Result<u32> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec("SELECT login_flags FROM bb_account_flags WHERE account_id=?", (account_id,)));
+        match results.next() {
+            Some(Ok(row)) => Ok(from_row::<u32>(row)),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(0)
+        }
+    }
+
+    fn fetch_bb_ban(&self, guildcard: u32) -> Result<Option<BbBan>> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = try_db!(conn.prep_exec("SELECT reason, expires FROM bb_bans WHERE guildcard=?", (guildcard,)));
+        match results.next() {
+            Some(Ok(row)) => {
+                let (reason, expires) = from_row::<(String, Option<u64>)>(row);
+                Ok(Some(BbBan {
+                    guildcard: guildcard,
+                    reason: reason,
+                    expires: expires
+                }))
+            },
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(None)
+        }
+    }
+
+    fn put_bb_chat_logs(&self, lines: &[ChatLine]) -> Result<()> {
+        self.transaction("batched chat log", || {
+            for l in lines {
+                try_db!(self.conn.borrow_mut().prep_exec(
+                    "INSERT INTO chat_log (guildcard, target, block, lobby, time, text) VALUES (?, ?, ?, ?, ?, ?)",
+                    (l.guildcard, l.target, l.block, l.lobby, l.time, &*l.text)));
+            }
+            Ok(())
+        })
+    }
+}
+
+fn serial_to_vec<S: Serial>(i: &S) -> Vec<u8> {
+    let mut cursor = Cursor::new(Vec::new());
+    i.serialize(&mut cursor).unwrap();
+    cursor.into_inner()
+}
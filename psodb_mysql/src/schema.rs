@@ -0,0 +1,86 @@
+/// The tables, one statement each since the server won't take several at
+/// once. They match the Sqlite backend's.
+pub static SCHEMA: &'static [&'static str] = &["
+CREATE TABLE IF NOT EXISTS version (
+    version INT PRIMARY KEY
+)", "
+INSERT IGNORE INTO version (version) VALUES (0)
+", "
+CREATE TABLE IF NOT EXISTS accounts (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    username VARCHAR(255) UNIQUE NOT NULL,
+    password_hash VARCHAR(255) NOT NULL,
+    password_invalidated TINYINT NOT NULL DEFAULT 0,
+    banned TINYINT NOT NULL DEFAULT 0
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_guildcard (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    account_id INT UNSIGNED UNIQUE NOT NULL,
+    team_id INT UNSIGNED NOT NULL DEFAULT 1,
+    options INT UNSIGNED NOT NULL DEFAULT 0,
+    key_config BLOB NOT NULL,
+    joy_config BLOB NOT NULL,
+    shortcuts BLOB NOT NULL,
+    symbol_chats BLOB NOT NULL
+) ENGINE=InnoDB AUTO_INCREMENT=400000000", "
+CREATE TABLE IF NOT EXISTS bb_team (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    name VARCHAR(255) NOT NULL
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_character (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    account_id INT UNSIGNED NOT NULL DEFAULT 0,
+    slot TINYINT UNSIGNED NOT NULL DEFAULT 0,
+    inventory BLOB NOT NULL,
+    char_data BLOB NOT NULL,
+    quest_data1 BLOB NOT NULL,
+    bank MEDIUMBLOB NOT NULL,
+    guildcard_desc TEXT NOT NULL,
+    autoreply TEXT NOT NULL,
+    infoboard TEXT NOT NULL,
+    challenge_data BLOB NOT NULL,
+    tech_menu BLOB NOT NULL,
+    quest_data2 BLOB NOT NULL,
+    UNIQUE (account_id, slot)
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_shared_bank (
+    account_id INT UNSIGNED PRIMARY KEY,
+    bank MEDIUMBLOB NOT NULL
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_character_name (
+    name VARCHAR(32) PRIMARY KEY,
+    account_id INT UNSIGNED NOT NULL,
+    slot TINYINT UNSIGNED NOT NULL,
+    UNIQUE (account_id, slot)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci", "
+CREATE TABLE IF NOT EXISTS bb_accepted_rules (
+    account_id INT UNSIGNED PRIMARY KEY,
+    version INT UNSIGNED NOT NULL
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_playtime (
+    account_id INT UNSIGNED NOT NULL,
+    slot TINYINT UNSIGNED NOT NULL,
+    seconds BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, slot)
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_account_flags (
+    account_id INT UNSIGNED PRIMARY KEY,
+    login_flags INT UNSIGNED NOT NULL DEFAULT 0
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS bb_bans (
+    guildcard INT UNSIGNED PRIMARY KEY,
+    reason TEXT NOT NULL,
+    expires BIGINT UNSIGNED NULL
+) ENGINE=InnoDB", "
+CREATE TABLE IF NOT EXISTS chat_log (
+    id BIGINT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    guildcard INT UNSIGNED NOT NULL,
+    target INT UNSIGNED NOT NULL DEFAULT 0,
+    block SMALLINT UNSIGNED NOT NULL,
+    lobby TINYINT UNSIGNED NOT NULL,
+    time BIGINT UNSIGNED NOT NULL,
+    text TEXT NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4"];
+
+/// Tables `maintain` optimizes.
+pub static TABLES: &'static str = "accounts, bb_guildcard, bb_team, bb_character, bb_shared_bank, bb_character_name, bb_accepted_rules, bb_playtime, bb_account_flags, bb_bans, chat_log";
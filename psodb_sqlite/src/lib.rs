@@ -16,22 +16,66 @@ use psodb_common::error::Error;
 
 use psodb_common::account::Account;
 use psodb_common::account::BbAccountInfo;
+use psodb_common::account::BbBan;
+use psodb_common::account::ChatLine;
 
-use psodata::chara::{BbFullCharData, BbTeamAndKeyData, BbChar};
+use psodata::chara::{BbFullCharData, BbTeamAndKeyData, BbChar, ItemBank};
 
 mod schema;
-use self::schema::SCHEMA;
+use self::schema::MIGRATIONS;
 
 #[cfg(test)] mod test;
 
+/// Sqlite's result code for a violated constraint.
+const SQLITE_CONSTRAINT: i32 = 19;
+/// Sqlite's result codes for failing to read or write the database file.
+const SQLITE_IOERR: i32 = 10;
+const SQLITE_CANTOPEN: i32 = 14;
+
 use rusqlite::Connection;
 use rusqlite::types::ToSql;
 
+/// The primary result code of a failed sqlite call, if `e` is one.
+fn result_code(e: &rusqlite::Error) -> Option<i32> {
+    match e {
+        &rusqlite::Error::SqliteFailure(ref f, _) => Some(f.extended_code & 0xff),
+        _ => None
+    }
+}
+
+/// Errors the backend can run into, turned into the Backend's error. Losing
+/// the database file partway through is `ConnectionLost`, so it's retried
+/// on a reopened connection.
+trait IntoDbError {
+    fn into_db_error(self) -> Error;
+}
+
+impl IntoDbError for rusqlite::Error {
+    fn into_db_error(self) -> Error {
+        match result_code(&self) {
+            Some(SQLITE_IOERR) | Some(SQLITE_CANTOPEN) => Error::ConnectionLost(Some(Box::new(self))),
+            _ => Error::BackendError(Some(Box::new(self)))
+        }
+    }
+}
+
+impl IntoDbError for std::io::Error {
+    fn into_db_error(self) -> Error {
+        Error::BackendError(Some(Box::new(self)))
+    }
+}
+
+impl IntoDbError for Error {
+    fn into_db_error(self) -> Error {
+        self
+    }
+}
+
 macro_rules! try_db {
     ($e:expr) => {
         match $e {
             Ok(s) => s,
-            Err(e) => return Err(Error::BackendError(Some(Box::new(e))))
+            Err(e) => return Err(e.into_db_error())
         }
     }
 }
@@ -43,19 +87,12 @@ pub struct Sqlite {
 }
 
 impl Sqlite {
-    /// Create a new Sqlite instance, initializing the schema of the database and applying
-    /// all migrations needed to update it. The `new` argument allows you to initialize a new
-    /// database up to the current schema, rather than checking and migrating the database.
-    ///
-    /// This is a destructive operation. Reverse migrations may destroy data from the database.
-    pub fn new<T: Into<String>>(path: T, new: bool) -> Result<Sqlite> {
+    /// Create a new Sqlite instance, creating the database if it doesn't exist and applying
+    /// all migrations needed to bring its schema up to date.
+    pub fn new<T: Into<String>>(path: T) -> Result<Sqlite> {
         let p = path.into();
         let conn = try_db!(Connection::open(&p));
-        if new {
-            try_db!(Sqlite::initialize_tables(&conn));
-        } else {
-            try_db!(Sqlite::migrate(&conn, 0));
-        }
+        try!(Sqlite::migrate(&conn));
 
         Ok(Sqlite {
             path: p,
@@ -63,22 +100,63 @@ impl Sqlite {
         })
     }
 
-    /// Initialize and update tables
-    fn initialize_tables(c: &Connection) -> Result<()> {
+    /// Apply the migrations the database hasn't had yet, returning how many there were.
+    fn migrate(c: &Connection) -> Result<usize> {
+        try_db!(c.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY NOT NULL);"));
+        let current = try_db!(c.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[], |r| r.get::<i64>(0))) as usize;
+        if current > MIGRATIONS.len() {
+            return Err(Error::Other(format!("database schema version {} is newer than the {} migrations known", current, MIGRATIONS.len()), None))
+        }
+        for (i, m) in MIGRATIONS.iter().enumerate().skip(current) {
+            let version = i + 1;
+            let batch = format!("BEGIN;\n{}\nINSERT INTO schema_version (version) VALUES ({});\nCOMMIT;", m, version);
+            if let Err(e) = c.execute_batch(&batch) {
+                let _ = c.execute_batch("ROLLBACK;");
+                return Err(Error::Other(format!("database schema migration {} failed", version), Some(Box::new(e))))
+            }
+            info!("Applied database schema migration {}", version);
+        }
+        Ok(MIGRATIONS.len() - current)
+    }
 
-        try_db!(c.execute_batch(SCHEMA));
+    fn put_bb_shared_bank(&self, account_id: u32, bank: &ItemBank) -> Result<()> {
+        let mut stmt = try_db!(self.conn.prepare("INSERT OR REPLACE INTO bb_shared_bank (account_id, bank) VALUES (?, ?)"));
+        let aid = account_id as i64;
+        let b = serial_to_vec(bank);
+        try_db!(stmt.execute(&[&aid, &b]));
         Ok(())
     }
 
-    /// Migrate the database
-    fn migrate(_: &Connection, _: i64) -> Result<()> {
-        //let version = try_db!(c.query_row("SELECT version FROM version LIMIT 1"), &[], |r| r.get::<i64>(0));
+    fn put_chat_lines(&self, lines: &[ChatLine]) -> Result<()> {
+        let mut stmt = try_db!(self.conn.prepare("INSERT INTO chat_log (guildcard, target, block, lobby, time, text) VALUES (?, ?, ?, ?, ?, ?)"));
+        for l in lines {
+            let gc = l.guildcard as i64;
+            let target = l.target as i64;
+            let b = l.block as i64;
+            let lobby = l.lobby as i64;
+            let t = l.time as i64;
+            try_db!(stmt.execute(&[&gc, &target, &b, &lobby, &t, &l.text]));
+        }
         Ok(())
     }
 
-    // fn reverse_migrations(c: &Connection, v: i64) -> Result<()> {
-    //     Ok(())
-    // }
+    /// Size of the database file in bytes.
+    fn size(&self) -> Result<u64> {
+        let mut pages = Vec::new();
+        for pragma in &["PRAGMA page_count", "PRAGMA page_size"] {
+            let mut stmt = try_db!(self.conn.prepare(pragma));
+            let mut results = try_db!(stmt.query_map(&[], |row| {
+                row.get::<i64>(0)
+            }));
+            match results.next() {
+                Some(Ok(v)) => pages.push(v as u64),
+                Some(Err(e)) => return Err(e.into_db_error()),
+                None => pages.push(0)
+            }
+        }
+        Ok(pages[0] * pages[1])
+    }
+
 }
 
 
@@ -95,6 +173,28 @@ impl Backend for Sqlite {
         }))
     }
 
+    fn ping(&self) -> Result<()> {
+        match self.conn.execute_batch("SELECT 1") {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::ConnectionLost(Some(Box::new(e))))
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.conn = match Connection::open(&self.path) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::ConnectionLost(Some(Box::new(e))))
+        };
+        Ok(())
+    }
+
+    fn maintain(&self) -> Result<(u64, u64)> {
+        let before = try!(self.size());
+        try_db!(self.conn.execute_batch("VACUUM; ANALYZE;"));
+        let after = try!(self.size());
+        Ok((before, after))
+    }
+
     fn get_account_by_id(&self, id: u32) -> Result<Option<Account>> {
         let id = id as i64;
         let mut stmt = try_db!(self.conn.prepare(
@@ -112,7 +212,7 @@ impl Backend for Sqlite {
         }));
         match results.next() {
             Some(Ok(a)) => Ok(Some(a)),
-            Some(Err(e)) => Err(Error::BackendError(Some(Box::new(e)))),
+            Some(Err(e)) => Err(e.into_db_error()),
             None => Ok(None)
         }
     }
@@ -133,7 +233,7 @@ impl Backend for Sqlite {
         }));
         match results.next() {
             Some(Ok(a)) => Ok(Some(a)),
-            Some(Err(e)) => Err(Error::BackendError(Some(Box::new(e)))),
+            Some(Err(e)) => Err(e.into_db_error()),
             None => Ok(None)
         }
     }
@@ -142,7 +242,7 @@ impl Backend for Sqlite {
         match account.id {
             Some(id) => {
                 let id = id as i64;
-                let mut stmt = try_db!(self.conn.prepare("UPDATE accounts SET (username=?,password_hash=?,password_invalidated=?,banned=?) WHERE id=?"));
+                let mut stmt = try_db!(self.conn.prepare("UPDATE accounts SET username=?,password_hash=?,password_invalidated=?,banned=? WHERE id=?"));
                 try_db!(stmt.execute(&[&account.username, &account.password_hash, &b2i(account.password_invalidated), &b2i(account.banned), &id]));
                 Ok(())
             },
@@ -180,7 +280,7 @@ impl Backend for Sqlite {
 
         match results.next() {
             Some(Ok(a)) => Ok(Some(a)),
-            Some(Err(e)) => Err(Error::BackendError(Some(Box::new(e)))),
+            Some(Err(e)) => Err(e.into_db_error()),
             None => {
                 // create defaults and push them to the database
                 let mut a = BbAccountInfo::new();
@@ -269,22 +369,25 @@ impl Backend for Sqlite {
         })
         {
             Ok(v) => v,
-            Err(e) => return Err(Error::BackendError(Some(Box::new(e))))
+            Err(e) => return Err(e.into_db_error())
         };
 
         debug!("Character lookup results: {}", results.size_hint().0);
 
         match results.next() {
             Some(Ok(Ok(c))) => Ok(Some(c)),
-            Some(Ok(Err(e))) => Err(Error::BackendError(Some(Box::new(e)))),
-            Some(Err(e)) => Err(Error::BackendError(Some(Box::new(e)))),
+            Some(Ok(Err(e))) => Err(e.into_db_error()),
+            Some(Err(e)) => Err(e.into_db_error()),
             None => Ok(None)
         }
     }
 
     fn put_bb_character(&self, account_id: u32, slot: u8, chara: BbFullCharData, save_acct_data: bool) -> Result<()> {
         // First, we need the existing account information
-        let mut acc_info = try_db!(self.fetch_bb_account_info(account_id)).unwrap();
+        let mut acc_info = match try_db!(self.fetch_bb_account_info(account_id)) {
+            Some(a) => a,
+            None => return Err(Error::NotFound(format!("bb account info for account {}", account_id)))
+        };
 
         // Save the options, key, joy, shortcuts, symbols, to account data
         if save_acct_data {
@@ -300,10 +403,12 @@ impl Backend for Sqlite {
         let exists;
         match stmt.query(&[&(account_id as i64), &(slot as i64)]) {
             Ok(rows) => {
-                info!("Character at {} exists for account {}; overwriting", slot, account_id);
                 exists = rows.count() >= 1;
+                if exists {
+                    info!("Character at {} exists for account {}; overwriting", slot, account_id);
+                }
             },
-            Err(e) => return Err(Error::BackendError(Some(Box::new(e))))
+            Err(e) => return Err(e.into_db_error())
         }
 
         // Build the params array (bind the values to bind their lifetime for borrows)
@@ -377,6 +482,140 @@ impl Backend for Sqlite {
         Ok(())
     }
 
+    fn delete_bb_character(&self, account_id: u32, slot: u8) -> Result<bool> {
+        let aid = account_id as i64;
+        let s = slot as i64;
+        try_db!(self.conn.execute_batch("BEGIN"));
+        let r = self.conn.execute("DELETE FROM bb_character WHERE account_id=? AND slot=?", &[&aid, &s])
+            .and_then(|n| self.conn.execute("DELETE FROM bb_character_name WHERE account_id=? AND slot=?", &[&aid, &s]).map(|_| n > 0))
+            .and_then(|d| self.conn.execute("DELETE FROM bb_playtime WHERE account_id=? AND slot=?", &[&aid, &s]).map(|_| d));
+        match r {
+            Ok(deleted) => {
+                try_db!(self.conn.execute_batch("COMMIT"));
+                Ok(deleted)
+            },
+            Err(e) => {
+                if let Err(re) = self.conn.execute_batch("ROLLBACK") {
+                    error!("Couldn't roll back deleting character slot {} for account {}: {}", slot, account_id, re);
+                }
+                Err(e.into_db_error())
+            }
+        }
+    }
+
+    fn fetch_bb_shared_bank(&self, account_id: u32) -> Result<ItemBank> {
+        let mut stmt = try_db!(self.conn.prepare("SELECT bank FROM bb_shared_bank WHERE account_id=?"));
+        let aid = account_id as i64;
+        let mut results = try_db!(stmt.query_map(&[&aid], |row| {
+            row.get::<Vec<u8>>(0)
+        }));
+        match results.next() {
+            Some(Ok(b)) => Ok(try_db!(Serial::deserialize(&mut Cursor::new(b)))),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(ItemBank::default())
+        }
+    }
+
+    fn put_bb_character_and_shared_bank(&self, account_id: u32, slot: u8, chara: BbFullCharData, bank: &ItemBank) -> Result<()> {
+        try_db!(self.conn.execute_batch("BEGIN"));
+        let r = self.put_bb_character(account_id, slot, chara, false)
+            .and_then(|_| self.put_bb_shared_bank(account_id, bank));
+        match r {
+            Ok(_) => {
+                try_db!(self.conn.execute_batch("COMMIT"));
+                Ok(())
+            },
+            Err(e) => {
+                if let Err(re) = self.conn.execute_batch("ROLLBACK") {
+                    error!("Couldn't roll back shared bank transfer for account {}: {}", account_id, re);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn claim_bb_character_name(&self, account_id: u32, slot: u8, name: &str) -> Result<bool> {
+        let aid = account_id as i64;
+        let s = slot as i64;
+        try_db!(self.conn.execute_batch("BEGIN"));
+        let r = self.conn.execute("DELETE FROM bb_character_name WHERE account_id=? AND slot=?", &[&aid, &s])
+            .and_then(|_| self.conn.execute("INSERT INTO bb_character_name (name, account_id, slot) VALUES (?, ?, ?)", &[&name, &aid, &s]));
+        let claimed = match r {
+            Ok(_) => true,
+            // The name's primary key is what settles two characters taking
+            // the same name at once.
+            Err(ref e) if result_code(e) == Some(SQLITE_CONSTRAINT) => false,
+            Err(e) => {
+                if let Err(re) = self.conn.execute_batch("ROLLBACK") {
+                    error!("Couldn't roll back name claim for account {}: {}", account_id, re);
+                }
+                return Err(e.into_db_error())
+            }
+        };
+        try_db!(self.conn.execute_batch(if claimed { "COMMIT" } else { "ROLLBACK" }));
+        Ok(claimed)
+    }
+
+    fn fetch_bb_rules_version(&self, account_id: u32) -> Result<u32> {
+        let mut stmt = try_db!(self.conn.prepare("SELECT version FROM bb_accepted_rules WHERE account_id=?"));
+        let aid = account_id as i64;
+        let mut results = try_db!(stmt.query_map(&[&aid], |row| {
+            row.get::<i64>(0)
+        }));
+        match results.next() {
+            Some(Ok(v)) => Ok(v as u32),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(0)
+        }
+    }
+
+    fn put_bb_rules_version(&self, account_id: u32, version: u32) -> Result<()> {
+        let mut stmt = try_db!(self.conn.prepare("INSERT OR REPLACE INTO bb_accepted_rules (account_id, version) VALUES (?, ?)"));
+        let aid = account_id as i64;
+        let v = version as i64;
+        try_db!(stmt.execute(&[&aid, &v]));
+        Ok(())
+    }
+
+    fn add_bb_playtime(&self, account_id: u32, slot: u8, seconds: u32) -> Result<()> {
+        let mut stmt = try_db!(self.conn.prepare("INSERT OR IGNORE INTO bb_playtime (account_id, slot, seconds) VALUES (?, ?, 0)"));
+        let aid = account_id as i64;
+        let s = slot as i64;
+        let secs = seconds as i64;
+        try_db!(stmt.execute(&[&aid, &s]));
+        let mut stmt = try_db!(self.conn.prepare("UPDATE bb_playtime SET seconds=seconds+? WHERE account_id=? AND slot=?"));
+        try_db!(stmt.execute(&[&secs, &aid, &s]));
+        Ok(())
+    }
+
+    fn add_bb_playtimes(&self, entries: &[(u32, u8, u32)]) -> Result<()> {
+        try_db!(self.conn.execute_batch("BEGIN"));
+        for &(account_id, slot, seconds) in entries {
+            if let Err(e) = self.add_bb_playtime(account_id, slot, seconds) {
+                if let Err(re) = self.conn.execute_batch("ROLLBACK") {
+                    error!("Couldn't roll back batched playtime: {}", re);
+                }
+                return Err(e)
+            }
+        }
+        try_db!(self.conn.execute_batch("COMMIT"));
+        Ok(())
+    }
+
+    fn fetch_bb_playtime(&self, account_id: u32, slot: u8) -> Result<(u64, u64)> {
+        let mut stmt = try_db!(self.conn.prepare("SELECT COALESCE(SUM(seconds), 0), COALESCE(SUM(CASE WHEN slot=? THEN seconds ELSE 0 END), 0) FROM bb_playtime WHERE account_id=?"));
+        let aid = account_id as i64;
+        let s = slot as i64;
+        let mut results = try_db!(stmt.query_map(&[&s, &aid], |row| {
+            (row.get::<i64>(0), row.get::<i64>(1))
+        }));
+        match results.next() {
+            Some(Ok((a, c))) => Ok((a as u64, c as u64)),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok((0, 0))
+        }
+    }
+
     fn set_bb_login_flags(&self, account_id: u32, flags: u32) -> Result<()> {
         let mut stmt = try_db!(self.conn.prepare("INSERT OR UPDATE INTO bb_flags (account_id,login_flags) VALUES (?,?)"));
         let aid = account_id as i64;
@@ -393,10 +632,39 @@ impl Backend for Sqlite {
         }));
         match results.next() {
             Some(Ok(f)) => Ok(f as u32),
-            Some(Err(e)) => Err(Error::BackendError(Some(Box::new(e)))),
+            Some(Err(e)) => Err(e.into_db_error()),
             None => Ok(0)
         }
     }
+
+    fn fetch_bb_ban(&self, guildcard: u32) -> Result<Option<BbBan>> {
+        let mut stmt = try_db!(self.conn.prepare("SELECT reason, expires FROM bb_bans WHERE guildcard=?"));
+        let gc = guildcard as i64;
+        let mut results = try_db!(stmt.query_map(&[&gc], |row| {
+            (row.get::<String>(0), row.get::<Option<i64>>(1))
+        }));
+        match results.next() {
+            Some(Ok((reason, expires))) => Ok(Some(BbBan {
+                guildcard: guildcard,
+                reason: reason,
+                expires: expires.map(|e| e as u64)
+            })),
+            Some(Err(e)) => Err(e.into_db_error()),
+            None => Ok(None)
+        }
+    }
+
+    fn put_bb_chat_logs(&self, lines: &[ChatLine]) -> Result<()> {
+        try_db!(self.conn.execute_batch("BEGIN"));
+        if let Err(e) = self.put_chat_lines(lines) {
+            if let Err(re) = self.conn.execute_batch("ROLLBACK") {
+                error!("Couldn't roll back batched chat log: {}", re);
+            }
+            return Err(e)
+        }
+        try_db!(self.conn.execute_batch("COMMIT"));
+        Ok(())
+    }
 }
 
 fn serial_to_vec<S: Serial>(i: &S) -> Vec<u8> {
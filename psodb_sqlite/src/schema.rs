@@ -1,11 +1,12 @@
-pub static SCHEMA: &'static str = "
-BEGIN;
-
-CREATE TABLE IF NOT EXISTS version (
-    version INTEGER PRIMARY KEY
-);
-INSERT OR REPLACE INTO version (version) VALUES (0);
-
+/// Schema changes in the order they're applied. The `schema_version` table
+/// records which a database has had, and each runs once, in its own
+/// transaction. Only ever add to the end.
+///
+/// Databases made before migrations were tracked already have some of these
+/// tables, so they're all created only if they don't exist.
+pub static MIGRATIONS: &'static [&'static str] = &[
+    // 1: The original tables.
+    "
 CREATE TABLE IF NOT EXISTS accounts (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     username TEXT UNIQUE NOT NULL,
@@ -50,8 +51,57 @@ CREATE TABLE IF NOT EXISTS bb_account_flags (
     account_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
     login_flags INTEGER NOT NULL DEFAULT 0
 );
-
-COMMIT;
-";
-
-//pub static MIGRATIONS: [&'static str] = [""];
+",
+    // 2: Shared banks.
+    "
+CREATE TABLE IF NOT EXISTS bb_shared_bank (
+    account_id INTEGER PRIMARY KEY NOT NULL,
+    bank BLOB NOT NULL
+);
+",
+    // 3: Claimed character names, for unique names.
+    "
+CREATE TABLE IF NOT EXISTS bb_character_name (
+    name TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+    account_id INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    UNIQUE (account_id, slot)
+);
+",
+    // 4: Accepted server rules.
+    "
+CREATE TABLE IF NOT EXISTS bb_accepted_rules (
+    account_id INTEGER PRIMARY KEY NOT NULL,
+    version INTEGER NOT NULL
+);
+",
+    // 5: Play time.
+    "
+CREATE TABLE IF NOT EXISTS bb_playtime (
+    account_id INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    seconds INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, slot)
+);
+",
+    // 6: Guild card bans.
+    "
+CREATE TABLE IF NOT EXISTS bb_bans (
+    guildcard INTEGER PRIMARY KEY NOT NULL,
+    reason TEXT NOT NULL DEFAULT '',
+    expires INTEGER
+);
+",
+    // 7: Chat kept for moderation.
+    "
+CREATE TABLE IF NOT EXISTS chat_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    guildcard INTEGER NOT NULL,
+    target INTEGER NOT NULL DEFAULT 0,
+    block INTEGER NOT NULL,
+    lobby INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    text TEXT NOT NULL
+);
+"
+];
@@ -1,21 +1,31 @@
+use std::io::Cursor;
+
+use rusqlite::Connection;
+use psoserial::Serial;
+use psodata::chara::BbFullCharData;
+
 use super::Sqlite;
-use super::super::Backend;
+use super::schema::MIGRATIONS;
+use psodb_common::Backend;
 use psodb_common::account::Account;
+use psodb_common::account::ChatLine;
+use psodb_common::error::Error;
+use psodb_common::pool::Pool;
 
 #[test]
 fn create_account() {
-    let s = Sqlite::new(":memory:", new).unwrap();
+    let s = Sqlite::new(":memory:").unwrap();
 
-    let mut a = Account::new("testuser", "testpassword", "pourthesalt");
+    let mut a = Account::new("testuser", "testpassword");
 
     s.put_account(&mut a).unwrap();
 }
 
 #[test]
 fn fetch_account_by_id() {
-    let s = Sqlite::new(":memory:", new).unwrap();
+    let s = Sqlite::new(":memory:").unwrap();
 
-    let mut a = Account::new("testuser", "testpassword", "pourthesalt");
+    let mut a = Account::new("testuser", "testpassword");
 
     s.put_account(&mut a).unwrap();
 
@@ -30,9 +40,9 @@ fn fetch_account_by_id() {
 
 #[test]
 fn fetch_account_by_username() {
-    let s = Sqlite::new(":memory:", new).unwrap();
+    let s = Sqlite::new(":memory:").unwrap();
 
-    let mut a = Account::new("testuser", "testpassword", "pourthesalt");
+    let mut a = Account::new("testuser", "testpassword");
 
     s.put_account(&mut a).unwrap();
 
@@ -42,3 +52,205 @@ fn fetch_account_by_username() {
 
     assert_eq!(a.id, Some(id));
 }
+
+#[test]
+fn claim_bb_character_name() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    assert!(s.claim_bb_character_name(1, 0, "Rico").unwrap());
+    // Names collide regardless of case.
+    assert!(!s.claim_bb_character_name(2, 0, "RICO").unwrap());
+    // Reclaiming your own name is fine, and renaming frees the old one.
+    assert!(s.claim_bb_character_name(1, 0, "Rico").unwrap());
+    assert!(s.claim_bb_character_name(1, 0, "Flowen").unwrap());
+    assert!(s.claim_bb_character_name(2, 0, "Rico").unwrap());
+}
+
+#[test]
+fn bb_rules_version() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    assert_eq!(s.fetch_bb_rules_version(1).unwrap(), 0);
+    s.put_bb_rules_version(1, 2).unwrap();
+    assert_eq!(s.fetch_bb_rules_version(1).unwrap(), 2);
+    s.put_bb_rules_version(1, 3).unwrap();
+    assert_eq!(s.fetch_bb_rules_version(1).unwrap(), 3);
+}
+
+#[test]
+fn update_account() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    let mut a = Account::new("testuser", "testpassword");
+    s.put_account(&mut a).unwrap();
+    let id = a.id.unwrap();
+
+    a.banned = true;
+    s.put_account(&mut a).unwrap();
+
+    let a = s.get_account_by_id(id).unwrap().unwrap();
+    assert!(a.banned);
+}
+
+#[test]
+fn maintain() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    s.add_bb_playtime(1, 0, 60).unwrap();
+    let (before, after) = s.maintain().unwrap();
+    assert!(before > 0 && after > 0);
+    assert_eq!(s.fetch_bb_playtime(1, 0).unwrap(), (60, 60));
+}
+
+#[test]
+fn bb_playtime() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    assert_eq!(s.fetch_bb_playtime(1, 0).unwrap(), (0, 0));
+    s.add_bb_playtime(1, 0, 60).unwrap();
+    s.add_bb_playtime(1, 0, 30).unwrap();
+    s.add_bb_playtime(1, 2, 100).unwrap();
+    s.add_bb_playtime(2, 0, 5).unwrap();
+    assert_eq!(s.fetch_bb_playtime(1, 0).unwrap(), (190, 90));
+    assert_eq!(s.fetch_bb_playtime(1, 2).unwrap(), (190, 100));
+    assert_eq!(s.fetch_bb_playtime(1, 1).unwrap(), (190, 0));
+
+    s.add_bb_playtimes(&[(1, 1, 10), (2, 0, 15)]).unwrap();
+    assert_eq!(s.fetch_bb_playtime(1, 1).unwrap(), (200, 10));
+    assert_eq!(s.fetch_bb_playtime(2, 0).unwrap(), (20, 20));
+}
+
+fn to_vec<S: Serial>(s: &S) -> Vec<u8> {
+    let mut c = Cursor::new(Vec::new());
+    s.serialize(&mut c).unwrap();
+    c.into_inner()
+}
+
+#[test]
+fn bb_character_round_trip() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    // Nothing is in the slot until the first save inserts it.
+    assert!(s.fetch_bb_character(1, 2).unwrap().is_none());
+
+    let mut fc = BbFullCharData::default();
+    fc.chara.level = 9;
+    fc.chara.meseta = 1200;
+    fc.infoboard = "back soon".to_string();
+    s.put_bb_character(1, 2, fc.clone(), false).unwrap();
+    let loaded = s.fetch_bb_character(1, 2).unwrap().unwrap();
+    assert_eq!(to_vec(&loaded.chara), to_vec(&fc.chara));
+    assert_eq!(to_vec(&loaded.inv), to_vec(&fc.inv));
+    assert_eq!(loaded.infoboard, "back soon");
+
+    // Saving again overwrites the row rather than adding another.
+    fc.chara.level = 10;
+    s.put_bb_character(1, 2, fc.clone(), false).unwrap();
+    let loaded = s.fetch_bb_character(1, 2).unwrap().unwrap();
+    assert_eq!(loaded.chara.level, 10);
+    assert!(s.fetch_bb_character(1, 1).unwrap().is_none());
+}
+
+#[test]
+fn delete_bb_character() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    let mut fc = BbFullCharData::default();
+    fc.chara.level = 20;
+    s.put_bb_character(1, 0, fc.clone(), false).unwrap();
+    s.put_bb_character(1, 1, fc, false).unwrap();
+    assert!(s.claim_bb_character_name(1, 0, "Rico").unwrap());
+    s.add_bb_playtime(1, 0, 60).unwrap();
+
+    assert!(s.delete_bb_character(1, 0).unwrap());
+    assert!(s.fetch_bb_character(1, 0).unwrap().is_none());
+    assert_eq!(s.fetch_bb_playtime(1, 0).unwrap(), (0, 0));
+    // The name is free again, and the other slot is untouched.
+    assert!(s.claim_bb_character_name(2, 0, "Rico").unwrap());
+    assert!(s.fetch_bb_character(1, 1).unwrap().is_some());
+
+    // Deleting an empty slot does nothing.
+    assert!(!s.delete_bb_character(1, 0).unwrap());
+    assert!(!s.delete_bb_character(3, 2).unwrap());
+}
+
+#[test]
+fn put_bb_chat_logs() {
+    let s = Sqlite::new(":memory:").unwrap();
+
+    let line = ChatLine {
+        guildcard: 42000001,
+        target: 0,
+        block: 1,
+        lobby: 3,
+        time: 1_500_000_000,
+        text: "Hello".to_string()
+    };
+    let whisper = ChatLine { target: 42000002, time: 1_500_000_010, text: "hi".to_string(), ..line.clone() };
+    s.put_bb_chat_logs(&[line, whisper]).unwrap();
+
+    let mut stmt = s.conn.prepare("SELECT guildcard, target, block, lobby, time, text FROM chat_log ORDER BY id").unwrap();
+    let rows: Vec<(i64, i64, i64, i64, i64, String)> = stmt.query_map(&[], |r| {
+        (r.get(0), r.get(1), r.get(2), r.get(3), r.get(4), r.get(5))
+    }).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(rows, vec![
+        (42000001, 0, 1, 3, 1_500_000_000, "Hello".to_string()),
+        (42000001, 42000002, 1, 3, 1_500_000_010, "hi".to_string())
+    ]);
+}
+
+#[test]
+fn pool_retries_lost_connection() {
+    let mut s = Sqlite::new(":memory:").unwrap();
+    let pool = Pool::new(2, &mut s).unwrap();
+
+    let mut calls = 0;
+    let v = pool.run(|db| {
+        calls += 1;
+        if calls == 1 {
+            Err(Error::ConnectionLost(None))
+        } else {
+            db.fetch_bb_rules_version(1)
+        }
+    });
+    assert_eq!(v.unwrap(), 0);
+    assert_eq!(calls, 2);
+
+    // Only once, and only for errors worth retrying.
+    let mut calls = 0;
+    assert!(pool.run(|_| -> Result<(), Error> { calls += 1; Err(Error::ConnectionLost(None)) }).is_err());
+    assert_eq!(calls, 2);
+    let mut calls = 0;
+    assert!(pool.run(|_| -> Result<(), Error> { calls += 1; Err(Error::NotFound("x".to_string())) }).is_err());
+    assert_eq!(calls, 1);
+    let mut calls = 0;
+    assert!(pool.run_once(|_| -> Result<(), Error> { calls += 1; Err(Error::ConnectionLost(None)) }).is_err());
+    assert_eq!(calls, 1);
+}
+
+fn schema_version(c: &Connection) -> i64 {
+    c.query_row("SELECT MAX(version) FROM schema_version", &[], |r| r.get::<i64>(0)).unwrap()
+}
+
+#[test]
+fn migrate_empty_to_current() {
+    let c = Connection::open(":memory:").unwrap();
+
+    assert_eq!(Sqlite::migrate(&c).unwrap(), MIGRATIONS.len());
+    assert_eq!(schema_version(&c), MIGRATIONS.len() as i64);
+    // Already current, so nothing happens.
+    assert_eq!(Sqlite::migrate(&c).unwrap(), 0);
+    assert_eq!(schema_version(&c), MIGRATIONS.len() as i64);
+}
+
+#[test]
+fn migrate_resumes() {
+    let c = Connection::open(":memory:").unwrap();
+    c.execute_batch("CREATE TABLE schema_version (version INTEGER PRIMARY KEY NOT NULL);").unwrap();
+    c.execute_batch(MIGRATIONS[0]).unwrap();
+    c.execute_batch("INSERT INTO schema_version (version) VALUES (1);").unwrap();
+
+    assert_eq!(Sqlite::migrate(&c).unwrap(), MIGRATIONS.len() - 1);
+    assert_eq!(schema_version(&c), MIGRATIONS.len() as i64);
+    c.execute_batch("INSERT INTO bb_bans (guildcard, reason) VALUES (42000001, 'test');").unwrap();
+}
@@ -20,6 +20,7 @@ pub mod default_config;
 
 use psoserial::Serial;
 use psomsg_common::util::read_exact;
+use psodata::bb_defaults::{KEY_CONFIG_LEN, JOY_CONFIG_LEN, SHORTCUTS_LEN};
 
 pub mod msgs;
 pub mod data;
@@ -38,6 +39,18 @@ pub use self::player::*;
 pub use self::subcmd::*;
 pub use self::game::*;
 
+/// The body size of messages that only come in one size. The header's size
+/// is checked against it before the body is parsed, since the padding after
+/// a short body would otherwise be read as part of it.
+fn fixed_body_size(msg_type: u16) -> Option<usize> {
+    match msg_type {
+        0x03ED => Some(SHORTCUTS_LEN),
+        0x04ED => Some(KEY_CONFIG_LEN),
+        0x05ED => Some(JOY_CONFIG_LEN),
+        _ => None
+    }
+}
+
 macro_rules! gen_message_enum {
     ($($id:expr => $name:ident),*) => {
         #[derive(Clone, Debug)]
@@ -112,6 +125,13 @@ macro_rules! gen_message_enum {
                 }
                 debug!("size: {size}, type: 0x{msg_type:x}, flags: {flags}", size=size, msg_type=msg_type, flags=flags);
 
+                if let Some(body) = fixed_body_size(msg_type) {
+                    if size as usize != body + 8 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("message 0x{:x} is {} bytes, should be {}", msg_type, size, body + 8)))
+                    }
+                }
+
                 let padding = if size % 8 == 0 { 0 } else { 8 - (size % 8) };
 
                 let mut msg_buf = vec![0u8; (size + padding) as usize - 8];
@@ -150,6 +170,16 @@ macro_rules! gen_message_enum {
                 Message::Unknown(val.0, val.1, val.2)
             }
         }
+
+        impl Message {
+            /// The name of this message's type, for logging.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    &Message::Unknown(..) => "Unknown",
+                    $(&Message::$name(..) => stringify!($name)),*
+                }
+            }
+        }
     }
 }
 
@@ -187,6 +217,7 @@ gen_message_enum! {
     0x00A0 => ShipList,
     0x00B1 => Timestamp,
     0x00C1 => BbCreateGame,
+    0x00DA => LobbyEvent,
     0x01DC => BbGuildCardHdr,
     0x02DC => BbGuildCardChunk,
     0x03DC => BbGuildCardChunkReq,
@@ -208,6 +239,7 @@ gen_message_enum! {
     0x04EB => BbParamHdrReq,
     0x00EC => BbSetFlags,
     0x01ED => BbUpdateOptions,
+    0x03ED => BbUpdateShortcuts,
     0x04ED => BbUpdateKeys,
     0x05ED => BbUpdateJoy,
     0x00EE => BbScrollMsg
@@ -138,6 +138,10 @@ derive_serial! {
     }
 }
 
+// Changes the event of the lobby the client is in. The event number goes in
+// the header flags.
+derive_serial!(LobbyEvent);
+
 #[cfg(test)]
 mod test {
     use super::*;
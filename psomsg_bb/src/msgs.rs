@@ -6,6 +6,7 @@ use std::io;
 use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
 
 use psomsg_common::util::*;
+use psodata::bb_defaults::SHORTCUTS_LEN;
 use super::PSOBB_COPYRIGHT_STRING;
 use super::data::*;
 use super::chara::*;
@@ -609,6 +610,19 @@ impl Serial for BbUpdateKeys {
     }
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct BbUpdateShortcuts(pub Vec<u8>);
+impl Serial for BbUpdateShortcuts {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(write_array(&self.0, SHORTCUTS_LEN, dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        Ok(BbUpdateShortcuts(try!(read_array(SHORTCUTS_LEN, src))))
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct BbUpdateJoy(pub Vec<u8>);
 impl Serial for BbUpdateJoy {
@@ -755,6 +769,7 @@ mod test {
     use psoserial::Serial;
     use super::*;
     use super::super::Message;
+    use psodata::bb_defaults::SHORTCUTS_LEN;
 
     #[test]
     fn test_bb_full_char_size() {
@@ -811,4 +826,33 @@ mod test {
         assert_eq!(cursor.position(), 200);
         let array = cursor.into_inner();
     }
+
+    #[test]
+    fn test_config_update_size_checked() {
+        let mut cursor = Cursor::new(Vec::new());
+        let a: Message = BbUpdateKeys(vec![1; 364]).into();
+        a.serialize(&mut cursor).unwrap();
+        let mut array = cursor.into_inner();
+        match Message::deserialize(&mut Cursor::new(&array[..])).unwrap() {
+            Message::BbUpdateKeys(_, BbUpdateKeys(k)) => assert_eq!(k, vec![1; 364]),
+            m => panic!("parsed as {}", m.name())
+        }
+        // Short enough that the body still fits in the padded packet.
+        array[0] -= 2;
+        assert!(Message::deserialize(&mut Cursor::new(&array[..])).is_err());
+
+        let mut cursor = Cursor::new(Vec::new());
+        let a: Message = BbUpdateJoy(vec![1; 56]).into();
+        a.serialize(&mut cursor).unwrap();
+        let mut array = cursor.into_inner();
+        array[0] -= 4;
+        assert!(Message::deserialize(&mut Cursor::new(&array[..])).is_err());
+
+        let mut cursor = Cursor::new(Vec::new());
+        let a: Message = BbUpdateShortcuts(vec![1; SHORTCUTS_LEN]).into();
+        a.serialize(&mut cursor).unwrap();
+        let mut array = cursor.into_inner();
+        array[0] -= 1;
+        assert!(Message::deserialize(&mut Cursor::new(&array[..])).is_err());
+    }
 }
@@ -147,6 +147,13 @@ impl Default for Bb60DoneBurst {
     }
 }
 
+// Sent when a player moves to another area, as they leave the old one.
+derive_serial_default! {
+    Bb60AreaChange {
+        pub area: u32
+    }
+}
+
 derive_serial_default! {
     Bb60DropItem {
         // uint16_t unk;
@@ -178,6 +185,54 @@ derive_serial_default! {
     }
 }
 
+derive_serial_default! {
+    Bb60CreateItem {
+        pub item: [u8; 12],
+        pub item_id: u32,
+        pub item2: [u8; 4],
+        pub unused: u32
+    }
+}
+
+derive_serial_default! {
+    Bb60EquipItem {
+        pub item_id: u32,
+        pub equip_slot: u32
+    }
+}
+
+// The tekker's appraisal of an item, sent only to the player who asked.
+derive_serial_default! {
+    Bb60IdentifyResult {
+        pub item: [u8; 12],
+        pub item_id: u32,
+        pub item2: [u8; 4]
+    }
+}
+
+derive_serial_default! {
+    Bb60PickUpItem {
+        pub client_id: u16,
+        pub area: u16,
+        pub item_id: u32
+    }
+}
+
+derive_serial_default! {
+    Bb60ItemDrop {
+        pub area: u8,
+        pub from_enemy: u8,
+        pub request_id: u16,
+        pub x: f32,
+        pub z: f32,
+        pub unk1: u32,
+        pub item: [u8; 12],
+        pub item_id: u32,
+        pub item2: [u8; 4],
+        pub unk2: u32
+    }
+}
+
 derive_serial_default! {
     Bb60DropPos {
         pub area: u32,
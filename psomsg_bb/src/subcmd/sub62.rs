@@ -82,6 +82,18 @@ derive_serial_default! {
     }
 }
 
+derive_serial_default! {
+    Bb62BankAction {
+        // 0xFFFFFFFF when moving meseta.
+        pub item_id: u32,
+        pub meseta_amount: u32,
+        // 0 deposit, 1 withdraw, 3 close.
+        pub action: u8,
+        pub item_amount: u8,
+        pub unused: u16
+    }
+}
+
 derive_serial_default! {
     Bb62ShopReq {
         pub shop_type: u8,
@@ -167,3 +179,22 @@ derive_serial_default! {
         pub area: u32
     }
 }
+
+derive_serial_default! {
+    Bb62IdentifyItem {
+        pub item_id: u32
+    }
+}
+
+derive_serial_default! {
+    Bb62AcceptIdentify {
+        pub item_id: u32
+    }
+}
+
+derive_serial_default! {
+    Bb62SellItem {
+        pub item_id: u32,
+        pub amount: u32
+    }
+}
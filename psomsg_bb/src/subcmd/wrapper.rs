@@ -23,6 +23,16 @@ macro_rules! impl_subcmd_enum {
             }),*
         }
 
+        impl $numname {
+            /// The subcommand's number, whether it's one we parse or not.
+            pub fn cmd(&self) -> u8 {
+                match self {
+                    &$numname::Unknown { cmd, .. } => cmd,
+                    $(&$numname::$name { .. } => $id),*
+                }
+            }
+        }
+
         impl Serial for $numname {
             fn serialize(&self, dst: &mut Write) -> io::Result<()> {
                 let w_cmd;
@@ -197,13 +207,19 @@ macro_rules! impl_subcmd_6d_enum {
 }
 
 impl_subcmd_enum! { BbSubCmd60 =
+    0x21 => Bb60AreaChange,
+    0x25 => Bb60EquipItem,
     0x30 => Bb60LevelUp,
     0x29 => Bb60DeleteItem,
     0x2A => Bb60DropItem,
+    0x59 => Bb60PickUpItem,
     0x5D => Bb60DropStack,
+    0x5F => Bb60ItemDrop,
     0x63 => Bb60DestroyItem,
     0x72 => Bb60DoneBurst,
     0x6F => QuestData1,
+    0xB9 => Bb60IdentifyResult,
+    0xBE => Bb60CreateItem,
     0xBF => Bb60GiveExp,
     0xC3 => Bb60DropPos,
     0xC8 => Bb60ReqExp
@@ -224,7 +240,11 @@ impl_subcmd_enum! { BbSubCmd62 =
     0x60 => Bb62ItemReq,
     0xB5 => Bb62ShopReq,
     0xB6 => Bb62ShopInv,
-    0xBB => Bb62OpenBank
+    0xB8 => Bb62IdentifyItem,
+    0xBA => Bb62AcceptIdentify,
+    0xBB => Bb62OpenBank,
+    0xBD => Bb62BankAction,
+    0xC0 => Bb62SellItem
 }
 
 impl_subcmd_6d_enum! { BbSubCmd6D =
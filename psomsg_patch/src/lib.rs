@@ -193,11 +193,36 @@ derive_serial!(FileSend {
     pub filename: StaticVec<u8, U48>
 });
 
-derive_serial!(DataSend {
+/// A chunk of the file opened by the last `FileSend`. The chunk's size is
+/// sent ahead of `data`, and `checksum` is the CRC32 of `data`.
+#[derive(Clone, Debug, Default)]
+pub struct DataSend {
     pub chunk_num: u32,
     pub checksum: u32,
-    pub chunk_size: u32
-});
+    pub data: Vec<u8>
+}
+impl Serial for DataSend {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(self.chunk_num.serialize(dst));
+        try!(self.checksum.serialize(dst));
+        try!((self.data.len() as u32).serialize(dst));
+        try!(dst.write_all(&self.data));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        let chunk_num = try!(Serial::deserialize(src));
+        let checksum = try!(Serial::deserialize(src));
+        let chunk_size: u32 = try!(Serial::deserialize(src));
+        let mut data = vec![0u8; chunk_size as usize];
+        try!(read_exact(src, &mut data));
+        Ok(DataSend {
+            chunk_num: chunk_num,
+            checksum: checksum,
+            data: data
+        })
+    }
+}
 
 derive_serial!(FileDone { pub padding: u32 });
 derive_serial!(SetDirectory { pub dirname: StaticVec<u8, U64> });
@@ -3,16 +3,19 @@ IDOLA PSO Server.
 
 Usage:
     idola [options]
+    idola --generate-config
     idola (-h | --help)
     idola --version
 
 Options:
     -c <config>, --config=<config>        Config path [default: idola.toml].
+    --generate-config                     Print a commented starter config.
     -h,--help                             This message.
     --version                             Print version.
 
 The config path defaults to 'idola.toml'. If no file exists, the program
-will immediately exit.
+will immediately exit. To make one, run `idola --generate-config > idola.toml`
+and edit it; change its passwords before running it anywhere public.
 
 The configuration file describes what services to run in this instance of the
 server. There are several kinds of services. The config in
@@ -23,5 +26,6 @@ for PSOBB to connect and play. See the docs for more details.
 #[derive(Debug, Clone, RustcDecodable)]
 pub struct Args {
     pub flag_config: String,
+    pub flag_generate_config: bool,
     pub flag_version: bool
 }
@@ -1,5 +1,24 @@
+use std::collections::VecDeque;
+
 use psomsg::bb::BbSecurityData;
 use psomsg::bb::BbFullCharData;
+use psomsg::bb::ItemBank;
+use psomsg::bb::ItemData;
+
+/// Suspicious events kept per session for GMs to review.
+pub const SUSPICION_LOG_LEN: usize = 8;
+/// Seconds an idle client has to answer a ping before it's dropped.
+pub const IDLE_PING_GRACE: u64 = 30;
+
+/// What to do about a client, going by how long it's been quiet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Idle {
+    Active,
+    /// Ping them to see if they're still there.
+    Ping,
+    /// They didn't answer the ping.
+    Drop
+}
 
 #[derive(Clone, Default)]
 pub struct ClientState {
@@ -7,6 +26,243 @@ pub struct ClientState {
     pub account_id: u32,
     pub team_id: u32,
     pub bb_guildcard: u32,
+    /// Their guild card is one of the block's GMs'.
+    pub is_gm: bool,
     pub full_char: Option<BbFullCharData>,
-    pub connection_id: usize
+    pub connection_id: usize,
+    /// They picked their lobby themselves, so don't move them around.
+    pub chose_lobby: bool,
+    /// Items stored by the account's other characters, as of the last time
+    /// the shipgate was asked.
+    pub storage_elsewhere: u32,
+    /// The account's storage quota, 0 if there is none or it isn't known yet.
+    pub storage_quota: u32,
+    /// The account's shared bank, once the shipgate has sent it.
+    pub shared_bank: Option<ItemBank>,
+    pub shared_bank_slots: u32,
+    /// The bank counter opens the shared bank instead of the character's.
+    pub using_shared_bank: bool,
+    /// Rested experience waiting to be paid out as a bonus.
+    pub rested_exp: u32,
+    /// `time::precise_time_ns` at which they last entered a lobby.
+    pub lobby_since: Option<u64>,
+    /// `time::precise_time_ns` up to which their play time has been sent to
+    /// the shipgate. Set once their character is loaded.
+    pub playing_since: Option<u64>,
+    /// `time::precise_time_ns` by which they must log in. Cleared once they
+    /// have.
+    pub handshake_deadline: Option<u64>,
+    /// They've been shown the MOTD since logging in.
+    pub seen_motd: bool,
+    /// `time::precise_time_ns` of their recent lobby changes, oldest first.
+    pub lobby_changes: VecDeque<u64>,
+    /// Lobby changes ignored for coming too quickly since the last one that
+    /// went through.
+    pub lobby_change_strikes: u32,
+    /// `time::precise_time_ns` of their recent chat messages, oldest first.
+    pub chat_times: VecDeque<u64>,
+    /// They've been told their chat is being dropped since their last
+    /// message that went through.
+    pub chat_warned: bool,
+    /// Points from failed validation checks this session.
+    pub suspicion: u32,
+    /// The most recent events that added to `suspicion`, oldest first.
+    pub suspicion_events: VecDeque<String>,
+    /// The tekker's appraisal of one of their items, waiting for them to
+    /// accept it.
+    pub identifying: Option<ItemData>,
+    /// They said goodbye before their connection closed.
+    pub logged_out: bool,
+    /// A character and shared bank save the shipgate hasn't confirmed yet.
+    pub transfer_pending: bool,
+    /// Banned items taken from their character as it loaded, for telling
+    /// them once they're in.
+    pub banned_items_removed: usize,
+    /// The guild card file they asked for, while they download it.
+    pub guildcard_file: Option<Vec<u8>>,
+    /// `time::precise_time_ns` of the last message from them.
+    pub last_activity: u64,
+    /// `time::precise_time_ns` at which they were pinged for being quiet,
+    /// if they haven't said anything since.
+    pub idle_pinged: Option<u64>
+}
+
+impl ClientState {
+    /// Bank rest for the time since they entered their lobby.
+    pub fn accrue_rest(&mut self, now: u64, per_minute: u32, cap: u32) {
+        if let Some(since) = self.lobby_since.take() {
+            let earned = now.saturating_sub(since) / 1_000_000_000 * per_minute as u64 / 60;
+            let total = self.rested_exp as u64 + earned;
+            self.rested_exp = if total > cap as u64 { cap } else { total as u32 };
+        }
+    }
+
+    /// Nothing of theirs is half done, so their character is safe to save
+    /// as it stands.
+    pub fn is_consistent(&self) -> bool {
+        !self.transfer_pending && self.identifying.is_none()
+    }
+
+    /// Items counting against the account's storage quota outside the
+    /// character being played: its other characters' and the shared bank's.
+    pub fn stored_elsewhere(&self) -> u32 {
+        self.storage_elsewhere + self.shared_bank.as_ref().map(|b| b.item_count).unwrap_or(0)
+    }
+
+    /// Take the bonus for `exp` experience out of their rest, up to `exp`.
+    pub fn take_rested_exp(&mut self, exp: u32) -> u32 {
+        let bonus = if self.rested_exp < exp { self.rested_exp } else { exp };
+        self.rested_exp -= bonus;
+        bonus
+    }
+
+    /// Whole seconds played since `playing_since`, moving it forward by
+    /// that much.
+    pub fn take_playtime(&mut self, now: u64) -> u32 {
+        match self.playing_since {
+            Some(since) => {
+                let secs = now.saturating_sub(since) / 1_000_000_000;
+                self.playing_since = Some(since + secs * 1_000_000_000);
+                secs as u32
+            },
+            None => 0
+        }
+    }
+
+    /// Add `points` to their suspicion score for `reason`, returning the new
+    /// score.
+    pub fn add_suspicion(&mut self, points: u32, reason: String) -> u32 {
+        self.suspicion = self.suspicion.saturating_add(points);
+        if self.suspicion_events.len() >= SUSPICION_LOG_LEN {
+            self.suspicion_events.pop_front();
+        }
+        self.suspicion_events.push_back(reason);
+        self.suspicion
+    }
+
+    /// Record a lobby change if they've made fewer than `limit` in the last
+    /// `window` nanoseconds. Returns whether it's allowed; if it isn't, it
+    /// counts as a strike instead.
+    pub fn note_lobby_change(&mut self, now: u64, limit: usize, window: u64) -> bool {
+        while self.lobby_changes.front().map(|t| now.saturating_sub(*t) >= window).unwrap_or(false) {
+            self.lobby_changes.pop_front();
+        }
+        if self.lobby_changes.len() < limit {
+            self.lobby_changes.push_back(now);
+            self.lobby_change_strikes = 0;
+            true
+        } else {
+            self.lobby_change_strikes += 1;
+            false
+        }
+    }
+
+    /// They sent something, so they're still there.
+    pub fn note_activity(&mut self, now: u64) {
+        self.last_activity = now;
+        self.idle_pinged = None;
+    }
+
+    /// Whether to ping or drop them, if they've been quiet `timeout`
+    /// nanoseconds, and quiet for `grace` more after a ping. After a drop
+    /// the clock starts again, in case the connection lingers.
+    pub fn check_idle(&mut self, now: u64, timeout: u64, grace: u64) -> Idle {
+        match self.idle_pinged {
+            Some(at) if now.saturating_sub(at) >= grace => {
+                self.note_activity(now);
+                Idle::Drop
+            },
+            Some(_) => Idle::Active,
+            None if now.saturating_sub(self.last_activity) >= timeout => {
+                self.idle_pinged = Some(now);
+                Idle::Ping
+            },
+            None => Idle::Active
+        }
+    }
+
+    /// Record a chat message if they've sent fewer than `limit` in the last
+    /// `window` nanoseconds. Returns whether it should go through.
+    pub fn note_chat(&mut self, now: u64, limit: usize, window: u64) -> bool {
+        while self.chat_times.front().map(|t| now.saturating_sub(*t) >= window).unwrap_or(false) {
+            self.chat_times.pop_front();
+        }
+        if self.chat_times.len() < limit {
+            self.chat_times.push_back(now);
+            self.chat_warned = false;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A dropped player's reserved place, kept for the reconnect grace window.
+#[derive(Clone, Debug)]
+pub struct PendingReconnect {
+    pub account_id: u32,
+    /// Lobby index they were in.
+    pub lobby: Option<usize>,
+    /// Unique ID of the party they were in.
+    pub party: Option<u32>,
+    /// `time::precise_time_ns` at which the reservation lapses.
+    pub expires: u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientState, Idle};
+
+    const SEC: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_chat_over_limit_dropped() {
+        let mut c = ClientState::default();
+        let sent: Vec<bool> = (0..6).map(|i| c.note_chat(100 * SEC + i, 4, 10 * SEC)).collect();
+        assert_eq!(sent, vec![true, true, true, true, false, false]);
+        // Dropped messages don't count against the window.
+        assert!(!c.note_chat(109 * SEC, 4, 10 * SEC));
+        assert!(c.note_chat(110 * SEC, 4, 10 * SEC));
+    }
+
+    #[test]
+    fn test_idle_client_dropped() {
+        let mut c = ClientState::default();
+        c.note_activity(100 * SEC);
+        assert_eq!(c.check_idle(159 * SEC, 60 * SEC, 30 * SEC), Idle::Active);
+        assert_eq!(c.check_idle(160 * SEC, 60 * SEC, 30 * SEC), Idle::Ping);
+        assert_eq!(c.check_idle(189 * SEC, 60 * SEC, 30 * SEC), Idle::Active);
+        assert_eq!(c.check_idle(190 * SEC, 60 * SEC, 30 * SEC), Idle::Drop);
+        // Dropped once, not every tick after.
+        assert_eq!(c.check_idle(191 * SEC, 60 * SEC, 30 * SEC), Idle::Active);
+    }
+
+    #[test]
+    fn test_idle_client_answers_ping() {
+        let mut c = ClientState::default();
+        c.note_activity(0);
+        assert_eq!(c.check_idle(60 * SEC, 60 * SEC, 30 * SEC), Idle::Ping);
+        c.note_activity(61 * SEC);
+        assert_eq!(c.check_idle(100 * SEC, 60 * SEC, 30 * SEC), Idle::Active);
+        assert_eq!(c.check_idle(121 * SEC, 60 * SEC, 30 * SEC), Idle::Ping);
+    }
+
+    #[test]
+    fn test_shared_bank_counts_toward_quota() {
+        let mut c = ClientState::default();
+        c.storage_elsewhere = 100;
+        // Not sent by the shipgate yet.
+        assert_eq!(c.stored_elsewhere(), 100);
+        c.shared_bank = Some(Default::default());
+        c.shared_bank.as_mut().unwrap().item_count = 25;
+        assert_eq!(c.stored_elsewhere(), 125);
+    }
+
+    #[test]
+    fn test_chat_spread_out_allowed() {
+        let mut c = ClientState::default();
+        for i in 0..20 {
+            assert!(c.note_chat(i * 3 * SEC, 4, 10 * SEC));
+        }
+    }
 }
@@ -0,0 +1,113 @@
+//! The guild card file a Blue Burst client asks for, built from the players
+//! it can see rather than a saved list.
+
+use std::io::Cursor;
+
+use crc::crc32::checksum_ieee;
+
+use psoserial::Serial;
+use psomsg::bb::{BbAddGuildCard, BbFullCharData};
+
+/// Size of the client's guild card file.
+pub const GUILDCARD_FILE_LEN: usize = 54672;
+/// Most the file is sent in at once.
+pub const GUILDCARD_CHUNK_LEN: usize = 0x6800;
+/// Cards the file has room for.
+pub const GUILDCARD_ENTRIES: usize = 104;
+
+/// Where the card entries start: after a header, the blocked list and some
+/// padding.
+const ENTRIES_OFFSET: usize = 0x114 + 29 * 0x108 + 0x78;
+/// A card, then a comment and some padding.
+const ENTRY_LEN: usize = 0x1BC;
+
+/// A player's card, from their character.
+pub fn card_for(guildcard: u32, fc: &BbFullCharData) -> BbAddGuildCard {
+    BbAddGuildCard {
+        guildcard: guildcard,
+        name: fc.chara.name.clone(),
+        team_name: fc.team_name.clone(),
+        text: fc.guildcard_desc.clone(),
+        one: 1,
+        lang: 0,
+        section: fc.chara.section,
+        char_class: fc.chara.class
+    }
+}
+
+/// The guild card file holding `cards`, in order. Past the file's room,
+/// cards are left out.
+pub fn guildcard_file(cards: &[BbAddGuildCard]) -> Vec<u8> {
+    let mut file = vec![0u8; GUILDCARD_FILE_LEN];
+    for (i, card) in cards.iter().take(GUILDCARD_ENTRIES).enumerate() {
+        let mut cur = Cursor::new(Vec::new());
+        card.serialize(&mut cur).unwrap();
+        let bytes = cur.into_inner();
+        let start = ENTRIES_OFFSET + i * ENTRY_LEN;
+        file[start..start + bytes.len()].copy_from_slice(&bytes);
+    }
+    file
+}
+
+/// The checksum the client expects in the file's header.
+pub fn guildcard_checksum(file: &[u8]) -> u32 {
+    checksum_ieee(file)
+}
+
+/// The `n`th chunk of the file, or `None` past its end.
+pub fn guildcard_chunk(file: &[u8], n: u32) -> Option<&[u8]> {
+    let start = n as usize * GUILDCARD_CHUNK_LEN;
+    if start >= file.len() {
+        return None
+    }
+    let end = ::std::cmp::min(start + GUILDCARD_CHUNK_LEN, file.len());
+    Some(&file[start..end])
+}
+
+#[cfg(test)]
+mod test {
+    use psomsg::bb::BbFullCharData;
+
+    use super::*;
+
+    fn card(guildcard: u32, name: &str) -> BbAddGuildCard {
+        let mut fc = BbFullCharData::default();
+        fc.chara.name = name.to_string();
+        fc.chara.section = 3;
+        card_for(guildcard, &fc)
+    }
+
+    fn entry_guildcard(file: &[u8], i: usize) -> u32 {
+        let at = ENTRIES_OFFSET + i * ENTRY_LEN;
+        file[at] as u32 | (file[at + 1] as u32) << 8 | (file[at + 2] as u32) << 16 | (file[at + 3] as u32) << 24
+    }
+
+    #[test]
+    fn test_guildcard_file_lobby() {
+        let cards = vec![card(42000001, "Me"), card(42000002, "Rico"), card(42000003, "Flowen")];
+        let file = guildcard_file(&cards);
+        assert_eq!(file.len(), GUILDCARD_FILE_LEN);
+        assert_eq!(entry_guildcard(&file, 0), 42000001);
+        assert_eq!(entry_guildcard(&file, 1), 42000002);
+        assert_eq!(entry_guildcard(&file, 2), 42000003);
+        assert_eq!(entry_guildcard(&file, 3), 0);
+        // The name follows the guild card number, as UTF-16.
+        let at = ENTRIES_OFFSET + ENTRY_LEN + 4;
+        assert_eq!(&file[at..at + 4], &[b'R', 0, b'i', 0]);
+    }
+
+    #[test]
+    fn test_guildcard_file_empty() {
+        let file = guildcard_file(&[]);
+        assert_eq!(file.len(), GUILDCARD_FILE_LEN);
+        assert!(file.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_guildcard_chunks() {
+        let file = guildcard_file(&[]);
+        assert_eq!(guildcard_chunk(&file, 0).unwrap().len(), GUILDCARD_CHUNK_LEN);
+        assert_eq!(guildcard_chunk(&file, 2).unwrap().len(), GUILDCARD_FILE_LEN - 2 * GUILDCARD_CHUNK_LEN);
+        assert!(guildcard_chunk(&file, 3).is_none());
+    }
+}
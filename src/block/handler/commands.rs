@@ -0,0 +1,447 @@
+//! Slash commands typed into block chat.
+
+use psomsg::bb::*;
+
+use super::BlockHandler;
+use ::block::lobbyhandler::event::Event;
+use ::shipgate::msg::SetShipEvent;
+
+/// A block chat command.
+pub struct Command {
+    pub name: &'static str,
+    /// Only GMs may use it.
+    pub gm_only: bool,
+    /// What it takes, for `/help`.
+    args: &'static str,
+    /// What it does, for `/help`.
+    about: &'static str,
+    run: fn(&mut BlockHandler, &str)
+}
+
+static COMMANDS: &'static [Command] = &[
+    Command { name: "announce", gm_only: true, args: "<message>", about: "Send a notice to everyone on the block", run: announce },
+    Command { name: "bank", gm_only: false, args: "", about: "Switch between your character's and the shared bank", run: bank },
+    Command { name: "block", gm_only: false, args: "<name>", about: "Move to another of the ship's blocks", run: block },
+    Command { name: "delete", gm_only: false, args: "<slot>", about: "Delete one of your characters", run: delete },
+    Command { name: "drain", gm_only: true, args: "", about: "Move everyone to other blocks so this one can be stopped", run: drain },
+    Command { name: "motd", gm_only: false, args: "", about: "Show the message of the day again", run: motd },
+    Command { name: "played", gm_only: false, args: "", about: "Show how long you've played", run: played },
+    Command { name: "restart", gm_only: true, args: "<minutes|cancel>", about: "Warn everyone, then drain the block, or call it off", run: restart },
+    Command { name: "setevent", gm_only: true, args: "<event|off>", about: "Set the event in the block's lobbies", run: setevent },
+    Command { name: "ship", gm_only: false, args: "<name>", about: "Move to another ship", run: ship },
+    Command { name: "shipevent", gm_only: true, args: "<event>", about: "Set the event on every block of the ship", run: shipevent },
+    Command { name: "suspicion", gm_only: true, args: "<guild card>", about: "Show a player's suspicion score", run: suspicion },
+    Command { name: "warp", gm_only: true, args: "<lobby>", about: "Move to another lobby", run: warp },
+    Command { name: "whisper", gm_only: false, args: "<name> <message>", about: "Send a message to one player", run: whisper },
+    Command { name: "who", gm_only: false, args: "", about: "List the players on the block", run: who }
+];
+
+/// Why a command can't be run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Refusal {
+    Unknown,
+    NotGm
+}
+
+/// Split a chat message into a command name, without its slash, and its
+/// arguments, trimmed. `None` if the message isn't a command.
+pub fn parse(msg: &str) -> Option<(&str, &str)> {
+    let msg = msg.trim_left_matches("\tE");
+    if !msg.starts_with('/') {
+        return None
+    }
+    let msg = &msg[1..];
+    match msg.find(char::is_whitespace) {
+        Some(i) => Some((&msg[..i], msg[i..].trim())),
+        None => Some((msg, ""))
+    }
+}
+
+/// The command called `name`, if a client that is or isn't a GM may use it.
+pub fn lookup(name: &str, is_gm: bool) -> Result<&'static Command, Refusal> {
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(c) if c.gm_only && !is_gm => Err(Refusal::NotGm),
+        Some(c) => Ok(c),
+        None => Err(Refusal::Unknown)
+    }
+}
+
+/// A line of `/help` for each command.
+pub fn help() -> String {
+    let mut text = String::new();
+    for c in COMMANDS {
+        text.push('/');
+        text.push_str(c.name);
+        if c.args.len() > 0 {
+            text.push(' ');
+            text.push_str(c.args);
+        }
+        text.push_str(" -- ");
+        if c.gm_only {
+            text.push_str("(GM) ");
+        }
+        text.push_str(c.about);
+        text.push('\n');
+    }
+    text
+}
+
+/// Run the command `name` for the handler's client. Returns false if there
+/// is no such command.
+pub fn run(handler: &mut BlockHandler, name: &str, args: &str) -> bool {
+    let cid = handler.client_id;
+    let is_gm = match handler.get_client_state(cid) {
+        Some(cs) => {
+            let c = cs.borrow();
+            c.is_gm
+        },
+        None => false
+    };
+    match lookup(name, is_gm) {
+        Ok(c) => {
+            (c.run)(handler, args);
+            true
+        },
+        Err(Refusal::NotGm) => {
+            handler.send_error(cid, "\tEOnly GMs can use that command.");
+            true
+        },
+        Err(Refusal::Unknown) => false
+    }
+}
+
+fn guildcard(h: &BlockHandler) -> u32 {
+    match h.get_client_state(h.client_id) {
+        Some(cs) => {
+            let c = cs.borrow();
+            c.bb_guildcard
+        },
+        None => 0
+    }
+}
+
+fn announce(h: &mut BlockHandler, args: &str) {
+    if args.len() == 0 {
+        h.send_error(h.client_id, "\tEUsage: /announce <message>");
+        return
+    }
+    warn!("Client {} (guild card {}) announced: {}", h.client_id, guildcard(h), args);
+    h.announce(&format!("\tE{}", args));
+}
+
+fn bank(h: &mut BlockHandler, _args: &str) {
+    let shared = {
+        let cr = h.get_client_state(h.client_id).unwrap();
+        let ref mut c = cr.borrow_mut();
+        c.using_shared_bank = !c.using_shared_bank;
+        c.using_shared_bank
+    };
+    if shared {
+        h.send_error(h.client_id, "\tEThe bank counter will\nopen your shared bank.");
+    } else {
+        h.send_error(h.client_id, "\tEThe bank counter will\nopen your character's bank.");
+    }
+}
+
+fn block(h: &mut BlockHandler, args: &str) {
+    if args.len() == 0 {
+        h.send_error(h.client_id, "\tEUsage: /block <name>");
+    } else {
+        h.change_block(args);
+    }
+}
+
+/// Delete a character by its slot, numbered from 1 as on the character
+/// select screen.
+fn delete(h: &mut BlockHandler, args: &str) {
+    match args.parse::<u8>() {
+        Ok(n) if n >= 1 && n <= 4 => h.delete_character(n - 1),
+        _ => h.send_error(h.client_id, "\tEUsage: /delete <1-4>")
+    }
+}
+
+fn drain(h: &mut BlockHandler, _args: &str) {
+    if h.draining.get() {
+        h.send_error(h.client_id, "\tEThis block is already\nbeing drained.");
+    } else {
+        warn!("Client {} (guild card {}) is draining the block", h.client_id, guildcard(h));
+        h.draining.set(true);
+    }
+}
+
+fn motd(h: &mut BlockHandler, _args: &str) {
+    if !h.send_motd(h.client_id) {
+        h.send_error(h.client_id, "\tEThere is no message\nof the day.");
+    }
+}
+
+fn played(h: &mut BlockHandler, _args: &str) {
+    h.show_playtime();
+}
+
+fn restart(h: &mut BlockHandler, args: &str) {
+    let gc_num = guildcard(h);
+    h.schedule_restart(gc_num, args);
+}
+
+/// Set the event for every lobby on the block, or with "off", go back to
+/// the holiday or ship event.
+fn setevent(h: &mut BlockHandler, args: &str) {
+    if args == "off" {
+        warn!("Client {} (guild card {}) cleared the block's event", h.client_id, guildcard(h));
+        h.gm_event.set(None);
+        h.send_error(h.client_id, "\tEThe lobbies will go back\nto their usual event.");
+        return
+    }
+    match args.parse::<u16>().ok().and_then(Event::from_u16) {
+        Some(e) => {
+            warn!("Client {} (guild card {}) set the block's event to {:?}", h.client_id, guildcard(h), e);
+            h.gm_event.set(Some(e as u16));
+        },
+        None => h.send_error(h.client_id, "\tEUsage: /setevent <event>\nor /setevent off.\nEvents are 0, 1 and 3-14.")
+    }
+}
+
+fn ship(h: &mut BlockHandler, args: &str) {
+    if args.len() == 0 {
+        h.send_error(h.client_id, "\tEUsage: /ship <name>");
+    } else {
+        h.change_ship(args.to_string());
+    }
+}
+
+/// Set the event for every block on the ship that doesn't set its own,
+/// through the shipgate.
+fn shipevent(h: &mut BlockHandler, args: &str) {
+    let ship = match *h.ship {
+        Some(ref s) => s.clone(),
+        None => {
+            h.send_error(h.client_id, "\tENo ship lists\nthis block.");
+            return
+        }
+    };
+    match args.parse::<u16>().ok().and_then(Event::from_u16) {
+        Some(e) => {
+            warn!("Client {} (guild card {}) set ship {}'s event to {:?}", h.client_id, guildcard(h), ship, e);
+            h.sg_sender.send(SetShipEvent { ship: ship, event: e as u16 }).unwrap();
+            h.send_error(h.client_id, "\tEThe ship's blocks will\nchange to that event.");
+        },
+        None => h.send_error(h.client_id, "\tEUsage: /shipevent <event>\nEvents are 0, 1 and 3-14.")
+    }
+}
+
+fn suspicion(h: &mut BlockHandler, args: &str) {
+    h.show_suspicion(args);
+}
+
+/// Move the GM to another lobby, past the lobby change limit.
+fn warp(h: &mut BlockHandler, args: &str) {
+    let cid = h.client_id;
+    let lr = h.lobbies.clone();
+    let ref mut lobbies = lr.borrow_mut();
+    let target = match args.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= lobbies.len() => n - 1,
+        _ => {
+            h.send_error(cid, &format!("\tEUsage: /warp <1-{}>", lobbies.len()));
+            return
+        }
+    };
+    let from = match lobbies.iter().position(|l| l.has_player(cid)) {
+        Some(f) => f,
+        None => {
+            h.send_error(cid, "\tEYou can only warp\nfrom a lobby.");
+            return
+        }
+    };
+    if from == target {
+        return
+    }
+    if !lobbies[target].has_room(h.reserved_in_lobby(target)) {
+        h.send_error(cid, "\tELobby is full.");
+        return
+    }
+    lobbies[from].remove_player(h, cid).unwrap();
+    lobbies[target].add_player(h, cid).unwrap();
+    if let Some(cs) = h.get_client_state(cid) {
+        cs.borrow_mut().chose_lobby = true;
+    }
+}
+
+/// The client of the player whose character is called `name`, ignoring case
+/// and the language prefix, and how many players on `players` are called
+/// that. When several are, the one connected first is picked.
+fn find_player(players: &[(usize, String)], name: &str) -> (Option<usize>, usize) {
+    let name = name.to_lowercase();
+    let mut found: Vec<usize> = players.iter()
+        .filter(|&&(_, ref n)| n.trim_left_matches("\tE").to_lowercase() == name)
+        .map(|&(id, _)| id)
+        .collect();
+    found.sort();
+    (found.first().cloned(), found.len())
+}
+
+/// What a player whispered to by `from` sees.
+fn whisper_text(from: &str, text: &str) -> String {
+    format!("\tE(whisper from {}) {}", from.trim_left_matches("\tE"), text)
+}
+
+/// Send a chat message to one player on the block.
+fn whisper(h: &mut BlockHandler, args: &str) {
+    let cid = h.client_id;
+    let (name, text) = match args.find(char::is_whitespace) {
+        Some(i) => (&args[..i], args[i..].trim()),
+        None => (args, "")
+    };
+    if name.len() == 0 || text.len() == 0 {
+        h.send_error(cid, "\tEUsage: /whisper <name> <message>");
+        return
+    }
+    let players: Vec<(usize, String)> = h.clients.borrow().iter().filter_map(|(&id, cs)| {
+        let c = cs.borrow();
+        c.full_char.as_ref().map(|fc| (id, fc.chara.name.clone()))
+    }).collect();
+    let (target, matches) = match find_player(&players, name) {
+        (Some(t), n) => (t, n),
+        (None, _) => {
+            h.send_error(cid, &format!("\tE{} is not\non this block.", name));
+            return
+        }
+    };
+    let from = match players.iter().find(|&&(id, _)| id == cid) {
+        Some(&(_, ref n)) => n.clone(),
+        None => return
+    };
+    let gc_num = guildcard(h);
+    let target_gc = match h.get_client_state(target) {
+        Some(cs) => {
+            let c = cs.borrow();
+            c.bb_guildcard
+        },
+        None => 0
+    };
+    let (block, lobby) = h.chat_location();
+    h.log_chat(gc_num, target_gc, block, lobby, text);
+    h.send_to_client(target, Message::BbChat(0, BbChat(gc_num, whisper_text(&from, text))));
+    if matches > 1 {
+        h.send_error(cid, &format!("\tE{} players are called\n{}. Whispered to one\nof them.", matches, name));
+    } else {
+        h.send_error(cid, &format!("\tEWhispered to {}.", name));
+    }
+}
+
+/// Most characters in one message of the `/who` list.
+const WHO_CHUNK: usize = 400;
+
+/// The `/who` list for players given as character name and lobby index, if
+/// they're in one, split into messages of at most `WHO_CHUNK` characters.
+fn who_lines(players: &[(String, Option<usize>)]) -> Vec<String> {
+    let mut msgs = Vec::new();
+    let mut msg = format!("\tC6{} players on this block\tC7\n", players.len());
+    for &(ref name, lobby) in players {
+        let line = match lobby {
+            Some(l) => format!("{} (lobby {})\n", name.trim_left_matches("\tE"), l + 1),
+            None => format!("{} (in a game)\n", name.trim_left_matches("\tE"))
+        };
+        if msg.len() + line.len() > WHO_CHUNK {
+            msgs.push(msg);
+            msg = String::new();
+        }
+        msg.push_str(&line);
+    }
+    msgs.push(msg);
+    msgs
+}
+
+/// List who's on the block and which lobby they're in.
+fn who(h: &mut BlockHandler, _args: &str) {
+    let cid = h.client_id;
+    let mut players: Vec<(usize, String, Option<usize>)> = {
+        let lobbies = h.lobbies.borrow();
+        h.clients.borrow().iter().filter_map(|(&id, cs)| {
+            let c = cs.borrow();
+            c.full_char.as_ref().map(|fc| {
+                let lobby = lobbies.iter().position(|l| l.has_player(id));
+                (id, fc.chara.name.clone(), lobby)
+            })
+        }).collect()
+    };
+    players.sort_by(|a, b| a.0.cmp(&b.0));
+    let players: Vec<(String, Option<usize>)> = players.into_iter().map(|(_, n, l)| (n, l)).collect();
+    for msg in who_lines(&players) {
+        h.send_to_client(cid, Message::LargeMsg(0, LargeMsg(msg)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("\tE/warp 3"), Some(("warp", "3")));
+        assert_eq!(parse("/announce  Server restarting soon "), Some(("announce", "Server restarting soon")));
+        assert_eq!(parse("\tE/who"), Some(("who", "")));
+        assert_eq!(parse("\tE/"), Some(("", "")));
+        assert_eq!(parse("\tEhello /who"), None);
+        assert_eq!(parse("hello"), None);
+    }
+
+    #[test]
+    fn test_announce_needs_gm() {
+        assert_eq!(lookup("announce", false).map(|c| c.name), Err(Refusal::NotGm));
+        assert_eq!(lookup("announce", true).map(|c| c.name), Ok("announce"));
+        assert_eq!(lookup("who", false).map(|c| c.name), Ok("who"));
+        assert_eq!(lookup("nonsense", true).map(|c| c.name), Err(Refusal::Unknown));
+    }
+
+    #[test]
+    fn test_help_lists_commands() {
+        let text = help();
+        assert_eq!(text.lines().count(), COMMANDS.len());
+        for c in COMMANDS {
+            assert!(text.contains(&format!("/{} ", c.name)), "/help is missing /{}", c.name);
+        }
+        assert!(text.contains("/who -- List the players on the block\n"));
+        assert!(text.contains("/warp <lobby> -- (GM) Move to another lobby\n"));
+    }
+
+    #[test]
+    fn test_find_player() {
+        let players = vec![
+            (7, "\tEAlice".to_string()),
+            (3, "\tEBob".to_string()),
+            (9, "\tEbob".to_string())
+        ];
+        assert_eq!(find_player(&players, "alice"), (Some(7), 1));
+        assert_eq!(find_player(&players, "Bob"), (Some(3), 2));
+        assert_eq!(find_player(&players, "Carol"), (None, 0));
+        assert_eq!(find_player(&[], "Alice"), (None, 0));
+    }
+
+    #[test]
+    fn test_whisper_text_names_sender() {
+        assert_eq!(whisper_text("\tEAlice", "meet at the bank"), "\tE(whisper from Alice) meet at the bank");
+    }
+
+    #[test]
+    fn test_who_lines() {
+        let players: Vec<(String, Option<usize>)> = (0..40)
+            .map(|i| (format!("\tEPlayer{:02}", i), if i % 3 == 0 { None } else { Some(i % 15) }))
+            .collect();
+        let msgs = who_lines(&players);
+        assert!(msgs.len() > 1);
+        assert!(msgs.iter().all(|m| m.len() <= WHO_CHUNK));
+        let all = msgs.concat();
+        assert!(all.starts_with("\tC640 players on this block"));
+        for i in 0..40 {
+            assert!(all.contains(&format!("Player{:02} (", i)));
+        }
+        assert!(all.contains("Player01 (lobby 2)\n"));
+        assert!(all.contains("Player03 (in a game)\n"));
+    }
+
+    #[test]
+    fn test_who_lines_empty() {
+        assert_eq!(who_lines(&[]), vec!["\tC60 players on this block\tC7\n".to_string()]);
+    }
+}
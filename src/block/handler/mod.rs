@@ -0,0 +1,2411 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+//use std::fs::File;
+
+use mio::Sender;
+
+use psomsg::bb::*;
+
+use psodata::battleparam::BattleParamTables;
+use psodata::leveltable::LevelTable;
+
+//use ::game::CharClass;
+use ::shipgate::client::callbacks::SgCbMgr;
+use ::loop_handler::LoopMsg;
+use ::shipgate::msg::Message as Sgm;
+use ::shipgate::msg::BbLoginChallenge;
+use ::shipgate::msg::{BbGetAccountInfo, BbGetAccountInfoAck};
+use ::shipgate::msg::{BbCheckBan, BbCheckBanAck};
+use ::shipgate::msg::BbGetCharacter;
+use ::shipgate::msg::BbGetCharacterAck;
+use ::shipgate::msg::BbPutCharacter;
+use ::shipgate::msg::{BbDeleteCharacter, BbDeleteCharacterAck};
+use ::shipgate::msg::{BbGetStorageUsage, BbGetStorageUsageAck};
+use ::shipgate::msg::{BbGetSharedBank, BbGetSharedBankAck, BbSharedBankTransfer};
+use ::shipgate::msg::{ShipList as SgShipList, ShipListAck};
+use ::shipgate::msg::{BbAddPlaytime, BbGetPlaytime};
+use ::shipgate::msg::BbChatLog;
+use ::shipgate::msg::BbBanAccount;
+use ::shipgate::msg::BbBlockTransfer;
+use ::shipgate::msg::BbIssueHandoff;
+use ::shipgate::ships::listed_name;
+use ::patch::Bulletin;
+use ::maps::Areas;
+use ::config::{BlockOptions, BlockConf};
+use ::eventlog::EventLog;
+use ::stacklimits::StackLimits;
+
+use time::precise_time_ns;
+
+use super::client::{ClientState, PendingReconnect};
+use super::guildcard::{card_for, guildcard_file, guildcard_checksum, guildcard_chunk};
+use super::lobbyhandler::{Lobby, first_lobby_with_room};
+use super::partyhandler::Party;
+use super::restart::ScheduledRestart;
+use super::restart_warning;
+
+pub mod commands;
+
+const MENU_GAME_LIST: u32 = 0x00080000;
+
+/// `BbSecurity` error code for a banned account, as the shipgate uses too.
+const BB_LOGIN_BANNED: u32 = 6;
+
+/// Suspicion points for each kind of failed validation check. Checks that
+/// lag or a race with another player can trip are worth less.
+pub const SUSPICION_BAD_EXP: u32 = 3;
+pub const SUSPICION_BAD_DROP: u32 = 2;
+pub const SUSPICION_BAD_PICKUP: u32 = 1;
+pub const SUSPICION_UNIDENTIFIED: u32 = 2;
+pub const SUSPICION_BAD_AREA: u32 = 1;
+
+/// What to tell a player turned away by this ban check, if it found a ban
+/// that hasn't lifted by `now`, a Unix time. A failed check lets them in; the
+/// account's own ban is checked at login regardless.
+pub fn ban_message(ban: &BbCheckBanAck, now: u64) -> Option<String> {
+    if ban.status != 0 {
+        warn!("Shipgate couldn't check for a ban, status code {}", ban.status);
+        return None
+    }
+    if !ban.banned || (ban.expires != 0 && ban.expires <= now) {
+        return None
+    }
+    let mut msg = "\tEYou are banned from this server.".to_string();
+    if ban.reason.len() > 0 {
+        msg.push_str(&format!("\nReason: {}", ban.reason));
+    }
+    if ban.expires != 0 {
+        let until = ::time::at_utc(::time::Timespec::new(ban.expires as i64, 0));
+        if let Ok(t) = until.strftime("%Y-%m-%d %H:%M UTC") {
+            msg.push_str(&format!("\nUntil: {}", t));
+        }
+    }
+    Some(msg)
+}
+
+/// What to send the shipgate's chat log for a message from `guildcard`, if
+/// the block keeps one. `target` is the guild card whispered to, or 0 for
+/// lobby chat. `lobby` counts from 1, or is 0 outside a lobby.
+pub fn chat_log_entry(options: &BlockOptions, guildcard: u32, target: u32, block: u16, lobby: u8, time: u64, text: &str) -> Option<BbChatLog> {
+    if !options.chat_log {
+        return None
+    }
+    Some(BbChatLog {
+        guildcard: guildcard,
+        target: target,
+        block: block,
+        lobby: lobby,
+        time: time,
+        text: text.trim_left_matches("\tE").to_string()
+    })
+}
+
+pub struct BlockHandler {
+    sender: Sender<LoopMsg>,
+    sg_sender: SgCbMgr<BlockHandler>,
+    pub client_id: usize,
+    clients: Rc<RefCell<HashMap<usize, Rc<RefCell<ClientState>>>>>,
+    lobbies: Rc<RefCell<Vec<Lobby>>>,
+    parties: Rc<RefCell<Vec<Party>>>,
+    pub battle_params: Arc<BattleParamTables>,
+    online_maps: Arc<Areas>,
+    offline_maps: Arc<Areas>,
+    pub level_table: Arc<LevelTable>,
+    stack_limits: Arc<StackLimits>,
+    party_counter: Rc<Cell<u32>>,
+    pub options: Rc<BlockOptions>,
+    /// The patch service's MOTD and news.
+    bulletin: Rc<RefCell<Bulletin>>,
+    reconnects: Rc<RefCell<Vec<PendingReconnect>>>,
+    /// The block is sending its players to other blocks before closing.
+    draining: Rc<Cell<bool>>,
+    /// A restart a GM has scheduled.
+    restart: Rc<Cell<Option<ScheduledRestart>>>,
+    /// The event a GM has set for the block's lobbies.
+    gm_event: Rc<Cell<Option<u16>>>,
+    /// The ship listing this block, if any.
+    ship: Rc<Option<String>>,
+    /// The ship's other blocks.
+    siblings: Rc<Vec<BlockConf>>,
+    pub event_log: EventLog
+}
+
+impl BlockHandler {
+    pub fn new(sender: Sender<LoopMsg>,
+               sg_sender: SgCbMgr<BlockHandler>,
+               client_id: usize,
+               clients: Rc<RefCell<HashMap<usize, Rc<RefCell<ClientState>>>>>,
+               lobbies: Rc<RefCell<Vec<Lobby>>>,
+               parties: Rc<RefCell<Vec<Party>>>,
+               battle_params: Arc<BattleParamTables>,
+               online_maps: Arc<Areas>,
+               offline_maps: Arc<Areas>,
+               level_table: Arc<LevelTable>,
+               stack_limits: Arc<StackLimits>,
+               party_counter: Rc<Cell<u32>>,
+               options: Rc<BlockOptions>,
+               bulletin: Rc<RefCell<Bulletin>>,
+               reconnects: Rc<RefCell<Vec<PendingReconnect>>>,
+               draining: Rc<Cell<bool>>,
+               restart: Rc<Cell<Option<ScheduledRestart>>>,
+               gm_event: Rc<Cell<Option<u16>>>,
+               ship: Rc<Option<String>>,
+               siblings: Rc<Vec<BlockConf>>,
+               event_log: EventLog) -> BlockHandler {
+        BlockHandler {
+            sender: sender,
+            sg_sender: sg_sender,
+            client_id: client_id,
+            clients: clients,
+            lobbies: lobbies,
+            parties: parties,
+            battle_params: battle_params,
+            online_maps: online_maps,
+            offline_maps: offline_maps,
+            level_table: level_table,
+            stack_limits: stack_limits,
+            party_counter: party_counter,
+            options: options,
+            bulletin: bulletin,
+            reconnects: reconnects,
+            draining: draining,
+            restart: restart,
+            gm_event: gm_event,
+            ship: ship,
+            siblings: siblings,
+            event_log: event_log
+        }
+    }
+
+    /// Get the client state for the given client ID. Be smart with RefCell borrows,
+    /// the program will panic if you fail the borrow invariant checks (basically,
+    /// don't borrow mutably unless you have to).
+    pub fn get_client_state(&self, client: usize) -> Option<Rc<RefCell<ClientState>>> {
+        self.clients.borrow().get(&client).map(|v| v.clone())
+    }
+
+    /// Most of `item` that fits in one inventory or bank stack.
+    pub fn stack_limit_for(&self, item: &ItemData) -> u8 {
+        self.stack_limits.limit_for(item, self.options.tool_stack_limit as u8)
+    }
+
+    /// The block's `banned_items` list covers `item`.
+    pub fn is_banned_item(&self, item: &ItemData) -> bool {
+        self.options.banned_items.iter().any(|code| item.data.starts_with(code))
+    }
+
+    /// Whether the client may equip the item with this ID, given the
+    /// block's `banned_items`.
+    pub fn check_not_banned(&self, item_id: u32, action: &str) -> bool {
+        let cid = self.client_id;
+        let banned = self.get_client_state(cid)
+            .and_then(|cs| cs.borrow().full_char.as_ref()
+                .and_then(|fc| fc.inv.find(item_id).map(|i| self.is_banned_item(&i.data))))
+            .unwrap_or(false);
+        if banned {
+            info!("Client {} tried to {} banned item {:08X}", cid, action, item_id);
+            self.send_error(cid, "\tEThat item is banned\non this server.");
+        }
+        !banned
+    }
+
+    /// Take banned items out of a character's inventory and bank, if the
+    /// block strips them. Returns how many went.
+    fn strip_banned_items(&self, fc: &mut BbFullCharData) -> usize {
+        if !self.options.strip_banned_items || self.options.banned_items.is_empty() {
+            return 0
+        }
+        let before = fc.inv.items.len();
+        fc.inv.items.retain(|i| !self.is_banned_item(&i.data));
+        let removed = before - fc.inv.items.len();
+        removed + fc.bank.remove_matching(|i| self.is_banned_item(i))
+    }
+
+    /// Bring a character just loaded within the block's caps.
+    fn enforce_caps(&self, fc: &mut BbFullCharData) {
+        let cap = self.options.max_meseta;
+        if fc.chara.meseta > cap {
+            warn!("Client {}'s character loaded with {} meseta, over the cap of {}; cutting it down", self.client_id, fc.chara.meseta, cap);
+            fc.chara.meseta = cap;
+        }
+        if fc.chara.level + 1 > self.options.max_level {
+            warn!("Client {}'s character loaded at level {}, over the cap of {}", self.client_id, fc.chara.level + 1, self.options.max_level);
+        }
+    }
+
+    /// Send a message to a client.
+    pub fn send_to_client(&self, client: usize, message: Message) {
+        // no support for versions other than BB yet...
+        self.sender.send((client, message.clone()).into()).unwrap();
+    }
+
+    /// Send a message and disconnect the client.
+    pub fn send_fatal_error(&self, client: usize, msg: &str) {
+        let m = Message::LargeMsg(0, LargeMsg(msg.to_string()));
+        self.send_to_client(client, m);
+        self.sender.send(LoopMsg::DropClient(client)).unwrap();
+    }
+
+    /// Send a non-fatal Msg1 to the client.
+    pub fn send_error(&self, client: usize, msg: &str) {
+        let m = Message::BbMsg1(0, BbMsg1(msg.to_string()));
+        self.send_to_client(client, m);
+    }
+
+    /// Send a notice to everyone on the block.
+    pub fn announce(&self, msg: &str) {
+        let ids: Vec<usize> = self.clients.borrow().keys().cloned().collect();
+        for id in ids {
+            self.send_error(id, msg);
+        }
+    }
+
+    /// GM command: schedule a restart `arg` minutes from now, or call off
+    /// the one scheduled with "cancel".
+    fn schedule_restart(&self, gc_num: u32, arg: &str) {
+        let arg = arg.trim();
+        if arg == "cancel" {
+            if self.restart.get().is_none() {
+                self.send_error(self.client_id, "\tENo restart is scheduled.");
+                return
+            }
+            warn!("Client {} (guild card {}) cancelled the scheduled restart", self.client_id, gc_num);
+            self.restart.set(None);
+            self.announce("\tEThe scheduled restart\nhas been cancelled.");
+            return
+        }
+        let minutes = match arg.parse::<u32>() {
+            Ok(m) if m >= 1 && m <= 24 * 60 => m,
+            _ => {
+                self.send_error(self.client_id, "\tEUsage: /restart <minutes>\nor /restart cancel");
+                return
+            }
+        };
+        if self.draining.get() {
+            self.send_error(self.client_id, "\tEThis block is already\nbeing drained.");
+            return
+        }
+        warn!("Client {} (guild card {}) scheduled a restart in {} minutes", self.client_id, gc_num, minutes);
+        let r = ScheduledRestart::new(precise_time_ns(), minutes);
+        self.restart.set(Some(r));
+        self.announce(&restart_warning(minutes));
+    }
+
+    /// Count a failed validation check against a client, disconnecting them
+    /// (and banning their account, if configured) once their suspicion score
+    /// reaches the block's limit.
+    pub fn flag_suspicious(&self, client: usize, points: u32, reason: String) {
+        let (score, account_id) = match self.get_client_state(client) {
+            Some(cs) => {
+                let mut c = cs.borrow_mut();
+                (c.add_suspicion(points, reason.clone()), c.account_id)
+            },
+            None => return
+        };
+        warn!("Client {} suspicion +{} ({}): {}", client, points, score, reason);
+        let kick = self.options.suspicion_kick;
+        if kick == 0 || score < kick || score - points >= kick {
+            return
+        }
+        if self.options.suspicion_ban && account_id != 0 {
+            warn!("Client {} reached {} suspicion points; banning account {}", client, score, account_id);
+            self.sg_sender.send(Sgm::BbBanAccount(0, BbBanAccount { account_id: account_id })).unwrap();
+        } else {
+            warn!("Client {} reached {} suspicion points; disconnecting", client, score);
+        }
+        self.send_fatal_error(client, "\tEYour client failed too many\nvalidation checks.\nDisconnected.");
+    }
+
+    /// GM command: show the suspicion score of the player on this block with
+    /// the given guild card.
+    fn show_suspicion(&self, arg: &str) {
+        let guildcard = match arg.trim().parse::<u32>() {
+            Ok(g) => g,
+            Err(_) => {
+                self.send_error(self.client_id, "\tEUsage: /suspicion <guild card>");
+                return
+            }
+        };
+        let found = self.clients.borrow().values()
+            .map(|cs| cs.borrow())
+            .find(|c| c.bb_guildcard == guildcard)
+            .map(|c| (c.suspicion, c.suspicion_events.iter().cloned().collect::<Vec<_>>()));
+        let msg = match found {
+            Some((score, events)) => {
+                let mut msg = format!("\tC6Guild card {}\tC7\nSuspicion: {}\n", guildcard, score);
+                for e in events.iter() {
+                    msg.push_str(e);
+                    msg.push('\n');
+                }
+                msg
+            },
+            None => format!("Guild card {} isn't on this block.", guildcard)
+        };
+        self.send_to_client(self.client_id, Message::LargeMsg(0, LargeMsg(msg)));
+    }
+
+    pub fn bb_login(&mut self, m: BbLogin) {
+        let sec_data = m.security_data.clone();
+        // Security data should be set when connecting to the Ship (sent by Login)
+        // Drop if it's invalid.
+        if sec_data.magic != 0xCAFEB00B {
+            let m = Message::LargeMsg(0, LargeMsg("Invalid security data".to_string()));
+            self.sender.send((self.client_id, m).into()).unwrap();
+            self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+            return
+        }
+
+        let sgm = BbLoginChallenge { username: m.username.clone(), password: m.password.clone() };
+        self.sg_sender.request(self.client_id, sgm, move|mut h, m| {
+            // We need the extended BB account data.
+            if let Sgm::BbLoginChallengeAck(_, a) = m {
+                if a.status != 0 {
+                    // The shipgate says this account isn't usable for whatever reason. Drop.
+                    let r = Message::BbSecurity(0, BbSecurity {
+                        err_code: a.status,
+                        tag: 0,
+                        guildcard: 0,
+                        team_id: 0,
+                        security_data: sec_data.clone(),
+                        caps: 0
+                    });
+                    h.sender.send((h.client_id, r).into()).unwrap();
+                    h.sender.send(LoopMsg::DropClient(h.client_id)).unwrap();
+                    return
+                }
+
+                let sec_data = sec_data.clone();
+
+                let sgm: Sgm = BbGetAccountInfo { account_id: a.account_id }.into();
+                h.sg_sender.request(h.client_id, sgm, move|mut h, m| {
+                    if let Sgm::BbGetAccountInfoAck(_, a) = m {
+                        let sec_data = sec_data.clone();
+                        let sgm: Sgm = BbCheckBan { guildcard: a.guildcard_num }.into();
+                        h.sg_sender.request(h.client_id, sgm, move |mut h, m| {
+                            if let Sgm::BbCheckBanAck(_, ban) = m {
+                                if let Some(msg) = ban_message(&ban, ::time::get_time().sec as u64) {
+                                    info!("Client {} (guild card {}) is banned; disconnecting", h.client_id, a.guildcard_num);
+                                    h.refuse_login(&msg, &sec_data);
+                                    return
+                                }
+                            }
+                            h.admit(a.clone(), sec_data.clone());
+                        }).unwrap();
+                    }
+                }).unwrap();
+            } else {
+                warn!("Unexpected response from shipgate: {:?}", m);
+                h.sender.send(LoopMsg::DropClient(h.client_id)).unwrap();
+                return
+            }
+        }).unwrap();
+    }
+
+    /// Let a client with a good login on to the block, and fetch their
+    /// character.
+    fn admit(&mut self, a: BbGetAccountInfoAck, sec_data: BbSecurityData) {
+        if self.draining.get() {
+            info!("Block is draining; turning away client {} (guild card {})", self.client_id, a.guildcard_num);
+            self.send_fatal_error(self.client_id, "\tEThis block is closing.\nPlease try another.");
+            return
+        }
+        if block_full(&self.clients.borrow(), self.client_id, a.guildcard_num, self.options.max_players) {
+            info!("Block is full; turning away client {} (guild card {})", self.client_id, a.guildcard_num);
+            self.send_fatal_error(self.client_id, "\tEThis block is full.\nPlease try another.");
+            return
+        }
+
+        // An earlier connection with this guild card that never went away
+        // would leave a ghost behind. Dropping it cleans up after it like any
+        // other disconnect.
+        let stale = stale_logins(&self.clients.borrow(), a.guildcard_num, self.client_id);
+        for id in stale {
+            info!("Guild card {} logged in again as client {}; dropping its old connection, client {}", a.guildcard_num, self.client_id, id);
+            self.send_fatal_error(id, "\tEYou have logged in\nfrom somewhere else.");
+        }
+
+        let r = Message::BbSecurity(0, BbSecurity {
+            err_code: 0,
+            tag: 0x00010000,
+            guildcard: a.guildcard_num,
+            team_id: 0xFFFFFFFF,
+            security_data: sec_data.clone(),
+            caps: 0x00000101
+        });
+        self.sender.send((self.client_id, r).into()).unwrap();
+
+        let cr = self.get_client_state(self.client_id).unwrap();
+        let ref mut c = cr.borrow_mut();
+        c.sec_data = sec_data.clone();
+        c.team_id = a.team_id;
+        c.bb_guildcard = a.guildcard_num;
+        c.is_gm = self.options.gm_guildcards.contains(&a.guildcard_num);
+        c.account_id = a.account_id;
+        c.handshake_deadline = None;
+
+        // We need to get their character now.
+        let sgm: Sgm = BbGetCharacter { account_id: a.account_id, slot: sec_data.slot }.into();
+        self.sg_sender.request(self.client_id, sgm, move |mut h, m| {
+            if let Sgm::BbGetCharacterAck(_, body) = m {
+                h.sg_get_character_ack(body)
+            }
+        }).unwrap();
+    }
+
+    /// Turn a client away at login, telling them why.
+    fn refuse_login(&self, msg: &str, sec_data: &BbSecurityData) {
+        self.send_to_client(self.client_id, Message::LargeMsg(0, LargeMsg(msg.to_string())));
+        let r = Message::BbSecurity(0, BbSecurity {
+            err_code: BB_LOGIN_BANNED,
+            tag: 0,
+            guildcard: 0,
+            team_id: 0,
+            security_data: sec_data.clone(),
+            caps: 0
+        });
+        self.send_to_client(self.client_id, r);
+        self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+    }
+
+    fn sg_get_character_ack(&mut self, m: BbGetCharacterAck) {
+        if m.status != 0 {
+            error!("Shipgate error retrieving character, status code {}", m.status);
+            self.send_fatal_error(self.client_id, "Shipgate error retrieving character");
+            return
+        }
+        if m.full_char.is_none() {
+            // Somehow they got here and the shipgate doesn't have a char.
+            error!("Illegal state. Character in slot is missing.");
+            self.send_fatal_error(self.client_id, "Illegal state. Character in slot is missing.");
+            return
+        }
+        let BbGetCharacterAck { full_char, .. } = m;
+        let mut full_char = full_char.unwrap();
+        let stripped = self.strip_banned_items(&mut full_char);
+        self.enforce_caps(&mut full_char);
+
+        let cs = self.get_client_state(self.client_id).unwrap();
+        let mut client_state = cs.borrow_mut();
+        {
+            {
+                let n = self.lobbies.borrow().len() as u32;
+                let mut ll: Vec<(u32, u32)> = (1..n + 1).map(|i| (60, i)).collect();
+                ll.push((0, 0));
+                let r = Message::LobbyList(n, LobbyList { items: ll });
+                self.sender.send((self.client_id, r).into()).unwrap();
+            }
+
+            let r = Message::BbFullChar(0, BbFullChar(full_char.clone()));
+            self.sender.send((self.client_id, r).into()).unwrap();
+            client_state.full_char = Some(full_char);
+            client_state.playing_since = Some(precise_time_ns());
+            client_state.banned_items_removed = stripped;
+            let r = Message::CharDataRequest(0, CharDataRequest);
+            self.sender.send((self.client_id, r).into()).unwrap();
+        }
+        self.request_storage_usage(client_state.account_id, client_state.sec_data.slot);
+        self.request_shared_bank(client_state.account_id);
+        return
+    }
+
+    /// Find out how much of the account's storage quota their other
+    /// characters use.
+    fn request_storage_usage(&mut self, account_id: u32, slot: u8) {
+        let sgm: Sgm = BbGetStorageUsage { account_id: account_id, slot: slot }.into();
+        self.sg_sender.request(self.client_id, sgm, move |h, m| {
+            if let Sgm::BbGetStorageUsageAck(_, body) = m {
+                h.sg_storage_usage_ack(body)
+            }
+        }).unwrap();
+    }
+
+    fn sg_storage_usage_ack(&self, m: BbGetStorageUsageAck) {
+        if m.status != 0 {
+            error!("Shipgate error counting storage for account {}, status code {}", m.account_id, m.status);
+            return
+        }
+        if let Some(cr) = self.get_client_state(self.client_id) {
+            let ref mut c = cr.borrow_mut();
+            if c.account_id == m.account_id {
+                c.storage_elsewhere = m.used_elsewhere;
+                c.storage_quota = m.quota;
+            }
+        }
+    }
+
+    fn request_shared_bank(&mut self, account_id: u32) {
+        let sgm: Sgm = BbGetSharedBank { account_id: account_id }.into();
+        self.sg_sender.request(self.client_id, sgm, move |h, m| {
+            if let Sgm::BbGetSharedBankAck(_, body) = m {
+                h.sg_shared_bank_ack(body)
+            }
+        }).unwrap();
+    }
+
+    fn sg_shared_bank_ack(&self, m: BbGetSharedBankAck) {
+        if m.status != 0 {
+            error!("Shipgate error getting shared bank for account {}, status code {}", m.account_id, m.status);
+            return
+        }
+        let mut bank = m.bank;
+        let stripped = if self.options.strip_banned_items {
+            bank.remove_matching(|i| self.is_banned_item(i))
+        } else {
+            0
+        };
+        if let Some(cr) = self.get_client_state(self.client_id) {
+            let ref mut c = cr.borrow_mut();
+            if c.account_id == m.account_id {
+                c.shared_bank = Some(bank);
+                c.shared_bank_slots = m.capacity;
+            }
+        }
+        if stripped > 0 {
+            info!("Removed {} banned items from account {}'s shared bank", stripped, m.account_id);
+            self.send_error(self.client_id, "\tEBanned items were removed\nfrom your shared bank.");
+        }
+    }
+
+    /// Save the client's character and shared bank together, after something
+    /// moved between them.
+    pub fn put_shared_bank(&mut self) {
+        let sgm: Sgm = {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref c = cr.borrow();
+            match (c.full_char.as_ref(), c.shared_bank.as_ref()) {
+                (Some(fc), Some(bank)) => BbSharedBankTransfer {
+                    account_id: c.account_id,
+                    slot: c.sec_data.slot,
+                    full_char: fc.clone(),
+                    bank: bank.clone()
+                }.into(),
+                _ => return
+            }
+        };
+        if let Some(cr) = self.get_client_state(self.client_id) {
+            cr.borrow_mut().transfer_pending = true;
+        }
+        let cid = self.client_id;
+        self.sg_sender.request(self.client_id, sgm, move |h, m| {
+            if let Sgm::BbSharedBankTransferAck(_, body) = m {
+                if body.status != 0 {
+                    error!("Shipgate refused shared bank save for client {} (account {}), status code {}", cid, body.account_id, body.status);
+                }
+                if let Some(cr) = h.get_client_state(cid) {
+                    cr.borrow_mut().transfer_pending = false;
+                }
+            }
+        }).unwrap();
+    }
+
+    /// Start counting rest for a player who just entered a lobby.
+    pub fn start_resting(&self, player: usize) {
+        if self.options.rest_exp_per_minute == 0 {
+            return
+        }
+        if let Some(cr) = self.get_client_state(player) {
+            cr.borrow_mut().lobby_since = Some(precise_time_ns());
+        }
+    }
+
+    /// Bank the rest a player earned in the lobby they're leaving.
+    pub fn stop_resting(&self, player: usize) {
+        if let Some(cr) = self.get_client_state(player) {
+            cr.borrow_mut().accrue_rest(precise_time_ns(), self.options.rest_exp_per_minute, self.options.rest_exp_cap);
+        }
+    }
+
+    fn get_new_party_id(&mut self) -> u32 {
+        let pcr = self.party_counter.clone();
+        let ret = pcr.get();
+        pcr.set(ret + 1);
+        ret
+    }
+
+    /// Number of lobby slots held for dropped players expected back.
+    fn reserved_in_lobby(&self, lobby: usize) -> usize {
+        let now = precise_time_ns();
+        self.reconnects.borrow().iter().filter(|r| r.lobby == Some(lobby) && r.party.is_none() && r.expires > now).count()
+    }
+
+    /// Number of party slots held for dropped players expected back.
+    fn reserved_in_party(&self, party: u32) -> usize {
+        let now = precise_time_ns();
+        self.reconnects.borrow().iter().filter(|r| r.party == Some(party) && r.expires > now).count()
+    }
+
+    /// If the block is quiet enough that lobbies should be merged.
+    pub fn is_low_population(&self) -> bool {
+        self.options.merge_below > 0 && (self.clients.borrow().len() as u32) < self.options.merge_below
+    }
+
+    /// The index of the most populated lobby that still has room.
+    pub fn busiest_lobby_with_room(&self, lobbies: &[Lobby]) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, l) in lobbies.iter().enumerate() {
+            if !l.has_room(self.reserved_in_lobby(i)) {
+                continue
+            }
+            let n = l.num_players();
+            if best.map(|(_, bn)| n > bn).unwrap_or(true) {
+                best = Some((i, n));
+            }
+        }
+        best.map(|(i, _)| i)
+    }
+
+    /// Take this client's account's reconnect reservation, if it's still valid.
+    fn take_reconnect(&mut self) -> Option<PendingReconnect> {
+        let account_id;
+        {
+            let cs = match self.get_client_state(self.client_id) {
+                Some(cs) => cs,
+                None => return None
+            };
+            account_id = cs.borrow().account_id;
+        }
+        let mut reconnects = self.reconnects.borrow_mut();
+        match reconnects.iter().position(|r| r.account_id == account_id) {
+            Some(i) => {
+                let r = reconnects.remove(i);
+                if r.expires > precise_time_ns() { Some(r) } else { None }
+            },
+            None => None
+        }
+    }
+
+    /// Put a reconnecting player back in the party they dropped from, if it
+    /// still exists and can take them.
+    fn rejoin_party(&mut self, unique_id: u32) -> bool {
+        let reserved = self.reserved_in_party(unique_id);
+        let pr = self.parties.clone();
+        let ref mut parties = pr.borrow_mut();
+        for p in parties.iter_mut() {
+            if p.unique_id == unique_id {
+                if p.is_bursting() || p.num_players() + reserved >= p.player_limit() {
+                    return false
+                }
+                let cid = self.client_id;
+                return p.add_player(self, cid).is_ok()
+            }
+        }
+        false
+    }
+
+    /// Show a player the patch service's message of the day and the next
+    /// news item, if there are any.
+    pub fn send_motd(&self, client: usize) -> bool {
+        let motd = self.bulletin.borrow_mut().current();
+        if motd.len() == 0 {
+            return false
+        }
+        self.send_to_client(client, Message::LargeMsg(0, LargeMsg(motd)));
+        true
+    }
+
+    pub fn bb_char_dat(&mut self, m: BbCharDat) {
+        let BbCharDat(data) = m;
+        self.save_char_dat(data);
+        let first = {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref mut c = cr.borrow_mut();
+            !::std::mem::replace(&mut c.seen_motd, true)
+        };
+        if first {
+            self.send_motd(self.client_id);
+        }
+        let stripped = {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref mut c = cr.borrow_mut();
+            ::std::mem::replace(&mut c.banned_items_removed, 0)
+        };
+        if stripped > 0 {
+            info!("Removed {} banned items from client {}'s character", stripped, self.client_id);
+            self.send_error(self.client_id, "\tEBanned items were removed\nfrom your character.");
+        }
+        // If they dropped a moment ago, put them back where they were.
+        if let Some(r) = self.take_reconnect() {
+            info!("Client {} reconnected within the grace window", self.client_id);
+            if let Some(party) = r.party {
+                if self.rejoin_party(party) {
+                    return
+                }
+            }
+            if let Some(i) = r.lobby {
+                let reserved = self.reserved_in_lobby(i);
+                let lr = self.lobbies.clone();
+                let ref mut lobbies = lr.borrow_mut();
+                if lobbies[i].has_room(reserved) {
+                    let cid = self.client_id;
+                    lobbies[i].add_player(self, cid).unwrap();
+                    return
+                }
+            }
+        }
+
+        // They are joining a lobby now. Find an empty lobby.
+        let lr = self.lobbies.clone();
+        let ref mut lobbies = lr.borrow_mut();
+
+        // On a quiet block, bring everyone together.
+        if self.is_low_population() {
+            if let Some(i) = self.busiest_lobby_with_room(lobbies) {
+                let cid = self.client_id;
+                lobbies[i].add_player(self, cid).unwrap();
+                return
+            }
+        }
+
+        if let Some(i) = first_lobby_with_room(lobbies, |i| self.reserved_in_lobby(i)) {
+            let cid = self.client_id;
+            lobbies[i].add_player(self, cid).unwrap();
+            return
+        }
+
+        info!("Unable to add client {} to a lobby because they're all full.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEAll lobbies on this block are full. Please try again.");
+    }
+
+    pub fn bb_chat(&mut self, mut m: BbChat) {
+        if !self.allow_chat() {
+            return
+        }
+        let gc_num;
+        let player_name;
+
+        {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref c = cr.borrow();
+            gc_num = c.bb_guildcard;
+            player_name = c.full_char.as_ref().unwrap().chara.name.clone();
+        }
+        // Commands that work anywhere on the block. Others are left for the
+        // party, which has its own.
+        if let Some((name, args)) = commands::parse(&m.1) {
+            if commands::run(self, name, args) {
+                return
+            }
+        }
+        // First, we'll check if they're in a lobby.
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                if l.has_player(self.client_id) {
+                    if let Some((name, _)) = commands::parse(&m.1) {
+                        self.send_error(self.client_id, &format!("\tEUnknown command\n/{}", name));
+                        return
+                    }
+                    info!("<{:02}-{:02}> {}: {}", l.block_num(), l.lobby_num() + 1, player_name.trim_left_matches("\tE"), m.1.trim_left_matches("\tE"));
+                    self.log_chat(gc_num, 0, l.block_num(), l.lobby_num() + 1, &m.1);
+                    m.0 = gc_num;
+                    l.bb_broadcast(self, None, m.into()).unwrap();
+                    return
+                }
+            }
+        }
+        // Then, check if they're in a party
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            for p in parties.iter_mut() {
+                if p.has_player(self.client_id) {
+                    let cid = self.client_id;
+                    info!("<{}> {}: {}", &p.name[2..], player_name.trim_left_matches("\tE"), m.1.trim_left_matches("\tE"));
+                    m.0 = gc_num;
+                    p.handle_chat(self, cid, &m.1).unwrap();
+                    return
+                }
+            }
+        }
+    }
+
+    /// Send a chat message to the shipgate's chat log, if the block keeps
+    /// one. Nothing waits for it to be written.
+    pub fn log_chat(&mut self, guildcard: u32, target: u32, block: u16, lobby: u8, text: &str) {
+        if let Some(entry) = chat_log_entry(&self.options, guildcard, target, block, lobby, ::time::get_time().sec as u64, text) {
+            if let Err(e) = self.sg_sender.send(Sgm::BbChatLog(0, entry)) {
+                warn!("Couldn't send chat to the shipgate's chat log: {}", e);
+            }
+        }
+    }
+
+    /// The block number and lobby (from 1) the current client is in. The
+    /// lobby is 0 if they aren't in one.
+    pub fn chat_location(&self) -> (u16, u8) {
+        let lobbies = self.lobbies.borrow();
+        let block = lobbies.first().map(|l| l.block_num()).unwrap_or(0);
+        match lobbies.iter().find(|l| l.has_player(self.client_id)) {
+            Some(l) => (block, l.lobby_num() + 1),
+            None => (block, 0)
+        }
+    }
+
+    /// Move the player to another of the ship's blocks, once the shipgate
+    /// has saved their character.
+    pub fn change_block(&mut self, name: &str) {
+        let cid = self.client_id;
+        {
+            let pr = self.parties.clone();
+            let ref parties = pr.borrow();
+            if parties.iter().any(|p| p.has_player(cid)) {
+                self.send_error(cid, "\tEYou must leave your\nparty before changing\nblocks.");
+                return
+            }
+        }
+        let addr = match self.siblings.iter().find(|b| b.name.to_lowercase() == name.to_lowercase()) {
+            Some(b) => b.addr,
+            None => {
+                self.send_error(cid, &format!("\tEThe block {}\nis unknown.", name));
+                return
+            }
+        };
+        let transfer = match self.get_client_state(cid) {
+            Some(cs) => {
+                let c = cs.borrow();
+                match c.full_char {
+                    Some(ref fc) => BbBlockTransfer {
+                        account_id: c.account_id,
+                        slot: c.sec_data.slot,
+                        full_char: fc.clone()
+                    },
+                    None => return
+                }
+            },
+            None => return
+        };
+        info!("Client {} wants to move to block {}", cid, name);
+        self.sg_sender.request(cid, transfer, move |mut h, m| {
+            h.sg_block_transfer(&m, addr)
+        }).unwrap();
+    }
+
+    /// The shipgate has saved, or failed to save, a player's character
+    /// before they move to the block at `addr`. If they left in the
+    /// meantime, leaving already took them out of their lobby.
+    fn sg_block_transfer(&mut self, m: &Sgm, addr: SocketAddrV4) {
+        let present = self.get_client_state(self.client_id).is_some();
+        match block_transfer(m, present, addr) {
+            Some(BlockTransfer::Move(r)) => {
+                info!("Moving client {} to the block at {}", self.client_id, addr);
+                self.redirect_saved(r, 0xCAFEB00B);
+            },
+            Some(BlockTransfer::Failed(status)) => {
+                warn!("Couldn't save client {}'s character to move blocks (status {})", self.client_id, status);
+                self.send_error(self.client_id, "\tEYour character couldn't\nbe saved, so you\ncan't change blocks now.");
+            },
+            Some(BlockTransfer::Gone) => debug!("Client {} left before moving to {}", self.client_id, addr),
+            None => ()
+        }
+    }
+
+    /// Move the player to another ship. The shipgate's ship list only has
+    /// ships that are connected, so that doubles as the online check.
+    pub fn change_ship(&mut self, name: String) {
+        {
+            let pr = self.parties.clone();
+            let ref parties = pr.borrow();
+            if parties.iter().any(|p| p.has_player(self.client_id)) {
+                self.send_error(self.client_id, "\tEYou must leave your\nparty before changing\nships.");
+                return
+            }
+        }
+        info!("Client {} wants to move to ship {}", self.client_id, name);
+        self.sg_sender.request(self.client_id, SgShipList, move |mut h, m| {
+            if let Sgm::ShipListAck(_, ShipListAck(ships)) = m {
+                h.sg_change_ship(&name, ships)
+            }
+        }).unwrap();
+    }
+
+    fn sg_change_ship(&mut self, name: &str, ships: Vec<(SocketAddrV4, String, u32)>) {
+        let addr = match ships.iter().find(|&&(_, ref n, _)| listed_name(n).to_lowercase() == name.to_lowercase()) {
+            Some(&(a, _, _)) => a,
+            None => {
+                self.send_error(self.client_id, &format!("\tEThe ship {}\nis unknown or offline.", name));
+                return
+            }
+        };
+        let account_id = match self.get_client_state(self.client_id) {
+            Some(cs) => cs.borrow().account_id,
+            None => return
+        };
+
+        // The other ship only takes them with a token it can check with the
+        // shipgate, good for this move alone.
+        let name = name.to_string();
+        self.sg_sender.request(self.client_id, BbIssueHandoff { account_id: account_id }, move |mut h, m| {
+            if let Sgm::BbIssueHandoffAck(_, ack) = m {
+                info!("Moving client {} to ship {}", h.client_id, name);
+                h.redirect_with_magic(addr, ack.token);
+            }
+        }).unwrap();
+    }
+
+    /// Save the player's character, take them out of their lobby and send
+    /// them to another block, already logged in. Returns false if they have
+    /// no character loaded to send.
+    pub fn redirect(&mut self, addr: SocketAddrV4) -> bool {
+        self.redirect_with_magic(addr, 0xCAFEB00B)
+    }
+
+    /// As `redirect`, with `magic` in the security data they log in with.
+    fn redirect_with_magic(&mut self, addr: SocketAddrV4, magic: u32) -> bool {
+        let cr = match self.get_client_state(self.client_id) {
+            Some(cr) => cr,
+            // They left while we were waiting on the shipgate.
+            None => return false
+        };
+        {
+            let ref c = cr.borrow();
+            if c.full_char.is_none() {
+                return false
+            }
+            info!("Saving {}'s character before redirecting to {}", self.client_id, addr);
+            self.sg_sender.send(Sgm::BbPutCharacter(0, BbPutCharacter {
+                account_id: c.account_id,
+                slot: c.sec_data.slot,
+                save_acct_data: 0,
+                full_char: c.full_char.clone().unwrap()
+            })).unwrap();
+        }
+        self.redirect_saved(redirect_to(addr), magic)
+    }
+
+    /// Send the client on with `redirect` once their character has been
+    /// saved.
+    fn redirect_saved(&mut self, redirect: Redirect, magic: u32) -> bool {
+        let cr = match self.get_client_state(self.client_id) {
+            Some(cr) => cr,
+            None => return false
+        };
+        let sec_data;
+        let guildcard;
+        {
+            let ref mut c = cr.borrow_mut();
+            // Reissue their session so the next ship or block takes them
+            // straight in with the same character.
+            c.sec_data.magic = magic;
+            c.sec_data.sel_char = 1;
+            sec_data = c.sec_data.clone();
+            guildcard = c.bb_guildcard;
+        }
+
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            let cid = self.client_id;
+            for l in lobbies.iter_mut() {
+                if l.has_player(cid) {
+                    l.remove_moving_player(self, cid).unwrap();
+                    break
+                }
+            }
+        }
+
+        let r = Message::BbSecurity(0, BbSecurity {
+            err_code: 0,
+            tag: 0x00010000,
+            guildcard: guildcard,
+            team_id: 0xFFFFFFFF,
+            security_data: sec_data,
+            caps: 0x00000101
+        });
+        self.send_to_client(self.client_id, r);
+        self.send_to_client(self.client_id, Message::Redirect(0, redirect));
+        true
+    }
+
+    pub fn bb_create_game(&mut self, m: BbCreateGame) {
+        info!("Client {} is creating party {}", self.client_id, &m.name[2..]);
+        if self.draining.get() {
+            self.send_error(self.client_id, "\tEThis block is closing.\nPlease make your party\non another block.");
+            return
+        }
+
+        let psr = self.parties.clone();
+        let mut parties = psr.borrow_mut();
+        // check if a party with that name already exists
+        for p in parties.iter_mut() {
+            if p.name == m.name {
+                self.send_error(self.client_id, "\tEA party with that\nname already exists.");
+                return
+            }
+        }
+
+        // validate based on server support
+        info!("Party is for episode {}", m.episode);
+        if m.challenge != 0 && m.episode == 3 {
+            self.send_error(self.client_id, "\tEChallenge mode is not supported\non Episode 4.\nOnly Episode 1 and 2 have\nChallenge mode.");
+            return
+        }
+        if m.battle != 0 {
+            self.send_error(self.client_id, "\tEBattle mode is not\nsupported yet. Sorry!");
+            return
+        }
+        if m.challenge != 0 {
+            self.send_error(self.client_id, "\tEChallenge mode is not\nsupported yet. Sorry!");
+            return
+        }
+
+        // we first want to remove them from their lobby...
+        let mut event = 0xFFFF;
+        {
+            let lsr = self.lobbies.clone();
+            let mut lobbies = lsr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                if l.has_player(self.client_id) {
+                    let cid = self.client_id;
+                    event = l.event_num();
+                    l.remove_player(self, cid).unwrap();
+                    break;
+                }
+            }
+            if event == 0xFFFF {
+                // player isn't in a lobby...
+                self.send_fatal_error(self.client_id, "\tEIllegal message");
+                return
+            }
+        }
+
+        // create the party
+        let unique_id = self.get_new_party_id();
+        let pass: Option<&str> = if m.password.len() == 0 { None } else { Some(&m.password) };
+        let mut p;
+        if m.single_player > 0 {
+            p = Party::new(&m.name, pass, m.episode, m.difficulty, m.battle != 0, m.challenge != 0, true, event, self.offline_maps.clone(), unique_id);
+        } else {
+            p = Party::new(&m.name, pass, m.episode, m.difficulty, m.battle != 0, m.challenge != 0, false, event, self.online_maps.clone(), unique_id);
+        }
+
+        let cid = self.client_id;
+        p.add_player(self, cid).unwrap();
+
+        parties.push(p);
+    }
+
+    pub fn bb_subcmd_60(&mut self, m: BbSubCmd60) {
+        if let BbSubCmd60::Bb60EquipItem { ref data, .. } = m {
+            if !self.check_identified(data.item_id, "equip") || !self.check_not_banned(data.item_id, "equip") {
+                return
+            }
+        }
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                let cid = self.client_id;
+                if l.has_player(cid) {
+                    let cmd = m.cmd();
+                    if !self.minigames_allowed(l.lobby_num()) && self.options.lobby_minigame_subcmds.contains(&cmd) {
+                        debug!("Ignoring lobby minigame subcommand {:#x} from client {}", cmd, cid);
+                        return
+                    }
+                    l.handle_bb_subcmd_60(self, m).unwrap();
+                    return
+                }
+            }
+        }
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            for p in parties.iter_mut() {
+                let cid = self.client_id;
+                if p.has_player(cid) {
+                    p.handle_bb_subcmd_60(self, cid, m).unwrap();
+                    return
+                }
+            }
+        }
+
+        warn!("Could not find the lobby or party this subcmd was sent by {}.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEIllegal message");
+    }
+
+    /// Whether the client may equip or sell the item with this ID. With
+    /// `enforce_identification` on, unidentified weapons can't be; the
+    /// client's own menus don't offer it, so trying counts as suspicious.
+    pub fn check_identified(&self, item_id: u32, action: &str) -> bool {
+        if !self.options.enforce_identification {
+            return true
+        }
+        let cid = self.client_id;
+        let unidentified = self.get_client_state(cid)
+            .and_then(|cs| cs.borrow().full_char.as_ref()
+                .and_then(|fc| fc.inv.find(item_id).map(|i| i.data.is_unidentified())))
+            .unwrap_or(false);
+        if unidentified {
+            self.flag_suspicious(cid, SUSPICION_UNIDENTIFIED, format!("tried to {} unidentified item {:08X}", action, item_id));
+            self.send_error(cid, "\tEThat weapon has to be\nidentified first.");
+        }
+        !unidentified
+    }
+
+    /// Whether lobby minigames are on in the lobby with this index.
+    fn minigames_allowed(&self, lobby: u8) -> bool {
+        self.options.lobby_minigames && !self.options.minigame_free_lobbies.contains(&(lobby + 1))
+    }
+
+    pub fn bb_subcmd_62(&mut self, dest: u32, m: BbSubCmd62) {
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                let cid = self.client_id;
+                if l.has_player(cid) {
+                    l.handle_bb_subcmd_62(self, dest, m).unwrap();
+                    return
+                }
+            }
+        }
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            for p in parties.iter_mut() {
+                let cid = self.client_id;
+                if p.has_player(cid) {
+                    p.handle_bb_subcmd_62(self, cid, dest, m).unwrap();
+                    return
+                }
+            }
+        }
+
+        warn!("Could not find the lobby or party this subcmd was sent by {}.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEIllegal message");
+    }
+
+    pub fn bb_subcmd_6c(&mut self, m: BbSubCmd6C) {
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                let cid = self.client_id;
+                if l.has_player(cid) {
+                    l.handle_bb_subcmd_6c(self, m).unwrap();
+                    return
+                }
+            }
+        }
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            for p in parties.iter_mut() {
+                let cid = self.client_id;
+                if p.has_player(cid) {
+                    p.handle_bb_subcmd_6c(self, cid, m).unwrap();
+                    return
+                }
+            }
+        }
+
+        warn!("Could not find the lobby or party this subcmd was sent by {}.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEIllegal message");
+    }
+
+    pub fn bb_subcmd_6d(&mut self, dest: u32, m: BbSubCmd6D) {
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for l in lobbies.iter_mut() {
+                let cid = self.client_id;
+                if l.has_player(cid) {
+                    l.handle_bb_subcmd_6d(self, dest, m).unwrap();
+                    return
+                }
+            }
+        }
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            for p in parties.iter_mut() {
+                let cid = self.client_id;
+                if p.has_player(cid) {
+                    p.handle_bb_subcmd_6d(self, cid, dest, m).unwrap();
+                    return
+                }
+            }
+        }
+
+        warn!("Could not find the lobby or party this subcmd was sent by {}.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEIllegal message");
+    }
+
+    pub fn bb_lobby_change(&mut self, m: LobbyChange) {
+        if !self.allow_lobby_change() {
+            return
+        }
+        let lr = self.lobbies.clone();
+        let ref mut lobbies = lr.borrow_mut();
+        // first, check if that lobby isn't full
+        match m.1 {
+            l if l >= 1 && (l as usize) <= lobbies.len() => {
+                if !lobbies[l as usize-1].has_room(self.reserved_in_lobby(l as usize-1)) {
+                    self.send_error(self.client_id, "\tELobby is full.");
+                    return
+                }
+            },
+            _ => {
+                warn!("Client {} tried to join an invalid lobby", self.client_id);
+                self.send_fatal_error(self.client_id, "\tEYou tried to join an invalid lobby.\nDisconnected.");
+                return
+            }
+        }
+        let cid = self.client_id;
+        for l in lobbies.iter_mut() {
+            if l.has_player(cid) {
+                l.remove_player(self, cid).unwrap();
+                break
+            }
+        }
+        lobbies[m.1 as usize-1].add_player(self, cid).unwrap();
+        if let Some(cs) = self.get_client_state(cid) {
+            cs.borrow_mut().chose_lobby = true;
+        }
+    }
+
+    /// Send the client's play time since it was last sent to the shipgate.
+    pub fn credit_playtime(&self, client: usize) {
+        if !self.options.track_playtime {
+            return
+        }
+        let cs = match self.get_client_state(client) {
+            Some(cs) => cs,
+            None => return
+        };
+        let (account_id, slot, seconds) = {
+            let mut c = cs.borrow_mut();
+            (c.account_id, c.sec_data.slot, c.take_playtime(precise_time_ns()))
+        };
+        if seconds > 0 {
+            self.sg_sender.send(Sgm::BbAddPlaytime(0, BbAddPlaytime {
+                account_id: account_id,
+                slot: slot,
+                seconds: seconds
+            })).unwrap();
+        }
+    }
+
+    /// Tell the client how long they've played, counting time not yet sent
+    /// to the shipgate.
+    fn show_playtime(&mut self) {
+        if !self.options.track_playtime {
+            self.send_error(self.client_id, "\tEPlay time isn't kept\non this block.");
+            return
+        }
+        let (account_id, slot, unsent) = {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let c = cr.borrow();
+            let unsent = c.playing_since.map(|s| precise_time_ns().saturating_sub(s) / 1_000_000_000).unwrap_or(0);
+            (c.account_id, c.sec_data.slot, unsent)
+        };
+        let sgm: Sgm = BbGetPlaytime { account_id: account_id, slot: slot }.into();
+        self.sg_sender.request(self.client_id, sgm, move |h, m| {
+            if let Sgm::BbGetPlaytimeAck(_, body) = m {
+                if body.status != 0 {
+                    error!("Shipgate couldn't get play time for account {}, status code {}", body.account_id, body.status);
+                    h.send_error(h.client_id, "\tECouldn't look up\nyour play time.");
+                    return
+                }
+                let msg = format!("\tEAccount: {}\nThis character: {}",
+                    format_playtime(body.account_seconds + unsent),
+                    format_playtime(body.character_seconds + unsent));
+                h.send_error(h.client_id, &msg);
+            }
+        }).unwrap();
+    }
+
+    /// Whether the client's chat message may go through, or they've been
+    /// sending too many. Tells them the first time one is dropped.
+    fn allow_chat(&self) -> bool {
+        let limit = self.options.chat_limit;
+        if limit == 0 {
+            return true
+        }
+        let cid = self.client_id;
+        match self.get_client_state(cid) {
+            Some(cs) => {
+                let window = self.options.chat_window as u64 * 1_000_000_000;
+                let mut c = cs.borrow_mut();
+                if c.note_chat(precise_time_ns(), limit as usize, window) {
+                    return true
+                }
+                if c.chat_warned {
+                    return false
+                }
+                c.chat_warned = true;
+            },
+            None => return true
+        }
+        warn!("Client {} is chatting too quickly, dropping messages", cid);
+        self.send_error(cid, "\tEYou're chatting too quickly.");
+        false
+    }
+
+    /// Whether the client may change lobbies now, or has been doing it too
+    /// quickly. Tells them, or drops them if they keep at it.
+    fn allow_lobby_change(&self) -> bool {
+        let limit = self.options.lobby_change_limit;
+        if limit == 0 {
+            return true
+        }
+        let cid = self.client_id;
+        let strikes = match self.get_client_state(cid) {
+            Some(cs) => {
+                let window = self.options.lobby_change_window as u64 * 1_000_000_000;
+                let mut c = cs.borrow_mut();
+                if c.note_lobby_change(precise_time_ns(), limit as usize, window) {
+                    return true
+                }
+                c.lobby_change_strikes
+            },
+            None => return true
+        };
+        let kick = self.options.lobby_change_kick;
+        if kick > 0 && strikes >= kick {
+            warn!("Client {} kept changing lobbies too quickly, disconnecting", cid);
+            self.send_fatal_error(cid, "\tEYou changed lobbies too many times.\nDisconnected.");
+        } else {
+            warn!("Client {} is changing lobbies too quickly ({} ignored)", cid, strikes);
+            self.send_error(cid, "\tEYou're changing lobbies\ntoo quickly.");
+        }
+        false
+    }
+
+    pub fn bb_game_name(&mut self) {
+        let pr = self.parties.clone();
+        let ref mut parties = pr.borrow_mut();
+        for p in parties.iter_mut() {
+            if p.has_player(self.client_id) {
+                p.handle_bb_game_name(self).unwrap();
+                return
+            }
+        }
+        warn!("Client {} requested party name when they weren't in a party.", self.client_id);
+        self.send_fatal_error(self.client_id, "\tEIllegal message.");
+        return
+    }
+
+    pub fn bb_game_list(&mut self) {
+        let mut games = Vec::new();
+        let pr = self.parties.clone();
+        let ref mut parties = pr.borrow_mut();
+
+        let mut game = BbGameListEntry::default();
+        game.menu_id = MENU_GAME_LIST;
+        game.item_id = 0xFFFFFFFF;
+        game.flags = 0x4;
+        games.push(game);
+
+        for p in parties.iter(){
+            let mut game = BbGameListEntry::default();
+            game.menu_id = MENU_GAME_LIST;
+            game.item_id = p.unique_id as u32;
+            game.name = p.name.clone();
+            game.episode = (4 << 4) | p.episode;
+            game.difficulty = 0x22 + p.difficulty;
+            game.players = p.num_players() as u8;
+            if p.password.is_some() {
+                game.flags |= 0x2;
+            }
+            if p.battle {
+                game.flags |= 0x10;
+            }
+            if p.challenge {
+                game.flags |= 0x20;
+            }
+            if p.single_player {
+                game.flags |= 0x4;
+            }
+            games.push(game);
+        }
+        let m: Message = Message::BbGameList(games.len() as u32 - 1, BbGameList { games: games });
+        self.send_to_client(self.client_id, m);
+    }
+
+    pub fn bb_player_leave_game(&mut self, _m: BbPlayerLeaveGame) {
+        let pr = self.parties.clone();
+        let ref mut parties = pr.borrow_mut();
+        let mut removed = 0;
+        let mut party_index = 0;
+        for (i, p) in parties.iter_mut().enumerate() {
+            if p.has_player(self.client_id) {
+                let cid = self.client_id;
+                if p.remove_player(self, cid).unwrap() {
+                    // remove the party
+                    party_index = i;
+                    removed = 2;
+                    break;
+                }
+                removed = 1;
+                break;
+            }
+        }
+        match removed {
+            1 => {
+                // party will live on
+            },
+            2 => {
+                // party is empty; remove now
+                parties.remove(party_index);
+            },
+            _ => {
+                warn!("Client {} was not in a game and tried to leave one", self.client_id);
+                self.send_fatal_error(self.client_id, "\tEIllegal message");
+            }
+        }
+    }
+
+    pub fn bb_update_options(&mut self, m: BbUpdateOptions) {
+        use ::shipgate::msg::BbUpdateOptions as SgBbUO;
+        info!("{} updated general options", self.client_id);
+        let options = m.0;
+        let cr = self.get_client_state(self.client_id).unwrap();
+        let ref client_state = cr.borrow();
+        self.sg_sender.send(Sgm::BbUpdateOptions(0, SgBbUO {
+            account_id: client_state.account_id,
+            options: options
+        })).unwrap();
+    }
+
+    pub fn bb_update_keys(&mut self, m: BbUpdateKeys) {
+        use ::shipgate::msg::BbUpdateKeys as SgBbUK;
+        info!("{} updated keyboard configuration", self.client_id);
+        let keys = m.0;
+        let cr = self.get_client_state(self.client_id).unwrap();
+        let ref mut client_state = cr.borrow_mut();
+        // Keep the loaded character in step so a later save doesn't revert it.
+        if let Some(ref mut fc) = client_state.full_char {
+            fc.key_config.key_config = keys.clone();
+        }
+        self.sg_sender.send(Sgm::BbUpdateKeys(0, SgBbUK {
+            account_id: client_state.account_id,
+            key_config: keys
+        })).unwrap();
+    }
+
+    pub fn bb_update_joy(&mut self, m: BbUpdateJoy) {
+        use ::shipgate::msg::BbUpdateJoy as SgBbJ;
+        info!("{} updated joystick configuration", self.client_id);
+        let joy = m.0;
+        let cr = self.get_client_state(self.client_id).unwrap();
+        let ref mut client_state = cr.borrow_mut();
+        if let Some(ref mut fc) = client_state.full_char {
+            fc.key_config.joy_config = joy.clone();
+        }
+        self.sg_sender.send(Sgm::BbUpdateJoy(0, SgBbJ {
+            account_id: client_state.account_id,
+            joy_config: joy
+        })).unwrap();
+    }
+
+    pub fn bb_update_shortcuts(&mut self, m: BbUpdateShortcuts) {
+        use ::shipgate::msg::BbUpdateShortcuts as SgBbUS;
+        info!("{} updated chat shortcuts", self.client_id);
+        let shortcuts = m.0;
+        let cr = self.get_client_state(self.client_id).unwrap();
+        let ref mut client_state = cr.borrow_mut();
+        if let Some(ref mut fc) = client_state.full_char {
+            fc.shortcuts = shortcuts.clone();
+        }
+        self.sg_sender.send(Sgm::BbUpdateShortcuts(0, SgBbUS {
+            account_id: client_state.account_id,
+            shortcuts: shortcuts
+        })).unwrap();
+    }
+
+    pub fn menu_select(&mut self, m: MenuSelect) {
+        let MenuSelect(menu_id, item_id) = m;
+        match menu_id {
+            MENU_GAME_LIST => {
+                let pr = self.parties.clone();
+                let mut parties = pr.borrow_mut();
+                for p in parties.iter_mut() {
+                    if p.unique_id == item_id {
+                        let cid = self.client_id;
+                        // First, verify they can join the game
+                        if p.is_bursting() {
+                            self.send_error(self.client_id, "\tEA player is bursting.\nPlease wait.");
+                            return
+                        }
+                        if p.player_limit() == 1 {
+                            self.send_error(self.client_id, "\tEParty is One Person only.");
+                            return
+                        }
+                        if p.is_full() || p.num_players() + self.reserved_in_party(p.unique_id) >= p.player_limit() {
+                            self.send_error(self.client_id, "\tEParty is full.");
+                            return
+                        }
+
+                        // Then, remove them from their lobby
+                        let lr = self.lobbies.clone();
+                        let mut lobbies = lr.borrow_mut();
+                        for l in lobbies.iter_mut() {
+                            if l.has_player(cid) {
+                                if let Err(e) = l.remove_player(self, cid) {
+                                    error!("Failed to remove player from lobby after party join: {:?}", e);
+                                    self.send_fatal_error(self.client_id, &format!("\tE{:?}", e));
+                                    return
+                                }
+                                break
+                            }
+                        }
+                        // Then add them to their game
+                        if let Err(e) = p.add_player(self, cid) {
+                            error!("Failed to join party: {:?}", e);
+                            self.send_fatal_error(self.client_id, &format!("\tE{:?}", e));
+                            return
+                        }
+                        break
+                    }
+                }
+                self.send_error(self.client_id, "\tEParty no longer\texists.");
+            },
+            _ => {
+                self.send_error(self.client_id, "\tEInvalid menu");
+                return
+            }
+        }
+    }
+
+    /// The client is logging out cleanly; its connection closes next.
+    pub fn bb_goodbye(&mut self) {
+        if let Some(cr) = self.get_client_state(self.client_id) {
+            cr.borrow_mut().logged_out = true;
+        }
+    }
+
+    pub fn done_burst(&mut self) {
+        let pr = self.parties.clone();
+        let mut parties = pr.borrow_mut();
+        for p in parties.iter_mut() {
+            if p.has_player(self.client_id) {
+                p.handle_bb_done_burst(self).unwrap();
+                break
+            }
+        }
+    }
+
+    /// Switch the client's active character to another slot without a
+    /// reconnect. The current character is saved first, then the new slot is
+    /// loaded from the shipgate and the client goes back through the
+    /// CharDataRequest -> BbCharDat flow to rejoin a lobby.
+    pub fn bb_char_select(&mut self, m: BbCharSelect) {
+        let BbCharSelect { slot, selecting } = m;
+        if !selecting {
+            // Slot previews only make sense on the login server.
+            let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: 2 });
+            self.send_to_client(self.client_id, r);
+            return
+        }
+        if slot >= 4 {
+            warn!("Client {} tried to switch to invalid slot {}", self.client_id, slot);
+            self.send_fatal_error(self.client_id, "\tEInvalid character slot.");
+            return
+        }
+
+        {
+            let pr = self.parties.clone();
+            let ref parties = pr.borrow();
+            for p in parties.iter() {
+                if p.has_player(self.client_id) {
+                    self.send_error(self.client_id, "\tEYou must leave your\nparty before switching\ncharacters.");
+                    return
+                }
+            }
+        }
+
+        let account_id;
+        {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref client_state = cr.borrow();
+            if client_state.full_char.is_none() {
+                warn!("Client {} tried to switch characters before logging in", self.client_id);
+                self.send_fatal_error(self.client_id, "\tEIllegal message");
+                return
+            }
+            account_id = client_state.account_id;
+            if client_state.sec_data.slot as u32 == slot {
+                let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: 0 });
+                self.send_to_client(self.client_id, r);
+                return
+            }
+
+            // Persist the outgoing character before anything else happens.
+            info!("Saving {}'s character before switching to slot {}", self.client_id, slot);
+            self.sg_sender.send(Sgm::BbPutCharacter(0, BbPutCharacter {
+                account_id: account_id,
+                slot: client_state.sec_data.slot,
+                save_acct_data: 0,
+                full_char: client_state.full_char.clone().unwrap()
+            })).unwrap();
+        }
+
+        // Characters are keyed by account, so this only finds slots they own.
+        let sgm: Sgm = BbGetCharacter { account_id: account_id, slot: slot as u8 }.into();
+        self.sg_sender.request(self.client_id, sgm, move |mut h, m| {
+            if let Sgm::BbGetCharacterAck(_, body) = m {
+                h.sg_switch_character_ack(slot, body)
+            }
+        }).unwrap();
+    }
+
+    fn sg_switch_character_ack(&mut self, slot: u32, m: BbGetCharacterAck) {
+        let BbGetCharacterAck { status, full_char, .. } = m;
+        if status != 0 || full_char.is_none() {
+            info!("Client {} tried to switch to empty slot {}", self.client_id, slot);
+            let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: 2 });
+            self.send_to_client(self.client_id, r);
+            return
+        }
+        let mut full_char = full_char.unwrap();
+        let stripped = self.strip_banned_items(&mut full_char);
+        self.enforce_caps(&mut full_char);
+
+        // They may have joined a party while waiting on the shipgate.
+        {
+            let pr = self.parties.clone();
+            let ref parties = pr.borrow();
+            for p in parties.iter() {
+                if p.has_player(self.client_id) {
+                    self.send_error(self.client_id, "\tEYou must leave your\nparty before switching\ncharacters.");
+                    return
+                }
+            }
+        }
+
+        // Pull them out of their lobby; BbCharDat will put them back in with
+        // the new character's data.
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            let cid = self.client_id;
+            for l in lobbies.iter_mut() {
+                if l.has_player(cid) {
+                    l.remove_player(self, cid).unwrap();
+                    break
+                }
+            }
+        }
+
+        // The time so far goes to the character they're leaving.
+        self.credit_playtime(self.client_id);
+        {
+            let cr = self.get_client_state(self.client_id).unwrap();
+            let ref mut client_state = cr.borrow_mut();
+            // Replace the whole character so nothing carries over from the old one.
+            client_state.full_char = Some(full_char.clone());
+            client_state.playing_since = Some(precise_time_ns());
+            client_state.banned_items_removed = stripped;
+            client_state.sec_data.slot = slot as u8;
+            client_state.sec_data.sel_char = 1;
+            // Until the shipgate answers, don't hold them to the old count.
+            client_state.storage_quota = 0;
+        }
+        info!("Client {} switched to character slot {}", self.client_id, slot);
+        let account_id = self.get_client_state(self.client_id).unwrap().borrow().account_id;
+        self.request_storage_usage(account_id, slot as u8);
+
+        let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: 0 });
+        self.send_to_client(self.client_id, r);
+        let r = Message::BbFullChar(0, BbFullChar(full_char));
+        self.send_to_client(self.client_id, r);
+        let r = Message::CharDataRequest(0, CharDataRequest);
+        self.send_to_client(self.client_id, r);
+    }
+
+    /// Delete one of the client's characters. The one they're playing can't
+    /// be deleted from inside a game; deleted from a lobby, it signs them
+    /// off, since there's nothing left to play.
+    pub fn delete_character(&mut self, slot: u8) {
+        let cid = self.client_id;
+        if slot >= 4 {
+            self.send_error(cid, "\tEUsage: /delete <1-4>");
+            return
+        }
+        let (account_id, playing) = {
+            let cr = self.get_client_state(cid).unwrap();
+            let ref c = cr.borrow();
+            if c.full_char.is_none() {
+                warn!("Client {} tried to delete a character before logging in", cid);
+                return
+            }
+            (c.account_id, c.sec_data.slot == slot)
+        };
+        if playing {
+            let pr = self.parties.clone();
+            let ref parties = pr.borrow();
+            if parties.iter().any(|p| p.has_player(cid)) {
+                self.send_error(cid, "\tEYou can't delete the\ncharacter you're playing\nin a game.");
+                return
+            }
+        }
+
+        let sgm: Sgm = BbDeleteCharacter { account_id: account_id, slot: slot }.into();
+        self.sg_sender.request(cid, sgm, move |mut h, m| {
+            if let Sgm::BbDeleteCharacterAck(_, body) = m {
+                h.sg_delete_character_ack(body)
+            }
+        }).unwrap();
+    }
+
+    fn sg_delete_character_ack(&mut self, m: BbDeleteCharacterAck) {
+        let cid = self.client_id;
+        if m.status != 0 {
+            error!("Shipgate error deleting character slot {} for account {}, status code {}", m.slot, m.account_id, m.status);
+            self.send_error(cid, "\tECouldn't delete\nthat character.");
+            return
+        }
+        if m.deleted == 0 {
+            self.send_error(cid, &format!("\tESlot {} is already empty.", m.slot + 1));
+            return
+        }
+        let playing = {
+            let cr = match self.get_client_state(cid) {
+                Some(c) => c,
+                None => return
+            };
+            let ref mut c = cr.borrow_mut();
+            match forget_deleted(c, &m) {
+                Some(p) => p,
+                None => return
+            }
+        };
+        info!("Client {} deleted their character in slot {}", cid, m.slot);
+        if playing {
+            self.send_fatal_error(cid, "\tEYour character\nhas been deleted.");
+        } else {
+            self.send_error(cid, &format!("\tECharacter {} deleted.", m.slot + 1));
+            // The deleted character's items no longer count against them.
+            let slot = self.get_client_state(cid).unwrap().borrow().sec_data.slot;
+            self.request_storage_usage(m.account_id, slot);
+        }
+    }
+
+    /// Take what the client may change from the character it sends as it
+    /// joins a lobby, its info board, auto-reply and options, into the one
+    /// we hold, and write it through to the shipgate so their edits survive
+    /// a restart. The shipgate inserts the row if the slot has none yet.
+    fn save_char_dat(&mut self, data: BbPlayerData) {
+        let BbPlayerData { chara, infoboard, autoreply, .. } = data;
+
+        let cs = self.get_client_state(self.client_id).unwrap();
+        let ref mut c = cs.borrow_mut();
+        {
+            let fc = match c.full_char {
+                Some(ref mut fc) => fc,
+                None => {
+                    warn!("Client {} sent character data with no character loaded", self.client_id);
+                    return
+                }
+            };
+            fc.chara.config = chara.config;
+            fc.infoboard = infoboard;
+            fc.autoreply = autoreply;
+        }
+        self.sg_sender.send(Sgm::BbPutCharacter(0, BbPutCharacter {
+            account_id: c.account_id,
+            slot: c.sec_data.slot,
+            save_acct_data: 0,
+            full_char: c.full_char.clone().unwrap()
+        })).unwrap();
+    }
+
+    /// Their card, then the cards of the others in their lobby who have a
+    /// character loaded. Anyone who has left is skipped.
+    fn lobby_cards(&self, client_id: usize) -> Vec<BbAddGuildCard> {
+        let mut ids = vec![client_id];
+        {
+            let lobbies = self.lobbies.borrow();
+            if let Some(l) = lobbies.iter().find(|l| l.has_player(client_id)) {
+                ids.extend(l.players().into_iter().filter(|&id| id != client_id));
+            }
+        }
+        let clients = self.clients.borrow();
+        ids.iter().filter_map(|id| clients.get(id)).filter_map(|cr| {
+            let c = cr.borrow();
+            c.full_char.as_ref().map(|fc| card_for(c.bb_guildcard, fc))
+        }).collect()
+    }
+
+    /// Send the header of a guild card file holding the cards of everyone
+    /// in their lobby. The client then asks for it in chunks.
+    pub fn bb_guildcard_req(&mut self) {
+        let file = guildcard_file(&self.lobby_cards(self.client_id));
+        let r = Message::BbGuildCardHdr(0, BbGuildCardHdr {
+            one: 1,
+            len: file.len() as u32,
+            checksum: guildcard_checksum(&file)
+        });
+        if let Some(cs) = self.get_client_state(self.client_id) {
+            cs.borrow_mut().guildcard_file = Some(file);
+        }
+        self.send_to_client(self.client_id, r);
+    }
+
+    pub fn bb_guildcard_chunk_req(&mut self, m: BbGuildCardChunkReq) {
+        let BbGuildCardChunkReq(_, chunk, cont) = m;
+        let cs = match self.get_client_state(self.client_id) {
+            Some(cs) => cs,
+            None => return
+        };
+        if !cont {
+            cs.borrow_mut().guildcard_file = None;
+            return
+        }
+        let data = match cs.borrow().guildcard_file {
+            Some(ref f) => guildcard_chunk(f, chunk).map(|d| d.to_vec()),
+            None => None
+        };
+        match data {
+            Some(data) => {
+                debug!("Sending guild card chunk {} of size {} to {}", chunk, data.len(), self.client_id);
+                let r = Message::BbGuildCardChunk(0, BbGuildCardChunk {
+                    unk: 0,
+                    chunk: chunk,
+                    data: data
+                });
+                self.send_to_client(self.client_id, r);
+            },
+            None => {
+                debug!("Client {} asked for guild card chunk {}, which isn't there", self.client_id, chunk);
+                cs.borrow_mut().guildcard_file = None;
+            }
+        }
+    }
+
+    pub fn bb_full_char(&mut self, m: BbFullChar) {
+        // TODO verify... or just track based on their other messages sent
+        // this is prone to being cheated. we'll just save some parts until
+        // the implementation is more thorough.
+        let BbFullChar(full_char) = m;
+
+        // The bank is tracked here, and the client's copy may be the shared
+        // bank's contents, so it's left alone.
+        let BbFullCharData { inv, chara, .. } = full_char;
+
+        let cs = self.get_client_state(self.client_id).unwrap();
+        let ref mut client_state = cs.borrow_mut();
+        if let Some(ref mut cur_fc) = client_state.full_char {
+            info!("Client {} triggered manual save", self.client_id);
+            cur_fc.inv = inv;
+            cur_fc.chara = chara;
+        } else {
+            warn!("Client sent full character but we didn't have one loaded for them. This is an abnormal state.");
+            return
+        }
+    }
+}
+
+/// Clients other than `client_id` already logged in with `guildcard`.
+fn stale_logins(clients: &HashMap<usize, Rc<RefCell<ClientState>>>, guildcard: u32, client_id: usize) -> Vec<usize> {
+    clients.iter()
+        .filter(|&(&id, c)| id != client_id && c.borrow().bb_guildcard == guildcard)
+        .map(|(&id, _)| id)
+        .collect()
+}
+
+/// Whether `max` players are already let on, so `client_id` can't join
+/// them. Players count from being admitted, while their character is still
+/// loading, so logins that arrive together can't all slip in. Connections
+/// the shipgate hasn't answered for yet aren't counted, and neither is an
+/// older login with `guildcard`, which is dropped to make way. 0 means no
+/// limit.
+fn block_full(clients: &HashMap<usize, Rc<RefCell<ClientState>>>, client_id: usize, guildcard: u32, max: u32) -> bool {
+    if max == 0 {
+        return false
+    }
+    let players = clients.iter()
+        .filter(|&(&id, c)| {
+            let c = c.borrow();
+            id != client_id && c.bb_guildcard != 0 && c.bb_guildcard != guildcard
+        })
+        .count();
+    players >= max as usize
+}
+
+fn redirect_to(addr: SocketAddrV4) -> Redirect {
+    Redirect {
+        ip: *addr.ip(),
+        port: addr.port()
+    }
+}
+
+/// What happens to a player moving blocks once the shipgate has answered.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockTransfer {
+    /// Their character is saved; send them on.
+    Move(Redirect),
+    /// Their character couldn't be saved, with the shipgate's status code.
+    /// They stay where they are.
+    Failed(u32),
+    /// They left while the shipgate was saving.
+    Gone
+}
+
+/// The outcome of the shipgate's reply `m` to a move to the block at
+/// `addr`, given whether the player is still connected. `None` if `m`
+/// isn't a reply to a block transfer.
+pub fn block_transfer(m: &Sgm, present: bool, addr: SocketAddrV4) -> Option<BlockTransfer> {
+    let ack = match m {
+        &Sgm::BbBlockTransferAck(_, ref ack) => ack,
+        _ => return None
+    };
+    Some(if !present {
+        BlockTransfer::Gone
+    } else if ack.status == 0 {
+        BlockTransfer::Move(redirect_to(addr))
+    } else {
+        BlockTransfer::Failed(ack.status)
+    })
+}
+
+/// Bring the client's state up to date with a character deletion the
+/// shipgate has done. `None` if it was for some other account (they've
+/// logged out and someone else has the connection), otherwise whether it
+/// was the character they're playing.
+fn forget_deleted(c: &mut ClientState, m: &BbDeleteCharacterAck) -> Option<bool> {
+    if c.account_id != m.account_id {
+        return None
+    }
+    if c.sec_data.slot == m.slot {
+        // Nothing to save on the way out, or the row would come back, and
+        // no play time to credit to it.
+        c.full_char = None;
+        c.playing_since = None;
+        Some(true)
+    } else {
+        Some(false)
+    }
+}
+
+/// Seconds as "12h 05m".
+fn format_playtime(seconds: u64) -> String {
+    format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::mpsc::{channel, Receiver};
+
+    use mio::{EventLoop, Handler};
+    use psoserial::Serial;
+
+    use ::block::BlockService;
+    use ::services::message::NetMsg;
+    use ::services::metrics::Metrics;
+    use ::shipgate::client::{SgSender, ClientMsg};
+    use ::shipgate::msg::{BbCheckBanAck, BbBlockTransferAck, BbDeleteCharacterAck};
+
+    /// Collects what the block sends to the loop.
+    pub struct Collect(Vec<LoopMsg>);
+
+    impl Handler for Collect {
+        type Timeout = ();
+        type Message = LoopMsg;
+
+        fn notify(&mut self, _event_loop: &mut EventLoop<Self>, msg: LoopMsg) {
+            self.0.push(msg);
+        }
+    }
+
+    /// A block with one lobby and no other blocks to go to, and what it asks
+    /// the shipgate.
+    pub fn test_block(event_loop: &EventLoop<Collect>, options: BlockOptions) -> (BlockService, Receiver<ClientMsg>) {
+        let (sg, sg_rx) = SgSender::detached();
+        let (tx, rx) = channel();
+        let mut b = BlockService::new(rx, event_loop.channel(), sg.clone_with(tx), 1, 1, 0,
+                                      None, None, Vec::new(), options, Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      EventLog::disabled(), 0, Vec::new(), Vec::new(), false,
+                                      Arc::new(Metrics::new()));
+        b.init_lobbies();
+        (b, sg_rx)
+    }
+
+    /// What the block has sent to the loop so far.
+    pub fn sent(event_loop: &mut EventLoop<Collect>) -> Vec<LoopMsg> {
+        let mut h = Collect(Vec::new());
+        event_loop.run_once(&mut h, Some(10)).unwrap();
+        h.0
+    }
+
+    fn account(account_id: u32, guildcard: u32) -> BbGetAccountInfoAck {
+        BbGetAccountInfoAck {
+            status: 0,
+            account_id: account_id,
+            guildcard_num: guildcard,
+            team_id: 0,
+            options: 0,
+            key_config: Vec::new(),
+            joy_config: Vec::new(),
+            shortcuts: Vec::new(),
+            symbol_chats: Vec::new()
+        }
+    }
+
+    /// The accounts whose characters the block has asked the shipgate for.
+    fn characters_fetched(sg_rx: &Receiver<ClientMsg>) -> Vec<u32> {
+        let mut accounts = Vec::new();
+        while let Ok(m) = sg_rx.try_recv() {
+            if let ClientMsg::Send(_, Sgm::BbGetCharacter(_, g)) = m {
+                accounts.push(g.account_id);
+            }
+        }
+        accounts
+    }
+
+    /// The (slot, guild card) of each member in the lobby join sent to
+    /// `client`, and the slot they were given.
+    fn lobby_join(msgs: &[LoopMsg], client: usize) -> (u8, Vec<(u32, u32)>) {
+        for m in msgs {
+            if let &LoopMsg::Client(id, NetMsg::Bb(Message::LobbyJoin(_, ref lj))) = m {
+                if id == client {
+                    return (lj.client_id, lj.members.iter().map(|m| (m.hdr.client_id, m.hdr.guildcard)).collect())
+                }
+            }
+        }
+        panic!("no lobby join sent to client {}", client)
+    }
+
+    #[test]
+    fn test_lobby_join_lists_members() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, _sg_rx) = test_block(&event_loop, BlockOptions::default());
+        for id in 1..5 {
+            b.clients.borrow_mut().insert(id, playing(42000000 + id as u32));
+        }
+        for id in 1..4 {
+            let mut h = b.make_handler(id);
+            b.lobbies.borrow_mut()[0].add_player(&mut h, id).unwrap();
+        }
+        let (slot, members) = lobby_join(&sent(&mut event_loop), 3);
+        assert_eq!(slot, 2);
+        assert_eq!(members, vec![(0, 42000001), (1, 42000002), (2, 42000003)]);
+
+        // Client 2 leaves for a game, 4 takes their slot, and 2 comes back
+        // to everyone who's there now.
+        let mut h = b.make_handler(2);
+        b.lobbies.borrow_mut()[0].remove_player(&mut h, 2).unwrap();
+        let mut h = b.make_handler(4);
+        b.lobbies.borrow_mut()[0].add_player(&mut h, 4).unwrap();
+        let mut h = b.make_handler(2);
+        b.lobbies.borrow_mut()[0].add_player(&mut h, 2).unwrap();
+        let msgs = sent(&mut event_loop);
+        assert_eq!(lobby_join(&msgs, 4).1, vec![(0, 42000001), (1, 42000004), (2, 42000003)]);
+        let (slot, members) = lobby_join(&msgs, 2);
+        assert_eq!(slot, 3);
+        assert_eq!(members, vec![(0, 42000001), (1, 42000004), (2, 42000003), (3, 42000002)]);
+    }
+
+    #[test]
+    fn test_draining_block_refuses_login() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, sg_rx) = test_block(&event_loop, BlockOptions::default());
+        b.clients.borrow_mut().insert(1, Rc::new(RefCell::new(ClientState::default())));
+        b.draining.set(true);
+        b.make_handler(1).admit(account(5, 42000005), BbSecurityData::default());
+
+        assert!(characters_fetched(&sg_rx).is_empty());
+        assert_eq!(b.clients.borrow()[&1].borrow().account_id, 0);
+        match sent(&mut event_loop).last() {
+            Some(&LoopMsg::DropClient(1)) => (),
+            _ => panic!("expected client 1 to be dropped")
+        }
+
+        // Once it's open again, the same login goes through.
+        b.draining.set(false);
+        b.make_handler(1).admit(account(5, 42000005), BbSecurityData::default());
+        assert_eq!(characters_fetched(&sg_rx), vec![5]);
+        assert_eq!(b.clients.borrow()[&1].borrow().account_id, 5);
+    }
+
+    fn ban(expires: u64) -> BbCheckBanAck {
+        BbCheckBanAck {
+            status: 0,
+            banned: true,
+            expires: expires,
+            reason: "Duping".to_string()
+        }
+    }
+
+    #[test]
+    fn test_suspicion_kick_off_by_default() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, _sg_rx) = test_block(&event_loop, BlockOptions::default());
+        b.clients.borrow_mut().insert(1, playing(42000001));
+        let h = b.make_handler(1);
+        for _ in 0..20 {
+            h.flag_suspicious(1, SUSPICION_BAD_PICKUP, "picked up item 00010000, which isn't on the floor".to_string());
+        }
+        assert_eq!(b.clients.borrow()[&1].borrow().suspicion, 20);
+        assert!(sent(&mut event_loop).is_empty());
+
+        let mut options = BlockOptions::default();
+        options.suspicion_kick = 3;
+        let (b, _sg_rx) = test_block(&event_loop, options);
+        b.clients.borrow_mut().insert(1, playing(42000001));
+        let h = b.make_handler(1);
+        h.flag_suspicious(1, SUSPICION_BAD_DROP, "dropped item 00010000, which isn't in their inventory".to_string());
+        assert!(sent(&mut event_loop).is_empty());
+        h.flag_suspicious(1, SUSPICION_BAD_PICKUP, "picked up item 00010000, which isn't on the floor".to_string());
+        match sent(&mut event_loop).last() {
+            Some(&LoopMsg::DropClient(1)) => (),
+            _ => panic!("expected client 1 to be dropped")
+        }
+    }
+
+    #[test]
+    fn test_banned_guildcard_refused() {
+        let msg = ban_message(&ban(0), 1_500_000_000).unwrap();
+        assert!(msg.contains("Reason: Duping"));
+        assert!(ban_message(&ban(1_500_000_100), 1_500_000_000).unwrap().contains("Until: 2017-07-14"));
+    }
+
+    #[test]
+    fn test_expired_ban_admitted() {
+        assert_eq!(ban_message(&ban(1_500_000_000), 1_500_000_000), None);
+        assert_eq!(ban_message(&ban(1_400_000_000), 1_500_000_000), None);
+        assert_eq!(ban_message(&BbCheckBanAck::default(), 1_500_000_000), None);
+        assert_eq!(ban_message(&BbCheckBanAck { status: 3, ..ban(0) }, 1_500_000_000), None);
+    }
+
+    #[test]
+    fn test_chat_log_follows_option() {
+        let mut options = BlockOptions::default();
+        assert_eq!(chat_log_entry(&options, 42000001, 0, 1, 3, 1_500_000_000, "\tEHello"), None);
+        options.chat_log = true;
+        assert_eq!(chat_log_entry(&options, 42000001, 0, 1, 3, 1_500_000_000, "\tEHello"), Some(BbChatLog {
+            guildcard: 42000001,
+            target: 0,
+            block: 1,
+            lobby: 3,
+            time: 1_500_000_000,
+            text: "Hello".to_string()
+        }));
+        // Whispers are kept too, with who they went to.
+        assert_eq!(chat_log_entry(&options, 42000001, 42000002, 1, 0, 1_500_000_000, "hi").unwrap().target, 42000002);
+    }
+
+    pub fn logged_in(guildcard: u32) -> Rc<RefCell<ClientState>> {
+        let mut c = ClientState::default();
+        c.bb_guildcard = guildcard;
+        Rc::new(RefCell::new(c))
+    }
+
+    #[test]
+    fn test_relogin_drops_old_connection() {
+        let mut clients = HashMap::new();
+        clients.insert(1, logged_in(42000001));
+        clients.insert(2, logged_in(42000002));
+        // Client 3 has just connected and is logging in as 42000001.
+        clients.insert(3, logged_in(0));
+        assert_eq!(stale_logins(&clients, 42000001, 3), vec![1]);
+        // Once admitted, it isn't stale to itself.
+        clients.insert(3, logged_in(42000001));
+        assert_eq!(stale_logins(&clients, 42000001, 3), vec![1]);
+        assert!(stale_logins(&clients, 42000003, 4).is_empty());
+    }
+
+    #[test]
+    fn test_relogin_through_admit_keeps_newer_connection() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (mut b, sg_rx) = test_block(&event_loop, BlockOptions::default());
+        b.clients.borrow_mut().insert(1, Rc::new(RefCell::new(ClientState::default())));
+        b.make_handler(1).admit(account(5, 42000005), BbSecurityData::default());
+        b.clients.borrow()[&1].borrow_mut().full_char = Some(Default::default());
+        {
+            let mut h = b.make_handler(1);
+            b.lobbies.borrow_mut()[0].add_player(&mut h, 1).unwrap();
+        }
+        sent(&mut event_loop);
+
+        // The same guild card logs in again on a new connection.
+        b.clients.borrow_mut().insert(2, Rc::new(RefCell::new(ClientState::default())));
+        b.make_handler(2).admit(account(5, 42000005), BbSecurityData::default());
+        let dropped: Vec<usize> = sent(&mut event_loop).iter().filter_map(|m| match m {
+            &LoopMsg::DropClient(id) => Some(id),
+            _ => None
+        }).collect();
+        assert_eq!(dropped, vec![1]);
+        assert_eq!(characters_fetched(&sg_rx), vec![5, 5]);
+
+        // The loop closes the old connection and the block hears it's gone.
+        b.client_disconnected(1, false);
+        assert!(!b.clients.borrow().contains_key(&1));
+        assert!(!b.lobbies.borrow()[0].has_player(1));
+        assert_eq!(b.clients.borrow()[&2].borrow().bb_guildcard, 42000005);
+        assert_eq!(b.clients.borrow()[&2].borrow().account_id, 5);
+    }
+
+    pub fn playing(guildcard: u32) -> Rc<RefCell<ClientState>> {
+        let c = logged_in(guildcard);
+        c.borrow_mut().full_char = Some(Default::default());
+        c
+    }
+
+    #[test]
+    fn test_full_block_refuses_login() {
+        let mut clients = HashMap::new();
+        for id in 0..3 {
+            assert!(!block_full(&clients, 10, 42000010, 3));
+            clients.insert(id, playing(42000000 + id as u32));
+        }
+        // Not admitted yet, so they don't count.
+        clients.insert(9, logged_in(0));
+        assert!(!block_full(&clients, 9, 42000009, 4));
+        // Admitted with their character still loading; they do.
+        clients.insert(8, logged_in(42000008));
+        assert!(block_full(&clients, 9, 42000009, 4));
+        clients.remove(&8);
+
+        clients.insert(10, logged_in(0));
+        assert!(block_full(&clients, 10, 42000010, 3));
+        // Logging in again replaces their old connection, so there's room.
+        assert!(!block_full(&clients, 10, 42000001, 3));
+        assert!(!block_full(&clients, 10, 42000010, 0));
+    }
+
+    #[test]
+    fn test_logins_together_respect_max_players() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let mut options = BlockOptions::default();
+        options.max_players = 1;
+        let (b, sg_rx) = test_block(&event_loop, options);
+        for id in 1..3 {
+            b.clients.borrow_mut().insert(id, Rc::new(RefCell::new(ClientState::default())));
+        }
+        // Both get in before the first one's character comes back.
+        b.make_handler(1).admit(account(1, 42000001), BbSecurityData::default());
+        b.make_handler(2).admit(account(2, 42000002), BbSecurityData::default());
+        assert_eq!(characters_fetched(&sg_rx), vec![1]);
+        match sent(&mut event_loop).last() {
+            Some(&LoopMsg::DropClient(2)) => (),
+            _ => panic!("expected client 2 to be dropped")
+        }
+    }
+
+    #[test]
+    fn test_char_data_keeps_server_inventory() {
+        let event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, sg_rx) = test_block(&event_loop, BlockOptions::default());
+        b.clients.borrow_mut().insert(1, playing(42000001));
+        b.clients.borrow()[&1].borrow_mut().full_char.as_mut().unwrap().chara.meseta = 500;
+
+        let mut data = BbPlayerData::default();
+        data.chara.meseta = 999999;
+        data.chara.level = 199;
+        data.chara.config = vec![7; data.chara.config.len()];
+        data.infoboard = "Hello".to_string();
+        b.make_handler(1).save_char_dat(data);
+
+        let saved = match sg_rx.try_recv() {
+            Ok(ClientMsg::SendForget(Sgm::BbPutCharacter(_, p))) => p.full_char,
+            _ => panic!("expected the character to be saved")
+        };
+        assert_eq!(saved.chara.meseta, 500);
+        assert_eq!(saved.chara.level, 0);
+        assert!(saved.chara.config.iter().all(|&b| b == 7));
+        assert_eq!(saved.infoboard, "Hello");
+    }
+
+    #[test]
+    fn test_deleted_character_forgotten() {
+        let ack = BbDeleteCharacterAck { status: 0, account_id: 5, slot: 1, deleted: 1 };
+        let c = playing(42000001);
+        c.borrow_mut().account_id = 5;
+        c.borrow_mut().sec_data.slot = 1;
+        c.borrow_mut().playing_since = Some(1);
+        assert_eq!(forget_deleted(&mut c.borrow_mut(), &ack), Some(true));
+        assert!(c.borrow().full_char.is_none());
+        assert!(c.borrow().playing_since.is_none());
+
+        // Another of their characters; the one they're playing stays.
+        let c = playing(42000001);
+        c.borrow_mut().account_id = 5;
+        assert_eq!(forget_deleted(&mut c.borrow_mut(), &ack), Some(false));
+        assert!(c.borrow().full_char.is_some());
+
+        // The connection has changed hands since the request went out.
+        let c = playing(42000002);
+        c.borrow_mut().account_id = 6;
+        c.borrow_mut().sec_data.slot = 1;
+        assert_eq!(forget_deleted(&mut c.borrow_mut(), &ack), None);
+        assert!(c.borrow().full_char.is_some());
+    }
+
+    #[test]
+    fn test_block_transfer_redirects() {
+        let addr: SocketAddrV4 = "192.168.1.5:13002".parse().unwrap();
+        let ok = Sgm::BbBlockTransferAck(1, BbBlockTransferAck { status: 0, account_id: 5 });
+        assert_eq!(block_transfer(&ok, true, addr), Some(BlockTransfer::Move(Redirect { ip: *addr.ip(), port: 13002 })));
+        let failed = Sgm::BbBlockTransferAck(1, BbBlockTransferAck { status: 3, account_id: 5 });
+        assert_eq!(block_transfer(&failed, true, addr), Some(BlockTransfer::Failed(3)));
+        assert_eq!(block_transfer(&Sgm::BbCheckBanAck(1, ban(0)), true, addr), None);
+    }
+
+    #[test]
+    fn test_block_transfer_player_gone() {
+        let addr: SocketAddrV4 = "192.168.1.5:13002".parse().unwrap();
+        // They disconnected while the shipgate was saving; whatever it
+        // says, there's nobody left to redirect or tell.
+        let ok = Sgm::BbBlockTransferAck(1, BbBlockTransferAck { status: 0, account_id: 5 });
+        assert_eq!(block_transfer(&ok, false, addr), Some(BlockTransfer::Gone));
+        let failed = Sgm::BbBlockTransferAck(1, BbBlockTransferAck { status: 3, account_id: 5 });
+        assert_eq!(block_transfer(&failed, false, addr), Some(BlockTransfer::Gone));
+    }
+
+    /// A player kicking the soccer ball, as the client sends it.
+    const SOCCER_KICK: &'static [u8] = &[
+        0x14, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x79, 0x03, 0x00, 0x00, 0x00, 0x00, 0x20, 0x42,
+        0x00, 0x00, 0xA0, 0xC1, 0x00, 0x00, 0x00, 0x00
+    ];
+
+    /// Whether a kick from client 1 in lobby 1 reaches client 2.
+    fn kick_forwarded(options: BlockOptions) -> bool {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, _sg_rx) = test_block(&event_loop, options);
+        for id in 1..3 {
+            b.clients.borrow_mut().insert(id, playing(42000000 + id as u32));
+            let mut h = b.make_handler(id);
+            b.lobbies.borrow_mut()[0].add_player(&mut h, id).unwrap();
+        }
+        sent(&mut event_loop);
+
+        let m = match Message::deserialize(&mut Cursor::new(SOCCER_KICK)).unwrap() {
+            Message::BbSubCmd60(_, m) => m,
+            m => panic!("expected a 0x60 subcommand, got {:?}", m)
+        };
+        assert_eq!(m.cmd(), 0x79);
+        b.make_handler(1).bb_subcmd_60(m);
+        let msgs = sent(&mut event_loop);
+        msgs.iter().any(|m| match m {
+            &LoopMsg::Client(2, NetMsg::Bb(Message::BbSubCmd60(..))) => true,
+            _ => false
+        })
+    }
+
+    #[test]
+    fn test_minigame_toggles() {
+        assert!(kick_forwarded(BlockOptions::default()));
+
+        let mut options = BlockOptions::default();
+        options.minigame_free_lobbies = vec![1];
+        assert!(!kick_forwarded(options));
+
+        let mut options = BlockOptions::default();
+        options.minigame_free_lobbies = vec![2];
+        assert!(kick_forwarded(options));
+
+        let mut options = BlockOptions::default();
+        options.lobby_minigames = false;
+        assert!(!kick_forwarded(options));
+    }
+}
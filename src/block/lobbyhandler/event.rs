@@ -18,3 +18,27 @@ pub enum Event {
     SpringFlag = 13,
     AltNormal = 14
 }
+
+impl Event {
+    /// The event numbered `n`, if the client has one.
+    pub fn from_u16(n: u16) -> Option<Event> {
+        use self::Event::*;
+        match n {
+            0 => Some(Normal),
+            1 => Some(Christmas),
+            3 => Some(Valentines),
+            4 => Some(Easter),
+            5 => Some(Halloween),
+            6 => Some(Sonic),
+            7 => Some(NewYears),
+            8 => Some(Spring),
+            9 => Some(WhiteDay),
+            10 => Some(Wedding),
+            11 => Some(Autumn),
+            12 => Some(Flags),
+            13 => Some(SpringFlag),
+            14 => Some(AltNormal),
+            _ => None
+        }
+    }
+}
@@ -11,12 +11,15 @@ pub mod event;
 
 use self::error::LobbyError;
 
-const MAX_PLAYERS: usize = 12;
+/// The most players a lobby can hold.
+pub const MAX_PLAYERS: usize = 12;
 
 #[derive(Clone, Debug)]
 pub struct Lobby {
     player_count: usize,
     players: [Option<usize>; MAX_PLAYERS],
+    /// How many of the player slots may be filled, up to `MAX_PLAYERS`.
+    capacity: usize,
     lobby_num: u8,
     block_num: u16,
     event: u16,
@@ -27,12 +30,14 @@ impl Lobby {
 
     /// `num` is the lobby number sent to joiners, `event` is the seasonal
     /// event for this lobby (yes, lobbies can have different events on the
-    /// same block). `num` is 0-14. (+1 for in-client number)
-    pub fn new(num: u8, block: u16, event: u16) -> Lobby {
+    /// same block). `num` is 0-14. (+1 for in-client number) `capacity` is
+    /// capped at `MAX_PLAYERS`.
+    pub fn new(num: u8, block: u16, event: u16, capacity: usize) -> Lobby {
         // TODO event as type-safe enum to prevent client crashes
         Lobby {
             player_count: 0,
-            players: [None; 12],
+            players: [None; MAX_PLAYERS],
+            capacity: ::std::cmp::min(capacity, MAX_PLAYERS),
             lobby_num: num,
             block_num: block,
             event: event,
@@ -45,19 +50,17 @@ impl Lobby {
     /// necessary. If sent_by is `Some`, that client will not receive the
     /// message.
     pub fn bb_broadcast(&self, handler: &mut BlockHandler, sent_by: Option<usize>, msg: BbMsg) -> Result<(), LobbyError> {
-        for co in self.players.iter() {
-            match co {
-                &Some(c) => {
-                    if (sent_by.is_some() && c != sent_by.unwrap()) || sent_by.is_none() {
-                        handler.send_to_client(c, msg.clone());
-                    }
-                },
-                _ => ()
-            }
+        for c in self.recipients(sent_by) {
+            handler.send_to_client(c, msg.clone());
         }
         Ok(())
     }
 
+    /// Everyone in the lobby but `sent_by`.
+    fn recipients(&self, sent_by: Option<usize>) -> Vec<usize> {
+        self.players.iter().filter_map(|p| *p).filter(|&c| Some(c) != sent_by).collect()
+    }
+
     /// Adds a player to this lobby.
     pub fn add_player(&mut self, handler: &mut BlockHandler, player: usize) -> Result<(), LobbyError> {
         if self.has_player(player) {
@@ -80,12 +83,14 @@ impl Lobby {
         };
 
         self.players[new_client_id as usize] = Some(player);
+        handler.start_resting(player);
 
         // Tell other clients that this player is joining.
         {
             let mut lm: LobbyMember = LobbyMember::default();
             let cr = handler.get_client_state(player).unwrap();
             let ref c = cr.borrow();
+            handler.event_log.lobby_change(player, c.account_id, self.lobby_num + 1);
             lm.hdr.tag = 0x00010000;
             lm.hdr.guildcard = c.bb_guildcard;
             lm.hdr.client_id = new_client_id as u32;
@@ -159,18 +164,30 @@ impl Lobby {
             handler.send_to_client(player, r);
         }
 
-        Ok(())
+        self.announce_presence(handler, player, true)
     }
 
     /// Removes a player from this lobby. All other lobby members will be told
     /// about the player leaving, but this player will not receive anything.
     /// You must remember to tell the player where they are going!
     pub fn remove_player(&mut self, handler: &mut BlockHandler, player: usize) -> Result<(), LobbyError> {
+        self.remove(handler, player, true)
+    }
+
+    /// Removes a player who is being sent to another block or ship. The
+    /// others aren't told they left in chat, since they'll be told they
+    /// joined wherever they end up.
+    pub fn remove_moving_player(&mut self, handler: &mut BlockHandler, player: usize) -> Result<(), LobbyError> {
+        self.remove(handler, player, false)
+    }
+
+    fn remove(&mut self, handler: &mut BlockHandler, player: usize, notice: bool) -> Result<(), LobbyError> {
         if !self.has_player(player) {
             return Err(LobbyError::NotInLobby)
         }
 
         info!("Removing client {} from lobby {}:{}", player, self.block_num, self.lobby_num + 1);
+        handler.stop_resting(player);
 
         if self.num_players() == 1 {
             // lobby is empty now...
@@ -189,14 +206,31 @@ impl Lobby {
 
             self.players[player_client_id as usize] = None;
 
-            self.bb_broadcast(handler, Some(player), BbMsg::LobbyLeave(0, LobbyLeave {
+            try!(self.bb_broadcast(handler, Some(player), BbMsg::LobbyLeave(0, LobbyLeave {
                 client_id: player_client_id,
                 leader_id: self.leader_id,
                 padding: 0
-            }))
+            })));
+            if notice {
+                try!(self.announce_presence(handler, player, false));
+            }
+            Ok(())
         }
     }
 
+    /// Tell everyone else in the lobby, in chat, that `player` joined or
+    /// left.
+    fn announce_presence(&self, handler: &mut BlockHandler, player: usize, joined: bool) -> Result<(), LobbyError> {
+        let name = handler.get_client_state(player).and_then(|cr| {
+            let c = cr.borrow();
+            c.full_char.as_ref().map(|fc| fc.chara.name.clone())
+        });
+        if let Some(name) = name {
+            try!(self.bb_broadcast(handler, Some(player), presence_notice(&name, joined)));
+        }
+        Ok(())
+    }
+
     /// Get the current player count.
     pub fn num_players(&self) -> usize {
         let mut count = 0;
@@ -212,9 +246,20 @@ impl Lobby {
         count
     }
 
+    /// How many players this lobby holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     /// If this lobby is currently full.
     pub fn is_full(&self) -> bool {
-        self.num_players() >= 12
+        self.num_players() >= self.capacity
+    }
+
+    /// If this lobby has room for another player once `reserved` slots are
+    /// set aside for players expected back.
+    pub fn has_room(&self, reserved: usize) -> bool {
+        self.num_players() + reserved < self.capacity
     }
 
     /// If this lobby is currently empty.
@@ -228,6 +273,11 @@ impl Lobby {
         self.event = event;
     }
 
+    /// Client IDs of everyone in this lobby.
+    pub fn players(&self) -> Vec<usize> {
+        self.players.iter().filter_map(|p| *p).collect()
+    }
+
     /// Whether or not this lobby has this player.
     pub fn has_player(&self, client: usize) -> bool {
         for i in 0..MAX_PLAYERS {
@@ -279,7 +329,7 @@ impl Lobby {
 
     /// `None` if this lobby is full. `Some(client ID slot)` if not.
     fn find_first_empty(&self) -> Option<u8> {
-        for (i, po) in self.players.iter().enumerate() {
+        for (i, po) in self.players[..self.capacity].iter().enumerate() {
             match po {
                 &None => {
                     return Some(i as u8)
@@ -327,3 +377,103 @@ impl Lobby {
         None
     }
 }
+
+/// The chat line telling a lobby that the player called `name` joined or
+/// left it.
+pub fn presence_notice(name: &str, joined: bool) -> BbMsg {
+    let text = format!("\tE{} has {}.", name.trim_left_matches("\tE"), if joined { "joined" } else { "left" });
+    BbMsg::BbChat(0, BbChat(0, text))
+}
+
+/// The index of the first lobby with room for another player, given how many
+/// slots `reserved` says are held in each for players expected back.
+pub fn first_lobby_with_room<F: Fn(usize) -> usize>(lobbies: &[Lobby], reserved: F) -> Option<usize> {
+    lobbies.iter().enumerate().position(|(i, l)| l.has_room(reserved(i)))
+}
+
+/// Give every lobby `event`, returning the players in them, who need telling.
+pub fn set_all_events(lobbies: &mut [Lobby], event: u16) -> Vec<usize> {
+    let mut players = Vec::new();
+    for l in lobbies.iter_mut() {
+        l.set_event(event);
+        players.extend(l.players());
+    }
+    players
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::event::Event;
+
+    fn fill(l: &mut Lobby, first_client: usize) {
+        for i in 0..l.capacity() {
+            l.players[i] = Some(first_client + i);
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut l = Lobby::new(0, 1, 0, 4);
+        assert_eq!(l.capacity(), 4);
+        assert!(!l.is_full());
+        fill(&mut l, 0);
+        assert!(l.is_full());
+        assert!(!l.has_room(0));
+        assert_eq!(l.find_first_empty(), None);
+        assert_eq!(Lobby::new(0, 1, 0, 20).capacity(), MAX_PLAYERS);
+    }
+
+    #[test]
+    fn test_join_skips_full_lobby() {
+        let mut lobbies: Vec<Lobby> = (0..3).map(|i| Lobby::new(i, 1, 0, MAX_PLAYERS)).collect();
+        assert_eq!(first_lobby_with_room(&lobbies, |_| 0), Some(0));
+        fill(&mut lobbies[0], 0);
+        assert_eq!(first_lobby_with_room(&lobbies, |_| 0), Some(1));
+        // A slot held for a returning player counts against the lobby.
+        lobbies[1].players[0] = Some(100);
+        assert_eq!(first_lobby_with_room(&lobbies, |i| if i == 1 { MAX_PLAYERS - 1 } else { 0 }), Some(2));
+        fill(&mut lobbies[1], 100);
+        fill(&mut lobbies[2], 200);
+        assert_eq!(first_lobby_with_room(&lobbies, |_| 0), None);
+    }
+
+    #[test]
+    fn test_set_all_events() {
+        let mut lobbies: Vec<Lobby> = (0..4).map(|i| Lobby::new(i, 1, 0, MAX_PLAYERS)).collect();
+        lobbies[1].players[0] = Some(7);
+        lobbies[3].players[2] = Some(9);
+        let mut told = set_all_events(&mut lobbies, Event::Halloween as u16);
+        told.sort();
+        assert_eq!(told, vec![7, 9]);
+        assert!(lobbies.iter().all(|l| l.event_num() == 5));
+    }
+
+    #[test]
+    fn test_join_notice_to_others() {
+        let mut l = Lobby::new(0, 1, 0, MAX_PLAYERS);
+        l.players[0] = Some(10);
+        l.players[1] = Some(11);
+        l.players[3] = Some(13);
+        // Client 12 joins; everyone but them gets the notice.
+        l.players[2] = Some(12);
+        assert_eq!(l.recipients(Some(12)), vec![10, 11, 13]);
+        assert_eq!(l.recipients(None), vec![10, 11, 12, 13]);
+        match presence_notice("\tERico", true) {
+            BbMsg::BbChat(_, BbChat(0, text)) => assert_eq!(text, "\tERico has joined."),
+            m => panic!("unexpected notice {:?}", m)
+        }
+        match presence_notice("Rico", false) {
+            BbMsg::BbChat(_, BbChat(0, text)) => assert_eq!(text, "\tERico has left."),
+            m => panic!("unexpected notice {:?}", m)
+        }
+    }
+
+    #[test]
+    fn test_event_from_u16() {
+        assert_eq!(Event::from_u16(5), Some(Event::Halloween));
+        assert_eq!(Event::from_u16(14), Some(Event::AltNormal));
+        assert_eq!(Event::from_u16(2), None);
+        assert_eq!(Event::from_u16(15), None);
+    }
+}
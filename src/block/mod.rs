@@ -7,15 +7,15 @@ use std::sync::mpsc::channel;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::{Cell, RefCell};
-use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
 
 use mio::Sender;
-use mio::tcp::TcpListener;
 
 use rand::random;
 
+use time::precise_time_ns;
+
 use psomsg::bb::*;
 
 use psodata::battleparam::BattleParamTables;
@@ -23,23 +23,49 @@ use psodata::leveltable::LevelTable;
 
 use ::shipgate::msg::Message as Sgm;
 use ::shipgate::msg::BbPutCharacter;
-use ::shipgate::client::SgSender;
+use ::shipgate::msg::ShipEventSubscribe;
+use ::shipgate::msg::ShipPopulation;
+use ::shipgate::ships::REPORT_INTERVAL;
+use ::shipgate::client::{SgSender, ConnectionState};
 use ::services::message::NetMsg;
+use ::services::listener::Listener;
+use ::eventlog::EventLog;
 use ::shipgate::client::callbacks::SgCbMgr;
-use ::services::{ServiceMsg, Service, ServiceType};
+use ::services::{ServiceMsg, Service, ServiceType, spawn_ticker, handshake_deadline, check_handshake};
+use ::services::metrics::Metrics;
 use ::loop_handler::LoopMsg;
 use ::maps::Areas;
 use ::droptables::DropTable;
+use ::config::{BlockOptions, BlockConf};
+use ::patch::{Balancer, Bulletin};
+use ::holidays::{self, Holiday};
 
 pub mod client;
+pub mod guildcard;
 pub mod handler;
 pub mod lobbyhandler;
 pub mod partyhandler;
+pub mod plugin;
+pub mod restart;
 
 use self::handler::BlockHandler;
-use self::client::ClientState;
+use self::client::{ClientState, PendingReconnect, Idle, IDLE_PING_GRACE};
 use self::lobbyhandler::Lobby;
 use self::partyhandler::Party;
+use self::plugin::BlockPlugin;
+use self::restart::{ScheduledRestart, RestartDue};
+
+/// The notice players get `minutes` before a restart.
+pub fn restart_warning(minutes: u32) -> String {
+    if minutes == 1 {
+        "\tEThis block restarts in\n1 minute.".to_string()
+    } else {
+        format!("\tEThis block restarts in\n{} minutes.", minutes)
+    }
+}
+
+/// Seconds between sweeps for floor items past their lifetime.
+const FLOOR_ITEM_CHECK_INTERVAL: u64 = 10;
 
 pub struct BlockService {
     receiver: Receiver<ServiceMsg>,
@@ -50,53 +76,174 @@ pub struct BlockService {
     parties: Rc<RefCell<Vec<Party>>>,
     party_counter: Rc<Cell<u32>>,
     block_num: u16,
+    num_lobbies: u8,
+    /// The event the lobbies have now.
     event: u16,
+    /// The configured or ship-wide event, used when no holiday is on.
+    base_event: u16,
+    /// The ship whose event changes this block follows, if it doesn't set its own.
+    event_ship: Option<String>,
+    event_sub_key: Option<u32>,
+    /// The ship listing this block, which its population is reported for.
+    ship: Rc<Option<String>>,
+    /// The shipgate connection's state as of the last tick.
+    sg_state: ConnectionState,
+    holidays: Vec<Holiday>,
+    options: Rc<BlockOptions>,
+    /// The patch service's MOTD and news, for players arriving and `/motd`.
+    bulletin: Rc<RefCell<Bulletin>>,
+    reconnects: Rc<RefCell<Vec<PendingReconnect>>>,
+    ticks: u64,
     battle_params: Arc<BattleParamTables>,
     online_maps: Arc<Areas>,
     offline_maps: Arc<Areas>,
     level_table: Arc<LevelTable>,
-    drop_table: Arc<DropTable>
+    drop_table: Arc<DropTable>,
+    stack_limits: Arc<StackLimits>,
+    event_log: EventLog,
+    handshake_timeout: u32,
+    plugins: Vec<Box<BlockPlugin + Send>>,
+    /// The ship's other blocks, to drain players to or for them to move to.
+    siblings: Rc<Vec<BlockConf>>,
+    /// Set by a GM's `/drain`.
+    draining: Rc<Cell<bool>>,
+    /// Picks which sibling each drained player goes to, in turn or at
+    /// random as the ship's `random_balance` says. None without siblings.
+    balancer: Option<Balancer>,
+    /// Players sent away while draining.
+    drained: usize,
+    /// The block has been reported empty since draining started.
+    drain_finished: bool,
+    /// Draining because a scheduled restart came due, so the block starts
+    /// over once it's empty.
+    restarting: bool,
+    /// Set by a GM's `/restart`.
+    restart: Rc<Cell<Option<ScheduledRestart>>>,
+    /// Set by a GM's `/setevent`; overrides holidays and the ship's event.
+    gm_event: Rc<Cell<Option<u16>>>,
+    /// `gm_event` as of the last time the lobbies' event was worked out.
+    gm_event_applied: Option<u16>,
+    /// Also held by the block's `Service`, which logs them.
+    metrics: Arc<Metrics>
 }
 
 impl BlockService {
-    pub fn spawn(bind: &SocketAddr,
+    pub fn spawn<L: Listener + 'static>(listener: L,
                  sender: Sender<LoopMsg>,
                  sg_sender: &SgSender,
                  key_table: Arc<Vec<u32>>,
                  block_num: u16,
+                 num_lobbies: u8,
                  event: u16,
+                 event_ship: Option<String>,
+                 ship: Option<String>,
+                 holidays: Vec<Holiday>,
+                 options: BlockOptions,
+                 bulletin: Bulletin,
                  battle_params: Arc<BattleParamTables>,
                  online_maps: Arc<Areas>,
                  offline_maps: Arc<Areas>,
                  level_table: Arc<LevelTable>,
-                 drop_table: Arc<DropTable>) -> Service {
+                 drop_table: Arc<DropTable>,
+                 stack_limits: Arc<StackLimits>,
+                 event_log: EventLog,
+                 handshake_timeout: u32,
+                 plugins: Vec<Box<BlockPlugin + Send>>,
+                 siblings: Vec<BlockConf>,
+                 random_balance: bool) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
-
         let sg_sender = sg_sender.clone_with(tx.clone());
 
-        thread::spawn(move|| {
-            let d = BlockService {
-                receiver: rx,
-                sender: sender,
-                sg_sender: sg_sender.into(),
-                clients: Default::default(),
-                lobbies: Default::default(),
-                parties: Default::default(),
-                party_counter: Rc::new(Cell::new(0)),
-                block_num: block_num,
-                event: event,
-                battle_params: battle_params,
-                online_maps: online_maps,
-                offline_maps: offline_maps,
-                level_table: level_table,
-                drop_table: drop_table
-            };
+        spawn_ticker(tx.clone());
+
+        let metrics = Arc::new(Metrics::new());
+        let thread_metrics = metrics.clone();
+
+        let thread = thread::spawn(move|| {
+            let d = BlockService::new(rx, sender, sg_sender.into(), block_num, num_lobbies, event,
+                                      event_ship, ship, holidays, options, bulletin, battle_params,
+                                      online_maps, offline_maps, level_table, drop_table,
+                                      stack_limits, event_log, handshake_timeout, plugins,
+                                      siblings, random_balance, thread_metrics);
             d.run();
         });
 
-        Service::new(listener, tx, ServiceType::Bb(key_table))
+        let mut s = Service::new(listener, tx, ServiceType::Bb(key_table));
+        s.set_thread(thread);
+        s.set_metrics(metrics);
+        s
+    }
+
+    fn new(receiver: Receiver<ServiceMsg>,
+           sender: Sender<LoopMsg>,
+           sg_sender: SgCbMgr<BlockHandler>,
+           block_num: u16,
+           num_lobbies: u8,
+           event: u16,
+           event_ship: Option<String>,
+           ship: Option<String>,
+           holidays: Vec<Holiday>,
+           options: BlockOptions,
+           bulletin: Bulletin,
+           battle_params: Arc<BattleParamTables>,
+           online_maps: Arc<Areas>,
+           offline_maps: Arc<Areas>,
+           level_table: Arc<LevelTable>,
+           drop_table: Arc<DropTable>,
+           stack_limits: Arc<StackLimits>,
+           event_log: EventLog,
+           handshake_timeout: u32,
+           plugins: Vec<Box<BlockPlugin + Send>>,
+           siblings: Vec<BlockConf>,
+           random_balance: bool,
+           metrics: Arc<Metrics>) -> BlockService {
+        let balancer = if siblings.is_empty() {
+            None
+        } else {
+            Some(Balancer::new(siblings.iter().map(|b| b.addr).collect(), random_balance))
+        };
+        BlockService {
+            receiver: receiver,
+            sender: sender,
+            sg_sender: sg_sender,
+            clients: Default::default(),
+            lobbies: Default::default(),
+            parties: Default::default(),
+            party_counter: Rc::new(Cell::new(0)),
+            block_num: block_num,
+            num_lobbies: num_lobbies,
+            event: event,
+            base_event: event,
+            event_ship: event_ship,
+            event_sub_key: None,
+            ship: Rc::new(ship),
+            sg_state: ConnectionState::Connecting,
+            holidays: holidays,
+            options: Rc::new(options),
+            bulletin: Rc::new(RefCell::new(bulletin)),
+            reconnects: Default::default(),
+            ticks: 0,
+            battle_params: battle_params,
+            online_maps: online_maps,
+            offline_maps: offline_maps,
+            level_table: level_table,
+            drop_table: drop_table,
+            stack_limits: stack_limits,
+            event_log: event_log,
+            handshake_timeout: handshake_timeout,
+            plugins: plugins,
+            siblings: Rc::new(siblings),
+            balancer: balancer,
+            draining: Rc::new(Cell::new(false)),
+            drained: 0,
+            drain_finished: false,
+            restarting: false,
+            restart: Rc::new(Cell::new(None)),
+            gm_event: Rc::new(Cell::new(None)),
+            gm_event_applied: None,
+            metrics: metrics
+        }
     }
 
     fn make_handler(&self, client_id: usize) -> BlockHandler {
@@ -111,29 +258,349 @@ impl BlockService {
             self.online_maps.clone(),
             self.offline_maps.clone(),
             self.level_table.clone(),
-            self.party_counter.clone()
+            self.stack_limits.clone(),
+            self.party_counter.clone(),
+            self.options.clone(),
+            self.bulletin.clone(),
+            self.reconnects.clone(),
+            self.draining.clone(),
+            self.restart.clone(),
+            self.gm_event.clone(),
+            self.ship.clone(),
+            self.siblings.clone(),
+            self.event_log.clone()
         )
     }
 
+    /// Make the block's lobbies, if they haven't been already.
     fn init_lobbies(&mut self) {
         let ref mut l = self.lobbies.borrow_mut();
-        for i in 0..15 {
-            let lobby = Lobby::new(i, self.block_num, self.event);
+        if l.len() > 0 {
+            return
+        }
+        for i in 0..self.num_lobbies {
+            let lobby = Lobby::new(i, self.block_num, self.event, lobbyhandler::MAX_PLAYERS);
             l.push(lobby);
         }
-        info!("Initialized 15 lobbies with event {}", self.event);
+        info!("Initialized {} lobbies with event {}", self.num_lobbies, self.event);
+    }
+
+    /// Change the event for every lobby, and show it to everyone in them.
+    fn set_event(&mut self, event: u16) {
+        self.event = event;
+        let players = lobbyhandler::set_all_events(&mut self.lobbies.borrow_mut(), event);
+        let h = self.make_handler(0);
+        for id in players {
+            h.send_to_client(id, Message::LobbyEvent(event as u32, LobbyEvent));
+        }
+        info!("Block {} event changed to {}", self.block_num, event);
+    }
+
+    /// The event the lobbies should have: the one a GM set, if any, then
+    /// today's holiday's, if there is one, otherwise the configured one.
+    fn current_event(&self) -> u16 {
+        match self.gm_event.get() {
+            Some(e) => e,
+            None => holidays::event_today(&self.holidays).unwrap_or(self.base_event)
+        }
+    }
+
+    fn update_event(&mut self) {
+        let event = self.current_event();
+        if event != self.event {
+            self.set_event(event);
+        }
+    }
+
+    /// While the block is quiet, move one player who didn't choose their
+    /// lobby into the busiest lobby.
+    fn migrate_straggler(&self) {
+        let h = self.make_handler(0);
+        if !h.is_low_population() {
+            return
+        }
+        let lr = self.lobbies.clone();
+        let ref mut lobbies = lr.borrow_mut();
+        let target = match h.busiest_lobby_with_room(lobbies) {
+            Some(t) => t,
+            None => return
+        };
+        let target_count = lobbies[target].num_players();
+        if target_count == 0 {
+            return
+        }
+        let mut found = None;
+        for (i, l) in lobbies.iter().enumerate() {
+            // Don't split up a lobby that's just as lively.
+            if i == target || l.num_players() >= target_count {
+                continue
+            }
+            for cid in l.players() {
+                let chose = h.get_client_state(cid).map(|cs| cs.borrow().chose_lobby).unwrap_or(true);
+                if !chose {
+                    found = Some((i, cid));
+                    break
+                }
+            }
+            if found.is_some() {
+                break
+            }
+        }
+        drop(h);
+        if let Some((from, cid)) = found {
+            let mut h = self.make_handler(cid);
+            info!("Moving client {} from lobby {} to lobby {} to merge a quiet block", cid, from + 1, target + 1);
+            lobbies[from].remove_player(&mut h, cid).unwrap();
+            lobbies[target].add_player(&mut h, cid).unwrap();
+        }
+    }
+
+    /// Send everyone in a lobby to the ship's other blocks, spread over them
+    /// by the balancer, while new logins are turned away. Players in a party
+    /// go once they're back in a lobby, so their game isn't cut short.
+    /// Without other blocks, players are disconnected instead.
+    fn drain(&mut self) {
+        let ids: Vec<usize> = self.lobbies.borrow().iter().flat_map(|l| l.players()).collect();
+        if ids.is_empty() {
+            if !self.drain_finished && self.parties.borrow().is_empty() {
+                self.drain_finished = true;
+                if self.restarting {
+                    info!("Block {} is drained after moving {} players; restarting", self.block_num, self.drained);
+                    self.reopen();
+                } else {
+                    info!("Block {} is drained after moving {} players; it's safe to stop", self.block_num, self.drained);
+                }
+            }
+            return
+        }
+        for id in ids {
+            let mut h = self.make_handler(id);
+            let addr = match self.balancer {
+                Some(ref b) => b.next(),
+                None => {
+                    h.send_fatal_error(id, "\tEThis block is closing.\nDisconnected.");
+                    continue
+                }
+            };
+            info!("Draining client {} from block {} to {}", id, self.block_num, addr);
+            if h.redirect(addr) {
+                self.drained += 1;
+            }
+        }
+    }
+
+    /// Start the block over after a restart's drain: fresh lobbies, and
+    /// logins taken again. The ship's other blocks and services carry on.
+    fn reopen(&mut self) {
+        self.lobbies.borrow_mut().clear();
+        self.init_lobbies();
+        self.reconnects.borrow_mut().clear();
+        self.draining.set(false);
+        self.restarting = false;
+        self.drain_finished = false;
+        self.drained = 0;
+        info!("Block {} restarted and is taking players again", self.block_num);
+    }
+
+    /// Log the shipgate connection going down or coming back.
+    fn check_shipgate(&mut self) {
+        let state = self.sg_sender.state();
+        if state == self.sg_state {
+            return
+        }
+        match state {
+            ConnectionState::Connecting => warn!("Block {} lost the shipgate; requests are queued until it's back", self.block_num),
+            ConnectionState::Connected => info!("Block {} is connected to the shipgate", self.block_num)
+        }
+        self.sg_state = state;
+    }
+
+    /// Check the shipgate is answering, and log how quickly.
+    fn send_heartbeat(&mut self, now: u64) {
+        if self.sg_state != ConnectionState::Connected {
+            return
+        }
+        let block_num = self.block_num;
+        let r = self.sg_sender.heartbeat(0, now, move|_, reply| match reply {
+            Ok(r) => debug!("Block {} heard from the shipgate in {} ms; up {} seconds", block_num, r.latency_ms, r.uptime),
+            Err(e) => warn!("Block {} shipgate heartbeat failed: {}", block_num, e)
+        });
+        if let Err(e) = r {
+            warn!("Couldn't send a shipgate heartbeat: {}", e);
+        }
+    }
+
+    /// Tell the shipgate how many players are on the block, for the ship
+    /// list.
+    fn report_population(&mut self) {
+        let ship = match *self.ship {
+            Some(ref s) => s.clone(),
+            None => return
+        };
+        let players = self.clients.borrow().values().filter(|c| c.borrow().full_char.is_some()).count();
+        self.sg_sender.send(ShipPopulation {
+            ship: ship,
+            block: self.block_num,
+            players: players as u32
+        }).unwrap();
+    }
+
+    /// Warn players about a scheduled restart, and start draining once it's
+    /// time. Only this block restarts, once the drain is done.
+    fn check_restart(&mut self) {
+        let mut restart = match self.restart.get() {
+            Some(r) => r,
+            None => return
+        };
+        match restart.tick(precise_time_ns(), &self.options.restart_warnings) {
+            RestartDue::Nothing => (),
+            RestartDue::Warn(minutes) => {
+                info!("Block {} restarts in {} minutes", self.block_num, minutes);
+                self.make_handler(0).announce(&restart_warning(minutes));
+            },
+            RestartDue::Now => {
+                warn!("Block {} is restarting; draining", self.block_num);
+                self.restart.set(None);
+                self.draining.set(true);
+                self.restarting = true;
+                return
+            }
+        }
+        self.restart.set(Some(restart));
+    }
+
+    /// Take a client that's gone out of their lobby or party and save their
+    /// character. While `shutting_down` their place isn't held for them.
+    fn client_disconnected(&mut self, id: usize, shutting_down: bool) {
+        let mut h = self.make_handler(id);
+
+        // First, we need to check if they're in a lobby or party.
+        let mut was_in_lobby = None;
+        let mut was_in_party = None;
+        {
+            let lr = self.lobbies.clone();
+            let ref mut lobbies = lr.borrow_mut();
+            for (i, l) in lobbies.iter_mut().enumerate() {
+                if l.has_player(id) {
+                    l.remove_player(&mut h, id).unwrap();
+                    was_in_lobby = Some(i);
+                    break
+                }
+            }
+        }
+        {
+            let pr = self.parties.clone();
+            let ref mut parties = pr.borrow_mut();
+            let mut party_index = 0;
+            let mut remove = false;
+            for (i, p) in parties.iter_mut().enumerate() {
+                if p.has_player(id) {
+                    remove = p.remove_player(&mut h, id).unwrap();
+                    was_in_party = Some(p.unique_id);
+                    party_index = i;
+                    break
+                }
+            }
+            if remove {
+                parties.remove(party_index);
+            }
+        }
+
+        // Hold their place in case the drop was a hiccup.
+        if !shutting_down && self.options.reconnect_grace > 0 && (was_in_lobby.is_some() || was_in_party.is_some()) {
+            let cs = h.get_client_state(id).unwrap();
+            let account_id = cs.borrow().account_id;
+            let mut reconnects = self.reconnects.borrow_mut();
+            // Only the latest drop for an account counts.
+            reconnects.retain(|r| r.account_id != account_id);
+            reconnects.push(PendingReconnect {
+                account_id: account_id,
+                lobby: was_in_lobby,
+                party: was_in_party,
+                expires: precise_time_ns() + self.options.reconnect_grace as u64 * 1_000_000_000
+            });
+            debug!("Holding account {}'s place for {} seconds", account_id, self.options.reconnect_grace);
+        }
+
+        h.credit_playtime(id);
+
+        // Now we will persist their current character to the
+        // shipgate, unless they dropped mid-operation, in which
+        // case the last save is the better one to keep.
+        {
+            let cs = h.get_client_state(id).unwrap();
+            let ref client_state = cs.borrow();
+            let save = if client_state.full_char.is_none() {
+                false
+            } else if client_state.logged_out {
+                info!("Client {} logged out; saving their character", id);
+                true
+            } else if shutting_down {
+                if client_state.is_consistent() {
+                    info!("Saving client {}'s character as the block shuts down", id);
+                    true
+                } else {
+                    warn!("Client {} is mid-operation as the block shuts down; keeping their last save", id);
+                    false
+                }
+            } else if !self.options.save_on_drop {
+                info!("Client {} dropped without logging out; keeping their last save", id);
+                false
+            } else if !client_state.is_consistent() {
+                warn!("Client {} dropped mid-operation; keeping their last save", id);
+                false
+            } else {
+                info!("Client {} dropped without logging out; saving their character", id);
+                true
+            };
+            if let (true, Some(full_char)) = (save, client_state.full_char.as_ref()) {
+                self.sg_sender.send(Sgm::BbPutCharacter(0, BbPutCharacter {
+                    account_id: client_state.account_id,
+                    slot: client_state.sec_data.slot,
+                    save_acct_data: 0,
+                    full_char: full_char.clone()
+                })).unwrap();
+            }
+        }
+
+        drop(h);
+
+        {self.clients.borrow_mut().remove(&id);}
+    }
+
+    /// See everyone off as the server stops: out of their lobbies and parties,
+    /// with their characters saved.
+    fn shutdown(&mut self) {
+        let ids: Vec<usize> = self.clients.borrow().keys().cloned().collect();
+        info!("Block {} shutting down with {} clients", self.block_num, ids.len());
+        for id in ids {
+            self.client_disconnected(id, true);
+        }
     }
 
     pub fn run(mut self) {
         // Initialize lobbies
+        self.event = self.current_event();
         self.init_lobbies();
 
+        for p in self.plugins.iter() {
+            info!("Block {} loaded plugin {}", self.block_num, p.name());
+        }
+
+        if let Some(ship) = self.event_ship.clone() {
+            match self.sg_sender.subscribe(ShipEventSubscribe(ship)) {
+                Ok(k) => self.event_sub_key = Some(k),
+                Err(e) => error!("Failed to subscribe to ship event changes: {}", e)
+            }
+        }
+
         info!("Block service running");
         loop {
             let msg = match self.receiver.recv() {
                 Ok(m) => m,
                 Err(_) => return
             };
+            self.metrics.note(&msg);
 
             match msg {
                 ServiceMsg::ClientConnected((_addr, id)) => {
@@ -147,63 +614,26 @@ impl BlockService {
                     {
                         let ref mut borrow = cs.borrow_mut();
                         borrow.connection_id = id;
+                        borrow.handshake_deadline = handshake_deadline(self.handshake_timeout);
+                        borrow.note_activity(precise_time_ns());
                     }
                     {self.clients.borrow_mut().insert(id, cs);}
                 },
                 ServiceMsg::ClientDisconnected(id) => {
                     info!("Client {} disconnected from block", id);
-
-                    let mut h = self.make_handler(id);
-
-                    // First, we need to check if they're in a lobby or party.
-                    {
-                        let lr = self.lobbies.clone();
-                        let ref mut lobbies = lr.borrow_mut();
-                        for l in lobbies.iter_mut() {
-                            if l.has_player(id) {
-                                l.remove_player(&mut h, id).unwrap();
-                                break
-                            }
-                        }
-                    }
-                    {
-                        let pr = self.parties.clone();
-                        let ref mut parties = pr.borrow_mut();
-                        let mut party_index = 0;
-                        let mut remove = false;
-                        for (i, p) in parties.iter_mut().enumerate() {
-                            if p.has_player(id) {
-                                remove = p.remove_player(&mut h, id).unwrap();
-                                party_index = i;
-                                break
-                            }
-                        }
-                        if remove {
-                            parties.remove(party_index);
-                        }
-                    }
-
-                    // Now we will persist their current character to the shipgate.
-                    {
-                        let cs = h.get_client_state(id).unwrap();
-                        let ref client_state = cs.borrow();
-                        if let Some(ref full_char) = client_state.full_char {
-                            info!("Saving {}'s character due to disconnect", id);
-                            self.sg_sender.send(Sgm::BbPutCharacter(0, BbPutCharacter {
-                                account_id: client_state.account_id,
-                                slot: client_state.sec_data.slot,
-                                save_acct_data: 0,
-                                full_char: full_char.clone()
-                            })).unwrap();
-                        }
-                    }
-
-                    drop(h);
-
-                    {self.clients.borrow_mut().remove(&id);}
+                    self.client_disconnected(id, false);
                 },
                 ServiceMsg::ClientSaid(id, NetMsg::Bb(m)) => {
+                    let start = precise_time_ns();
+                    if let Some(cr) = self.clients.borrow().get(&id) {
+                        cr.borrow_mut().note_activity(start);
+                    }
+                    let msg_name = m.name();
                     let mut h = self.make_handler(id);
+                    if self.plugins.iter_mut().any(|p| p.on_message(&mut h, &m)) {
+                        debug!("{} from client {} consumed by a plugin", msg_name, id);
+                        continue
+                    }
                     match m {
                         Message::BbLogin(_, m) => { h.bb_login(m) },
                         Message::BbCharDat(_, m) => { h.bb_char_dat(m) },
@@ -220,13 +650,36 @@ impl BlockService {
                         Message::BbUpdateOptions(_, m) => { h.bb_update_options(m) },
                         Message::BbUpdateKeys(_, m) => { h.bb_update_keys(m) },
                         Message::BbUpdateJoy(_, m) => { h.bb_update_joy(m) },
+                        Message::BbUpdateShortcuts(_, m) => { h.bb_update_shortcuts(m) },
                         Message::MenuSelect(_, m) => { h.menu_select(m) },
                         Message::DoneBursting(_, _) => { h.done_burst() },
                         Message::BbFullChar(_, b) => { h.bb_full_char(b) },
+                        Message::BbCharSelect(_, m) => { h.bb_char_select(m) },
+                        Message::BbGuildRequest(_, _) => { h.bb_guildcard_req() },
+                        Message::BbGuildCardChunkReq(_, m) => { h.bb_guildcard_chunk_req(m) },
+                        Message::Goodbye(_, _) => { h.bb_goodbye() },
+                        // Answering an idle check; hearing from them was enough.
+                        Message::Ping(_, _) => (),
                         a => {
                             info!("{:?}", a);
+                            for p in self.plugins.iter_mut() {
+                                p.on_unhandled(&mut h, &a);
+                            }
                         }
                     }
+                    drop(h);
+                    let elapsed_ms = (precise_time_ns() - start) / 1_000_000;
+                    if self.options.slow_handler_ms > 0 && elapsed_ms > self.options.slow_handler_ms as u64 {
+                        warn!("Handling {} from client {} took {} ms", msg_name, id, elapsed_ms);
+                    }
+                },
+                ServiceMsg::ShipGateMsg(Sgm::SetShipEvent(req, body)) => {
+                    if Some(req) == self.event_sub_key {
+                        self.base_event = body.event;
+                        self.update_event();
+                    } else {
+                        warn!("Got a ship event change for an unexpected request ID {}.", req);
+                    }
                 },
                 ServiceMsg::ShipGateMsg(m) => {
                     let req = m.get_response_key();
@@ -241,8 +694,159 @@ impl BlockService {
                         None => warn!("Got a SG request response for an unexpected request ID {}.", req)
                     }
                 }
+                ServiceMsg::Tick => {
+                    self.ticks += 1;
+                    self.check_shipgate();
+                    let now = precise_time_ns();
+                    for (client, mut t) in self.sg_sender.expire(now) {
+                        t(self.make_handler(client));
+                    }
+                    // From the first tick, so the ship list fills in quickly.
+                    if self.ticks % REPORT_INTERVAL == 1 {
+                        self.report_population();
+                        self.send_heartbeat(now);
+                    }
+                    let interval = self.options.merge_migrate_interval as u64;
+                    if interval > 0 && self.ticks % interval == 0 {
+                        self.migrate_straggler();
+                    }
+                    if self.gm_event.get() != self.gm_event_applied {
+                        self.gm_event_applied = self.gm_event.get();
+                        self.update_event();
+                    } else if !self.holidays.is_empty() && self.ticks % 60 == 0 {
+                        self.update_event();
+                    }
+                    self.check_restart();
+                    if self.draining.get() {
+                        self.drain();
+                    }
+                    let lifetime = self.options.floor_item_lifetime as u64;
+                    if lifetime > 0 && self.ticks % FLOOR_ITEM_CHECK_INTERVAL == 0 {
+                        let now = precise_time_ns();
+                        let mut h = self.make_handler(0);
+                        let pr = self.parties.clone();
+                        for p in pr.borrow_mut().iter_mut() {
+                            p.expire_floor_items(&mut h, now, lifetime * 1_000_000_000);
+                        }
+                    }
+                    let checkpoint = self.options.playtime_checkpoint as u64;
+                    if checkpoint > 0 && self.ticks % checkpoint == 0 {
+                        let h = self.make_handler(0);
+                        let ids: Vec<usize> = self.clients.borrow().keys().cloned().collect();
+                        for id in ids {
+                            h.credit_playtime(id);
+                        }
+                    }
+                    let now = precise_time_ns();
+                    let idle_timeout = self.options.idle_timeout as u64 * 1_000_000_000;
+                    for (id, cr) in self.clients.borrow().iter() {
+                        let mut c = cr.borrow_mut();
+                        check_handshake(&self.sender, *id, &mut c.handshake_deadline, now, self.handshake_timeout);
+                        if idle_timeout == 0 {
+                            continue
+                        }
+                        match c.check_idle(now, idle_timeout, IDLE_PING_GRACE * 1_000_000_000) {
+                            Idle::Active => (),
+                            Idle::Ping => {
+                                debug!("Client {} has been quiet; pinging", id);
+                                self.sender.send((*id, Message::Ping(0, Ping)).into()).unwrap();
+                            },
+                            Idle::Drop => {
+                                info!("Client {} didn't answer an idle ping; dropping", id);
+                                self.sender.send(LoopMsg::DropClient(*id)).unwrap();
+                            }
+                        }
+                    }
+                    self.reconnects.borrow_mut().retain(|r| {
+                        if r.expires <= now {
+                            debug!("Reconnect window for account {} expired", r.account_id);
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                },
+                ServiceMsg::Shutdown => {
+                    self.shutdown();
+                    return
+                },
                 _ => unreachable!()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use mio::EventLoop;
+
+    use ::loop_handler::LoopHandler;
+    use ::shipgate::client::ClientMsg;
+
+    #[test]
+    fn test_shutdown_empties_lobbies() {
+        let event_loop = EventLoop::<LoopHandler>::new().unwrap();
+        let (sg, sg_rx) = SgSender::detached();
+        let (tx, rx) = channel();
+        let mut b = BlockService::new(rx, event_loop.channel(), sg.clone_with(tx.clone()), 1, 2, 0,
+                                      None, None, Vec::new(), BlockOptions::default(),
+                                      Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      EventLog::disabled(), 0, Vec::new(), Vec::new(), false,
+                                      Arc::new(Metrics::new()));
+        b.init_lobbies();
+        for id in 1..4 {
+            let mut c = ClientState::default();
+            c.connection_id = id;
+            c.account_id = id as u32;
+            c.full_char = Some(Default::default());
+            b.clients.borrow_mut().insert(id, Rc::new(RefCell::new(c)));
+            let mut h = b.make_handler(id);
+            b.lobbies.borrow_mut()[id % 2].add_player(&mut h, id).unwrap();
+        }
+        let lobbies = b.lobbies.clone();
+        let clients = b.clients.clone();
+
+        tx.send(ServiceMsg::Shutdown).unwrap();
+        b.run();
+        assert!(lobbies.borrow().iter().all(|l| l.is_empty()));
+        assert!(clients.borrow().is_empty());
+
+        // Everyone's character is saved on the way out.
+        drop(sg);
+        let mut saved: Vec<u32> = sg_rx.iter().filter_map(|m| match m {
+            ClientMsg::SendForget(Sgm::BbPutCharacter(_, p)) => Some(p.account_id),
+            _ => None
+        }).collect();
+        saved.sort();
+        assert_eq!(saved, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_restart_reopens_block() {
+        let event_loop = EventLoop::<LoopHandler>::new().unwrap();
+        let (sg, _sg_rx) = SgSender::detached();
+        let (_tx, rx) = channel();
+        let mut b = BlockService::new(rx, event_loop.channel(), sg, 1, 2, 0,
+                                      None, None, Vec::new(), BlockOptions::default(),
+                                      Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      Default::default(), Default::default(), Default::default(),
+                                      EventLog::disabled(), 0, Vec::new(), Vec::new(), false,
+                                      Arc::new(Metrics::new()));
+        b.init_lobbies();
+        b.draining.set(true);
+        b.restarting = true;
+
+        // Once the block is empty it takes players again with fresh lobbies.
+        b.drain();
+        assert!(!b.draining.get());
+        assert!(!b.restarting);
+        assert_eq!(b.lobbies.borrow().len(), 2);
+    }
+}
@@ -8,23 +8,32 @@ pub mod enemygen;
 
 use rand::random;
 
+use time::precise_time_ns;
+
 use psomsg::bb::Message as BbMsg;
 use psomsg::bb::*;
 
 use psodata::map::MapEnemy;
 
 use ::maps::{Areas, InstanceEnemy, Ep1Areas, Ep2Areas, Ep4Areas};
+use ::maps::graph::valid_area_change;
+
+use ::config::AreaValidation;
 
-use super::handler::BlockHandler;
+use super::handler::commands;
+use super::handler::{BlockHandler, SUSPICION_BAD_EXP, SUSPICION_BAD_DROP, SUSPICION_BAD_PICKUP, SUSPICION_BAD_AREA};
 
 use self::error::PartyError;
 use self::enemygen::convert_enemy;
 
-static SLASH_COMMAND_HELP_MSG: &'static str = "\tC6Slash commands\tC7
-/help -- Show this message
+/// The commands only a party has. `/help` lists the block's after them.
+static PARTY_COMMAND_HELP: &'static str = "/help -- Show this message
 /giveexp <exp> -- Give yourself <exp>
 ";
 
+/// Meseta the tekker charges to identify a weapon.
+pub const TEKKER_COST: u32 = 100;
+
 #[derive(Clone, Debug)]
 pub struct Party {
     pub name: String,
@@ -37,18 +46,31 @@ pub struct Party {
     pub unique_id: u32,
     section_id: Option<u8>,
     members: [Option<usize>; 4],
+    /// The area each member is in, by their last area change.
+    member_areas: [u8; 4],
     bursting: [bool; 4],
     leader_id: u8,
     maps: Arc<Areas>,
     variants: Vec<u32>,
     enemies: Vec<InstanceEnemy>,
     bc_queue: VecDeque<(usize, Message)>,
-    items: Vec<InvItem>,
+    floor_items: Vec<FloorItem>,
     next_drop_pos: [Option<NextDropPos>; 4],
     player_drop_counter: [u32; 4],
     party_drop_counter: u32
 }
 
+/// An item lying on the floor of the party's map.
+#[derive(Clone, Debug)]
+struct FloorItem {
+    pub area: u32,
+    pub x: f32,
+    pub z: f32,
+    pub item: ItemData,
+    /// `time::precise_time_ns` at which it hit the floor.
+    pub dropped_at: u64
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 struct NextDropPos {
     pub area: u32,
@@ -88,13 +110,14 @@ impl Party {
             unique_id: unique_id,
             section_id: None,
             members: Default::default(),
+            member_areas: Default::default(),
             bursting: Default::default(),
             bc_queue: Default::default(),
             leader_id: 0,
             maps: maps,
             variants: variants,
             enemies: enemies,
-            items: Vec::new(),
+            floor_items: Vec::new(),
             next_drop_pos: Default::default(),
             player_drop_counter: Default::default(),
             party_drop_counter: 0x00810000
@@ -133,6 +156,8 @@ impl Party {
 
         // put them in that slot
         self.members[new_client_id as usize] = Some(player);
+        // Everyone starts on Pioneer 2.
+        self.member_areas[new_client_id as usize] = 0;
 
         debug!("New client ID is {}", new_client_id);
 
@@ -224,46 +249,52 @@ impl Party {
         Ok(())
     }
 
+    /// Removes a player from the party, handing leadership to another member
+    /// if they led it. Returns true once the party is empty and should be
+    /// destroyed.
     pub fn remove_player(&mut self, handler: &mut BlockHandler, player: usize) -> Result<bool, PartyError> {
-        let mut ret = false;
-        match self.client_id_for_player(player) {
-            Some(i) => {
-                info!("Removing client {} from party \"{}\"", player, &self.name[2..]);
-                if self.leader_id == i {
-                    // pick a new leader
-                    match self.find_first_player_not_matching(player) {
-                        Some((ii, _)) => {
-                            info!("New leader for \"{}\" elected to {}", &self.name[2..], ii);
-                            self.leader_id = ii;
-                        },
-                        None => {
-                            info!("Party {} is being removed", self.name);
-                            // the party is empty, we'll return true to destroy the party
-                            ret = true;
-                        }
-                    }
-                }
-                self.members[i as usize] = None;
-                // ensure their bursting flag is unset
-                self.bursting[i as usize] = false;
-
-                // tell the other clients that this player has left, and maybe
-                // the new elected leader
-                // if the party is empty, this will do nothing
-                let gl = BbGameLeave {
-                    client_id: i,
-                    leader_id: self.leader_id,
-                    padding: 0
-                };
-                self.bb_broadcast(handler, Some(player), gl.into()).unwrap();
-                debug!("{:?}", self.members);
-            },
-            _ => {
-                return Err(PartyError::NotInParty)
+        let i = match self.client_id_for_player(player) {
+            Some(i) => i,
+            None => return Err(PartyError::NotInParty)
+        };
+        info!("Removing client {} from party \"{}\"", player, &self.name[2..]);
+        let mut new_leader = None;
+        if self.leader_id == i {
+            if let Some(ii) = elect_leader(&self.members, &self.bursting, i) {
+                info!("New leader for \"{}\" elected to {}", &self.name[2..], ii);
+                self.leader_id = ii;
+                new_leader = self.members[ii as usize];
             }
         }
+        self.members[i as usize] = None;
+        // ensure their bursting flag is unset
+        self.bursting[i as usize] = false;
 
-        Ok(ret)
+        if self.is_empty() {
+            info!("Party {} is being removed", self.name);
+            return Ok(true)
+        }
+
+        // tell the other clients that this player has left, and maybe
+        // the new elected leader
+        let gl = BbGameLeave {
+            client_id: i,
+            leader_id: self.leader_id,
+            padding: 0
+        };
+        self.bb_broadcast(handler, Some(player), gl.into()).unwrap();
+        if let Some(leader) = new_leader {
+            let name = handler.get_client_state(leader).and_then(|cr| {
+                let c = cr.borrow();
+                c.full_char.as_ref().map(|fc| fc.chara.name.clone())
+            });
+            if let Some(name) = name {
+                self.bb_broadcast(handler, Some(player), leader_notice(&name)).unwrap();
+            }
+        }
+        debug!("{:?}", self.members);
+
+        Ok(false)
     }
 
     pub fn handle_bb_game_name(&mut self, handler: &mut BlockHandler) -> Result<(), PartyError> {
@@ -392,7 +423,8 @@ impl Party {
             if let Some(w) = s_w.next() {
                 match w {
                     "/help" => {
-                        let reply = Message::LargeMsg(0, LargeMsg(SLASH_COMMAND_HELP_MSG.to_string()));
+                        let text = format!("\tC6Slash commands\tC7\n{}{}", PARTY_COMMAND_HELP, commands::help());
+                        let reply = Message::LargeMsg(0, LargeMsg(text));
                         handler.send_to_client(sender, reply);
                         return Ok(())
                     },
@@ -423,14 +455,16 @@ impl Party {
     pub fn handle_bb_subcmd_60(&mut self, handler: &mut BlockHandler, sender: usize, m: BbSubCmd60) -> Result<(), PartyError> {
         let mut handled = false;
         if self.is_bursting() {
-            if let &BbSubCmd60::Unknown { cmd, .. } = &m {
-                if cmd == 0x7C {
+            match &m {
+                &BbSubCmd60::Unknown { cmd: 0x7C, .. } => {
                     // safe to send during burst, ignore
-                } else {
+                },
+                &BbSubCmd60::Unknown { .. } | &BbSubCmd60::Bb60AreaChange { .. } => {
                     // enqueue
-                    self.bc_queue.push_back((sender, Message::BbSubCmd60(0, m)));
+                    self.bc_queue.push_back((sender, Message::BbSubCmd60(0, m.clone())));
                     return Ok(())
-                }
+                },
+                _ => ()
             }
         }
         match m.clone() {
@@ -442,6 +476,10 @@ impl Party {
                 self.handle_bb_dropitem(handler, data, client_id);
                 handled = true;
             },
+            BbSubCmd60::Bb60ItemDrop { ref data, .. } => {
+                // The leader generated an item drop; broadcast as usual.
+                self.track_item_drop(data);
+            },
             BbSubCmd60::Bb60DropPos { data, client_id, .. } => {
                 self.handle_bb_droppos(handler, data, client_id);
                 handled = true;
@@ -449,7 +487,10 @@ impl Party {
             BbSubCmd60::Bb60DeleteItem { data, client_id, .. } => {
                 self.handle_bb_delete_item(handler, data, client_id);
                 handled = true;
-            }
+            },
+            BbSubCmd60::Bb60AreaChange { data, .. } => {
+                handled = !self.handle_bb_area_change(handler, sender, data);
+            },
             _ => ()
         }
         debug!("{} bc 0x60: {:?}", sender, m);
@@ -489,6 +530,10 @@ impl Party {
                 self.handle_bb_openbank(handler, data.clone());
                 handled = true;
             },
+            &BbSubCmd62::Bb62BankAction { ref data, .. } => {
+                self.handle_bb_bank_action(handler, data.clone());
+                handled = true;
+            },
             &BbSubCmd62::Bb62ShopReq { ref data, .. } => {
                 self.handle_bb_shopreq(handler, data.clone());
                 handled = true;
@@ -496,6 +541,17 @@ impl Party {
             &BbSubCmd62::Bb62PickUp { ref data, .. } => {
                 self.handle_bb_pick_up(handler, dest, data.clone());
                 handled = true;
+            },
+            &BbSubCmd62::Bb62IdentifyItem { ref data, .. } => {
+                self.handle_bb_identify(handler, data.item_id);
+                handled = true;
+            },
+            &BbSubCmd62::Bb62AcceptIdentify { ref data, .. } => {
+                self.handle_bb_accept_identify(handler, data.item_id);
+                handled = true;
+            },
+            &BbSubCmd62::Bb62SellItem { ref data, .. } => {
+                handled = !handler.check_identified(data.item_id, "sell");
             }
             _ => ()
         }
@@ -591,31 +647,75 @@ impl Party {
             };
 
             if let Some(bp) = bp {
-                if m.last_hitter == 1 {
-                    let exp = bp.exp;
-                    info!("Client {} request verified; +{} EXP for last-hitting on {} ({})", cid, exp, enemy.name, m.enemy_id);
-                    self.award_exp(cid, handler, exp);
+                let exp = if m.last_hitter == 1 {
+                    info!("Client {} request verified; +{} EXP for last-hitting on {} ({})", cid, bp.exp, enemy.name, m.enemy_id);
+                    bp.exp
                 } else {
                     let exp = bp.exp * 80 / 100;
                     info!("Client {} request verified; +{} EXP for assisting on {} ({})", cid, exp, enemy.name, m.enemy_id);
-                    self.award_exp(cid, handler, exp);
+                    exp
+                };
+                // Rested experience only ever tops up verified gains.
+                let bonus = handler.get_client_state(cid).unwrap().borrow_mut().take_rested_exp(exp);
+                if bonus > 0 {
+                    debug!("Client {} gets {} rested EXP", cid, bonus);
                 }
+                self.award_exp(cid, handler, exp + bonus);
 
             } else {
                 error!("Battle param entry for enemy id {} doesn't exist", m.enemy_id);
                 return
             }
         } else {
-            warn!("Client {} tried to gain exp for an enemy that doesn't exist: {}", cid, m.enemy_id);
+            handler.flag_suspicious(cid, SUSPICION_BAD_EXP, format!("asked for exp for enemy {}, which doesn't exist", m.enemy_id));
             return
         }
     }
 
+    /// Track a member's move to another area, checking it against the map
+    /// as the block is configured to. Returns whether to pass it on to the
+    /// rest of the game.
+    pub fn handle_bb_area_change(&mut self, handler: &mut BlockHandler, sender: usize, m: Bb60AreaChange) -> bool {
+        let slot = match self.members.iter().position(|c| *c == Some(sender)) {
+            Some(s) => s,
+            None => return false
+        };
+        let from = self.member_areas[slot];
+        let to = m.area as u8;
+        let mode = handler.options.area_validation;
+        if mode != AreaValidation::Off && !self.battle && (m.area > 0xFF || !valid_area_change(self.episode, from, to)) {
+            warn!("Client {} in \"{}\" moved from area {} to {}, which the map doesn't allow", sender, &self.name[2..], from, m.area);
+            if mode == AreaValidation::Enforce {
+                handler.flag_suspicious(sender, SUSPICION_BAD_AREA, format!("moved from area {} to {} in episode {}", from, m.area, self.episode));
+                return false
+            }
+        }
+        self.member_areas[slot] = to;
+        true
+    }
+
     pub fn handle_bb_dropitem(&mut self, handler: &mut BlockHandler, m: Bb60DropItem, slot: u8) {
         let cid = handler.client_id;
         info!("Client {} dropping item: {:?}", cid, m);
-        warn!("Item dropping is stubbed");
-        // TODO Add this item to the lobby inventory.
+
+        let dropped = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut c = cr.borrow_mut();
+            c.full_char.as_mut().and_then(|fc| fc.inv.remove_item(m.item_id))
+        };
+        match dropped {
+            Some(item) => self.floor_items.push(FloorItem {
+                area: m.area as u32,
+                x: m.x,
+                z: m.z,
+                item: item.data,
+                dropped_at: precise_time_ns()
+            }),
+            None => {
+                handler.flag_suspicious(cid, SUSPICION_BAD_DROP, format!("dropped item {:08X}, which isn't in their inventory", m.item_id));
+                return
+            }
+        }
 
         self.bb_broadcast(handler, Some(cid), BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DropItem { client_id: slot, unused: 0, data: m})).unwrap();
     }
@@ -635,51 +735,427 @@ impl Party {
         self.bb_broadcast(handler, Some(cid), BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DropPos { client_id: slot, unused: 0, data: m })).unwrap();
     }
 
-    /// This is sent when a client is dropping a stack from their inventory.
+    /// This is sent when a client is dropping some of a stack, or of their
+    /// meseta, at the spot their last drop pos gave.
     pub fn handle_bb_delete_item(&mut self, handler: &mut BlockHandler, m: Bb60DeleteItem, slot: u8) {
         let cid = handler.client_id;
 
-        warn!("Stack dropping is stubbed");
-
-        if let Some(nd) = self.next_drop_pos[slot as usize] {
-            // we need to get the item they are dropping from their inventory, but we don't do character tracking yet
-            // first, drop the stack for everyone
-            info!("Dropping item stack from item ID {}", m.item_id);
-            self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DropStack { client_id: slot, unused: 0, data: Bb60DropStack {
-                area: nd.area,
-                x: nd.x,
-                z: nd.z,
-                item: [4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                item_id: self.player_drop_counter[slot as usize],
-                item2: [nd.amount as u8, 0, 0, 0]
-            }})).unwrap();
-
+        let nd = match self.next_drop_pos[slot as usize].take() {
+            Some(nd) => nd,
+            None => {
+                warn!("Client {} tried to drop stack without sending drop pos first", cid);
+                handler.send_fatal_error(cid, "\tEIllegal message.");
+                return
+            }
+        };
+        let new_id = 0x00010000 | ((slot as u32) << 21) | self.player_drop_counter[slot as usize];
+        let taken = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut c = cr.borrow_mut();
+            c.full_char.as_mut().and_then(|fc| take_stack(fc, m.item_id, m.amount, new_id))
+        };
+        let item = match taken {
+            Some(item) => item,
+            None => {
+                handler.flag_suspicious(cid, SUSPICION_BAD_DROP, format!("dropped {} of item {:08X}, which they don't have", m.amount, m.item_id));
+                return
+            }
+        };
+        info!("Client {} dropping {} of item {:08X} as item {:08X}", cid, m.amount, m.item_id, item.item_id);
+        if item.item_id == new_id {
             self.player_drop_counter[slot as usize] += 1;
+        }
 
-            // broadcast delete item from inventory
-            self.bb_broadcast(handler, Some(cid), BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DeleteItem { client_id: slot, unused: 0, data: m })).unwrap();
+        let mut stack = Bb60DropStack {
+            area: nd.area,
+            x: nd.x,
+            z: nd.z,
+            item_id: item.item_id,
+            ..Default::default()
+        };
+        for (d, s) in stack.item.iter_mut().zip(item.data.iter()) {
+            *d = *s;
+        }
+        for (d, s) in stack.item2.iter_mut().zip(item.data2.iter()) {
+            *d = *s;
+        }
+        self.floor_items.push(FloorItem {
+            area: nd.area,
+            x: nd.x,
+            z: nd.z,
+            item: item,
+            dropped_at: precise_time_ns()
+        });
+        self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DropStack { client_id: slot, unused: 0, data: stack })).unwrap();
+        // broadcast delete item from inventory
+        self.bb_broadcast(handler, Some(cid), BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DeleteItem { client_id: slot, unused: 0, data: m })).unwrap();
+    }
 
-            self.next_drop_pos[slot as usize] = None;
-        } else {
-            warn!("Client {} tried to drop stack without sending drop pos first", cid);
-            handler.send_fatal_error(cid, "\tEIllegal message.");
+    /// Remember an item the leader dropped from an enemy or box.
+    fn track_item_drop(&mut self, m: &Bb60ItemDrop) {
+        self.floor_items.push(FloorItem {
+            area: m.area as u32,
+            x: m.x,
+            z: m.z,
+            item: ItemData {
+                data: m.item.to_vec(),
+                item_id: m.item_id,
+                data2: m.item2.to_vec()
+            },
+            dropped_at: precise_time_ns()
+        });
+    }
+
+    /// Take items that have lain on the floor longer than `lifetime`
+    /// nanoseconds off it, for everyone in the party.
+    pub fn expire_floor_items(&mut self, handler: &mut BlockHandler, now: u64, lifetime: u64) {
+        let (expired, kept): (Vec<FloorItem>, Vec<FloorItem>) = self.floor_items.drain(..)
+            .partition(|f| now.saturating_sub(f.dropped_at) >= lifetime);
+        self.floor_items = kept;
+        if expired.is_empty() {
             return
         }
+        debug!("Party {} cleared {} items off the floor", self.unique_id, expired.len());
+        for f in expired {
+            // For an item on the floor, the second field is its area.
+            self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DestroyItem { client_id: self.leader_id, unused: 0, data: Bb60DestroyItem {
+                item_id: f.item.item_id,
+                amount: f.area
+            }})).unwrap();
+        }
     }
 
-    pub fn handle_bb_pick_up(&mut self, handler: &mut BlockHandler, _dest: u32, _m: Bb62PickUp) {
+    pub fn handle_bb_pick_up(&mut self, handler: &mut BlockHandler, _dest: u32, m: Bb62PickUp) {
         let cid = handler.client_id;
-        debug!("Client {} picking up item", cid);
-        warn!("Item pick up is stubbed");
-        // do nothing yet, we need to track characters and lobby items to be able to do this
+        debug!("Client {} picking up item {}", cid, m.item_id);
+        let slot = match self.client_id_for_player(cid) {
+            Some(s) => s,
+            None => return
+        };
+        let idx = match self.floor_items.iter().position(|f| f.item.item_id == m.item_id && f.area == m.area) {
+            Some(i) => i,
+            None => {
+                handler.flag_suspicious(cid, SUSPICION_BAD_PICKUP, format!("picked up item {:08X}, which isn't on the floor", m.item_id));
+                return
+            }
+        };
+        if handler.is_banned_item(&self.floor_items[idx].item) {
+            info!("Client {} tried to pick up banned item {:08X}", cid, m.item_id);
+            handler.send_error(cid, "\tEThat item is banned\non this server.");
+            return
+        }
+
+        let picked_up = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut c = cr.borrow_mut();
+            let quota = c.storage_quota;
+            let elsewhere = c.stored_elsewhere();
+            let fc = match c.full_char.as_mut() {
+                Some(fc) => fc,
+                None => return
+            };
+            let item = &self.floor_items[idx].item;
+            if item.is_meseta() {
+                let cap = handler.options.max_meseta;
+                let total = fc.chara.meseta as u64 + item.meseta_amount() as u64;
+                if total > cap as u64 {
+                    // Leave it on the floor rather than throw the excess away.
+                    info!("Client {} can't pick up {} meseta past the cap of {}", cid, item.meseta_amount(), cap);
+                    handler.send_error(cid, "\tEYou can't carry\nthat much meseta.");
+                    return
+                }
+                fc.chara.meseta = total as u32;
+                true
+            } else {
+                let slots = handler.options.inventory_slots as usize;
+                let stack = handler.stack_limit_for(item);
+                let new_slot = fc.inv.fit_for(item, slots, stack) == Some(InventoryFit::NewSlot);
+                if new_slot && quota > 0 && elsewhere + fc.stored_items() >= quota {
+                    info!("Client {} is at their account's storage quota of {}", cid, quota);
+                    handler.send_error(cid, "\tEYour account has no room\nfor more items.");
+                    return
+                }
+                fc.inv.add_item(item.clone(), slots, stack)
+            }
+        };
+        if !picked_up {
+            // Leave it on the floor for someone else.
+            debug!("Client {} has no room for item {}", cid, m.item_id);
+            handler.send_error(cid, "\tEYour inventory is full.");
+            return
+        }
+
+        let fi = self.floor_items.remove(idx);
+        self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60PickUpItem { client_id: slot, unused: 0, data: Bb60PickUpItem {
+            client_id: slot as u16,
+            area: fi.area as u16,
+            item_id: fi.item.item_id
+        }})).unwrap();
     }
 
     pub fn handle_bb_openbank(&mut self, handler: &mut BlockHandler, m: Bb62OpenBank) {
         let cid = handler.client_id;
         debug!("Client {} opening bank: {:?}", cid, m);
-        warn!("Bank inventory is stubbed");
-        // deliver a blank bank inventory msg...
-        handler.send_to_client(cid, BbMsg::BbSubCmd6D(0, BbSubCmd6D::Bb6DBankInv { flags: 0, unused: 0, data: Bb6DBankInv::default() }));
+        let slot = match self.client_id_for_player(cid) {
+            Some(s) => s,
+            None => return
+        };
+        let (inv, missing) = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut cref = cr.borrow_mut();
+            let c = &mut *cref;
+            let bank = if c.using_shared_bank {
+                c.shared_bank.as_mut()
+            } else {
+                c.full_char.as_mut().map(|fc| &mut fc.bank)
+            };
+            match bank {
+                Some(bank) => {
+                    // Bank items get fresh IDs each time so they can't
+                    // collide with anything on the floor.
+                    let mut id = 0x00010000 | ((slot as u32) << 21) | self.player_drop_counter[slot as usize];
+                    for b in bank.live_items_mut().iter_mut() {
+                        b.data.item_id = id;
+                        id += 1;
+                    }
+                    self.player_drop_counter[slot as usize] = id;
+                    (Bb6DBankInv {
+                        checksum: 0,
+                        meseta: bank.meseta,
+                        items: bank.live_items().to_vec()
+                    }, false)
+                },
+                None => (Bb6DBankInv::default(), true)
+            }
+        };
+        if missing {
+            warn!("Client {} opened the shared bank before it was loaded", cid);
+            handler.send_error(cid, "\tEThe shared bank isn't\navailable right now.");
+        }
+        handler.send_to_client(cid, BbMsg::BbSubCmd6D(0, BbSubCmd6D::Bb6DBankInv { flags: 0, unused: 0, data: inv }));
+    }
+
+    /// Move items or meseta between a player's inventory and whichever bank
+    /// they have open. Everything is checked here; the client's idea of what
+    /// fits is not trusted.
+    pub fn handle_bb_bank_action(&mut self, handler: &mut BlockHandler, m: Bb62BankAction) {
+        let cid = handler.client_id;
+        debug!("Client {} bank action: {:?}", cid, m);
+        let slot = match self.client_id_for_player(cid) {
+            Some(s) => s,
+            None => return
+        };
+        let slots = handler.options.inventory_slots as usize;
+        let new_id = 0x00010000 | ((slot as u32) << 21) | self.player_drop_counter[slot as usize];
+
+        let (result, shared) = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut cref = cr.borrow_mut();
+            let c = &mut *cref;
+            let shared = c.using_shared_bank;
+            let fc = match c.full_char.as_mut() {
+                Some(fc) => fc,
+                None => return
+            };
+            let (bank, capacity) = if shared {
+                match c.shared_bank.as_mut() {
+                    Some(b) => (b, c.shared_bank_slots as usize),
+                    None => return
+                }
+            } else {
+                (&mut fc.bank, BANK_SLOTS)
+            };
+            let result = match (m.action, m.item_id) {
+                (0, 0xFFFFFFFF) => {
+                    if m.meseta_amount > fc.chara.meseta || bank.meseta as u64 + m.meseta_amount as u64 > 999999 {
+                        Err("\tEYou can't deposit\nthat much meseta.")
+                    } else {
+                        fc.chara.meseta -= m.meseta_amount;
+                        bank.meseta += m.meseta_amount;
+                        Ok(None)
+                    }
+                },
+                (1, 0xFFFFFFFF) => {
+                    if m.meseta_amount > bank.meseta || fc.chara.meseta as u64 + m.meseta_amount as u64 > handler.options.max_meseta as u64 {
+                        Err("\tEYou can't withdraw\nthat much meseta.")
+                    } else {
+                        bank.meseta -= m.meseta_amount;
+                        fc.chara.meseta += m.meseta_amount;
+                        Ok(None)
+                    }
+                },
+                (0, item_id) => {
+                    let before = fc.inv.clone();
+                    match fc.inv.take(item_id, m.item_amount, new_id) {
+                        Some(item) => {
+                            let stack = handler.stack_limit_for(&item);
+                            if bank.deposit(item, capacity, stack) {
+                                Ok(None)
+                            } else {
+                                fc.inv = before;
+                                Err("\tEThe bank is full.")
+                            }
+                        },
+                        None => Err("\tEYou don't have that item.")
+                    }
+                },
+                (1, item_id) => {
+                    let mut wanted = match bank.live_items().iter().find(|b| b.data.item_id == item_id) {
+                        Some(b) => b.data.clone(),
+                        None => {
+                            warn!("Client {} tried to withdraw item {} that isn't in the bank", cid, item_id);
+                            return
+                        }
+                    };
+                    if wanted.is_stackable() && m.item_amount > 0 {
+                        wanted.data[5] = m.item_amount;
+                    }
+                    let stack = handler.stack_limit_for(&wanted);
+                    let fit = fc.inv.fit_for(&wanted, slots, stack);
+                    if handler.is_banned_item(&wanted) {
+                        Err("\tEThat item is banned\non this server.")
+                    } else if fit.is_none() {
+                        Err("\tEYour inventory is full.")
+                    } else {
+                        match bank.withdraw(item_id, m.item_amount) {
+                            Some(mut item) => {
+                                item.item_id = new_id;
+                                fc.inv.add_item(item.clone(), slots, stack);
+                                Ok(Some(item))
+                            },
+                            None => Err("\tEThere aren't that many\nin the bank.")
+                        }
+                    }
+                },
+                // Closing the bank.
+                _ => return
+            };
+            (result, shared)
+        };
+
+        match result {
+            Ok(created) => {
+                self.player_drop_counter[slot as usize] += 1;
+                if m.action == 0 && m.item_id != 0xFFFFFFFF {
+                    self.bb_broadcast(handler, Some(cid), BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DeleteItem { client_id: slot, unused: 0, data: Bb60DeleteItem {
+                        item_id: m.item_id,
+                        amount: m.item_amount as u32
+                    }})).unwrap();
+                }
+                if let Some(item) = created {
+                    let mut ci = Bb60CreateItem::default();
+                    for (d, s) in ci.item.iter_mut().zip(item.data.iter()) {
+                        *d = *s;
+                    }
+                    ci.item_id = item.item_id;
+                    for (d, s) in ci.item2.iter_mut().zip(item.data2.iter()) {
+                        *d = *s;
+                    }
+                    self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60CreateItem { client_id: slot, unused: 0, data: ci })).unwrap();
+                }
+                if shared {
+                    handler.put_shared_bank();
+                }
+            },
+            Err(e) => {
+                info!("Client {} bank action refused: {:?}", cid, m);
+                handler.send_error(cid, e);
+            }
+        }
+    }
+
+    /// The tekker appraises an unidentified weapon. The result waits on the
+    /// client until they accept it.
+    pub fn handle_bb_identify(&mut self, handler: &mut BlockHandler, item_id: u32) {
+        let cid = handler.client_id;
+        let result = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut cref = cr.borrow_mut();
+            let c = &mut *cref;
+            let fc = match c.full_char.as_mut() {
+                Some(fc) => fc,
+                None => return
+            };
+            match fc.inv.find(item_id).map(|i| i.data.clone()) {
+                None => Err("\tEYou don't have that item."),
+                Some(ref i) if !i.is_unidentified() => Err("\tEThat item doesn't need\nto be identified."),
+                Some(_) if fc.chara.meseta < TEKKER_COST => Err("\tEYou don't have enough\nmeseta."),
+                Some(mut item) => {
+                    fc.chara.meseta -= TEKKER_COST;
+                    item.identify();
+                    c.identifying = Some(item.clone());
+                    Ok(item)
+                }
+            }
+        };
+        match result {
+            Ok(item) => {
+                debug!("Client {} had item {:08X} identified", cid, item_id);
+                let mut r = Bb60IdentifyResult::default();
+                for (d, s) in r.item.iter_mut().zip(item.data.iter()) {
+                    *d = *s;
+                }
+                r.item_id = item.item_id;
+                for (d, s) in r.item2.iter_mut().zip(item.data2.iter()) {
+                    *d = *s;
+                }
+                let slot = self.client_id_for_player(cid).unwrap_or(0);
+                handler.send_to_client(cid, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60IdentifyResult { client_id: slot, unused: 0, data: r }));
+            },
+            Err(e) => {
+                info!("Client {} identify refused for item {:08X}", cid, item_id);
+                handler.send_error(cid, e);
+            }
+        }
+    }
+
+    /// Swap the appraised weapon into the inventory in place of the
+    /// unidentified one.
+    pub fn handle_bb_accept_identify(&mut self, handler: &mut BlockHandler, item_id: u32) {
+        let cid = handler.client_id;
+        let slot = match self.client_id_for_player(cid) {
+            Some(s) => s,
+            None => return
+        };
+        let item = {
+            let cr = handler.get_client_state(cid).unwrap();
+            let mut cref = cr.borrow_mut();
+            let c = &mut *cref;
+            let appraised = c.identifying.as_ref().map(|i| i.item_id == item_id).unwrap_or(false);
+            let item = if appraised { c.identifying.take() } else { None };
+            match (item, c.full_char.as_mut()) {
+                (Some(item), Some(fc)) => {
+                    match fc.inv.items.iter_mut().find(|i| i.data.item_id == item_id) {
+                        Some(i) => {
+                            i.data = item.clone();
+                            Some(item)
+                        },
+                        None => None
+                    }
+                },
+                _ => None
+            }
+        };
+        let item = match item {
+            Some(i) => i,
+            None => {
+                warn!("Client {} accepted identification of item {:08X} without having it appraised", cid, item_id);
+                return
+            }
+        };
+        self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60DeleteItem { client_id: slot, unused: 0, data: Bb60DeleteItem {
+            item_id: item_id,
+            amount: 1
+        }})).unwrap();
+        let mut ci = Bb60CreateItem::default();
+        for (d, s) in ci.item.iter_mut().zip(item.data.iter()) {
+            *d = *s;
+        }
+        ci.item_id = item.item_id;
+        for (d, s) in ci.item2.iter_mut().zip(item.data2.iter()) {
+            *d = *s;
+        }
+        self.bb_broadcast(handler, None, BbMsg::BbSubCmd60(0, BbSubCmd60::Bb60CreateItem { client_id: slot, unused: 0, data: ci })).unwrap();
     }
 
     pub fn handle_bb_shopreq(&mut self, handler: &mut BlockHandler, m: Bb62ShopReq) {
@@ -706,15 +1182,18 @@ impl Party {
             let mut chara = client_state.full_char.as_mut().unwrap();
             current_level = chara.chara.level as usize;
             let current_exp = chara.chara.exp as usize;
+            // Levels count from 0.
+            let top = handler.options.max_level as usize - 1;
 
-            if current_level >= 199 {
+            if current_level >= top {
                 // Can't gain any more experience.
+                debug!("Client {} is at the level cap", client);
                 return
             }
 
             loop {
-                if current_level >= 199 {
-                    // We hit 200 while adding levels, break
+                if current_level >= top {
+                    // We hit the cap while adding levels, break
                     break
                 }
                 // Level table entry.
@@ -743,7 +1222,13 @@ impl Party {
                 }
             }
 
-            chara.chara.exp += exp;
+            chara.chara.exp = match chara.chara.exp.checked_add(exp) {
+                Some(e) => e,
+                None => {
+                    warn!("Client {}'s experience would overflow adding {}; capping it", client, exp);
+                    ::std::u32::MAX
+                }
+            };
             chara.chara.level = current_level as u32;
         }
         let slot = self.client_id_for_player(client).unwrap();
@@ -991,3 +1476,190 @@ impl Party {
         }
     }
 }
+
+/// The slot to hand the party to when the leader in `leaving` goes. Members
+/// who have finished loading in are preferred, since a bursting client can't
+/// act as leader yet; `None` if nobody else is left.
+fn elect_leader(members: &[Option<usize>; 4], bursting: &[bool; 4], leaving: u8) -> Option<u8> {
+    let others = || (0..4u8).filter(move |&i| i != leaving && members[i as usize].is_some());
+    others().find(|&i| !bursting[i as usize]).or_else(|| others().next())
+}
+
+/// Take what a stack drop asks for out of the character: `amount` meseta
+/// for item ID 0xFFFFFFFF, otherwise that many of an inventory item, split
+/// off as `split_id` if it's part of a stack. `None` if they don't have it.
+fn take_stack(fc: &mut BbFullCharData, item_id: u32, amount: u32, split_id: u32) -> Option<ItemData> {
+    if item_id == 0xFFFFFFFF {
+        if amount == 0 || amount > fc.chara.meseta {
+            return None
+        }
+        fc.chara.meseta -= amount;
+        return Some(ItemData {
+            data: vec![4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            item_id: split_id,
+            data2: vec![amount as u8, (amount >> 8) as u8, (amount >> 16) as u8, (amount >> 24) as u8]
+        })
+    }
+    if amount > 255 {
+        return None
+    }
+    fc.inv.take(item_id, amount as u8, split_id)
+}
+
+/// The chat line telling a party that the player called `name` leads it now.
+fn leader_notice(name: &str) -> BbMsg {
+    let text = format!("\tE{} is now the party leader.", name.trim_left_matches("\tE"));
+    BbMsg::BbChat(0, BbChat(0, text))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::collections::VecDeque;
+
+    use mio::EventLoop;
+
+    use psomsg::bb::*;
+
+    use ::block::handler::test::{Collect, test_block, sent, playing};
+    use ::config::BlockOptions;
+    use ::loop_handler::LoopMsg;
+    use ::maps::Areas;
+    use ::services::message::NetMsg;
+
+    use super::{Party, FloorItem, elect_leader, leader_notice, take_stack};
+
+    /// A party with no maps, with clients 1 to 3 in it and 1 leading.
+    fn party() -> Party {
+        Party {
+            name: "\tEParty".to_string(),
+            password: None,
+            episode: 1,
+            difficulty: 0,
+            battle: false,
+            challenge: false,
+            single_player: false,
+            unique_id: 1,
+            section_id: Some(0),
+            members: [Some(1), Some(2), Some(3), None],
+            member_areas: Default::default(),
+            bursting: Default::default(),
+            leader_id: 0,
+            maps: Arc::new(Areas::default()),
+            variants: Vec::new(),
+            enemies: Vec::new(),
+            bc_queue: VecDeque::new(),
+            floor_items: Vec::new(),
+            next_drop_pos: Default::default(),
+            player_drop_counter: Default::default(),
+            party_drop_counter: 0x00810000
+        }
+    }
+
+    #[test]
+    fn test_stack_drop_takes_from_character() {
+        let mut fc = BbFullCharData::default();
+        fc.chara.meseta = 1000;
+        let m = take_stack(&mut fc, 0xFFFFFFFF, 300, 0x00210001).unwrap();
+        assert!(m.is_meseta());
+        assert_eq!(m.meseta_amount(), 300);
+        assert_eq!(m.item_id, 0x00210001);
+        assert_eq!(fc.chara.meseta, 700);
+        // More than they have, or nothing at all, isn't dropped.
+        assert!(take_stack(&mut fc, 0xFFFFFFFF, 701, 0x00210002).is_none());
+        assert!(take_stack(&mut fc, 0xFFFFFFFF, 0, 0x00210002).is_none());
+        assert_eq!(fc.chara.meseta, 700);
+        // Nor is an item they don't hold.
+        assert!(take_stack(&mut fc, 0x00010005, 1, 0x00210002).is_none());
+    }
+
+    #[test]
+    fn test_leader_leaving_elects_another() {
+        let mut members = [Some(10), Some(11), None, Some(13)];
+        let mut bursting = [false; 4];
+        // The leader in slot 0 leaves; the next member takes over.
+        assert_eq!(elect_leader(&members, &bursting, 0), Some(1));
+        members[0] = None;
+        // That one leaves right after, before anyone else has joined.
+        assert_eq!(elect_leader(&members, &bursting, 1), Some(3));
+        members[1] = None;
+        // The last member leaving leaves nobody to lead.
+        assert_eq!(elect_leader(&members, &bursting, 3), None);
+
+        // Someone still loading in is only picked if there's no one else.
+        members = [Some(10), Some(11), Some(12), None];
+        bursting[1] = true;
+        assert_eq!(elect_leader(&members, &bursting, 0), Some(2));
+        members[2] = None;
+        assert_eq!(elect_leader(&members, &bursting, 0), Some(1));
+    }
+
+    #[test]
+    fn test_leader_notice() {
+        match leader_notice("\tERico") {
+            Message::BbChat(_, BbChat(0, text)) => assert_eq!(text, "\tERico is now the party leader."),
+            m => panic!("unexpected notice {:?}", m)
+        }
+    }
+
+    #[test]
+    fn test_leader_leaving_party() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (b, _sg_rx) = test_block(&event_loop, BlockOptions::default());
+        for id in 1..4 {
+            b.clients.borrow_mut().insert(id, playing(42000000 + id as u32));
+        }
+        let mut p = party();
+        let mut h = b.make_handler(1);
+        assert_eq!(p.remove_player(&mut h, 1), Ok(false));
+        assert_eq!(p.leader_id, 1);
+        assert_eq!(p.members, [None, Some(2), Some(3), None]);
+
+        // Everyone left hears who went and who leads now; the leaver
+        // doesn't.
+        let msgs = sent(&mut event_loop);
+        for client in 2..4 {
+            let leaves: Vec<(u8, u8)> = msgs.iter().filter_map(|m| match m {
+                &LoopMsg::Client(id, NetMsg::Bb(Message::BbGameLeave(_, ref gl))) if id == client => Some((gl.client_id, gl.leader_id)),
+                _ => None
+            }).collect();
+            assert_eq!(leaves, vec![(0, 1)]);
+        }
+        assert!(!msgs.iter().any(|m| match m {
+            &LoopMsg::Client(1, _) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn test_meseta_past_cap_left_on_floor() {
+        let event_loop = EventLoop::<Collect>::new().unwrap();
+        let mut options = BlockOptions::default();
+        options.max_meseta = 1000;
+        let (b, _sg_rx) = test_block(&event_loop, options);
+        let c = playing(42000001);
+        c.borrow_mut().full_char.as_mut().unwrap().chara.meseta = 800;
+        b.clients.borrow_mut().insert(1, c.clone());
+
+        let mut p = party();
+        let mut fc = BbFullCharData::default();
+        fc.chara.meseta = 300;
+        p.floor_items.push(FloorItem {
+            area: 2,
+            x: 0.0,
+            z: 0.0,
+            item: take_stack(&mut fc, 0xFFFFFFFF, 300, 0x00810000).unwrap(),
+            dropped_at: 0
+        });
+        let mut h = b.make_handler(1);
+        p.handle_bb_pick_up(&mut h, 0, Bb62PickUp { item_id: 0x00810000, area: 2 });
+        assert_eq!(c.borrow().full_char.as_ref().unwrap().chara.meseta, 800);
+        assert_eq!(p.floor_items.len(), 1);
+
+        // With room for it, it's picked up whole.
+        c.borrow_mut().full_char.as_mut().unwrap().chara.meseta = 700;
+        p.handle_bb_pick_up(&mut h, 0, Bb62PickUp { item_id: 0x00810000, area: 2 });
+        assert_eq!(c.borrow().full_char.as_ref().unwrap().chara.meseta, 1000);
+        assert!(p.floor_items.is_empty());
+    }
+}
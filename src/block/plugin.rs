@@ -0,0 +1,39 @@
+//! In-process extension points for blocks, so a server can add its own
+//! behavior without changing core dispatch.
+//!
+//! Plugins are plain trait objects, registered in `registered` below and
+//! handed to each block when it is spawned. They live on the block's thread
+//! for as long as the block runs. For every message a client sends, the block:
+//!
+//! 1. Offers it to each plugin's `on_message`, in registration order. The
+//!    first plugin to return `true` consumes it; later plugins and the
+//!    block's own handling never see it.
+//! 2. Otherwise runs the block's built-in handler for it.
+//! 3. If the block has no handler for that message, passes it to every
+//!    plugin's `on_unhandled`.
+//!
+//! The `BlockHandler` a plugin gets is for the client that sent the message,
+//! so `handler.client_id` is the sender, and `send_to_client` and friends
+//! reply to it or anyone else on the block.
+
+use psomsg::bb::Message;
+
+use super::handler::BlockHandler;
+
+pub trait BlockPlugin {
+    /// Shown in the log when the plugin is loaded.
+    fn name(&self) -> &str;
+
+    /// Called before the block handles `msg`. Return `true` to consume it.
+    fn on_message(&mut self, _handler: &mut BlockHandler, _msg: &Message) -> bool {
+        false
+    }
+
+    /// Called with messages the block has no handler for.
+    fn on_unhandled(&mut self, _handler: &mut BlockHandler, _msg: &Message) {}
+}
+
+/// The plugins to load for block `block_num`. Add your own here.
+pub fn registered(_block_num: u16) -> Vec<Box<BlockPlugin + Send>> {
+    Vec::new()
+}
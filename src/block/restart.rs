@@ -0,0 +1,79 @@
+//! A restart a GM has scheduled for the block. Players are warned as it gets
+//! close, then the block drains.
+
+/// What the block should do about a scheduled restart after a tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartDue {
+    Nothing,
+    /// Tell players it's this many minutes away.
+    Warn(u32),
+    Now
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledRestart {
+    /// `time::precise_time_ns` at which to drain.
+    at: u64,
+    /// Minutes of the last warning given. Only shorter ones go out after it.
+    warned: u32
+}
+
+impl ScheduledRestart {
+    /// A restart `minutes` from `now`. Scheduling it counts as the first
+    /// warning.
+    pub fn new(now: u64, minutes: u32) -> ScheduledRestart {
+        ScheduledRestart {
+            at: now + minutes as u64 * 60_000_000_000,
+            warned: minutes
+        }
+    }
+
+    /// Whole minutes left at `now`, rounded up.
+    pub fn minutes_left(&self, now: u64) -> u32 {
+        let ns = self.at.saturating_sub(now);
+        ((ns + 59_999_999_999) / 60_000_000_000) as u32
+    }
+
+    /// Check on the restart at `now`, given the minutes before it at which
+    /// players should be warned. Each warning goes out once; if several
+    /// are due together, only the closest does.
+    pub fn tick(&mut self, now: u64, warnings: &[u8]) -> RestartDue {
+        if now >= self.at {
+            return RestartDue::Now
+        }
+        let left = self.at - now;
+        let due = warnings.iter()
+            .map(|w| *w as u32)
+            .filter(|w| *w < self.warned && left <= *w as u64 * 60_000_000_000)
+            .min();
+        match due {
+            Some(w) => {
+                self.warned = w;
+                RestartDue::Warn(w)
+            },
+            None => RestartDue::Nothing
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ScheduledRestart, RestartDue};
+
+    const MIN: u64 = 60_000_000_000;
+
+    #[test]
+    fn test_warns_on_the_way_down() {
+        let warnings = [30, 15, 5, 1];
+        let mut r = ScheduledRestart::new(0, 20);
+        assert_eq!(r.minutes_left(0), 20);
+        assert_eq!(r.tick(MIN, &warnings), RestartDue::Nothing);
+        assert_eq!(r.tick(5 * MIN, &warnings), RestartDue::Warn(15));
+        assert_eq!(r.tick(6 * MIN, &warnings), RestartDue::Nothing);
+        // Both 5 and 1 minute warnings were missed; only the later goes out.
+        assert_eq!(r.tick(19 * MIN + 1, &warnings), RestartDue::Warn(1));
+        assert_eq!(r.tick(19 * MIN + 2, &warnings), RestartDue::Nothing);
+        assert_eq!(r.minutes_left(19 * MIN + 2), 1);
+        assert_eq!(r.tick(20 * MIN, &warnings), RestartDue::Now);
+    }
+}
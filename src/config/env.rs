@@ -0,0 +1,92 @@
+//! Expansion of environment variables in config strings, so secrets and
+//! paths can be left out of the committed config.
+
+use std::env;
+
+use toml::{Table, Value};
+
+/// Expand `${VAR}` and `${VAR:-default}` in every string in the table,
+/// including those in nested tables and arrays.
+pub fn expand_table(t: &mut Table) -> Result<(), String> {
+    for (_, v) in t.iter_mut() {
+        try!(expand_value(v));
+    }
+    Ok(())
+}
+
+fn expand_value(v: &mut Value) -> Result<(), String> {
+    match v {
+        &mut Value::String(ref mut s) => {
+            if s.contains("${") {
+                *s = try!(expand_str(s, |k| env::var(k).ok()));
+            }
+        },
+        &mut Value::Array(ref mut a) => for v in a.iter_mut() {
+            try!(expand_value(v));
+        },
+        &mut Value::Table(ref mut t) => try!(expand_table(t)),
+        _ => ()
+    }
+    Ok(())
+}
+
+/// Expand the variables in `s`, looking them up with `lookup`. A variable
+/// that isn't set and has no default is an error. A `$` that doesn't start
+/// `${` is left alone.
+fn expand_str<F: Fn(&str) -> Option<String>>(s: &str, lookup: F) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            Some(e) => e,
+            None => return Err(format!("unterminated ${{ in config value \"{}\"", s))
+        };
+        let inner = &after[..end];
+        let (name, default) = match inner.find(":-") {
+            Some(i) => (&inner[..i], Some(&inner[i + 2..])),
+            None => (inner, None)
+        };
+        match (lookup(name), default) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(d)) => out.push_str(d),
+            (None, None) => return Err(format!("environment variable {} is not set", name))
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_str;
+
+    fn lookup(k: &str) -> Option<String> {
+        match k {
+            "FOO" => Some("foo".to_string()),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn test_expand_set() {
+        assert_eq!(expand_str("${FOO}", lookup), Ok("foo".to_string()));
+        assert_eq!(expand_str("a/${FOO}/b", lookup), Ok("a/foo/b".to_string()));
+        assert_eq!(expand_str("${FOO:-bar}", lookup), Ok("foo".to_string()));
+        assert_eq!(expand_str("$FOO", lookup), Ok("$FOO".to_string()));
+    }
+
+    #[test]
+    fn test_expand_default() {
+        assert_eq!(expand_str("${UNSET:-fallback}", lookup), Ok("fallback".to_string()));
+        assert_eq!(expand_str("${UNSET:-}", lookup), Ok("".to_string()));
+    }
+
+    #[test]
+    fn test_expand_unset() {
+        assert_eq!(expand_str("${UNSET}", lookup), Err("environment variable UNSET is not set".to_string()));
+        assert!(expand_str("${FOO", lookup).is_err());
+    }
+}
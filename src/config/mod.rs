@@ -1,65 +1,450 @@
+use std::fmt;
 use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use toml::{Parser, Table};
+use toml::{Parser, Table, Value};
+
+use log::LogLevelFilter;
 
 use psodb_common::pool::Pool;
 use psodb_common::Result as DbResult;
 use psodb_sqlite::Sqlite;
+use psodb_mysql::MySql;
+
+use ::game::{Version, CharClass, CHAR_CLASSES};
+use ::holidays::{self, Holiday, HolidayDates};
+use ::block::lobbyhandler::event::Event;
 
-use ::game::Version;
+pub mod env;
+pub mod schema;
+pub mod write;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     pub data_path: String,
     pub bb_keytable_path: String,
-    pub shipgate_addr: SocketAddr,
+    /// Per-item tool stack limits. The file doesn't have to exist.
+    pub stack_limits_path: String,
+    pub shipgate_addr: ServiceAddr,
     pub shipgate_password: String,
+    /// Where to write JSON line connection events: a file path or "stdout".
+    pub event_log: Option<String>,
+    /// Seconds a BB client has from connecting to finish logging in. 0
+    /// means no limit.
+    pub handshake_timeout: u32,
+    /// Drop BB clients whose first packet doesn't decrypt to a login as having
+    /// the wrong key table, rather than as a malformed packet.
+    pub reject_key_mismatch: bool,
+    /// Milliseconds a BB client has after the welcome to send its login.
+    /// Clients that don't, or that send anything else first, are dropped. 0
+    /// turns this off.
+    pub first_packet_window_ms: u32,
+    /// Seconds to wait for the shipgate at startup before giving up. 0 waits
+    /// forever.
+    pub shipgate_timeout: u32,
+    /// Megabytes of memory over which new clients are turned away. 0 means
+    /// no limit.
+    pub memory_limit_mb: u32,
+    /// Holidays whose event blocks switch to by themselves. Empty unless
+    /// turned on.
+    pub holidays: Vec<Holiday>,
+    /// How to check the system clock at startup.
+    pub clock_check: ClockCheckConf,
     pub services: Vec<ServiceConf>
 }
 
-#[derive(Debug, Clone)]
+/// Startup check of the system clock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockCheckConf {
+    /// NTP server to compare against, as a host or host:port. Without one,
+    /// the clock is only checked for being plausible at all.
+    pub ntp_server: Option<String>,
+    /// Seconds the clock may be off before it's a problem.
+    pub max_skew: u32,
+    /// Refuse to start on a bad clock, rather than warning.
+    pub fatal: bool
+}
+
+/// Where a service listens, or where the shipgate is reached: a TCP address,
+/// or with `unix:` in front, the path of a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServiceConf {
     Patch {
-        bind: SocketAddr,
+        bind: ServiceAddr,
         motd: String,
         v4_servers: Vec<SocketAddrV4>,
-        random_balance: bool
+        random_balance: bool,
+        /// Per-version overrides of `motd` and `v4_servers`.
+        versions: Vec<PatchVersionConf>,
+        /// News items shown below the MOTD, one at a time.
+        news: Vec<NewsItem>,
+        /// Seconds each news item stays up. 0 moves to the next item on every
+        /// connection.
+        news_interval: u32,
+        throttle: Option<ThrottleConf>,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     },
     Data {
-        bind: SocketAddr
+        bind: ServiceAddr,
+        /// The directory of files kept up to date on clients, relative to
+        /// `data_path` unless it's absolute. Without it there are none.
+        files_path: Option<String>,
+        throttle: Option<ThrottleConf>,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     },
     Login {
-        bind: SocketAddr,
+        bind: ServiceAddr,
         version: Version,
-        addr: SocketAddrV4,
+        /// Where clients are sent for the character step. Blue Burst
+        /// redirects only carry IPv4, so for it this is always V4.
+        addr: SocketAddr,
+        throttle: Option<ThrottleConf>,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// Rules players must accept before they can pick a ship.
+        rules: Option<RulesConf>,
+        /// Classes players can't create, or with `existing`, play.
+        class_restrictions: Option<ClassRestrictions>,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     },
     Ship {
-        bind: SocketAddr,
+        bind: ServiceAddr,
         name: String,
-        my_ipv4: SocketAddrV4,
-        blocks: Vec<BlockConf>
+        /// Address clients are sent to for this ship. Always V4, since ships
+        /// register with the shipgate by IPv4 address.
+        addr: SocketAddr,
+        blocks: Vec<BlockConf>,
+        /// Most blocks the ship may list.
+        max_blocks: u32,
+        /// Default event for this ship's blocks.
+        event: Option<u16>,
+        /// Drain a block's players to the others at random instead of in
+        /// turn.
+        random_balance: bool,
+        throttle: Option<ThrottleConf>,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     },
     Block {
-        bind: SocketAddr,
+        bind: ServiceAddr,
         num: u16,
-        event: u16
+        /// How many lobbies the block has, up to the client's 15.
+        lobbies: u8,
+        /// The effective event: the block's own if set, otherwise its ship's,
+        /// otherwise 0.
+        event: u16,
+        /// The block set its own event, so ship-wide changes don't apply.
+        event_override: bool,
+        /// The name of the ship listing this block, if any.
+        ship: Option<String>,
+        /// The other blocks on that ship, where players go when this block
+        /// is drained or they ask to move.
+        siblings: Vec<BlockConf>,
+        /// The ship's `random_balance`, for picking among `siblings`.
+        random_balance: bool,
+        options: BlockOptions,
+        throttle: Option<ThrottleConf>,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
+    },
+    /// Sends every Blue Burst client straight on to `target`, for fronting
+    /// another server or moving players off an address being retired.
+    Proxy {
+        bind: ServiceAddr,
+        /// Always V4; Blue Burst redirects only carry IPv4.
+        target: SocketAddr,
+        /// Most connections at once from one IP address. 0 means no limit.
+        max_per_ip: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     },
     ShipGate {
-        bind: SocketAddr,
+        bind: ServiceAddr,
         password: String,
-        db: DbConf
+        db: DbConf,
+        /// Most items an account may store across all its characters'
+        /// inventories and banks and its shared bank. 0 means no limit.
+        storage_quota: u32,
+        /// Most entries an account's shared bank may hold.
+        shared_bank_slots: u32,
+        /// No two characters may share a name.
+        unique_names: bool,
+        /// Seconds to collect play time updates and chat log lines for
+        /// before saving them together. 0 saves each as it comes.
+        batch_interval: u32,
+        /// Save collected updates early once this many are waiting.
+        batch_size: u32,
+        /// Local hour, 0 to 23, during which to compact the database each
+        /// day. None turns maintenance off.
+        maintenance_hour: Option<u8>,
+        /// Maintenance waits for a minute with no more requests than this.
+        maintenance_max_requests: u32,
+        /// This service's log level, if not the global one.
+        log_level: Option<LogLevelFilter>
     }
     // ...
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DbConf {
     Sqlite {
-        file: String
+        file: String,
+        /// Connections in the pool.
+        pool_size: usize,
+        /// Check the connection before each use and reopen it if it was lost.
+        reconnect: bool
+    },
+    MySql {
+        host: String,
+        port: u16,
+        user: String,
+        password: String,
+        database: String,
+        /// Connections in the pool.
+        pool_size: usize,
+        /// Check connections before each use and reopen any that were lost.
+        reconnect: bool
+    }
+}
+
+/// Patch settings for one client version. Unset fields fall back to the
+/// patch service's own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchVersionConf {
+    pub version: Version,
+    pub motd: Option<String>,
+    pub v4_servers: Option<Vec<SocketAddrV4>>
+}
+
+/// One news item in the patch service's rotation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewsItem {
+    pub text: String,
+    /// How many turns the item gets in each pass through the list.
+    pub weight: u32
+}
+
+/// Outbound bandwidth limit for each client of a service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleConf {
+    /// Bytes per second.
+    pub rate: u32,
+    /// Messages smaller than this many bytes are never delayed.
+    pub min_size: usize
+}
+
+/// The config file format this build reads. Bump it when a change would make
+/// an older file mean something different, so it's refused instead.
+pub const CONFIG_VERSION: i64 = 1;
+/// Default per-account storage quota. Four characters with full inventories
+/// and banks fit, along with a full shared bank.
+pub const DEFAULT_STORAGE_QUOTA: u32 = 1200;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u32 = 30;
+pub const DEFAULT_SHIPGATE_TIMEOUT: u32 = 60;
+pub const DEFAULT_SHARED_BANK_SLOTS: u32 = 200;
+pub const DEFAULT_BATCH_SIZE: u32 = 500;
+pub const DEFAULT_MAINTENANCE_MAX_REQUESTS: u32 = 30;
+pub const DEFAULT_CLOCK_MAX_SKEW: u32 = 60;
+pub const DEFAULT_LOBBIES: u8 = 15;
+/// Blocks a ship lists. Past this the block select gets unwieldy, and each
+/// one is a service to run.
+pub const DEFAULT_MAX_BLOCKS: u32 = 20;
+/// Connections a client-facing service takes at once from one address. High
+/// enough for a household or a LAN party behind one NAT.
+pub const DEFAULT_MAX_PER_IP: u32 = 16;
+/// The lobby minigame subcommands: the soccer ball in lobbies 14 and 15.
+pub const LOBBY_MINIGAME_SUBCMDS: &'static [u8] = &[0x79];
+/// The most meseta the client can show a character holding.
+pub const MAX_MESETA: u32 = 999999;
+/// The highest level the client knows.
+pub const MAX_LEVEL: u32 = 200;
+/// Minutes before a scheduled restart at which players are warned.
+pub const DEFAULT_RESTART_WARNINGS: &'static [u8] = &[30, 15, 5, 1];
+
+/// What a block does about players moving between areas in ways the map
+/// doesn't allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaValidation {
+    /// Don't check.
+    Off,
+    /// Log it and let the move through.
+    Log,
+    /// Log it, count it as suspicious and don't tell the rest of the game.
+    Enforce
+}
+
+/// Gameplay tunables for a block service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockOptions {
+    /// Seconds to hold a dropped player's lobby or party slot for them. 0
+    /// disables this.
+    pub reconnect_grace: u32,
+    /// Warn when handling a single client message takes longer than this
+    /// many milliseconds. 0 disables this.
+    pub slow_handler_ms: u32,
+    /// Ping a player who has sent nothing for this many seconds, and drop
+    /// them if they don't answer. 0 disables this.
+    pub idle_timeout: u32,
+    /// Most players logged in on the block at once; more are turned away at
+    /// login. 0 means no limit.
+    pub max_players: u32,
+    /// While fewer than this many players are on the block, new arrivals go
+    /// to the busiest lobby instead of the first free one. 0 disables this.
+    pub merge_below: u32,
+    /// While merging, move one player who didn't pick their lobby into the
+    /// busiest lobby every this many seconds. 0 disables moving players.
+    pub merge_migrate_interval: u32,
+    /// Inventory slots a player may fill by picking items up, at most 30.
+    pub inventory_slots: u32,
+    /// Most tools of one kind that stack in a single inventory slot.
+    pub tool_stack_limit: u32,
+    /// Rested experience earned per minute spent in a lobby. 0 disables
+    /// rested experience.
+    pub rest_exp_per_minute: u32,
+    /// Most rested experience a player can bank.
+    pub rest_exp_cap: u32,
+    /// Lobby changes a player may make in `lobby_change_window` seconds.
+    /// Changes past that are ignored. 0 disables the limit.
+    pub lobby_change_limit: u32,
+    pub lobby_change_window: u32,
+    /// Disconnect a player once this many lobby changes in a row have been
+    /// ignored. 0 never disconnects.
+    pub lobby_change_kick: u32,
+    /// Chat messages a player may send in `chat_window` seconds. Messages
+    /// past that are dropped. 0 disables the limit.
+    pub chat_limit: u32,
+    pub chat_window: u32,
+    /// Send lobby chat and whispers to the shipgate to be kept in the
+    /// database's chat log.
+    pub chat_log: bool,
+    /// Keep a running total of each account's and character's play time.
+    pub track_playtime: bool,
+    /// Send play time to the shipgate every this many seconds, so little is
+    /// lost if the server goes down. 0 only sends it on disconnect.
+    pub playtime_checkpoint: u32,
+    /// Let players use lobby minigames. Turns them off in every lobby when
+    /// false.
+    pub lobby_minigames: bool,
+    /// Lobbies, numbered from 1, where lobby minigames are off regardless.
+    pub minigame_free_lobbies: Vec<u8>,
+    /// The 0x60 subcommands that count as lobby minigames.
+    pub lobby_minigame_subcmds: Vec<u8>,
+    /// Disconnect a player once failed validation checks add up to this
+    /// many suspicion points in one session. 0, the default, never
+    /// disconnects.
+    pub suspicion_kick: u32,
+    /// Ban the account as well when disconnecting for suspicion.
+    pub suspicion_ban: bool,
+    /// Guild card numbers of players allowed to use GM commands.
+    pub gm_guildcards: Vec<u32>,
+    /// Refuse to equip or sell weapons that haven't been identified.
+    pub enforce_identification: bool,
+    /// Save the character of a player who drops without logging out, as
+    /// long as nothing of theirs is half done.
+    pub save_on_drop: bool,
+    /// Leading bytes of items nobody may pick up, withdraw or equip: one
+    /// item's type, subtype and index, or a type and subtype for the lot.
+    pub banned_items: Vec<Vec<u8>>,
+    /// Take banned items out of characters and banks as they load.
+    pub strip_banned_items: bool,
+    /// Seconds an item lies on a game's floor before it disappears. 0 keeps
+    /// items until the game ends.
+    pub floor_item_lifetime: u32,
+    /// Minutes before a scheduled restart at which players are warned.
+    pub restart_warnings: Vec<u8>,
+    /// Most meseta a character may carry.
+    pub max_meseta: u32,
+    /// Level past which characters stop gaining experience.
+    pub max_level: u32,
+    /// Checking of area changes in games against the map.
+    pub area_validation: AreaValidation
+}
+
+impl Default for BlockOptions {
+    fn default() -> BlockOptions {
+        BlockOptions {
+            reconnect_grace: 0,
+            slow_handler_ms: 100,
+            idle_timeout: 600,
+            max_players: 0,
+            merge_below: 0,
+            merge_migrate_interval: 0,
+            inventory_slots: 30,
+            tool_stack_limit: 10,
+            rest_exp_per_minute: 0,
+            rest_exp_cap: 10000,
+            lobby_change_limit: 5,
+            lobby_change_window: 10,
+            lobby_change_kick: 0,
+            chat_limit: 8,
+            chat_window: 10,
+            chat_log: false,
+            track_playtime: true,
+            playtime_checkpoint: 300,
+            lobby_minigames: true,
+            minigame_free_lobbies: Vec::new(),
+            lobby_minigame_subcmds: LOBBY_MINIGAME_SUBCMDS.to_vec(),
+            suspicion_kick: 0,
+            suspicion_ban: false,
+            gm_guildcards: Vec::new(),
+            enforce_identification: true,
+            save_on_drop: true,
+            banned_items: Vec::new(),
+            strip_banned_items: false,
+            floor_item_lifetime: 3600,
+            restart_warnings: DEFAULT_RESTART_WARNINGS.to_vec(),
+            max_meseta: MAX_MESETA,
+            max_level: MAX_LEVEL,
+            area_validation: AreaValidation::Log
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Server rules shown to players until they accept them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RulesConf {
+    pub text: String,
+    /// Raise this when the rules change to have everyone accept them again.
+    pub version: u32
+}
+
+/// Classes turned off on this server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassRestrictions {
+    pub disallowed: Vec<CharClass>,
+    /// Existing characters of those classes can't be played either.
+    pub existing: bool
+}
+
+impl ClassRestrictions {
+    pub fn allows(&self, class: u8) -> bool {
+        match CharClass::from_u8(class) {
+            Some(c) => !self.disallowed.contains(&c),
+            None => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockConf {
     pub name: String,
     pub addr: SocketAddrV4
@@ -68,9 +453,16 @@ pub struct BlockConf {
 impl DbConf {
     pub fn make_pool(&self) -> DbResult<Pool> {
         match self {
-            &DbConf::Sqlite { ref file } => {
-                let mut s = try!(Sqlite::new(file.as_ref(), true));
-                let p = try!(Pool::new(1, &mut s));
+            &DbConf::Sqlite { ref file, pool_size, reconnect } => {
+                let mut s = try!(Sqlite::new(file.as_ref()));
+                let mut p = try!(Pool::new(pool_size, &mut s));
+                p.set_reconnect(reconnect);
+                Ok(p)
+            },
+            &DbConf::MySql { ref host, port, ref user, ref password, ref database, pool_size, reconnect } => {
+                let mut m = try!(MySql::new(host, port, user, password, database));
+                let mut p = try!(Pool::new(pool_size, &mut m));
+                p.set_reconnect(reconnect);
                 Ok(p)
             }
         }
@@ -78,9 +470,41 @@ impl DbConf {
 }
 
 impl Config {
+    /// The commented config in data/default, for starting a new one from. It
+    /// runs one of each service on 127.0.0.1, with placeholder passwords.
+    pub fn default_example() -> String {
+        include_str!("../../data/default/idola_local.toml").to_string()
+    }
+
+    /// Each service log target with its own level. When services of the
+    /// same kind ask for different levels, the most verbose one is used,
+    /// since they log under the same target.
+    pub fn log_filters(&self) -> Vec<(String, LogLevelFilter)> {
+        let mut filters: Vec<(String, LogLevelFilter)> = Vec::new();
+        for s in self.services.iter() {
+            let level = match s.log_level() {
+                Some(l) => l,
+                None => continue
+            };
+            let target = s.log_target();
+            match filters.iter_mut().find(|f| f.0 == target) {
+                Some(f) => {
+                    if level > f.1 {
+                        f.1 = level;
+                    }
+                    continue
+                },
+                None => ()
+            }
+            filters.push((target, level));
+        }
+        filters
+    }
+
     pub fn from_toml_string(s: &str) -> Result<Config, String> {
         let mut parser = Parser::new(s);
-        if let Some(value) = parser.parse() {
+        if let Some(mut value) = parser.parse() {
+            try!(env::expand_table(&mut value));
             Config::from_toml_value(&value)
         } else {
             let errors: Vec<String> = parser.errors.into_iter().map(|e| format!("{}", e)).collect();
@@ -91,9 +515,27 @@ impl Config {
     pub fn from_toml_value(t: &Table) -> Result<Config, String> {
         let data_path;
         let bb_keytable_path;
+        let stack_limits_path;
         let shipgate_addr;
         let shipgate_password;
+        let event_log;
+        let handshake_timeout;
+        let reject_key_mismatch;
+        let first_packet_window_ms;
+        let shipgate_timeout;
+        let memory_limit_mb;
+        let clock_check;
         if let Some(i) = t.get("idola") {
+            let version = match i.lookup("version").map(|v| v.as_integer()) {
+                Some(Some(v)) => v,
+                Some(None) => return Err("idola version must be a number".to_string()),
+                None => 1
+            };
+            if version != CONFIG_VERSION {
+                return Err(format!("config file is version {}, but this IDOLA expects version {}; \
+                                    see data/default/idola_local.toml for the current format",
+                                   version, CONFIG_VERSION));
+            }
             data_path = i.lookup("data_path")
                 .and_then(|v| v.as_str())
                 .map(|s| s.to_string())
@@ -102,10 +544,13 @@ impl Config {
                 .and_then(|v| v.as_str())
                 .map(|s| s.to_string())
                 .unwrap_or(format!("{}/crypto/bb_table.bin", data_path));
+            stack_limits_path = i.lookup("stack_limits_path")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .unwrap_or(format!("{}/stack_limits.toml", data_path));
             shipgate_addr = match i.lookup("shipgate_addr")
                 .and_then(|v| v.as_str())
-                .and_then(|s| s.to_socket_addrs().ok())
-                .and_then(|mut s| s.next()) {
+                .and_then(|s| ServiceAddr::parse(s).ok()) {
                     Some(v) => v,
                     None => return Err("Shipgate address not specified or malformed".to_string())
                 };
@@ -115,35 +560,204 @@ impl Config {
                     Some(v) => v,
                     None => return Err("Shipgate password is not specified.".to_string())
                 };
+            event_log = match i.lookup("event_log") {
+                Some(v) => match v.as_str() {
+                    Some(s) => Some(s.to_string()),
+                    None => return Err("event_log must be a file path or \"stdout\"".to_string())
+                },
+                None => None
+            };
+            handshake_timeout = match i.lookup("handshake_timeout").map(|v| v.as_integer()) {
+                Some(Some(v)) if v >= 0 => v as u32,
+                Some(_) => return Err("handshake_timeout must be a non-negative number of seconds".to_string()),
+                None => DEFAULT_HANDSHAKE_TIMEOUT
+            };
+            reject_key_mismatch = match i.lookup("reject_key_mismatch") {
+                Some(v) => match v.as_bool() {
+                    Some(b) => b,
+                    None => return Err("reject_key_mismatch must be true or false".to_string())
+                },
+                None => true
+            };
+            first_packet_window_ms = match i.lookup("first_packet_window_ms").map(|v| v.as_integer()) {
+                Some(Some(v)) if v >= 0 => v as u32,
+                Some(_) => return Err("first_packet_window_ms must be a non-negative number of milliseconds".to_string()),
+                None => 0
+            };
+            shipgate_timeout = match i.lookup("shipgate_timeout").map(|v| v.as_integer()) {
+                Some(Some(v)) if v >= 0 => v as u32,
+                Some(_) => return Err("shipgate_timeout must be a non-negative number of seconds".to_string()),
+                None => DEFAULT_SHIPGATE_TIMEOUT
+            };
+            memory_limit_mb = match i.lookup("memory_limit_mb").map(|v| v.as_integer()) {
+                Some(Some(v)) if v >= 0 => v as u32,
+                Some(_) => return Err("memory_limit_mb must be a non-negative number of megabytes".to_string()),
+                None => 0
+            };
+            clock_check = ClockCheckConf {
+                ntp_server: match i.lookup("clock_ntp_server") {
+                    Some(v) => match v.as_str() {
+                        Some(s) => Some(s.to_string()),
+                        None => return Err("clock_ntp_server must be a host name or host:port".to_string())
+                    },
+                    None => None
+                },
+                max_skew: match i.lookup("clock_max_skew").map(|v| v.as_integer()) {
+                    Some(Some(v)) if v >= 0 => v as u32,
+                    Some(_) => return Err("clock_max_skew must be a non-negative number of seconds".to_string()),
+                    None => DEFAULT_CLOCK_MAX_SKEW
+                },
+                fatal: match i.lookup("clock_skew_fatal") {
+                    Some(v) => match v.as_bool() {
+                        Some(b) => b,
+                        None => return Err("clock_skew_fatal must be true or false".to_string())
+                    },
+                    None => false
+                }
+            };
         } else {
             return Err("No idola section".to_string())
         }
+        let holidays = match t.get("holidays") {
+            Some(v) => match v.as_table() {
+                Some(h) => try!(holidays_from_toml_table(h)),
+                None => return Err("holidays must be a table".to_string())
+            },
+            None => Vec::new()
+        };
         let mut services = Vec::new();
         if let Some(s_slice) = t.get("service").and_then(|v| v.as_slice()) {
             for s in s_slice {
                 match s.as_table() {
-                    Some(stab) => services.push(try!(ServiceConf::from_toml_table(stab))),
+                    Some(stab) => services.push(try!(ServiceConf::from_toml_table(stab, &data_path))),
                     None => return Err("a configured service is not a TOML table".to_string())
                 }
             }
         }
+        resolve_block_events(&mut services);
+        try!(check_bind_conflicts(&services));
         Ok(Config {
             data_path: data_path,
             bb_keytable_path: bb_keytable_path,
+            stack_limits_path: stack_limits_path,
             services: services,
             shipgate_addr: shipgate_addr,
-            shipgate_password: shipgate_password
+            shipgate_password: shipgate_password,
+            event_log: event_log,
+            handshake_timeout: handshake_timeout,
+            reject_key_mismatch: reject_key_mismatch,
+            first_packet_window_ms: first_packet_window_ms,
+            shipgate_timeout: shipgate_timeout,
+            memory_limit_mb: memory_limit_mb,
+            holidays: holidays,
+            clock_check: clock_check
         })
     }
 }
 
+/// Read the `[holidays]` table: `enabled` turns the built-in calendar on, and
+/// each other key either turns a holiday off with `false` or overrides its
+/// `event` and `start`/`end` dates. Keys that aren't built-in holidays add
+/// new ones.
+fn holidays_from_toml_table(t: &Table) -> Result<Vec<Holiday>, String> {
+    match t.get("enabled").map(|v| v.as_bool()) {
+        Some(Some(true)) => (),
+        Some(Some(false)) | None => return Ok(Vec::new()),
+        Some(None) => return Err("holidays enabled must be true or false".to_string())
+    }
+    let mut list = holidays::defaults();
+    for (name, v) in t.iter() {
+        if name == "enabled" {
+            continue
+        }
+        if let Some(on) = v.as_bool() {
+            if !on {
+                list.retain(|h| h.name != *name);
+            }
+            continue
+        }
+        let o = match v.as_table() {
+            Some(o) => o,
+            None => return Err(format!("holiday {} must be false or a table", name))
+        };
+        let event = match o.get("event").map(|v| v.as_integer()) {
+            Some(Some(e)) if e >= 0 && e <= ::std::u16::MAX as i64 => Some(e as u16),
+            Some(_) => return Err(format!("holiday {} event must be an event number", name)),
+            None => None
+        };
+        let date = |key: &str| -> Result<Option<(u8, u8)>, String> {
+            match o.get(key).map(|v| v.as_str().and_then(holidays::parse_month_day)) {
+                Some(Some(d)) => Ok(Some(d)),
+                Some(None) => Err(format!("holiday {} {} must be a \"MM-DD\" date", name, key)),
+                None => Ok(None)
+            }
+        };
+        let dates = match (try!(date("start")), try!(date("end"))) {
+            (Some(s), Some(e)) => Some(HolidayDates::Fixed(s, e)),
+            (None, None) => None,
+            _ => return Err(format!("holiday {} needs both start and end dates", name))
+        };
+        match list.iter_mut().find(|h| h.name == *name) {
+            Some(h) => {
+                if let Some(e) = event {
+                    h.event = e;
+                }
+                if let Some(d) = dates {
+                    h.dates = d;
+                }
+                continue
+            },
+            None => ()
+        }
+        match (event, dates) {
+            (Some(e), Some(d)) => list.push(Holiday::new(name, e, d)),
+            _ => return Err(format!("holiday {} isn't built in, so it needs an event and start and end dates", name))
+        }
+    }
+    Ok(list)
+}
+
+/// Read a file named in the config, relative to `data_path` unless it's an
+/// absolute path.
+fn read_data_file(data_path: &str, name: &str) -> Result<String, String> {
+    let path = Path::new(data_path).join(name);
+    let mut s = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => Ok(s),
+        Err(e) => Err(format!("can't read {}: {}", path.display(), e))
+    }
+}
+
 impl ServiceConf {
-    pub fn from_toml_table(t: &Table) -> Result<ServiceConf, String> {
-        if let Some(bind) = t.get("bind").and_then(|v| v.as_str()).and_then(|s| s.to_socket_addrs().ok()).and_then(|mut s| s.next()) {
+    /// Files the service names are read relative to `data_path`.
+    pub fn from_toml_table(t: &Table, data_path: &str) -> Result<ServiceConf, String> {
+        if let Some(bind) = t.get("bind").and_then(|v| v.as_str()).and_then(|s| ServiceAddr::parse(s).ok()) {
+            let throttle = match t.get("throttle").and_then(|v| v.as_table()).map(|v| ThrottleConf::from_toml_table(v)) {
+                Some(Ok(th)) => Some(th),
+                Some(Err(e)) => return Err(e),
+                None => None
+            };
+            let max_per_ip = match t.get("max_per_ip").map(|v| v.as_integer()) {
+                Some(Some(v)) if v >= 0 => v as u32,
+                Some(_) => return Err("service max_per_ip must be a non-negative number of connections".to_string()),
+                None => DEFAULT_MAX_PER_IP
+            };
+            let log_level = match t.get("log_level").map(|v| v.as_str()) {
+                Some(Some(l)) => Some(try!(parse_log_level(l).map_err(|e| format!("service log_level: {}", e)))),
+                Some(None) => return Err(format!("service log_level must be one of {}", LOG_LEVELS.join(", "))),
+                None => None
+            };
             if let Some(ty) = t.get("type").and_then(|v| v.as_str()) {
                 match ty {
                     "patch" => {
-                        let motd = t.get("motd").and_then(|v| v.as_str()).map(|s| s.to_string()).unwrap_or_default();
+                        let motd = match (t.get("motd"), t.get("motd_file")) {
+                            (Some(_), Some(_)) => return Err("patch service can't have both motd and motd_file".to_string()),
+                            (None, Some(f)) => match f.as_str() {
+                                Some(f) => try!(read_data_file(data_path, f).map_err(|e| format!("patch service motd_file: {}", e))),
+                                None => return Err("patch service motd_file must be a path".to_string())
+                            },
+                            (m, None) => m.and_then(|v| v.as_str()).map(|s| s.to_string()).unwrap_or_default()
+                        };
                         let random_balance = t.get("random_balance").and_then(|v| v.as_bool()).unwrap_or_default();
                         let mut v4_servers = Vec::new();
                         if let Some(v4_values) = t.get("v4_servers").and_then(|v| v.as_slice()) {
@@ -160,16 +774,73 @@ impl ServiceConf {
                         if v4_servers.len() == 0 {
                             return Err("patch service has no IPv4 data nodes declared".to_string())
                         }
+                        let mut versions = Vec::new();
+                        if let Some(v) = t.get("versions") {
+                            match v.as_table() {
+                                Some(vt) => {
+                                    for (name, vv) in vt.iter() {
+                                        match vv.as_table() {
+                                            Some(vvt) => versions.push(try!(PatchVersionConf::from_toml_table(name, vvt))),
+                                            None => return Err(format!("patch service version override {} is not a table", name))
+                                        }
+                                    }
+                                },
+                                None => return Err("patch service versions field is not a table".to_string())
+                            }
+                        }
+                        let mut news = Vec::new();
+                        if let Some(v) = t.get("news") {
+                            match v.as_slice() {
+                                Some(items) => for i in items {
+                                    news.push(try!(NewsItem::from_toml_value(i)));
+                                },
+                                None => return Err("patch service news field is not an array".to_string())
+                            }
+                        }
+                        let news_interval = match t.get("news_interval").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 0 => v as u32,
+                            Some(_) => return Err("patch service news_interval must be a non-negative number of seconds".to_string()),
+                            None => 0
+                        };
                         Ok(ServiceConf::Patch {
                             bind: bind,
                             motd: motd,
                             v4_servers: v4_servers,
-                            random_balance: random_balance
+                            random_balance: random_balance,
+                            versions: versions,
+                            news: news,
+                            news_interval: news_interval,
+                            throttle: throttle,
+                            max_per_ip: max_per_ip,
+                            log_level: log_level
                         })
                     },
                     "data" => {
+                        let files_path = match t.get("files_path").map(|v| v.as_str()) {
+                            Some(Some(p)) => Some(p.to_string()),
+                            Some(None) => return Err("data service files_path must be a path".to_string()),
+                            None => None
+                        };
                         Ok(ServiceConf::Data {
-                            bind: bind
+                            bind: bind,
+                            files_path: files_path,
+                            throttle: throttle,
+                            max_per_ip: max_per_ip,
+                            log_level: log_level
+                        })
+                    },
+                    "proxy" => {
+                        let target = match t.get("target").and_then(|v| v.as_str()) {
+                            Some(a) => try!(redirect_addr(a).map_err(|e| format!("proxy service target: {}", e))),
+                            None => return Err("No target address specified for proxy service".to_string())
+                        };
+                        try!(redirect_v4(&target, "the Blue Burst redirect").map_err(|e| format!("proxy service target: {}", e)));
+                        try!(redirect_reachable(&bind, &target).map_err(|e| format!("proxy service target: {}", e)));
+                        Ok(ServiceConf::Proxy {
+                            bind: bind,
+                            target: target,
+                            max_per_ip: max_per_ip,
+                            log_level: log_level
                         })
                     },
                     "login" => {
@@ -181,18 +852,67 @@ impl ServiceConf {
                             Some(Err(e)) => return Err(e),
                             None => return Err("No version specified for login service".to_string())
                         }
-                        let addr = match t.get("addr")
-                            .and_then(|v| v.as_str())
-                            .map(|v| v.parse())
-                        {
-                            Some(Ok(v)) => v,
-                            Some(Err(e)) => return Err(format!("{:?}", e)),
+                        let addr = match t.get("addr").and_then(|v| v.as_str()) {
+                            Some(a) => try!(redirect_addr(a).map_err(|e| format!("login service addr: {}", e))),
                             None => return Err("No redirect address specified for login service (It needs to be accessible by clients, but it can be the same as the bind)".to_string())
                         };
+                        if version == Version::BlueBurst {
+                            try!(redirect_v4(&addr, "the Blue Burst redirect").map_err(|e| format!("login service addr: {}", e)));
+                        }
+                        try!(redirect_reachable(&bind, &addr).map_err(|e| format!("login service addr: {}", e)));
+                        let rules = match t.get("rules").map(|v| v.as_str()) {
+                            Some(Some(text)) => {
+                                let version = match t.get("rules_version").map(|v| v.as_integer()) {
+                                    Some(Some(v)) if v >= 1 && v <= ::std::u32::MAX as i64 => v as u32,
+                                    Some(_) => return Err("login rules_version must be a positive number".to_string()),
+                                    None => 1
+                                };
+                                Some(RulesConf {
+                                    text: text.to_string(),
+                                    version: version
+                                })
+                            },
+                            Some(None) => return Err("login rules must be a string".to_string()),
+                            None => None
+                        };
+                        let allowed = match t.get("allowed_classes").map(|v| class_list(v)) {
+                            Some(Ok(l)) => Some(l),
+                            Some(Err(e)) => return Err(format!("login allowed_classes: {}", e)),
+                            None => None
+                        };
+                        let disallowed = match t.get("disallowed_classes").map(|v| class_list(v)) {
+                            Some(Ok(l)) => Some(l),
+                            Some(Err(e)) => return Err(format!("login disallowed_classes: {}", e)),
+                            None => None
+                        };
+                        let disallowed = match (allowed, disallowed) {
+                            (Some(_), Some(_)) => return Err("login can have allowed_classes or disallowed_classes, not both".to_string()),
+                            (Some(a), None) => CHAR_CLASSES.iter().cloned().filter(|c| !a.contains(c)).collect(),
+                            (None, Some(d)) => d,
+                            (None, None) => Vec::new()
+                        };
+                        let existing = match t.get("restrict_existing_classes").map(|v| v.as_bool()) {
+                            Some(Some(b)) => b,
+                            Some(None) => return Err("login restrict_existing_classes must be true or false".to_string()),
+                            None => false
+                        };
+                        let class_restrictions = if disallowed.is_empty() {
+                            None
+                        } else {
+                            Some(ClassRestrictions {
+                                disallowed: disallowed,
+                                existing: existing
+                            })
+                        };
                         Ok(ServiceConf::Login {
                             bind: bind,
                             version: version,
-                            addr: addr
+                            addr: addr,
+                            throttle: throttle,
+                            max_per_ip: max_per_ip,
+                            rules: rules,
+                            class_restrictions: class_restrictions,
+                            log_level: log_level
                         })
                     },
                     "ship" => {
@@ -218,29 +938,61 @@ impl ServiceConf {
                             },
                             None => return Err("No blocks defined for ship".to_string())
                         };
-                        let my_ipv4 = match t.get("my_ipv4")
-                            .and_then(|v| v.as_str())
-                            .map(|s| s.parse())
-                        {
-                            Some(Ok(ip)) => ip,
-                            Some(Err(_)) => return Err(format!("Invalid IPv4 bind address for ship {}", name)),
-                            None => return Err(format!("No IPv4 bind address for ship {}", name))
+                        let max_blocks = match t.get("max_blocks").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 1 && v <= u32::max_value() as i64 => v as u32,
+                            Some(_) => return Err(format!("ship {} max_blocks must be at least 1", name)),
+                            None => DEFAULT_MAX_BLOCKS
+                        };
+                        try!(check_ship_blocks(&blocks, max_blocks).map_err(|e| format!("ship {}: {}", name, e)));
+                        // `my_ipv4` is what `addr` was called before.
+                        let addr = match t.get("addr").or(t.get("my_ipv4")).and_then(|v| v.as_str()) {
+                            Some(a) => try!(redirect_addr(a).map_err(|e| format!("ship {} addr: {}", name, e))),
+                            None => return Err(format!("No redirect address specified for ship {}", name))
+                        };
+                        try!(redirect_v4(&addr, "ship registration with the shipgate").map_err(|e| format!("ship {} addr: {}", name, e)));
+                        try!(redirect_reachable(&bind, &addr).map_err(|e| format!("ship {} addr: {}", name, e)));
+                        let event = match t.get("event").map(|v| v.as_integer()) {
+                            Some(Some(e)) if e >= 0 && e <= ::std::u16::MAX as i64 && Event::from_u16(e as u16).is_some() => Some(e as u16),
+                            Some(_) => return Err(format!("ship {} event must be 0, 1 or 3-14", name)),
+                            None => None
                         };
+                        let random_balance = t.get("random_balance").and_then(|v| v.as_bool()).unwrap_or_default();
 
                         Ok(ServiceConf::Ship {
                             bind: bind,
                             name: name,
-                            my_ipv4: my_ipv4,
-                            blocks: blocks
+                            addr: addr,
+                            blocks: blocks,
+                            max_blocks: max_blocks,
+                            event: event,
+                            random_balance: random_balance,
+                            throttle: throttle,
+                            max_per_ip: max_per_ip,
+                            log_level: log_level
                         })
                     },
                     "block" => {
                         let num = t.get("num").and_then(|v| v.as_integer()).map(|v| v as u16).unwrap_or(1);
-                        let event = t.get("event").and_then(|v| v.as_integer()).map(|v| v as u16).unwrap_or(0);
+                        let lobbies = match t.get("lobbies").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 1 && v <= DEFAULT_LOBBIES as i64 => v as u8,
+                            Some(_) => return Err(format!("block lobbies must be between 1 and {}", DEFAULT_LOBBIES)),
+                            None => DEFAULT_LOBBIES
+                        };
+                        let event = t.get("event").and_then(|v| v.as_integer()).map(|v| v as u16);
+                        let options = try!(BlockOptions::from_toml_table(t));
                         Ok(ServiceConf::Block {
                             bind: bind,
                             num: num,
-                            event: event
+                            lobbies: lobbies,
+                            event: event.unwrap_or(0),
+                            event_override: event.is_some(),
+                            ship: None,
+                            siblings: Vec::new(),
+                            random_balance: false,
+                            options: options,
+                            throttle: throttle,
+                            max_per_ip: max_per_ip,
+                            log_level: log_level
                         })
                     },
                     "shipgate" => {
@@ -262,10 +1014,53 @@ impl ServiceConf {
                         } else {
                             return Err("No db configured for shipgate".to_string())
                         }
+                        let storage_quota = match t.get("storage_quota").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 0 => v as u32,
+                            Some(_) => return Err("shipgate storage_quota must be a non-negative number of items".to_string()),
+                            None => DEFAULT_STORAGE_QUOTA
+                        };
+                        let shared_bank_slots = match t.get("shared_bank_slots").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 1 && v <= 200 => v as u32,
+                            Some(_) => return Err("shipgate shared_bank_slots must be between 1 and 200".to_string()),
+                            None => DEFAULT_SHARED_BANK_SLOTS
+                        };
+                        let unique_names = match t.get("unique_names").map(|v| v.as_bool()) {
+                            Some(Some(b)) => b,
+                            Some(None) => return Err("shipgate unique_names must be true or false".to_string()),
+                            None => false
+                        };
+                        let batch_interval = match t.get("batch_interval").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 0 => v as u32,
+                            Some(_) => return Err("shipgate batch_interval must be a non-negative number of seconds".to_string()),
+                            None => 0
+                        };
+                        let batch_size = match t.get("batch_size").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 1 => v as u32,
+                            Some(_) => return Err("shipgate batch_size must be a positive number of updates".to_string()),
+                            None => DEFAULT_BATCH_SIZE
+                        };
+                        let maintenance_hour = match t.get("maintenance_hour").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 0 && v <= 23 => Some(v as u8),
+                            Some(_) => return Err("shipgate maintenance_hour must be an hour between 0 and 23".to_string()),
+                            None => None
+                        };
+                        let maintenance_max_requests = match t.get("maintenance_max_requests").map(|v| v.as_integer()) {
+                            Some(Some(v)) if v >= 0 => v as u32,
+                            Some(_) => return Err("shipgate maintenance_max_requests must be a non-negative number of requests".to_string()),
+                            None => DEFAULT_MAINTENANCE_MAX_REQUESTS
+                        };
                         Ok(ServiceConf::ShipGate {
                             bind: bind,
                             password: password,
-                            db: db
+                            db: db,
+                            storage_quota: storage_quota,
+                            shared_bank_slots: shared_bank_slots,
+                            unique_names: unique_names,
+                            batch_interval: batch_interval,
+                            batch_size: batch_size,
+                            maintenance_hour: maintenance_hour,
+                            maintenance_max_requests: maintenance_max_requests,
+                            log_level: log_level
                         })
                     }
                     _ => return Err("invalid service type specified".to_string())
@@ -291,8 +1086,59 @@ impl DbConf {
                 } else {
                     return Err("sqlite DB type file path missing.".to_string())
                 }
+                let pool_size = try!(db_pool_size(t, "sqlite", 1));
+                let reconnect = match t.get("reconnect") {
+                    Some(v) => match v.as_bool() {
+                        Some(b) => b,
+                        None => return Err("sqlite DB reconnect must be true or false".to_string())
+                    },
+                    None => true
+                };
                 Ok(DbConf::Sqlite {
-                    file: file
+                    file: file,
+                    pool_size: pool_size,
+                    reconnect: reconnect
+                })
+            },
+            Some("mysql") => {
+                let host = match t.get("host").and_then(|v| v.as_str()) {
+                    Some(h) => h.to_string(),
+                    None => return Err("mysql DB host missing.".to_string())
+                };
+                let port = match t.get("port").map(|v| v.as_integer()) {
+                    Some(Some(p)) if p > 0 && p <= 65535 => p as u16,
+                    Some(_) => return Err("mysql DB port must be from 1 to 65535".to_string()),
+                    None => 3306
+                };
+                let user = match t.get("user").and_then(|v| v.as_str()) {
+                    Some(u) => u.to_string(),
+                    None => return Err("mysql DB user missing.".to_string())
+                };
+                let password = match t.get("password").map(|v| v.as_str()) {
+                    Some(Some(p)) => p.to_string(),
+                    Some(None) => return Err("mysql DB password must be a string".to_string()),
+                    None => String::new()
+                };
+                let database = match t.get("database").and_then(|v| v.as_str()) {
+                    Some(d) => d.to_string(),
+                    None => return Err("mysql DB database name missing.".to_string())
+                };
+                let pool_size = try!(db_pool_size(t, "mysql", 4));
+                let reconnect = match t.get("reconnect") {
+                    Some(v) => match v.as_bool() {
+                        Some(b) => b,
+                        None => return Err("mysql DB reconnect must be true or false".to_string())
+                    },
+                    None => true
+                };
+                Ok(DbConf::MySql {
+                    host: host,
+                    port: port,
+                    user: user,
+                    password: password,
+                    database: database,
+                    pool_size: pool_size,
+                    reconnect: reconnect
                 })
             },
             Some(t) => { Err(format!("unsupported db type {}", t)) },
@@ -301,6 +1147,578 @@ impl DbConf {
     }
 }
 
+impl ServiceAddr {
+    /// A `host:port` address, or `unix:` and a socket path.
+    pub fn parse(s: &str) -> Result<ServiceAddr, String> {
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err(format!("\"{}\" has no socket path", s))
+            }
+            return Ok(ServiceAddr::Unix(PathBuf::from(path)))
+        }
+        match s.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            Some(a) => Ok(ServiceAddr::Tcp(a)),
+            None => Err(format!("\"{}\" is not an address like \"127.0.0.1:11000\" or \"unix:/run/idola/shipgate.sock\"", s))
+        }
+    }
+
+    /// The TCP address, if this is one.
+    pub fn tcp(&self) -> Option<&SocketAddr> {
+        match self {
+            &ServiceAddr::Tcp(ref a) => Some(a),
+            &ServiceAddr::Unix(_) => None
+        }
+    }
+}
+
+impl fmt::Display for ServiceAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ServiceAddr::Tcp(ref a) => write!(f, "{}", a),
+            &ServiceAddr::Unix(ref p) => write!(f, "unix:{}", p.display())
+        }
+    }
+}
+
+/// An address clients are redirected to, of either family. IPv6 literals
+/// are bracketed.
+fn redirect_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse().map_err(|_| format!("\"{}\" is not an address like \"127.0.0.1:12000\" or \"[::1]:12000\"", s))
+}
+
+/// `addr` as an IPv4 address, for `path`, one of the protocol paths that
+/// can't carry IPv6.
+pub fn redirect_v4(addr: &SocketAddr, path: &str) -> Result<SocketAddrV4, String> {
+    match addr {
+        &SocketAddr::V4(a) => Ok(a),
+        &SocketAddr::V6(_) => Err(format!("{} is IPv6, but {} is IPv4-only", addr, path))
+    }
+}
+
+/// A loopback redirect only reaches clients on this machine. That's fine
+/// for a service only listening on loopback itself, but otherwise it's the
+/// usual reason remote players can't get in. LAN addresses are allowed.
+fn redirect_reachable(bind: &ServiceAddr, addr: &SocketAddr) -> Result<(), String> {
+    if is_loopback(addr) && !bind.tcp().map(is_loopback).unwrap_or(false) {
+        Err(format!("{} is a loopback address, which only clients on this machine can reach, \
+                     but the service listens on {}; give the address clients should connect to", addr, bind))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_loopback(addr: &SocketAddr) -> bool {
+    match addr {
+        &SocketAddr::V4(ref a) => a.ip().is_loopback(),
+        &SocketAddr::V6(ref a) => a.ip().is_loopback()
+    }
+}
+
+/// A ship has to list at least one block and no more than `max`, and clients
+/// can't tell two blocks apart by name or reach two at the same address.
+fn check_ship_blocks(blocks: &[BlockConf], max: u32) -> Result<(), String> {
+    if blocks.is_empty() {
+        return Err("no blocks defined".to_string())
+    }
+    if blocks.len() > max as usize {
+        return Err(format!("{} blocks defined, but max_blocks is {}", blocks.len(), max))
+    }
+    for (i, b) in blocks.iter().enumerate() {
+        for other in blocks[..i].iter() {
+            if other.name == b.name {
+                return Err(format!("block name {} is used twice", b.name))
+            }
+            if other.addr == b.addr {
+                return Err(format!("blocks {} and {} share the address {}", other.name, b.name, b.addr))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The level names a service's `log_level` may be.
+pub const LOG_LEVELS: &'static [&'static str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// A log level by name, ignoring case.
+fn parse_log_level(s: &str) -> Result<LogLevelFilter, String> {
+    s.parse().map_err(|_| format!("\"{}\" is not a log level; use one of {}", s, LOG_LEVELS.join(", ")))
+}
+
+/// A db table's `pool_size`, or `default` if it doesn't set one.
+fn db_pool_size(t: &Table, ty: &str, default: usize) -> Result<usize, String> {
+    match t.get("pool_size").map(|v| v.as_integer()) {
+        Some(Some(n)) if n >= 1 => Ok(n as usize),
+        Some(_) => Err(format!("{} DB pool_size must be at least 1", ty)),
+        None => Ok(default)
+    }
+}
+
+/// Whether a block bound on `bind` is the one a ship lists at `addr`.
+fn block_listed_at(bind: &ServiceAddr, addr: &SocketAddrV4) -> bool {
+    match bind {
+        &ServiceAddr::Tcp(SocketAddr::V4(ref b)) => b.port() == addr.port() && (b.ip().is_unspecified() || b.ip() == addr.ip()),
+        &ServiceAddr::Tcp(SocketAddr::V6(ref b)) => b.port() == addr.port() && b.ip().is_unspecified(),
+        &ServiceAddr::Unix(_) => false
+    }
+}
+
+/// Whether listening on both `a` and `b` would fail. A wildcard address
+/// takes its port on every interface, so it clashes with any other address
+/// on that port.
+fn binds_conflict(a: &ServiceAddr, b: &ServiceAddr) -> bool {
+    let unspecified = |s: &SocketAddr| match s {
+        &SocketAddr::V4(ref s) => s.ip().is_unspecified(),
+        &SocketAddr::V6(ref s) => s.ip().is_unspecified()
+    };
+    match (a, b) {
+        (&ServiceAddr::Tcp(ref a), &ServiceAddr::Tcp(ref b)) => a.port() == b.port() && (a.ip() == b.ip() || unspecified(a) || unspecified(b)),
+        (&ServiceAddr::Unix(ref a), &ServiceAddr::Unix(ref b)) => a == b,
+        _ => false
+    }
+}
+
+/// Refuse configs where two services would listen on the same address,
+/// rather than failing to bind the second at startup.
+fn check_bind_conflicts(services: &[ServiceConf]) -> Result<(), String> {
+    for (i, a) in services.iter().enumerate() {
+        for b in services[i + 1..].iter() {
+            if binds_conflict(a.bind(), b.bind()) {
+                return Err(format!("{} service bind {} conflicts with {} service bind {}", a.kind(), a.bind(), b.kind(), b.bind()))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Link blocks to the ship that lists them and its other blocks, with how
+/// it balances between them, and fill in event precedence: a block's own
+/// `event` wins, then its ship's `event`, then 0.
+fn resolve_block_events(services: &mut Vec<ServiceConf>) {
+    let ships: Vec<(String, Option<u16>, Vec<BlockConf>, bool)> = services.iter().filter_map(|s| match s {
+        &ServiceConf::Ship { ref name, event, ref blocks, random_balance, .. } => Some((name.clone(), event, blocks.clone(), random_balance)),
+        _ => None
+    }).collect();
+    for s in services.iter_mut() {
+        if let &mut ServiceConf::Block { ref bind, ref mut event, event_override, ref mut ship, ref mut siblings, ref mut random_balance, .. } = s {
+            for &(ref name, ship_event, ref blocks, ship_random) in ships.iter() {
+                if blocks.iter().any(|b| block_listed_at(bind, &b.addr)) {
+                    *ship = Some(name.clone());
+                    *siblings = blocks.iter().filter(|b| !block_listed_at(bind, &b.addr)).cloned().collect();
+                    *random_balance = ship_random;
+                    if !event_override {
+                        *event = ship_event.unwrap_or(0);
+                    }
+                    break
+                }
+            }
+        }
+    }
+}
+
+impl ServiceConf {
+    /// The outbound throttle for this service's clients, if any.
+    pub fn throttle(&self) -> Option<&ThrottleConf> {
+        match self {
+            &ServiceConf::Patch { ref throttle, .. } => throttle.as_ref(),
+            &ServiceConf::Data { ref throttle, .. } => throttle.as_ref(),
+            &ServiceConf::Login { ref throttle, .. } => throttle.as_ref(),
+            &ServiceConf::Ship { ref throttle, .. } => throttle.as_ref(),
+            &ServiceConf::Block { ref throttle, .. } => throttle.as_ref(),
+            &ServiceConf::Proxy { .. } => None,
+            &ServiceConf::ShipGate { .. } => None
+        }
+    }
+
+    /// The cap on connections from one IP address, if the service takes
+    /// connections from clients.
+    pub fn max_per_ip(&self) -> Option<u32> {
+        match self {
+            &ServiceConf::Patch { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::Data { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::Login { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::Ship { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::Block { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::Proxy { max_per_ip, .. } => Some(max_per_ip),
+            &ServiceConf::ShipGate { .. } => None
+        }
+    }
+
+    /// The address this service listens on.
+    pub fn bind(&self) -> &ServiceAddr {
+        match self {
+            &ServiceConf::Patch { ref bind, .. } => bind,
+            &ServiceConf::Data { ref bind, .. } => bind,
+            &ServiceConf::Login { ref bind, .. } => bind,
+            &ServiceConf::Ship { ref bind, .. } => bind,
+            &ServiceConf::Block { ref bind, .. } => bind,
+            &ServiceConf::Proxy { ref bind, .. } => bind,
+            &ServiceConf::ShipGate { ref bind, .. } => bind
+        }
+    }
+
+    /// This service's own log level, if it has one.
+    pub fn log_level(&self) -> Option<LogLevelFilter> {
+        match self {
+            &ServiceConf::Patch { log_level, .. } => log_level,
+            &ServiceConf::Data { log_level, .. } => log_level,
+            &ServiceConf::Login { log_level, .. } => log_level,
+            &ServiceConf::Ship { log_level, .. } => log_level,
+            &ServiceConf::Block { log_level, .. } => log_level,
+            &ServiceConf::Proxy { log_level, .. } => log_level,
+            &ServiceConf::ShipGate { log_level, .. } => log_level
+        }
+    }
+
+    /// The log target the service's module logs under, which `log_level`
+    /// applies to.
+    pub fn log_target(&self) -> String {
+        format!("{}::{}", env!("CARGO_PKG_NAME"), self.kind())
+    }
+
+    /// The `type` value this service was configured with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &ServiceConf::Patch { .. } => "patch",
+            &ServiceConf::Data { .. } => "data",
+            &ServiceConf::Login { .. } => "login",
+            &ServiceConf::Ship { .. } => "ship",
+            &ServiceConf::Block { .. } => "block",
+            &ServiceConf::Proxy { .. } => "proxy",
+            &ServiceConf::ShipGate { .. } => "shipgate"
+        }
+    }
+}
+
+impl PatchVersionConf {
+    /// `name` is the version string the override table is keyed by. Only
+    /// Blue Burst and PC clients use the patch server, so they're the only
+    /// versions it can tell apart.
+    pub fn from_toml_table(name: &str, t: &Table) -> Result<PatchVersionConf, String> {
+        let version = try!(name.parse());
+        if version != Version::BlueBurst && version != Version::PC {
+            return Err(format!("patch service can only override BlueBurst and PC, which are the only clients that patch, not {}", name))
+        }
+        let motd = t.get("motd").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let v4_servers = match t.get("v4_servers") {
+            Some(v) => {
+                let mut servers = Vec::new();
+                match v.as_slice() {
+                    Some(vs) => for s in vs {
+                        match s.as_str().and_then(|s| s.parse().ok()) {
+                            Some(sockaddr) => servers.push(sockaddr),
+                            None => return Err(format!("patch service {} override data address is not a valid IPv4 address:port string", name))
+                        }
+                    },
+                    None => return Err(format!("patch service {} override v4_servers field is not an array", name))
+                }
+                if servers.len() == 0 {
+                    return Err(format!("patch service {} override has no IPv4 data nodes declared", name))
+                }
+                Some(servers)
+            },
+            None => None
+        };
+        Ok(PatchVersionConf {
+            version: version,
+            motd: motd,
+            v4_servers: v4_servers
+        })
+    }
+}
+
+impl NewsItem {
+    /// A news item is either a plain string or a table with `text` and an
+    /// optional `weight`. TOML doesn't allow both kinds in one array.
+    pub fn from_toml_value(v: &Value) -> Result<NewsItem, String> {
+        if let Some(s) = v.as_str() {
+            return Ok(NewsItem { text: s.to_string(), weight: 1 })
+        }
+        let t = match v.as_table() {
+            Some(t) => t,
+            None => return Err("patch service news item must be a string or a table".to_string())
+        };
+        let text = match t.get("text").and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => return Err("patch service news item has no text".to_string())
+        };
+        let weight = match t.get("weight").map(|v| v.as_integer()) {
+            Some(Some(w)) if w >= 1 && w <= 100 => w as u32,
+            Some(_) => return Err("patch service news item weight must be between 1 and 100".to_string()),
+            None => 1
+        };
+        Ok(NewsItem {
+            text: text,
+            weight: weight
+        })
+    }
+}
+
+impl ThrottleConf {
+    pub fn from_toml_table(t: &Table) -> Result<ThrottleConf, String> {
+        let rate = match t.get("rate").and_then(|v| v.as_integer()) {
+            Some(r) if r > 0 && r <= ::std::u32::MAX as i64 => r as u32,
+            Some(_) => return Err("throttle rate must be a positive number of bytes per second".to_string()),
+            None => return Err("throttle rate not specified".to_string())
+        };
+        let min_size = match t.get("min_size").and_then(|v| v.as_integer()) {
+            Some(s) if s >= 0 => s as usize,
+            Some(_) => return Err("throttle min_size must not be negative".to_string()),
+            None => 1024
+        };
+        Ok(ThrottleConf {
+            rate: rate,
+            min_size: min_size
+        })
+    }
+}
+
+impl BlockOptions {
+    /// Read the block tunables from a block service's table. Missing keys
+    /// keep their defaults.
+    pub fn from_toml_table(t: &Table) -> Result<BlockOptions, String> {
+        let mut o = BlockOptions::default();
+        match t.get("reconnect_grace").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.reconnect_grace = v as u32,
+            Some(_) => return Err("block reconnect_grace must be a non-negative number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("slow_handler_ms").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.slow_handler_ms = v as u32,
+            Some(_) => return Err("block slow_handler_ms must be a non-negative number of milliseconds".to_string()),
+            None => ()
+        }
+        match t.get("idle_timeout").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.idle_timeout = v as u32,
+            Some(_) => return Err("block idle_timeout must be a non-negative number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("max_players").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.max_players = v as u32,
+            Some(_) => return Err("block max_players must be a non-negative number of players".to_string()),
+            None => ()
+        }
+        match t.get("merge_below").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.merge_below = v as u32,
+            Some(_) => return Err("block merge_below must be a non-negative number of players".to_string()),
+            None => ()
+        }
+        match t.get("merge_migrate_interval").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.merge_migrate_interval = v as u32,
+            Some(_) => return Err("block merge_migrate_interval must be a non-negative number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("inventory_slots").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 1 && v <= 30 => o.inventory_slots = v as u32,
+            Some(_) => return Err("block inventory_slots must be between 1 and 30".to_string()),
+            None => ()
+        }
+        match t.get("tool_stack_limit").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 1 && v <= 255 => o.tool_stack_limit = v as u32,
+            Some(_) => return Err("block tool_stack_limit must be between 1 and 255".to_string()),
+            None => ()
+        }
+        match t.get("rest_exp_per_minute").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.rest_exp_per_minute = v as u32,
+            Some(_) => return Err("block rest_exp_per_minute must be a non-negative amount of experience".to_string()),
+            None => ()
+        }
+        match t.get("rest_exp_cap").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.rest_exp_cap = v as u32,
+            Some(_) => return Err("block rest_exp_cap must be a non-negative amount of experience".to_string()),
+            None => ()
+        }
+        match t.get("lobby_change_limit").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.lobby_change_limit = v as u32,
+            Some(_) => return Err("block lobby_change_limit must be a non-negative number of changes".to_string()),
+            None => ()
+        }
+        match t.get("lobby_change_window").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 1 => o.lobby_change_window = v as u32,
+            Some(_) => return Err("block lobby_change_window must be a positive number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("lobby_change_kick").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.lobby_change_kick = v as u32,
+            Some(_) => return Err("block lobby_change_kick must be a non-negative number of changes".to_string()),
+            None => ()
+        }
+        match t.get("chat_limit").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.chat_limit = v as u32,
+            Some(_) => return Err("block chat_limit must be a non-negative number of messages".to_string()),
+            None => ()
+        }
+        match t.get("chat_window").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 1 => o.chat_window = v as u32,
+            Some(_) => return Err("block chat_window must be a positive number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("chat_log").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.chat_log = b,
+            Some(None) => return Err("block chat_log must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("track_playtime").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.track_playtime = b,
+            Some(None) => return Err("block track_playtime must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("playtime_checkpoint").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.playtime_checkpoint = v as u32,
+            Some(_) => return Err("block playtime_checkpoint must be a non-negative number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("lobby_minigames").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.lobby_minigames = b,
+            Some(None) => return Err("block lobby_minigames must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("minigame_free_lobbies").map(|v| byte_list(v, 1, 15)) {
+            Some(Some(l)) => o.minigame_free_lobbies = l,
+            Some(None) => return Err("block minigame_free_lobbies must be an array of lobby numbers from 1 to 15".to_string()),
+            None => ()
+        }
+        match t.get("lobby_minigame_subcmds").map(|v| byte_list(v, 0, 255)) {
+            Some(Some(l)) => o.lobby_minigame_subcmds = l,
+            Some(None) => return Err("block lobby_minigame_subcmds must be an array of subcommand numbers from 0 to 255".to_string()),
+            None => ()
+        }
+        match t.get("suspicion_kick").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => o.suspicion_kick = v as u32,
+            Some(_) => return Err("block suspicion_kick must be a non-negative number of points".to_string()),
+            None => ()
+        }
+        match t.get("suspicion_ban").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.suspicion_ban = b,
+            Some(None) => return Err("block suspicion_ban must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("gm_guildcards").map(|v| guildcard_list(v)) {
+            Some(Some(l)) => o.gm_guildcards = l,
+            Some(None) => return Err("block gm_guildcards must be an array of guild card numbers".to_string()),
+            None => ()
+        }
+        match t.get("enforce_identification").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.enforce_identification = b,
+            Some(None) => return Err("block enforce_identification must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("save_on_drop").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.save_on_drop = b,
+            Some(None) => return Err("block save_on_drop must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("banned_items").map(|v| item_code_list(v)) {
+            Some(Ok(l)) => o.banned_items = l,
+            Some(Err(e)) => return Err(format!("block banned_items: {}", e)),
+            None => ()
+        }
+        match t.get("strip_banned_items").map(|v| v.as_bool()) {
+            Some(Some(b)) => o.strip_banned_items = b,
+            Some(None) => return Err("block strip_banned_items must be true or false".to_string()),
+            None => ()
+        }
+        match t.get("floor_item_lifetime").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 && v <= ::std::u32::MAX as i64 => o.floor_item_lifetime = v as u32,
+            Some(_) => return Err("block floor_item_lifetime must be a non-negative number of seconds".to_string()),
+            None => ()
+        }
+        match t.get("restart_warnings").map(|v| byte_list(v, 1, 255)) {
+            Some(Some(l)) => o.restart_warnings = l,
+            Some(None) => return Err("block restart_warnings must be an array of minutes from 1 to 255".to_string()),
+            None => ()
+        }
+        match t.get("max_meseta").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 && v <= MAX_MESETA as i64 => o.max_meseta = v as u32,
+            Some(_) => return Err(format!("block max_meseta must be between 0 and {}", MAX_MESETA)),
+            None => ()
+        }
+        match t.get("max_level").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 1 && v <= MAX_LEVEL as i64 => o.max_level = v as u32,
+            Some(_) => return Err(format!("block max_level must be between 1 and {}", MAX_LEVEL)),
+            None => ()
+        }
+        match t.get("area_validation").map(|v| v.as_str()) {
+            Some(Some("off")) => o.area_validation = AreaValidation::Off,
+            Some(Some("log")) => o.area_validation = AreaValidation::Log,
+            Some(Some("enforce")) => o.area_validation = AreaValidation::Enforce,
+            Some(_) => return Err("block area_validation must be \"off\", \"log\" or \"enforce\"".to_string()),
+            None => ()
+        }
+        Ok(o)
+    }
+}
+
+/// An array of integers from `min` to `max`, or `None` if it isn't one.
+fn byte_list(v: &Value, min: u8, max: u8) -> Option<Vec<u8>> {
+    let items = match v.as_slice() {
+        Some(s) => s,
+        None => return None
+    };
+    let mut list = Vec::new();
+    for i in items {
+        match i.as_integer() {
+            Some(n) if n >= min as i64 && n <= max as i64 => list.push(n as u8),
+            _ => return None
+        }
+    }
+    Some(list)
+}
+
+/// An array of guild card numbers, or `None` if it isn't one.
+fn guildcard_list(v: &Value) -> Option<Vec<u32>> {
+    let items = match v.as_slice() {
+        Some(s) => s,
+        None => return None
+    };
+    let mut list = Vec::new();
+    for i in items {
+        match i.as_integer() {
+            Some(n) if n >= 1 && n <= ::std::u32::MAX as i64 => list.push(n as u32),
+            _ => return None
+        }
+    }
+    Some(list)
+}
+
+/// An array of class names like "HUmar".
+fn class_list(v: &Value) -> Result<Vec<CharClass>, String> {
+    let items = match v.as_slice() {
+        Some(s) => s,
+        None => return Err("must be an array of class names".to_string())
+    };
+    let mut list = Vec::new();
+    for i in items {
+        match i.as_str().map(|s| s.parse::<CharClass>()) {
+            Some(Ok(c)) => list.push(c),
+            Some(Err(e)) => return Err(e),
+            None => return Err("class names must be strings".to_string())
+        }
+    }
+    Ok(list)
+}
+
+/// An array of item codes like "TTSS" or "TTSSII", in hex.
+fn item_code_list(v: &Value) -> Result<Vec<Vec<u8>>, String> {
+    let items = match v.as_slice() {
+        Some(s) => s,
+        None => return Err("must be an array of item codes".to_string())
+    };
+    let mut list = Vec::new();
+    for i in items {
+        let code = match i.as_str() {
+            Some(c) => c,
+            None => return Err("item codes must be strings".to_string())
+        };
+        match ::stacklimits::parse_code(code) {
+            // Meseta (type 4) isn't an item anyone can ban.
+            Some(b) if b[0] <= 3 => list.push(b),
+            _ => return Err(format!("{} is not an item code like \"TTSS\" or \"TTSSII\"", code))
+        }
+    }
+    Ok(list)
+}
+
 impl BlockConf {
     pub fn from_toml_table(t: &Table) -> Result<BlockConf, String> {
         let name = match t.get("name").and_then(|v| v.as_str()) {
@@ -318,3 +1736,584 @@ impl BlockConf {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_event_precedence() {
+        let s = r#"
+            [idola]
+            shipgate_addr = "127.0.0.1:6813"
+            shipgate_password = "pw"
+
+            [[service]]
+            bind = "127.0.0.1:13000"
+            type = "ship"
+            name = "IDOLA"
+            addr = "127.0.0.1:13000"
+            event = 5
+            random_balance = true
+              [[service.block]]
+              name = "BLOCK01"
+              addr = "127.0.0.1:13001"
+              [[service.block]]
+              name = "BLOCK02"
+              addr = "127.0.0.1:13002"
+
+            [[service]]
+            bind = "0.0.0.0:13001"
+            type = "block"
+
+            [[service]]
+            bind = "127.0.0.1:13002"
+            type = "block"
+            event = 7
+
+            [[service]]
+            bind = "127.0.0.1:13003"
+            type = "block"
+        "#;
+        let c = Config::from_toml_string(s).unwrap();
+        let blocks: Vec<_> = c.services.iter().filter_map(|s| match s {
+            &ServiceConf::Block { event, event_override, ref ship, .. } => Some((event, event_override, ship.clone())),
+            _ => None
+        }).collect();
+        assert_eq!(blocks[0], (5, false, Some("IDOLA".to_string())));
+        assert_eq!(blocks[1], (7, true, Some("IDOLA".to_string())));
+        assert_eq!(blocks[2], (0, false, None));
+
+        let siblings: Vec<_> = c.services.iter().filter_map(|s| match s {
+            &ServiceConf::Block { ref siblings, .. } => Some(siblings.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(siblings[0], vec![BlockConf { name: "BLOCK02".to_string(), addr: "127.0.0.1:13002".parse().unwrap() }]);
+        assert_eq!(siblings[1], vec![BlockConf { name: "BLOCK01".to_string(), addr: "127.0.0.1:13001".parse().unwrap() }]);
+        assert!(siblings[2].is_empty());
+
+        let random: Vec<_> = c.services.iter().filter_map(|s| match s {
+            &ServiceConf::Block { random_balance, .. } => Some(random_balance),
+            _ => None
+        }).collect();
+        assert_eq!(random, vec![true, true, false]);
+    }
+
+    fn two_services(bind1: &str, bind2: &str) -> String {
+        format!(r#"
+            [idola]
+            shipgate_addr = "127.0.0.1:6813"
+            shipgate_password = "pw"
+
+            [[service]]
+            bind = "{}"
+            type = "data"
+
+            [[service]]
+            bind = "{}"
+            type = "block"
+        "#, bind1, bind2)
+    }
+
+    #[test]
+    fn test_distinct_binds() {
+        assert!(Config::from_toml_string(&two_services("127.0.0.1:11001", "127.0.0.1:13001")).is_ok());
+        assert!(Config::from_toml_string(&two_services("0.0.0.0:11001", "0.0.0.0:13001")).is_ok());
+        assert!(Config::from_toml_string(&two_services("127.0.0.1:13001", "127.0.0.2:13001")).is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_bind() {
+        match Config::from_toml_string(&two_services("127.0.0.1:13001", "127.0.0.1:13001")) {
+            Err(e) => assert_eq!(e, "data service bind 127.0.0.1:13001 conflicts with block service bind 127.0.0.1:13001"),
+            Ok(_) => panic!("duplicate bind accepted")
+        }
+    }
+
+    #[test]
+    fn test_wildcard_bind_conflict() {
+        match Config::from_toml_string(&two_services("0.0.0.0:13001", "127.0.0.1:13001")) {
+            Err(e) => assert_eq!(e, "data service bind 0.0.0.0:13001 conflicts with block service bind 127.0.0.1:13001"),
+            Ok(_) => panic!("wildcard and specific bind on one port accepted")
+        }
+        assert!(Config::from_toml_string(&two_services("127.0.0.1:13001", "0.0.0.0:13001")).is_err());
+        assert!(Config::from_toml_string(&two_services("0.0.0.0:13001", "0.0.0.0:13001")).is_err());
+    }
+
+    #[test]
+    fn test_service_addr_tcp() {
+        assert_eq!(ServiceAddr::parse("127.0.0.1:11001"), Ok(ServiceAddr::Tcp("127.0.0.1:11001".parse().unwrap())));
+        assert_eq!(ServiceAddr::parse("[::1]:6813"), Ok(ServiceAddr::Tcp("[::1]:6813".parse().unwrap())));
+        assert!(ServiceAddr::parse("127.0.0.1").is_err());
+        assert_eq!(ServiceAddr::parse("127.0.0.1:11001").unwrap().to_string(), "127.0.0.1:11001");
+    }
+
+    #[test]
+    fn test_service_log_level() {
+        let parse = |level: &str| {
+            let t = Parser::new(&format!("bind = \"127.0.0.1:11001\"\ntype = \"data\"\nlog_level = \"{}\"", level)).parse().unwrap();
+            ServiceConf::from_toml_table(&t, "data").map(|s| s.log_level())
+        };
+        assert_eq!(parse("off"), Ok(Some(LogLevelFilter::Off)));
+        assert_eq!(parse("error"), Ok(Some(LogLevelFilter::Error)));
+        assert_eq!(parse("warn"), Ok(Some(LogLevelFilter::Warn)));
+        assert_eq!(parse("info"), Ok(Some(LogLevelFilter::Info)));
+        assert_eq!(parse("debug"), Ok(Some(LogLevelFilter::Debug)));
+        assert_eq!(parse("TRACE"), Ok(Some(LogLevelFilter::Trace)));
+        let e = parse("verbose").unwrap_err();
+        assert!(e.contains("off, error, warn, info, debug, trace"), "{}", e);
+
+        // Without one, the service follows the global level.
+        let t = Parser::new("bind = \"127.0.0.1:11001\"\ntype = \"data\"").parse().unwrap();
+        assert_eq!(ServiceConf::from_toml_table(&t, "data").unwrap().log_level(), None);
+    }
+
+    #[test]
+    fn test_log_filters() {
+        let c = Config::from_toml_string(r#"
+            [idola]
+            shipgate_addr = "127.0.0.1:6813"
+            shipgate_password = "pw"
+
+            [[service]]
+            bind = "127.0.0.1:13001"
+            type = "block"
+            log_level = "info"
+
+            [[service]]
+            bind = "127.0.0.1:13002"
+            type = "block"
+            num = 2
+            log_level = "debug"
+
+            [[service]]
+            bind = "127.0.0.1:6813"
+            type = "shipgate"
+            password = "pw"
+            db = { type = "sqlite", file = "local.db" }
+        "#).unwrap();
+        assert_eq!(c.log_filters(), vec![("idola::block".to_string(), LogLevelFilter::Debug)]);
+    }
+
+    #[test]
+    fn test_service_addr_unix() {
+        let a = ServiceAddr::parse("unix:/run/idola/shipgate.sock").unwrap();
+        assert_eq!(a, ServiceAddr::Unix(PathBuf::from("/run/idola/shipgate.sock")));
+        assert_eq!(a.to_string(), "unix:/run/idola/shipgate.sock");
+        assert!(ServiceAddr::parse("unix:").is_err());
+
+        let t = Parser::new("bind = \"unix:/run/idola/data.sock\"\ntype = \"data\"").parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data") {
+            Ok(ServiceConf::Data { bind, .. }) => assert_eq!(bind, ServiceAddr::Unix(PathBuf::from("/run/idola/data.sock"))),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        let c = Config::from_toml_string(r#"
+            [idola]
+            shipgate_addr = "unix:/run/idola/shipgate.sock"
+            shipgate_password = "pw"
+
+            [[service]]
+            bind = "unix:/run/idola/shipgate.sock"
+            type = "shipgate"
+            password = "pw"
+            db = { type = "sqlite", file = "local.db" }
+        "#).unwrap();
+        assert_eq!(c.shipgate_addr, ServiceAddr::Unix(PathBuf::from("/run/idola/shipgate.sock")));
+        assert_eq!(*c.services[0].bind(), c.shipgate_addr);
+    }
+
+    fn parse_ship(blocks: &str) -> Result<ServiceConf, String> {
+        let s = format!("bind = \"127.0.0.1:13000\"\ntype = \"ship\"\nname = \"IDOLA\"\naddr = \"127.0.0.1:13000\"\n{}", blocks);
+        let t = Parser::new(&s).parse().unwrap();
+        ServiceConf::from_toml_table(&t, "data")
+    }
+
+    #[test]
+    fn test_ship_blocks_valid() {
+        let r = parse_ship(r#"
+            [[block]]
+            name = "BLOCK01"
+            addr = "127.0.0.1:13001"
+            [[block]]
+            name = "BLOCK02"
+            addr = "127.0.0.1:13002"
+            [[block]]
+            name = "BLOCK03"
+            addr = "127.0.0.1:13003"
+        "#);
+        match r {
+            Ok(ServiceConf::Ship { ref blocks, max_blocks, .. }) => {
+                assert_eq!(blocks.len(), 3);
+                assert_eq!(max_blocks, DEFAULT_MAX_BLOCKS);
+            },
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        let r = parse_ship(r#"
+            max_blocks = 1
+            [[block]]
+            name = "BLOCK01"
+            addr = "127.0.0.1:13001"
+            [[block]]
+            name = "BLOCK02"
+            addr = "127.0.0.1:13002"
+        "#);
+        assert_eq!(r.unwrap_err(), "ship IDOLA: 2 blocks defined, but max_blocks is 1");
+    }
+
+    #[test]
+    fn test_ship_event_checked() {
+        let blocks = "[[block]]\nname = \"BLOCK01\"\naddr = \"127.0.0.1:13001\"";
+        match parse_ship(&format!("event = 5\n{}", blocks)) {
+            Ok(ServiceConf::Ship { event, .. }) => assert_eq!(event, Some(5)),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        for bad in &["2", "15", "65541", "-1"] {
+            assert_eq!(parse_ship(&format!("event = {}\n{}", bad, blocks)).unwrap_err(), "ship IDOLA event must be 0, 1 or 3-14");
+        }
+    }
+
+    #[test]
+    fn test_ship_blocks_empty() {
+        assert_eq!(parse_ship("block = []").unwrap_err(), "ship IDOLA: no blocks defined");
+    }
+
+    #[test]
+    fn test_ship_blocks_duplicate_name() {
+        let r = parse_ship(r#"
+            [[block]]
+            name = "BLOCK01"
+            addr = "127.0.0.1:13001"
+            [[block]]
+            name = "BLOCK01"
+            addr = "127.0.0.1:13002"
+        "#);
+        assert_eq!(r.unwrap_err(), "ship IDOLA: block name BLOCK01 is used twice");
+    }
+
+    #[test]
+    fn test_ship_blocks_duplicate_addr() {
+        let r = parse_ship(r#"
+            [[block]]
+            name = "BLOCK01"
+            addr = "127.0.0.1:13001"
+            [[block]]
+            name = "BLOCK02"
+            addr = "127.0.0.1:13001"
+        "#);
+        assert_eq!(r.unwrap_err(), "ship IDOLA: blocks BLOCK01 and BLOCK02 share the address 127.0.0.1:13001");
+    }
+
+    #[test]
+    fn test_block_lobbies_default() {
+        let t = Parser::new("bind = \"127.0.0.1:13001\"\ntype = \"block\"").parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data") {
+            Ok(ServiceConf::Block { lobbies, .. }) => assert_eq!(lobbies, DEFAULT_LOBBIES),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        let t = Parser::new("bind = \"127.0.0.1:13001\"\ntype = \"block\"\nlobbies = 4").parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data") {
+            Ok(ServiceConf::Block { lobbies, .. }) => assert_eq!(lobbies, 4),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_block_lobbies_range() {
+        for n in &["0", "16", "-1", "\"4\""] {
+            let t = Parser::new(&format!("bind = \"127.0.0.1:13001\"\ntype = \"block\"\nlobbies = {}", n)).parse().unwrap();
+            match ServiceConf::from_toml_table(&t, "data") {
+                Err(e) => assert_eq!(e, "block lobbies must be between 1 and 15"),
+                Ok(c) => panic!("accepted lobbies = {}: {:?}", n, c)
+            }
+        }
+    }
+
+    #[test]
+    fn test_patch_version_overrides() {
+        let s = r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+            motd = "default"
+              [versions.PC]
+              motd = "pc"
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data").unwrap() {
+            ServiceConf::Patch { versions, .. } => {
+                assert_eq!(versions.len(), 1);
+                assert_eq!(versions[0].version, Version::PC);
+                assert_eq!(versions[0].motd, Some("pc".to_string()));
+                assert!(versions[0].v4_servers.is_none());
+            },
+            _ => panic!("not a patch service")
+        }
+
+        let s = r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+              [versions.Dreamcast]
+              motd = "?"
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        assert!(ServiceConf::from_toml_table(&t, "data").is_err());
+
+        // A real version, but one that never connects to the patch server.
+        let s = r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+              [versions.Gamecube]
+              motd = "?"
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        assert!(ServiceConf::from_toml_table(&t, "data").is_err());
+    }
+
+    fn patch_motd(motd: &str, data_path: &str) -> Result<String, String> {
+        let s = format!(r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+            {}
+        "#, motd);
+        let t = Parser::new(&s).parse().unwrap();
+        ServiceConf::from_toml_table(&t, data_path).map(|c| match c {
+            ServiceConf::Patch { motd, .. } => motd,
+            _ => panic!("not a patch service")
+        })
+    }
+
+    #[test]
+    fn test_patch_motd_inline() {
+        assert_eq!(patch_motd("motd = \"Hello\\nthere\"", "data"), Ok("Hello\nthere".to_string()));
+        assert_eq!(patch_motd("", "data"), Ok("".to_string()));
+    }
+
+    #[test]
+    fn test_patch_motd_file() {
+        let dir = ::std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        {
+            use std::io::Write;
+            let mut f = File::create(Path::new(dir).join("idola_test_patch_motd.txt")).unwrap();
+            f.write_all(b"Line one\n\tC6Line two\n").unwrap();
+        }
+        assert_eq!(patch_motd("motd_file = \"idola_test_patch_motd.txt\"", dir), Ok("Line one\n\tC6Line two\n".to_string()));
+        assert!(patch_motd("motd_file = \"idola_test_no_such_motd.txt\"", dir).is_err());
+    }
+
+    #[test]
+    fn test_patch_motd_conflict() {
+        assert_eq!(patch_motd("motd = \"Hello\"\nmotd_file = \"motd.txt\"", "data"),
+            Err("patch service can't have both motd and motd_file".to_string()));
+    }
+
+    #[test]
+    fn test_patch_news() {
+        let s = r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+            news = [{ text = "plain" }, { text = "weighted", weight = 3 }]
+            news_interval = 60
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data").unwrap() {
+            ServiceConf::Patch { news, news_interval, .. } => {
+                assert_eq!(news, vec![
+                    NewsItem { text: "plain".to_string(), weight: 1 },
+                    NewsItem { text: "weighted".to_string(), weight: 3 }
+                ]);
+                assert_eq!(news_interval, 60);
+            },
+            _ => panic!("not a patch service")
+        }
+
+        let s = r#"
+            bind = "127.0.0.1:11000"
+            type = "patch"
+            v4_servers = ["127.0.0.1:11001"]
+            news = [{ weight = 2 }]
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        assert!(ServiceConf::from_toml_table(&t, "data").is_err());
+    }
+
+    #[test]
+    fn test_holidays() {
+        let s = r#"
+            enabled = true
+            easter = false
+            christmas = { start = "12-20", end = "12-25" }
+            summer = { event = 8, start = "07-01", end = "08-31" }
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        let h = holidays_from_toml_table(&t).unwrap();
+        assert!(h.iter().all(|h| h.name != "easter"));
+        let christmas = h.iter().find(|h| h.name == "christmas").unwrap();
+        assert_eq!(christmas.dates, HolidayDates::Fixed((12, 20), (12, 25)));
+        assert_eq!(christmas.event, holidays::EVENT_CHRISTMAS);
+        assert_eq!(holidays::event_on(&h, 2016, 7, 15), Some(8));
+
+        let t = Parser::new("enabled = false").parse().unwrap();
+        assert!(holidays_from_toml_table(&t).unwrap().is_empty());
+
+        let t = Parser::new("enabled = true\nsummer = { event = 8 }").parse().unwrap();
+        assert!(holidays_from_toml_table(&t).is_err());
+    }
+
+    #[test]
+    fn test_mysql_requires_host() {
+        let s = r#"
+            type = "mysql"
+            user = "idola"
+            database = "idola"
+        "#;
+        let t = Parser::new(s).parse().unwrap();
+        match DbConf::from_toml_table(&t) {
+            Err(e) => assert_eq!(e, "mysql DB host missing."),
+            Ok(c) => panic!("accepted without a host: {:?}", c)
+        }
+    }
+
+    #[test]
+    fn test_redirect_addr() {
+        assert_eq!(redirect_addr("127.0.0.1:12000"), Ok("127.0.0.1:12000".parse().unwrap()));
+        assert_eq!(redirect_addr("[2001:db8::1]:12000"), Ok("[2001:db8::1]:12000".parse().unwrap()));
+        assert!(redirect_addr("2001:db8::1:12000").is_err());
+        assert!(redirect_addr("localhost").is_err());
+    }
+
+    #[test]
+    fn test_bb_login_needs_ipv4() {
+        let login = |addr: &str| {
+            let s = format!("bind = \"127.0.0.1:12000\"\ntype = \"login\"\nversion = \"BlueBurst\"\naddr = \"{}\"", addr);
+            let t = Parser::new(&s).parse().unwrap();
+            ServiceConf::from_toml_table(&t, "data")
+        };
+        assert!(login("127.0.0.1:12000").is_ok());
+        assert_eq!(login("[::1]:12000").err(),
+            Some("login service addr: [::1]:12000 is IPv6, but the Blue Burst redirect is IPv4-only".to_string()));
+    }
+
+    #[test]
+    fn test_proxy_target() {
+        let proxy = |target: &str| {
+            let s = format!("bind = \"0.0.0.0:12000\"\ntype = \"proxy\"\n{}", target);
+            let t = Parser::new(&s).parse().unwrap();
+            ServiceConf::from_toml_table(&t, "data")
+        };
+        match proxy("target = \"10.0.0.2:12000\"") {
+            Ok(ServiceConf::Proxy { target, max_per_ip, .. }) => {
+                assert_eq!(target, "10.0.0.2:12000".parse().unwrap());
+                assert_eq!(max_per_ip, DEFAULT_MAX_PER_IP);
+            },
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        assert!(proxy("target = \"10.0.0.2\"").is_err());
+        assert!(proxy("").is_err());
+        assert_eq!(proxy("target = \"[::1]:12000\"").err(),
+            Some("proxy service target: [::1]:12000 is IPv6, but the Blue Burst redirect is IPv4-only".to_string()));
+    }
+
+    fn ship_at(bind: &str, addr: &str) -> Result<ServiceConf, String> {
+        let s = format!("bind = \"{}\"\ntype = \"ship\"\nname = \"IDOLA\"\naddr = \"{}\"\nblock = [{{ name = \"BLOCK01\", addr = \"127.0.0.1:13001\" }}]", bind, addr);
+        let t = Parser::new(&s).parse().unwrap();
+        ServiceConf::from_toml_table(&t, "data")
+    }
+
+    #[test]
+    fn test_ship_addr() {
+        match ship_at("0.0.0.0:13000", "203.0.113.5:13000") {
+            Ok(ServiceConf::Ship { addr, .. }) => assert_eq!(addr, "203.0.113.5:13000".parse().unwrap()),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+        assert_eq!(ship_at("[::]:13000", "[2001:db8::1]:13000").err(),
+            Some("ship IDOLA addr: [2001:db8::1]:13000 is IPv6, but ship registration with the shipgate is IPv4-only".to_string()));
+        // Configs from before the rename still load.
+        let s = "bind = \"0.0.0.0:13000\"\ntype = \"ship\"\nname = \"IDOLA\"\nmy_ipv4 = \"203.0.113.5:13000\"\nblock = [{ name = \"BLOCK01\", addr = \"127.0.0.1:13001\" }]";
+        let t = Parser::new(s).parse().unwrap();
+        match ServiceConf::from_toml_table(&t, "data") {
+            Ok(ServiceConf::Ship { addr, .. }) => assert_eq!(addr, "203.0.113.5:13000".parse().unwrap()),
+            r => panic!("unexpected parse: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_loopback_redirect_rejected() {
+        assert_eq!(ship_at("0.0.0.0:13000", "127.0.0.1:13000").err(),
+            Some("ship IDOLA addr: 127.0.0.1:13000 is a loopback address, which only clients on this machine can reach, \
+                  but the service listens on 0.0.0.0:13000; give the address clients should connect to".to_string()));
+        let s = "bind = \"192.168.1.2:12000\"\ntype = \"login\"\nversion = \"BlueBurst\"\naddr = \"127.0.0.1:12000\"";
+        let t = Parser::new(s).parse().unwrap();
+        assert!(ServiceConf::from_toml_table(&t, "data").is_err());
+    }
+
+    #[test]
+    fn test_reachable_redirect_accepted() {
+        assert!(ship_at("0.0.0.0:13000", "203.0.113.5:13000").is_ok());
+        // LAN-only servers and ones only listening on loopback are fine too.
+        assert!(ship_at("0.0.0.0:13000", "192.168.1.2:13000").is_ok());
+        assert!(ship_at("127.0.0.1:13000", "127.0.0.1:13000").is_ok());
+    }
+
+    fn sqlite_pool_size(extra: &str) -> Result<usize, String> {
+        let s = format!("type = \"sqlite\"\nfile = \":memory:\"\n{}", extra);
+        let t = Parser::new(&s).parse().unwrap();
+        let c = try!(DbConf::from_toml_table(&t));
+        let p = c.make_pool().unwrap();
+        Ok(p.size())
+    }
+
+    #[test]
+    fn test_db_pool_size() {
+        assert_eq!(sqlite_pool_size(""), Ok(1));
+        assert_eq!(sqlite_pool_size("pool_size = 3"), Ok(3));
+        assert_eq!(sqlite_pool_size("pool_size = 0"), Err("sqlite DB pool_size must be at least 1".to_string()));
+    }
+
+    #[test]
+    fn test_default_example_parses() {
+        let c = Config::from_toml_string(&Config::default_example()).unwrap();
+        for kind in &["patch", "data", "login", "ship", "block", "shipgate"] {
+            assert!(c.services.iter().any(|s| s.kind() == *kind), "no {} service in the example", kind);
+        }
+        // The proxy is only an example, left commented out.
+        assert!(!c.services.iter().any(|s| s.kind() == "proxy"));
+        assert!(c.shipgate_password.starts_with("CHANGE_ME"));
+    }
+
+    fn with_version(version: &str) -> Result<Config, String> {
+        Config::from_toml_string(&format!(r#"
+            [idola]
+            {}
+            shipgate_addr = "127.0.0.1:6813"
+            shipgate_password = "pw"
+        "#, version))
+    }
+
+    #[test]
+    fn test_config_version_matches() {
+        assert!(with_version(&format!("version = {}", CONFIG_VERSION)).is_ok());
+    }
+
+    #[test]
+    fn test_config_version_mismatch() {
+        match with_version(&format!("version = {}", CONFIG_VERSION + 1)) {
+            Err(e) => assert_eq!(e, format!("config file is version {}, but this IDOLA expects version {}; \
+                                             see data/default/idola_local.toml for the current format",
+                                            CONFIG_VERSION + 1, CONFIG_VERSION)),
+            Ok(_) => panic!("mismatched version accepted")
+        }
+        assert!(with_version("version = \"1\"").is_err());
+    }
+
+    #[test]
+    fn test_config_version_missing() {
+        // Files written before the version field existed are version 1.
+        assert_eq!(CONFIG_VERSION, 1);
+        assert!(with_version("").is_ok());
+    }
+}
@@ -0,0 +1,325 @@
+//! A description of the configuration file's shape, for generating
+//! documentation or driving a config editor. This describes what the parsers
+//! in the parent module accept; it is not a config instance.
+//!
+//! When you add a field to a parser, add it here too. The tests at the bottom
+//! build a config out of each variant's `example` values and check the
+//! parser agrees with what is marked required.
+
+use toml::{Table, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Integer,
+    Bool,
+    /// A socket address string, IPv4 or IPv6, e.g. "127.0.0.1:11000"
+    Address,
+    /// An IPv4 socket address string.
+    Ipv4Address,
+    /// An array of the given type.
+    Array(&'static FieldType),
+    /// A table described by the named entry in `tables()`.
+    Table(&'static str),
+    /// An array of tables described by the named entry in `tables()`.
+    TableArray(&'static str)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub ty: FieldType,
+    pub required: bool,
+    /// The value used when the field is omitted, as a TOML literal.
+    pub default: Option<&'static str>,
+    /// A valid value for the field, as a TOML literal.
+    pub example: &'static str,
+    pub doc: &'static str
+}
+
+/// One variant of `ServiceConf` or `DbConf`, or a nested table.
+#[derive(Debug, Clone, Copy)]
+pub struct VariantSchema {
+    /// The value of the `type` key that selects this variant, or the name of
+    /// the nested table.
+    pub name: &'static str,
+    pub fields: &'static [FieldSchema]
+}
+
+const BIND: FieldSchema = FieldSchema { name: "bind", ty: FieldType::Address, required: true, default: None, example: "\"127.0.0.1:11000\"", doc: "Address to listen on, or \"unix:\" and a path to listen on a Unix domain socket." };
+const THROTTLE: FieldSchema = FieldSchema { name: "throttle", ty: FieldType::Table("throttle"), required: false, default: None, example: "{ rate = 131072 }", doc: "Per-client outbound bandwidth limit." };
+const LOG_LEVEL: FieldSchema = FieldSchema { name: "log_level", ty: FieldType::String, required: false, default: None, example: "\"debug\"", doc: "This service's log level: off, error, warn, info, debug or trace. Unset follows RUST_LOG." };
+const MAX_PER_IP: FieldSchema = FieldSchema { name: "max_per_ip", ty: FieldType::Integer, required: false, default: Some("16"), example: "4", doc: "Most connections at once from one IP address; more are closed as they connect. 0 disables." };
+
+static PATCH: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "v4_servers", ty: FieldType::Array(&FieldType::Ipv4Address), required: true, default: None, example: "[\"127.0.0.1:11001\"]", doc: "Data servers to redirect clients to." },
+    FieldSchema { name: "random_balance", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Pick data servers randomly instead of round-robin." },
+    FieldSchema { name: "motd", ty: FieldType::String, required: false, default: Some("\"\""), example: "\"Welcome\"", doc: "Message of the day. Alternatively, motd_file names a file under data_path to read it from." },
+    FieldSchema { name: "versions", ty: FieldType::Table("patch_version"), required: false, default: None, example: "{ PC = { motd = \"Hello PC\" } }", doc: "Overrides keyed by client version name, BlueBurst or PC." },
+    FieldSchema { name: "news", ty: FieldType::TableArray("news_item"), required: false, default: Some("[]"), example: "[{ text = \"Double drops this weekend!\", weight = 2 }]", doc: "News items shown below the MOTD in rotation. An array of plain strings works too." },
+    FieldSchema { name: "news_interval", ty: FieldType::Integer, required: false, default: Some("0"), example: "300", doc: "Seconds each news item stays up. 0 shows the next one on every connection." },
+    THROTTLE,
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static DATA: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "files_path", ty: FieldType::String, required: false, default: None, example: "\"patch\"", doc: "Directory of files to keep up to date on clients, relative to data_path. Read at startup; it must have files in it." },
+    THROTTLE,
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static LOGIN: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "version", ty: FieldType::String, required: true, default: None, example: "\"BlueBurst\"", doc: "Client version served. Only BlueBurst is supported." },
+    FieldSchema { name: "addr", ty: FieldType::Address, required: true, default: None, example: "\"127.0.0.1:12000\"", doc: "Address clients are redirected to for the character step. Must be IPv4 for Blue Burst." },
+    FieldSchema { name: "rules", ty: FieldType::String, required: false, default: None, example: "\"Be nice.\"", doc: "Rules players must accept before picking a ship." },
+    FieldSchema { name: "rules_version", ty: FieldType::Integer, required: false, default: Some("1"), example: "2", doc: "Raise to make everyone accept the rules again." },
+    FieldSchema { name: "allowed_classes", ty: FieldType::Array(&FieldType::String), required: false, default: None, example: "[\"HUmar\", \"RAmar\"]", doc: "The only classes players may create. Not with disallowed_classes." },
+    FieldSchema { name: "disallowed_classes", ty: FieldType::Array(&FieldType::String), required: false, default: None, example: "[\"FOnewm\"]", doc: "Classes players may not create. Not with allowed_classes." },
+    FieldSchema { name: "restrict_existing_classes", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Existing characters of disallowed classes can't be played either." },
+    THROTTLE,
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static PROXY: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "target", ty: FieldType::Ipv4Address, required: true, default: None, example: "\"127.0.0.1:12000\"", doc: "Address every client is redirected to." },
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static SHIP: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "name", ty: FieldType::String, required: true, default: None, example: "\"IDOLA\"", doc: "Ship name shown in the ship list." },
+    FieldSchema { name: "addr", ty: FieldType::Ipv4Address, required: true, default: None, example: "\"127.0.0.1:13000\"", doc: "Address clients use to reach this ship. Formerly my_ipv4, which is still read." },
+    FieldSchema { name: "block", ty: FieldType::TableArray("block"), required: true, default: None, example: "[{ name = \"BLOCK01\", addr = \"127.0.0.1:13001\" }]", doc: "Blocks listed on this ship." },
+    FieldSchema { name: "max_blocks", ty: FieldType::Integer, required: false, default: Some("20"), example: "20", doc: "Most blocks the ship may list." },
+    FieldSchema { name: "event", ty: FieldType::Integer, required: false, default: None, example: "0", doc: "Default seasonal event for this ship's blocks: 0, 1 or 3-14." },
+    FieldSchema { name: "random_balance", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Drain a block's players to the ship's other blocks randomly instead of round-robin." },
+    THROTTLE,
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static BLOCK: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "num", ty: FieldType::Integer, required: false, default: Some("1"), example: "1", doc: "Block number." },
+    FieldSchema { name: "lobbies", ty: FieldType::Integer, required: false, default: Some("15"), example: "15", doc: "Number of lobbies, 1 to 15." },
+    FieldSchema { name: "event", ty: FieldType::Integer, required: false, default: None, example: "0", doc: "Seasonal event for the lobbies. Overrides the ship's event; 0 if neither is set." },
+    FieldSchema { name: "reconnect_grace", ty: FieldType::Integer, required: false, default: Some("0"), example: "10", doc: "Seconds to hold a dropped player's lobby or party slot." },
+    FieldSchema { name: "slow_handler_ms", ty: FieldType::Integer, required: false, default: Some("100"), example: "250", doc: "Warn when one client message takes longer than this to handle. 0 disables." },
+    FieldSchema { name: "idle_timeout", ty: FieldType::Integer, required: false, default: Some("600"), example: "300", doc: "Ping players silent for this many seconds, and drop those who don't answer. 0 disables." },
+    FieldSchema { name: "max_players", ty: FieldType::Integer, required: false, default: Some("0"), example: "150", doc: "Most players logged in on the block at once; more are refused at login. 0 disables." },
+    FieldSchema { name: "merge_below", ty: FieldType::Integer, required: false, default: Some("0"), example: "8", doc: "Below this block population, send new players to the busiest lobby. 0 disables." },
+    FieldSchema { name: "merge_migrate_interval", ty: FieldType::Integer, required: false, default: Some("0"), example: "60", doc: "While merging, move one straggler to the busiest lobby this often, in seconds. 0 disables." },
+    FieldSchema { name: "inventory_slots", ty: FieldType::Integer, required: false, default: Some("30"), example: "30", doc: "Inventory slots a player may fill by picking items up, 1 to 30." },
+    FieldSchema { name: "tool_stack_limit", ty: FieldType::Integer, required: false, default: Some("10"), example: "10", doc: "Most tools of one kind in a single inventory slot." },
+    FieldSchema { name: "rest_exp_per_minute", ty: FieldType::Integer, required: false, default: Some("0"), example: "50", doc: "Rested experience earned per minute in a lobby. 0 disables." },
+    FieldSchema { name: "rest_exp_cap", ty: FieldType::Integer, required: false, default: Some("10000"), example: "10000", doc: "Most rested experience a player can bank." },
+    FieldSchema { name: "lobby_change_limit", ty: FieldType::Integer, required: false, default: Some("5"), example: "5", doc: "Lobby changes a player may make per lobby_change_window. 0 disables." },
+    FieldSchema { name: "lobby_change_window", ty: FieldType::Integer, required: false, default: Some("10"), example: "10", doc: "Seconds over which lobby_change_limit counts." },
+    FieldSchema { name: "lobby_change_kick", ty: FieldType::Integer, required: false, default: Some("0"), example: "20", doc: "Disconnect after this many ignored lobby changes in a row. 0 disables." },
+    FieldSchema { name: "chat_limit", ty: FieldType::Integer, required: false, default: Some("8"), example: "8", doc: "Chat messages a player may send per chat_window. 0 disables." },
+    FieldSchema { name: "chat_window", ty: FieldType::Integer, required: false, default: Some("10"), example: "10", doc: "Seconds over which chat_limit counts." },
+    FieldSchema { name: "chat_log", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Keep lobby chat and whispers in the database's chat_log table for moderation." },
+    FieldSchema { name: "track_playtime", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Total up each account's and character's play time, shown by /played." },
+    FieldSchema { name: "playtime_checkpoint", ty: FieldType::Integer, required: false, default: Some("300"), example: "60", doc: "Save play time this often, in seconds, as well as on disconnect. 0 only on disconnect." },
+    FieldSchema { name: "lobby_minigames", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Let players use lobby minigames." },
+    FieldSchema { name: "minigame_free_lobbies", ty: FieldType::Array(&FieldType::Integer), required: false, default: Some("[]"), example: "[1, 2]", doc: "Lobbies, 1 to 15, where lobby minigames are off." },
+    FieldSchema { name: "lobby_minigame_subcmds", ty: FieldType::Array(&FieldType::Integer), required: false, default: Some("[121]"), example: "[121]", doc: "The 0x60 subcommands that count as lobby minigames." },
+    FieldSchema { name: "suspicion_kick", ty: FieldType::Integer, required: false, default: Some("0"), example: "10", doc: "Disconnect after failed validation checks add up to this many points in a session. 0 disables." },
+    FieldSchema { name: "suspicion_ban", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Also ban the account when disconnecting for suspicion." },
+    FieldSchema { name: "gm_guildcards", ty: FieldType::Array(&FieldType::Integer), required: false, default: Some("[]"), example: "[42000001]", doc: "Guild card numbers allowed to use GM commands." },
+    FieldSchema { name: "enforce_identification", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Refuse to equip or sell weapons that haven't been identified at the tekker." },
+    FieldSchema { name: "save_on_drop", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Save players who drop without logging out, unless they were in the middle of something." },
+    FieldSchema { name: "banned_items", ty: FieldType::Array(&FieldType::String), required: false, default: None, example: "[\"000105\", \"0301\"]", doc: "Hex item codes that can't be picked up, withdrawn from a bank or equipped." },
+    FieldSchema { name: "strip_banned_items", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Remove banned items from characters and banks when they load." },
+    FieldSchema { name: "floor_item_lifetime", ty: FieldType::Integer, required: false, default: Some("3600"), example: "1800", doc: "Seconds before an item left on a game's floor disappears. 0 disables." },
+    FieldSchema { name: "restart_warnings", ty: FieldType::Array(&FieldType::Integer), required: false, default: Some("[30, 15, 5, 1]"), example: "[10, 1]", doc: "Minutes before a GM's scheduled restart at which players are warned." },
+    FieldSchema { name: "max_meseta", ty: FieldType::Integer, required: false, default: Some("999999"), example: "500000", doc: "Most meseta a character may carry." },
+    FieldSchema { name: "max_level", ty: FieldType::Integer, required: false, default: Some("200"), example: "100", doc: "Level past which characters gain no experience." },
+    FieldSchema { name: "area_validation", ty: FieldType::String, required: false, default: Some("\"log\""), example: "\"enforce\"", doc: "Checking of area changes in games: off, log or enforce." },
+    THROTTLE,
+    MAX_PER_IP,
+    LOG_LEVEL
+];
+
+static SHIPGATE: &'static [FieldSchema] = &[
+    BIND,
+    FieldSchema { name: "password", ty: FieldType::String, required: true, default: None, example: "\"CHANGE_ME\"", doc: "Password ships use to authenticate." },
+    FieldSchema { name: "db", ty: FieldType::Table("db"), required: true, default: None, example: "{ type = \"sqlite\", file = \"local.db\" }", doc: "Database backend." },
+    FieldSchema { name: "storage_quota", ty: FieldType::Integer, required: false, default: Some("1200"), example: "600", doc: "Most items an account may store across characters, their banks and the shared bank. The default fits four characters with everything full. 0 disables." },
+    FieldSchema { name: "shared_bank_slots", ty: FieldType::Integer, required: false, default: Some("200"), example: "200", doc: "Entries in each account's shared bank, 1 to 200." },
+    FieldSchema { name: "unique_names", ty: FieldType::Bool, required: false, default: Some("false"), example: "true", doc: "Refuse to create a character whose name another character already has." },
+    FieldSchema { name: "batch_interval", ty: FieldType::Integer, required: false, default: Some("0"), example: "10", doc: "Seconds to collect play time updates and chat log lines before saving them in one transaction. 0 disables." },
+    FieldSchema { name: "batch_size", ty: FieldType::Integer, required: false, default: Some("500"), example: "500", doc: "Save collected updates early once this many are waiting." },
+    FieldSchema { name: "maintenance_hour", ty: FieldType::Integer, required: false, default: None, example: "4", doc: "Local hour, 0 to 23, to compact the database each day. Unset disables." },
+    FieldSchema { name: "maintenance_max_requests", ty: FieldType::Integer, required: false, default: Some("30"), example: "30", doc: "Put maintenance off while a minute sees more requests than this." },
+    LOG_LEVEL
+];
+
+static SQLITE: &'static [FieldSchema] = &[
+    FieldSchema { name: "file", ty: FieldType::String, required: true, default: None, example: "\"local.db\"", doc: "Path to the database file." },
+    FieldSchema { name: "pool_size", ty: FieldType::Integer, required: false, default: Some("1"), example: "1", doc: "Connections in the pool." },
+    FieldSchema { name: "reconnect", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Check the connection before each use and reopen it if it was lost." }
+];
+
+static MYSQL: &'static [FieldSchema] = &[
+    FieldSchema { name: "host", ty: FieldType::String, required: true, default: None, example: "\"db.example.com\"", doc: "Host name or address of the MySQL server." },
+    FieldSchema { name: "port", ty: FieldType::Integer, required: false, default: Some("3306"), example: "3306", doc: "Port of the MySQL server." },
+    FieldSchema { name: "user", ty: FieldType::String, required: true, default: None, example: "\"idola\"", doc: "User to connect as." },
+    FieldSchema { name: "password", ty: FieldType::String, required: false, default: Some("\"\""), example: "\"secret\"", doc: "The user's password." },
+    FieldSchema { name: "database", ty: FieldType::String, required: true, default: None, example: "\"idola\"", doc: "Database to use. Tables are created in it as needed." },
+    FieldSchema { name: "pool_size", ty: FieldType::Integer, required: false, default: Some("4"), example: "8", doc: "Connections in the pool." },
+    FieldSchema { name: "reconnect", ty: FieldType::Bool, required: false, default: Some("true"), example: "false", doc: "Check connections before each use and reopen any that were lost." }
+];
+
+static THROTTLE_TABLE: &'static [FieldSchema] = &[
+    FieldSchema { name: "rate", ty: FieldType::Integer, required: true, default: None, example: "131072", doc: "Bytes per second." },
+    FieldSchema { name: "min_size", ty: FieldType::Integer, required: false, default: Some("1024"), example: "1024", doc: "Messages smaller than this are never delayed." }
+];
+
+static PATCH_VERSION_TABLE: &'static [FieldSchema] = &[
+    FieldSchema { name: "motd", ty: FieldType::String, required: false, default: None, example: "\"Hello\"", doc: "Message of the day for this version." },
+    FieldSchema { name: "v4_servers", ty: FieldType::Array(&FieldType::Ipv4Address), required: false, default: None, example: "[\"127.0.0.1:11001\"]", doc: "Data servers for this version." }
+];
+
+static NEWS_ITEM_TABLE: &'static [FieldSchema] = &[
+    FieldSchema { name: "text", ty: FieldType::String, required: true, default: None, example: "\"Double drops this weekend!\"", doc: "The news item." },
+    FieldSchema { name: "weight", ty: FieldType::Integer, required: false, default: Some("1"), example: "2", doc: "Turns the item gets in each pass through the list, 1 to 100." }
+];
+
+static BLOCK_TABLE: &'static [FieldSchema] = &[
+    FieldSchema { name: "name", ty: FieldType::String, required: true, default: None, example: "\"BLOCK01\"", doc: "Name shown in the block list." },
+    FieldSchema { name: "addr", ty: FieldType::Ipv4Address, required: true, default: None, example: "\"127.0.0.1:13001\"", doc: "Address clients are redirected to." }
+];
+
+/// Every `ServiceConf` variant, keyed by its `type` value.
+pub fn services() -> Vec<VariantSchema> {
+    vec![
+        VariantSchema { name: "patch", fields: PATCH },
+        VariantSchema { name: "data", fields: DATA },
+        VariantSchema { name: "login", fields: LOGIN },
+        VariantSchema { name: "ship", fields: SHIP },
+        VariantSchema { name: "block", fields: BLOCK },
+        VariantSchema { name: "proxy", fields: PROXY },
+        VariantSchema { name: "shipgate", fields: SHIPGATE }
+    ]
+}
+
+/// Every `DbConf` variant, keyed by its `type` value.
+pub fn dbs() -> Vec<VariantSchema> {
+    vec![
+        VariantSchema { name: "sqlite", fields: SQLITE },
+        VariantSchema { name: "mysql", fields: MYSQL }
+    ]
+}
+
+/// Nested tables referenced by `FieldType::Table` and `FieldType::TableArray`.
+/// The `db` table is one of the variants in `dbs()`.
+pub fn tables() -> Vec<VariantSchema> {
+    vec![
+        VariantSchema { name: "throttle", fields: THROTTLE_TABLE },
+        VariantSchema { name: "patch_version", fields: PATCH_VERSION_TABLE },
+        VariantSchema { name: "news_item", fields: NEWS_ITEM_TABLE },
+        VariantSchema { name: "block", fields: BLOCK_TABLE }
+    ]
+}
+
+impl FieldType {
+    pub fn describe(&self) -> String {
+        match self {
+            &FieldType::String => "string".to_string(),
+            &FieldType::Integer => "integer".to_string(),
+            &FieldType::Bool => "bool".to_string(),
+            &FieldType::Address => "address".to_string(),
+            &FieldType::Ipv4Address => "ipv4 address".to_string(),
+            &FieldType::Array(t) => format!("array of {}", t.describe()),
+            &FieldType::Table(n) => format!("table {}", n),
+            &FieldType::TableArray(n) => format!("array of table {}", n)
+        }
+    }
+}
+
+impl FieldSchema {
+    pub fn to_toml(&self) -> Value {
+        let mut t = Table::new();
+        t.insert("name".to_string(), Value::String(self.name.to_string()));
+        t.insert("type".to_string(), Value::String(self.ty.describe()));
+        t.insert("required".to_string(), Value::Boolean(self.required));
+        if let Some(d) = self.default {
+            t.insert("default".to_string(), Value::String(d.to_string()));
+        }
+        t.insert("example".to_string(), Value::String(self.example.to_string()));
+        t.insert("doc".to_string(), Value::String(self.doc.to_string()));
+        Value::Table(t)
+    }
+}
+
+impl VariantSchema {
+    pub fn to_toml(&self) -> Value {
+        let mut t = Table::new();
+        t.insert("name".to_string(), Value::String(self.name.to_string()));
+        t.insert("fields".to_string(), Value::Array(self.fields.iter().map(|f| f.to_toml()).collect()));
+        Value::Table(t)
+    }
+}
+
+/// The whole schema as a TOML value, with `service`, `db` and `table` arrays.
+pub fn to_toml() -> Value {
+    let mut t = Table::new();
+    t.insert("service".to_string(), Value::Array(services().iter().map(|v| v.to_toml()).collect()));
+    t.insert("db".to_string(), Value::Array(dbs().iter().map(|v| v.to_toml()).collect()));
+    t.insert("table".to_string(), Value::Array(tables().iter().map(|v| v.to_toml()).collect()));
+    Value::Table(t)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toml::Parser;
+    use ::config::{ServiceConf, DbConf};
+
+    fn example_table(ty: &str, fields: &[FieldSchema], skip: Option<&str>) -> String {
+        let mut s = format!("type = \"{}\"\n", ty);
+        for f in fields {
+            if Some(f.name) != skip {
+                s.push_str(&format!("{} = {}\n", f.name, f.example));
+            }
+        }
+        s
+    }
+
+    #[test]
+    fn test_service_schema_matches_parser() {
+        for v in services() {
+            let s = example_table(v.name, v.fields, None);
+            let t = Parser::new(&s).parse().unwrap();
+            assert!(ServiceConf::from_toml_table(&t, "data").is_ok(), "{} example rejected", v.name);
+            for f in v.fields.iter().filter(|f| f.required) {
+                let s = example_table(v.name, v.fields, Some(f.name));
+                let t = Parser::new(&s).parse().unwrap();
+                assert!(ServiceConf::from_toml_table(&t, "data").is_err(), "{} accepted without {}", v.name, f.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_schema_matches_parser() {
+        for v in dbs() {
+            let s = example_table(v.name, v.fields, None);
+            let t = Parser::new(&s).parse().unwrap();
+            assert!(DbConf::from_toml_table(&t).is_ok(), "{} example rejected", v.name);
+            for f in v.fields.iter().filter(|f| f.required) {
+                let s = example_table(v.name, v.fields, Some(f.name));
+                let t = Parser::new(&s).parse().unwrap();
+                assert!(DbConf::from_toml_table(&t).is_err(), "{} accepted without {}", v.name, f.name);
+            }
+        }
+    }
+}
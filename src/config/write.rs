@@ -0,0 +1,497 @@
+//! Writing a `Config` back out as TOML, for tools that change settings and
+//! save them. What's written parses back to an equal `Config`. Environment
+//! variables in the original file were expanded when it was read, so their
+//! values are what's written.
+
+use toml::{Table, Value};
+
+use ::holidays::{self, Holiday, HolidayDates};
+
+use super::*;
+
+fn string<T: ToString>(v: T) -> Value {
+    Value::String(v.to_string())
+}
+
+fn int(v: i64) -> Value {
+    Value::Integer(v)
+}
+
+fn strings<T: ToString>(v: &[T]) -> Value {
+    Value::Array(v.iter().map(string).collect())
+}
+
+fn ints<T: Copy + Into<i64>>(v: &[T]) -> Value {
+    Value::Array(v.iter().map(|i| int((*i).into())).collect())
+}
+
+fn month_day(d: (u8, u8)) -> Value {
+    Value::String(format!("{:02}-{:02}", d.0, d.1))
+}
+
+impl Config {
+    pub fn to_toml_string(&self) -> String {
+        Value::Table(self.to_toml_table()).to_string()
+    }
+
+    pub fn to_toml_table(&self) -> Table {
+        let mut i = Table::new();
+        i.insert("version".to_string(), int(CONFIG_VERSION));
+        i.insert("data_path".to_string(), string(&self.data_path));
+        i.insert("bb_keytable_path".to_string(), string(&self.bb_keytable_path));
+        i.insert("stack_limits_path".to_string(), string(&self.stack_limits_path));
+        i.insert("shipgate_addr".to_string(), string(&self.shipgate_addr));
+        i.insert("shipgate_password".to_string(), string(&self.shipgate_password));
+        if let Some(ref l) = self.event_log {
+            i.insert("event_log".to_string(), string(l));
+        }
+        i.insert("handshake_timeout".to_string(), int(self.handshake_timeout as i64));
+        i.insert("reject_key_mismatch".to_string(), Value::Boolean(self.reject_key_mismatch));
+        i.insert("first_packet_window_ms".to_string(), int(self.first_packet_window_ms as i64));
+        i.insert("shipgate_timeout".to_string(), int(self.shipgate_timeout as i64));
+        i.insert("memory_limit_mb".to_string(), int(self.memory_limit_mb as i64));
+        if let Some(ref s) = self.clock_check.ntp_server {
+            i.insert("clock_ntp_server".to_string(), string(s));
+        }
+        i.insert("clock_max_skew".to_string(), int(self.clock_check.max_skew as i64));
+        i.insert("clock_skew_fatal".to_string(), Value::Boolean(self.clock_check.fatal));
+
+        let mut t = Table::new();
+        t.insert("idola".to_string(), Value::Table(i));
+        t.insert("holidays".to_string(), Value::Table(holidays_to_toml_table(&self.holidays)));
+        if !self.services.is_empty() {
+            t.insert("service".to_string(), Value::Array(self.services.iter().map(|s| Value::Table(s.to_toml_table())).collect()));
+        }
+        t
+    }
+}
+
+/// The `[holidays]` table for `list`: built-in holidays it leaves out are
+/// turned off, and everything in it is written out in full.
+fn holidays_to_toml_table(list: &[Holiday]) -> Table {
+    let mut t = Table::new();
+    t.insert("enabled".to_string(), Value::Boolean(!list.is_empty()));
+    if list.is_empty() {
+        return t
+    }
+    for d in holidays::defaults() {
+        if !list.iter().any(|h| h.name == d.name) {
+            t.insert(d.name.clone(), Value::Boolean(false));
+        }
+    }
+    for h in list {
+        let mut o = Table::new();
+        o.insert("event".to_string(), int(h.event as i64));
+        // Easter's dates move, so only the built-in one can have them, and
+        // they can't be written.
+        if let HolidayDates::Fixed(start, end) = h.dates {
+            o.insert("start".to_string(), month_day(start));
+            o.insert("end".to_string(), month_day(end));
+        }
+        t.insert(h.name.clone(), Value::Table(o));
+    }
+    t
+}
+
+impl ServiceConf {
+    /// The `[[service]]` table for this service, with its `type`.
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        t.insert("type".to_string(), string(self.kind()));
+        t.insert("bind".to_string(), string(self.bind()));
+        if let Some(th) = self.throttle() {
+            t.insert("throttle".to_string(), Value::Table(th.to_toml_table()));
+        }
+        if let Some(m) = self.max_per_ip() {
+            t.insert("max_per_ip".to_string(), int(m as i64));
+        }
+        if let Some(l) = self.log_level() {
+            t.insert("log_level".to_string(), string(l.to_string().to_lowercase()));
+        }
+        match self {
+            &ServiceConf::Patch { ref motd, ref v4_servers, random_balance, ref versions, ref news, news_interval, .. } => {
+                t.insert("motd".to_string(), string(motd));
+                t.insert("v4_servers".to_string(), strings(v4_servers));
+                t.insert("random_balance".to_string(), Value::Boolean(random_balance));
+                if !versions.is_empty() {
+                    let mut vt = Table::new();
+                    for v in versions {
+                        vt.insert(format!("{:?}", v.version), Value::Table(v.to_toml_table()));
+                    }
+                    t.insert("versions".to_string(), Value::Table(vt));
+                }
+                if !news.is_empty() {
+                    t.insert("news".to_string(), Value::Array(news.iter().map(|n| Value::Table(n.to_toml_table())).collect()));
+                }
+                t.insert("news_interval".to_string(), int(news_interval as i64));
+            },
+            &ServiceConf::Data { ref files_path, .. } => {
+                if let Some(ref p) = *files_path {
+                    t.insert("files_path".to_string(), string(p));
+                }
+            },
+            &ServiceConf::Login { version, addr, ref rules, ref class_restrictions, .. } => {
+                t.insert("version".to_string(), string(format!("{:?}", version)));
+                t.insert("addr".to_string(), string(addr));
+                if let Some(ref r) = *rules {
+                    t.insert("rules".to_string(), string(&r.text));
+                    t.insert("rules_version".to_string(), int(r.version as i64));
+                }
+                if let Some(ref c) = *class_restrictions {
+                    t.insert("disallowed_classes".to_string(), strings(&c.disallowed));
+                    t.insert("restrict_existing_classes".to_string(), Value::Boolean(c.existing));
+                }
+            },
+            &ServiceConf::Ship { ref name, addr, ref blocks, max_blocks, event, random_balance, .. } => {
+                t.insert("name".to_string(), string(name));
+                t.insert("addr".to_string(), string(addr));
+                t.insert("block".to_string(), Value::Array(blocks.iter().map(|b| Value::Table(b.to_toml_table())).collect()));
+                t.insert("max_blocks".to_string(), int(max_blocks as i64));
+                if let Some(e) = event {
+                    t.insert("event".to_string(), int(e as i64));
+                }
+                t.insert("random_balance".to_string(), Value::Boolean(random_balance));
+            },
+            &ServiceConf::Block { num, lobbies, event, event_override, ref options, .. } => {
+                t.insert("num".to_string(), int(num as i64));
+                t.insert("lobbies".to_string(), int(lobbies as i64));
+                // Otherwise the event comes from the ship, which fills it in
+                // again as the config is read.
+                if event_override {
+                    t.insert("event".to_string(), int(event as i64));
+                }
+                options.write_toml_table(&mut t);
+            },
+            &ServiceConf::Proxy { target, .. } => {
+                t.insert("target".to_string(), string(target));
+            },
+            &ServiceConf::ShipGate { ref password, ref db, storage_quota, shared_bank_slots, unique_names, batch_interval, batch_size, maintenance_hour, maintenance_max_requests, .. } => {
+                t.insert("password".to_string(), string(password));
+                t.insert("db".to_string(), Value::Table(db.to_toml_table()));
+                t.insert("storage_quota".to_string(), int(storage_quota as i64));
+                t.insert("shared_bank_slots".to_string(), int(shared_bank_slots as i64));
+                t.insert("unique_names".to_string(), Value::Boolean(unique_names));
+                t.insert("batch_interval".to_string(), int(batch_interval as i64));
+                t.insert("batch_size".to_string(), int(batch_size as i64));
+                if let Some(h) = maintenance_hour {
+                    t.insert("maintenance_hour".to_string(), int(h as i64));
+                }
+                t.insert("maintenance_max_requests".to_string(), int(maintenance_max_requests as i64));
+            }
+        }
+        t
+    }
+}
+
+impl DbConf {
+    /// The `db` table for this database, with its `type`.
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        match self {
+            &DbConf::Sqlite { ref file, pool_size, reconnect } => {
+                t.insert("type".to_string(), string("sqlite"));
+                t.insert("file".to_string(), string(file));
+                t.insert("pool_size".to_string(), int(pool_size as i64));
+                t.insert("reconnect".to_string(), Value::Boolean(reconnect));
+            },
+            &DbConf::MySql { ref host, port, ref user, ref password, ref database, pool_size, reconnect } => {
+                t.insert("type".to_string(), string("mysql"));
+                t.insert("host".to_string(), string(host));
+                t.insert("port".to_string(), int(port as i64));
+                t.insert("user".to_string(), string(user));
+                t.insert("password".to_string(), string(password));
+                t.insert("database".to_string(), string(database));
+                t.insert("pool_size".to_string(), int(pool_size as i64));
+                t.insert("reconnect".to_string(), Value::Boolean(reconnect));
+            }
+        }
+        t
+    }
+}
+
+impl PatchVersionConf {
+    /// The override table, without the version it's keyed by.
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        if let Some(ref m) = self.motd {
+            t.insert("motd".to_string(), string(m));
+        }
+        if let Some(ref s) = self.v4_servers {
+            t.insert("v4_servers".to_string(), strings(s));
+        }
+        t
+    }
+}
+
+impl NewsItem {
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        t.insert("text".to_string(), string(&self.text));
+        t.insert("weight".to_string(), int(self.weight as i64));
+        t
+    }
+}
+
+impl ThrottleConf {
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        t.insert("rate".to_string(), int(self.rate as i64));
+        t.insert("min_size".to_string(), int(self.min_size as i64));
+        t
+    }
+}
+
+impl BlockConf {
+    pub fn to_toml_table(&self) -> Table {
+        let mut t = Table::new();
+        t.insert("name".to_string(), string(&self.name));
+        t.insert("addr".to_string(), string(self.addr));
+        t
+    }
+}
+
+impl BlockOptions {
+    /// Add the block tunables to a block service's table.
+    pub fn write_toml_table(&self, t: &mut Table) {
+        t.insert("reconnect_grace".to_string(), int(self.reconnect_grace as i64));
+        t.insert("slow_handler_ms".to_string(), int(self.slow_handler_ms as i64));
+        t.insert("idle_timeout".to_string(), int(self.idle_timeout as i64));
+        t.insert("max_players".to_string(), int(self.max_players as i64));
+        t.insert("merge_below".to_string(), int(self.merge_below as i64));
+        t.insert("merge_migrate_interval".to_string(), int(self.merge_migrate_interval as i64));
+        t.insert("inventory_slots".to_string(), int(self.inventory_slots as i64));
+        t.insert("tool_stack_limit".to_string(), int(self.tool_stack_limit as i64));
+        t.insert("rest_exp_per_minute".to_string(), int(self.rest_exp_per_minute as i64));
+        t.insert("rest_exp_cap".to_string(), int(self.rest_exp_cap as i64));
+        t.insert("lobby_change_limit".to_string(), int(self.lobby_change_limit as i64));
+        t.insert("lobby_change_window".to_string(), int(self.lobby_change_window as i64));
+        t.insert("lobby_change_kick".to_string(), int(self.lobby_change_kick as i64));
+        t.insert("chat_limit".to_string(), int(self.chat_limit as i64));
+        t.insert("chat_window".to_string(), int(self.chat_window as i64));
+        t.insert("chat_log".to_string(), Value::Boolean(self.chat_log));
+        t.insert("track_playtime".to_string(), Value::Boolean(self.track_playtime));
+        t.insert("playtime_checkpoint".to_string(), int(self.playtime_checkpoint as i64));
+        t.insert("lobby_minigames".to_string(), Value::Boolean(self.lobby_minigames));
+        t.insert("minigame_free_lobbies".to_string(), ints(&self.minigame_free_lobbies));
+        t.insert("lobby_minigame_subcmds".to_string(), ints(&self.lobby_minigame_subcmds));
+        t.insert("suspicion_kick".to_string(), int(self.suspicion_kick as i64));
+        t.insert("suspicion_ban".to_string(), Value::Boolean(self.suspicion_ban));
+        t.insert("gm_guildcards".to_string(), ints(&self.gm_guildcards));
+        t.insert("enforce_identification".to_string(), Value::Boolean(self.enforce_identification));
+        t.insert("save_on_drop".to_string(), Value::Boolean(self.save_on_drop));
+        t.insert("banned_items".to_string(), Value::Array(self.banned_items.iter().map(|code| {
+            Value::String(code.iter().map(|b| format!("{:02X}", b)).collect())
+        }).collect()));
+        t.insert("strip_banned_items".to_string(), Value::Boolean(self.strip_banned_items));
+        t.insert("floor_item_lifetime".to_string(), int(self.floor_item_lifetime as i64));
+        t.insert("restart_warnings".to_string(), ints(&self.restart_warnings));
+        t.insert("max_meseta".to_string(), int(self.max_meseta as i64));
+        t.insert("max_level".to_string(), int(self.max_level as i64));
+        t.insert("area_validation".to_string(), string(match self.area_validation {
+            AreaValidation::Off => "off",
+            AreaValidation::Log => "log",
+            AreaValidation::Enforce => "enforce"
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use ::config::*;
+
+    use log::LogLevelFilter;
+
+    use ::game::{Version, CharClass};
+    use ::holidays::{self, Holiday, HolidayDates};
+
+    fn addr(s: &str) -> ServiceAddr {
+        ServiceAddr::Tcp(s.parse().unwrap())
+    }
+
+    /// One of every service and db type, with most settings away from their
+    /// defaults.
+    fn every_service() -> Config {
+        let mut options = BlockOptions::default();
+        options.reconnect_grace = 30;
+        options.max_players = 150;
+        options.chat_log = true;
+        options.minigame_free_lobbies = vec![1, 15];
+        options.gm_guildcards = vec![42000001];
+        options.banned_items = vec![vec![0x00, 0x01, 0x05], vec![0x03, 0x01]];
+        options.restart_warnings = vec![10, 1];
+        options.max_level = 100;
+        options.area_validation = AreaValidation::Enforce;
+        let mut hols = holidays::defaults();
+        hols.retain(|h| h.name != "easter");
+        hols[0].event = 8;
+        hols.push(Holiday::new("summer", 8, HolidayDates::Fixed((7, 1), (8, 31))));
+        let throttle = Some(ThrottleConf { rate: 131072, min_size: 512 });
+        Config {
+            data_path: "data".to_string(),
+            bb_keytable_path: "data/crypto/bb_table.bin".to_string(),
+            stack_limits_path: "data/stack_limits.toml".to_string(),
+            shipgate_addr: addr("127.0.0.1:6813"),
+            shipgate_password: "pw \"quoted\"".to_string(),
+            event_log: Some("events.jsonl".to_string()),
+            handshake_timeout: 20,
+            reject_key_mismatch: false,
+            first_packet_window_ms: 5000,
+            shipgate_timeout: 0,
+            memory_limit_mb: 1024,
+            holidays: hols,
+            clock_check: ClockCheckConf {
+                ntp_server: Some("pool.ntp.org".to_string()),
+                max_skew: 30,
+                fatal: true
+            },
+            services: vec![
+                ServiceConf::Patch {
+                    bind: addr("127.0.0.1:11000"),
+                    motd: "Hello".to_string(),
+                    v4_servers: vec!["127.0.0.1:11001".parse().unwrap()],
+                    random_balance: true,
+                    versions: vec![PatchVersionConf {
+                        version: Version::PC,
+                        motd: Some("pc".to_string()),
+                        v4_servers: None
+                    }],
+                    news: vec![NewsItem { text: "Event this weekend".to_string(), weight: 2 }],
+                    news_interval: 60,
+                    throttle: throttle.clone(),
+                    max_per_ip: 4,
+                    log_level: None
+                },
+                ServiceConf::Data {
+                    bind: ServiceAddr::Unix(PathBuf::from("/run/idola/data.sock")),
+                    files_path: Some("patch".to_string()),
+                    throttle: None,
+                    max_per_ip: DEFAULT_MAX_PER_IP,
+                    log_level: None
+                },
+                ServiceConf::Login {
+                    bind: addr("127.0.0.1:12000"),
+                    version: Version::BlueBurst,
+                    addr: "127.0.0.1:12000".parse().unwrap(),
+                    throttle: None,
+                    max_per_ip: 0,
+                    rules: Some(RulesConf { text: "Be nice.".to_string(), version: 2 }),
+                    class_restrictions: Some(ClassRestrictions {
+                        disallowed: vec![CharClass::HUcast, CharClass::FOmarl],
+                        existing: true
+                    }),
+                    log_level: Some(LogLevelFilter::Warn)
+                },
+                ServiceConf::Ship {
+                    bind: addr("127.0.0.1:13000"),
+                    name: "IDOLA".to_string(),
+                    addr: "127.0.0.1:13000".parse().unwrap(),
+                    blocks: vec![
+                        BlockConf { name: "BLOCK01".to_string(), addr: "127.0.0.1:13001".parse().unwrap() },
+                        BlockConf { name: "BLOCK02".to_string(), addr: "127.0.0.1:13002".parse().unwrap() }
+                    ],
+                    max_blocks: 10,
+                    event: Some(5),
+                    random_balance: true,
+                    throttle: None,
+                    max_per_ip: DEFAULT_MAX_PER_IP,
+                    log_level: None
+                },
+                ServiceConf::Block {
+                    bind: addr("127.0.0.1:13001"),
+                    num: 1,
+                    lobbies: 15,
+                    event: 5,
+                    event_override: false,
+                    ship: Some("IDOLA".to_string()),
+                    siblings: vec![BlockConf { name: "BLOCK02".to_string(), addr: "127.0.0.1:13002".parse().unwrap() }],
+                    random_balance: true,
+                    options: options,
+                    throttle: throttle,
+                    max_per_ip: DEFAULT_MAX_PER_IP,
+                    log_level: Some(LogLevelFilter::Debug)
+                },
+                ServiceConf::Block {
+                    bind: addr("127.0.0.1:13002"),
+                    num: 2,
+                    lobbies: 4,
+                    event: 7,
+                    event_override: true,
+                    ship: Some("IDOLA".to_string()),
+                    siblings: vec![BlockConf { name: "BLOCK01".to_string(), addr: "127.0.0.1:13001".parse().unwrap() }],
+                    random_balance: true,
+                    options: BlockOptions::default(),
+                    throttle: None,
+                    max_per_ip: DEFAULT_MAX_PER_IP,
+                    log_level: None
+                },
+                ServiceConf::Proxy {
+                    bind: addr("0.0.0.0:12500"),
+                    target: "127.0.0.1:12000".parse().unwrap(),
+                    max_per_ip: 8,
+                    log_level: None
+                },
+                ServiceConf::ShipGate {
+                    bind: addr("127.0.0.1:6813"),
+                    password: "pw".to_string(),
+                    db: DbConf::Sqlite { file: "local.db".to_string(), pool_size: 1, reconnect: false },
+                    storage_quota: 0,
+                    shared_bank_slots: 100,
+                    unique_names: true,
+                    batch_interval: 10,
+                    batch_size: 500,
+                    maintenance_hour: Some(4),
+                    maintenance_max_requests: 30,
+                    log_level: None
+                },
+                ServiceConf::ShipGate {
+                    bind: addr("127.0.0.1:6814"),
+                    password: "pw2".to_string(),
+                    db: DbConf::MySql {
+                        host: "db.example.com".to_string(),
+                        port: 3307,
+                        user: "idola".to_string(),
+                        password: "secret".to_string(),
+                        database: "idola".to_string(),
+                        pool_size: 8,
+                        reconnect: true
+                    },
+                    storage_quota: 600,
+                    shared_bank_slots: 200,
+                    unique_names: false,
+                    batch_interval: 0,
+                    batch_size: 500,
+                    maintenance_hour: None,
+                    maintenance_max_requests: 30,
+                    log_level: None
+                }
+            ]
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let c = every_service();
+        let s = c.to_toml_string();
+        let back = Config::from_toml_string(&s).unwrap();
+        assert_eq!(back, c);
+        // And again, from what was read back.
+        assert_eq!(back.to_toml_string(), s);
+    }
+
+    #[test]
+    fn test_round_trip_defaults() {
+        let s = r#"
+            [idola]
+            shipgate_addr = "127.0.0.1:6813"
+            shipgate_password = "pw"
+
+            [[service]]
+            bind = "0.0.0.0:13001"
+            type = "block"
+        "#;
+        let c = Config::from_toml_string(s).unwrap();
+        assert_eq!(Config::from_toml_string(&c.to_toml_string()).unwrap(), c);
+    }
+}
@@ -0,0 +1,177 @@
+//! The patch file tree the data service keeps clients' copies of up to date,
+//! read once at startup.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crc::crc32::checksum_ieee;
+
+/// Longest file name, in bytes, the patch file list has room for.
+pub const MAX_NAME_LEN: usize = 31;
+/// Longest directory name, in bytes.
+pub const MAX_DIR_LEN: usize = 63;
+
+/// One file clients are kept up to date with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchFile {
+    /// The directories from the root down to the file.
+    pub dirs: Vec<String>,
+    pub name: String,
+    pub size: u32,
+    /// CRC32 of the contents, which is what clients report for their copy.
+    pub checksum: u32,
+    /// Where it is on disk.
+    pub path: PathBuf
+}
+
+/// Every file under the data service's `files_path`. A file's patch ID is
+/// its index in `files`.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub files: Vec<PatchFile>
+}
+
+impl Manifest {
+    /// Walk `root`, checksumming every file. Files in a directory come
+    /// before its subdirectories, and both go in name order, so each
+    /// directory only has to be entered once. It's an error for `root` to
+    /// be missing or to have no files.
+    pub fn build(root: &Path) -> Result<Manifest, String> {
+        match fs::metadata(root) {
+            Ok(ref m) if m.is_dir() => (),
+            Ok(_) => return Err(format!("{} is not a directory", root.display())),
+            Err(e) => return Err(format!("can't read {}: {}", root.display(), e))
+        }
+        let mut files = Vec::new();
+        try!(walk(root, &mut Vec::new(), &mut files));
+        if files.is_empty() {
+            return Err(format!("{} has no files to serve", root.display()))
+        }
+        Ok(Manifest {
+            files: files
+        })
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().fold(0, |t, f| t + f.size as u64)
+    }
+
+    /// The patch IDs of the files a client has to be sent, given the
+    /// (checksum, size) it reported for each patch ID. A file it didn't
+    /// report on is one it doesn't have.
+    pub fn outdated(&self, have: &HashMap<u32, (u32, u32)>) -> Vec<u32> {
+        self.files.iter().enumerate().filter(|&(i, f)| {
+            have.get(&(i as u32)) != Some(&(f.checksum, f.size))
+        }).map(|(i, _)| i as u32).collect()
+    }
+}
+
+fn entry_name(path: &Path, max: usize) -> Result<String, String> {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) if n.len() <= max => Ok(n.to_string()),
+        Some(n) => Err(format!("{}: the name {} is longer than {} bytes", path.display(), n, max)),
+        None => Err(format!("{}: the name isn't valid UTF-8", path.display()))
+    }
+}
+
+fn walk(dir: &Path, dirs: &mut Vec<String>, files: &mut Vec<PatchFile>) -> Result<(), String> {
+    let read = fs::read_dir(dir).and_then(|rd| rd.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>());
+    let mut entries = try!(read.map_err(|e| format!("can't read {}: {}", dir.display(), e)));
+    entries.sort();
+
+    let mut subdirs = Vec::new();
+    for path in entries {
+        if path.is_dir() {
+            subdirs.push(path);
+            continue
+        }
+        let name = try!(entry_name(&path, MAX_NAME_LEN));
+        let mut buf = Vec::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|e| format!("can't read {}: {}", path.display(), e)));
+        if buf.len() > u32::max_value() as usize {
+            return Err(format!("{} is too big to send", path.display()))
+        }
+        files.push(PatchFile {
+            dirs: dirs.clone(),
+            name: name,
+            size: buf.len() as u32,
+            checksum: checksum_ieee(&buf),
+            path: path
+        });
+    }
+    for path in subdirs {
+        dirs.push(try!(entry_name(&path, MAX_DIR_LEN)));
+        try!(walk(&path, dirs, files));
+        dirs.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::Manifest;
+
+    /// An empty directory under the system temp directory.
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: PathBuf, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_build() {
+        let dir = fresh_dir("idola_test_manifest");
+        fs::create_dir(dir.join("data")).unwrap();
+        write(dir.join("data").join("check.bin"), b"123456789");
+        write(dir.join("psobb.exe"), b"hello");
+        write(dir.join("empty.txt"), b"");
+
+        let m = Manifest::build(&dir).unwrap();
+        let files: Vec<_> = m.files.iter().map(|f| (f.dirs.join("/"), f.name.as_str(), f.size, f.checksum)).collect();
+        assert_eq!(files, vec![
+            ("".to_string(), "empty.txt", 0, 0),
+            ("".to_string(), "psobb.exe", 5, 0x3610A686),
+            ("data".to_string(), "check.bin", 9, 0xCBF43926)
+        ]);
+        assert_eq!(m.total_size(), 14);
+        assert_eq!(m.files[2].path, dir.join("data").join("check.bin"));
+    }
+
+    #[test]
+    fn test_manifest_missing_or_empty() {
+        let dir = fresh_dir("idola_test_manifest_empty");
+        assert_eq!(Manifest::build(&dir).unwrap_err(), format!("{} has no files to serve", dir.display()));
+        // Directories with nothing in them don't count either.
+        fs::create_dir(dir.join("data")).unwrap();
+        assert!(Manifest::build(&dir).is_err());
+        assert!(Manifest::build(&dir.join("missing")).unwrap_err().starts_with("can't read"));
+    }
+
+    #[test]
+    fn test_manifest_outdated() {
+        let dir = fresh_dir("idola_test_manifest_outdated");
+        write(dir.join("a.txt"), b"hello");
+        write(dir.join("b.txt"), b"123456789");
+        write(dir.join("c.txt"), b"");
+        let m = Manifest::build(&dir).unwrap();
+        let mut have = HashMap::new();
+        have.insert(0, (0x3610A686, 5));
+        have.insert(1, (0x3610A686, 9));
+        assert_eq!(m.outdated(&have), vec![1, 2]);
+        assert_eq!(m.outdated(&HashMap::new()), vec![0, 1, 2]);
+    }
+}
@@ -1,39 +1,62 @@
-//! The data service, an extension of the patch service.
+//! The data service, an extension of the patch service. It lists the files
+//! in its manifest to each client, then sends the ones the client is missing
+//! or has a different copy of.
 
 use ::services::{Service, ServiceMsg};
 use ::loop_handler::LoopMsg;
 
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 
 use std::thread;
 
-use std::net::SocketAddr;
+use crc::crc32::checksum_ieee;
 
-use mio::tcp::TcpListener;
 use mio::Sender;
 
+use typenum::{Unsigned, NonZero};
+
+use staticvec::StaticVec;
+
 use psomsg::patch::*;
 
 use ::services::message::NetMsg;
+use ::services::listener::Listener;
 
 use ::services::ServiceType;
 
+pub mod manifest;
+
+use self::manifest::{Manifest, PatchFile};
+
+/// Most file data sent in one `DataSend`.
+pub const CHUNK_SIZE: usize = 0x6000;
+
+/// The directory a client starts out in, which every file is under.
+const ROOT_DIR: &'static str = ".";
+
 pub struct DataService {
     receiver: Receiver<ServiceMsg>,
-    sender: Sender<LoopMsg>
+    sender: Sender<LoopMsg>,
+    manifest: Manifest,
+    /// The (checksum, size) each client has reported for each patch ID.
+    reports: HashMap<usize, HashMap<u32, (u32, u32)>>
 }
 
 impl DataService {
-    pub fn spawn(bind: &SocketAddr, sender: Sender<LoopMsg>) -> Service {
+    pub fn spawn<L: Listener + 'static>(listener: L, sender: Sender<LoopMsg>, manifest: Manifest) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
-
         thread::spawn(move|| {
             let d = DataService {
                 receiver: rx,
-                sender: sender
+                sender: sender,
+                manifest: manifest,
+                reports: HashMap::new()
             };
             d.run()
         });
@@ -44,11 +67,19 @@ impl DataService {
     pub fn run(self) {
         let DataService {
             receiver,
-            sender
+            sender,
+            manifest,
+            mut reports
         } = self;
 
         info!("Data service running");
 
+        let send = |id: usize, msgs: Vec<Message>| {
+            for m in msgs {
+                sender.send(LoopMsg::Client(id, m.into())).unwrap();
+            }
+        };
+
         for msg in receiver.iter() {
             match msg {
                 ServiceMsg::ClientConnected((_addr, id)) => {
@@ -57,8 +88,8 @@ impl DataService {
                     sender.send(LoopMsg::Client(id, w.into())).unwrap();
                 },
                 ServiceMsg::ClientDisconnected(id) => {
-                    info!("Client {} disconnected from data service.", id)
-
+                    info!("Client {} disconnected from data service.", id);
+                    reports.remove(&id);
                 },
                 ServiceMsg::ClientSaid(id, NetMsg::Patch(m)) => {
                     match m {
@@ -68,33 +99,32 @@ impl DataService {
                             )).unwrap();
                         },
                         Message::Login(Some(..)) => {
-                            // Message::StartList(None).serialize(&mut w_s).unwrap();
-                            // Message::SetDirectory(Some(SetDirectory { dirname: StaticVec::default() })).serialize(&mut w_s).unwrap();
-                            // Message::InfoFinished(None).serialize(&mut w_s).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::StartList(None).into()
-                            )).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::SetDirectory(Some(SetDirectory { dirname: Default::default() })).into()
-                            )).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::InfoFinished(None).into()
-                            )).unwrap();
+                            reports.insert(id, HashMap::new());
+                            send(id, file_list(&manifest));
+                        },
+                        Message::FileInfoReply(Some(r)) => {
+                            if let Some(have) = reports.get_mut(&id) {
+                                have.insert(r.patch_id, (r.checksum, r.size));
+                            }
                         },
                         Message::FileListDone(_) => {
-                            // Message::SetDirectory(Some(SetDirectory { dirname: StaticVec::default() })).serialize(&mut w_s).unwrap();
-                            // Message::OneDirUp(None).serialize(&mut w_s).unwrap();
-                            // Message::SendDone(None).serialize(&mut w_s).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::SetDirectory(Some(SetDirectory { dirname: Default::default() })).into()
-                            )).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::OneDirUp(None).into()
-                            )).unwrap();
-                            sender.send(LoopMsg::Client(id,
-                                Message::SendDone(None).into()
-                            )).unwrap();
-                            info!("client {} was 'updated' successfully", id);
+                            let have = reports.remove(&id).unwrap_or_else(HashMap::new);
+                            let outdated = manifest.outdated(&have);
+                            let files: Vec<&PatchFile> = outdated.iter().map(|&i| &manifest.files[i as usize]).collect();
+                            info!("Sending client {} {} of {} files", id, files.len(), manifest.files.len());
+                            send(id, send_info(&files));
+                            let mut dirs: &[String] = &[];
+                            for f in files.iter() {
+                                send(id, change_dir(dirs, &f.dirs));
+                                dirs = &f.dirs;
+                                match file_data(f) {
+                                    Ok(msgs) => send(id, msgs),
+                                    Err(e) => error!("Couldn't send {} to client {}: {}", f.path.display(), id, e)
+                                }
+                            }
+                            send(id, change_dir(dirs, &[]));
+                            send(id, vec![Message::OneDirUp(None), Message::SendDone(None)]);
+                            info!("client {} was updated successfully", id);
                         },
                         u => { warn!("client sent weird message: {:?}", u) }
                     }
@@ -104,3 +134,90 @@ impl DataService {
         }
     }
 }
+
+/// A name in one of the patch protocol's fixed-size, NUL-padded fields. The
+/// manifest has already checked that it fits.
+fn name_field<L: Unsigned + NonZero>(name: &str) -> StaticVec<u8, L> {
+    let mut v: StaticVec<u8, L> = StaticVec::default();
+    let n = min(name.len(), L::to_usize() - 1);
+    v[..n].copy_from_slice(&name.as_bytes()[..n]);
+    v
+}
+
+/// The moves from directory `from` to `to`, both under the root.
+fn change_dir(from: &[String], to: &[String]) -> Vec<Message> {
+    let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+    let mut msgs: Vec<Message> = (common..from.len()).map(|_| Message::OneDirUp(None)).collect();
+    for d in to[common..].iter() {
+        msgs.push(SetDirectory { dirname: name_field(d) }.into());
+    }
+    msgs
+}
+
+/// Every file in the manifest, for the client to report its own copies of.
+fn file_list(manifest: &Manifest) -> Vec<Message> {
+    let mut msgs = vec![Message::StartList(None), SetDirectory { dirname: name_field(ROOT_DIR) }.into()];
+    let mut dirs: &[String] = &[];
+    for (i, f) in manifest.files.iter().enumerate() {
+        msgs.append(&mut change_dir(dirs, &f.dirs));
+        dirs = &f.dirs;
+        msgs.push(FileInfo { patch_id: i as u32, filename: name_field(&f.name) }.into());
+    }
+    msgs.append(&mut change_dir(dirs, &[]));
+    msgs.push(Message::OneDirUp(None));
+    msgs.push(Message::InfoFinished(None));
+    msgs
+}
+
+/// What comes before the files themselves: how much is being sent, if
+/// anything, then into the root.
+fn send_info(files: &[&PatchFile]) -> Vec<Message> {
+    let mut msgs: Vec<Message> = Vec::new();
+    if !files.is_empty() {
+        let total = files.iter().fold(0, |t, f| t + f.size as u64);
+        msgs.push(SendInfo { total_length: total as u32, total_file: files.len() as u32 }.into());
+    }
+    msgs.push(SetDirectory { dirname: name_field(ROOT_DIR) }.into());
+    msgs
+}
+
+/// A file as it's sent, read from disk now so it's whatever is there.
+fn file_data(f: &PatchFile) -> io::Result<Vec<Message>> {
+    let mut buf = Vec::new();
+    try!(File::open(&f.path).and_then(|mut file| file.read_to_end(&mut buf)));
+    let mut msgs: Vec<Message> = vec![FileSend { padding: 0, size: buf.len() as u32, filename: name_field(&f.name) }.into()];
+    for (i, chunk) in buf.chunks(CHUNK_SIZE).enumerate() {
+        msgs.push(DataSend { chunk_num: i as u32, checksum: checksum_ieee(chunk), data: chunk.to_vec() }.into());
+    }
+    msgs.push(FileDone { padding: 0 }.into());
+    Ok(msgs)
+}
+
+#[cfg(test)]
+mod test {
+    use psomsg::patch::*;
+
+    use super::change_dir;
+
+    fn dirs(path: &str) -> Vec<String> {
+        path.split('/').filter(|d| d.len() > 0).map(|d| d.to_string()).collect()
+    }
+
+    /// The moves as text: ".." for up, otherwise the directory entered.
+    fn moves(from: &str, to: &str) -> Vec<String> {
+        change_dir(&dirs(from), &dirs(to)).into_iter().map(|m| match m {
+            Message::OneDirUp(None) => "..".to_string(),
+            Message::SetDirectory(Some(d)) => String::from_utf8(d.dirname.iter().cloned().take_while(|&b| b != 0).collect()).unwrap(),
+            m => panic!("unexpected move {:?}", m)
+        }).collect()
+    }
+
+    #[test]
+    fn test_change_dir() {
+        assert_eq!(moves("", "data/scene"), vec!["data", "scene"]);
+        assert_eq!(moves("data/scene", "data/scene"), Vec::<String>::new());
+        assert_eq!(moves("data/scene", "data/param"), vec!["..", "param"]);
+        assert_eq!(moves("data/scene", ""), vec!["..", ".."]);
+        assert_eq!(moves("data", "datb"), vec!["..", "datb"]);
+    }
+}
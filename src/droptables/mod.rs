@@ -11,6 +11,7 @@ use psodata::gsl;
 
 /// A struct storing all the probability tables inside an ItemPT.gsl file.
 /// Provides methods for retrieving references to them by episode and mode.
+#[derive(Default)]
 pub struct DropTable {
     ep1: Option<Vec<ItemPT>>,
     ep2: Option<Vec<ItemPT>>,
@@ -0,0 +1,193 @@
+//! Structured connection and session events, written as JSON lines.
+//!
+//! This is for log pipelines, separate from the human-readable `log` output.
+//! Each line is one JSON object. Every object has these keys:
+//!
+//! - `v`: the schema version, currently 1. It is bumped when a key changes
+//!   meaning or is removed; new keys may appear without a bump.
+//! - `ts`: RFC 3339 UTC timestamp of the event.
+//! - `event`: one of `connect`, `login`, `lobby_change` or `disconnect`.
+//! - `conn`: the connection ID, unique across services among open
+//!   connections. IDs are reused once a connection closes, so a `conn`
+//!   means the connection from the latest `connect` with that ID.
+//! - `service`: the kind of service the connection is on, e.g. `"block"`.
+//! - `ip`: the client's address, without the port.
+//! - `account`: the account ID, or null before login.
+//! - `lobby`: the lobby number (1-based) for `lobby_change`, otherwise null.
+//!
+//! `service`, `ip` and `account` are filled in from earlier events on the
+//! same connection, so a `disconnect` carries the account that logged in.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use rustc_serialize::json;
+
+use time;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug)]
+enum Event {
+    Connect(usize, &'static str, SocketAddr),
+    Login(usize, u32),
+    LobbyChange(usize, u32, u8),
+    Disconnect(usize)
+}
+
+#[derive(RustcEncodable)]
+struct Record {
+    v: u32,
+    ts: String,
+    event: &'static str,
+    conn: usize,
+    service: Option<&'static str>,
+    ip: Option<String>,
+    account: Option<u32>,
+    lobby: Option<u8>
+}
+
+#[derive(Default)]
+struct ConnInfo {
+    service: Option<&'static str>,
+    ip: Option<String>,
+    account: Option<u32>
+}
+
+/// A handle for recording events. Cheap to clone; a disabled handle drops
+/// everything.
+#[derive(Clone)]
+pub struct EventLog {
+    sender: Option<Sender<Event>>
+}
+
+impl EventLog {
+    pub fn disabled() -> EventLog {
+        EventLog { sender: None }
+    }
+
+    /// Start writing events to `dest`, which is a file path to append to or
+    /// `"stdout"`.
+    pub fn spawn(dest: &str) -> io::Result<EventLog> {
+        let out: Box<Write + Send> = if dest == "stdout" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(try!(OpenOptions::new().create(true).append(true).open(dest)))
+        };
+        let (tx, rx) = channel();
+        thread::spawn(move|| {
+            let mut out = out;
+            let mut conns: HashMap<usize, ConnInfo> = HashMap::new();
+            for e in rx.iter() {
+                let r = Record::from_event(&mut conns, e);
+                let line = match json::encode(&r) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        error!("Couldn't encode event: {:?}", e);
+                        continue
+                    }
+                };
+                if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+                    error!("Couldn't write to the event log: {}", e);
+                }
+            }
+        });
+        Ok(EventLog { sender: Some(tx) })
+    }
+
+    fn send(&self, e: Event) {
+        if let Some(ref s) = self.sender {
+            let _ = s.send(e);
+        }
+    }
+
+    pub fn connect(&self, conn: usize, service: &'static str, addr: SocketAddr) {
+        self.send(Event::Connect(conn, service, addr));
+    }
+
+    pub fn login(&self, conn: usize, account: u32) {
+        self.send(Event::Login(conn, account));
+    }
+
+    pub fn lobby_change(&self, conn: usize, account: u32, lobby: u8) {
+        self.send(Event::LobbyChange(conn, account, lobby));
+    }
+
+    pub fn disconnect(&self, conn: usize) {
+        self.send(Event::Disconnect(conn));
+    }
+}
+
+impl Record {
+    fn from_event(conns: &mut HashMap<usize, ConnInfo>, e: Event) -> Record {
+        let (name, conn, lobby) = match e {
+            Event::Connect(conn, service, addr) => {
+                conns.insert(conn, ConnInfo {
+                    service: Some(service),
+                    ip: Some(format!("{}", addr.ip())),
+                    account: None
+                });
+                ("connect", conn, None)
+            },
+            Event::Login(conn, account) => {
+                conns.entry(conn).or_insert_with(Default::default).account = Some(account);
+                ("login", conn, None)
+            },
+            Event::LobbyChange(conn, account, lobby) => {
+                conns.entry(conn).or_insert_with(Default::default).account = Some(account);
+                ("lobby_change", conn, Some(lobby))
+            },
+            Event::Disconnect(conn) => ("disconnect", conn, None)
+        };
+        let info = if name == "disconnect" {
+            conns.remove(&conn).unwrap_or(Default::default())
+        } else {
+            let i = conns.get(&conn);
+            ConnInfo {
+                service: i.and_then(|i| i.service),
+                ip: i.and_then(|i| i.ip.clone()),
+                account: i.and_then(|i| i.account)
+            }
+        };
+        Record {
+            v: SCHEMA_VERSION,
+            ts: format!("{}", time::now_utc().rfc3339()),
+            event: name,
+            conn: conn,
+            service: info.service,
+            ip: info.ip,
+            account: info.account,
+            lobby: lobby
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{Event, Record};
+    use std::collections::HashMap;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn test_records_carry_connection_info() {
+        let mut conns = HashMap::new();
+        Record::from_event(&mut conns, Event::Connect(10001, "block", "10.0.0.5:4000".parse().unwrap()));
+        Record::from_event(&mut conns, Event::LobbyChange(10001, 42, 3));
+        let r = Record::from_event(&mut conns, Event::Disconnect(10001));
+        let j = Json::from_str(&::rustc_serialize::json::encode(&r).unwrap()).unwrap();
+        let o = j.as_object().unwrap();
+        assert_eq!(o["v"].as_u64(), Some(SCHEMA_VERSION as u64));
+        assert_eq!(o["event"].as_string(), Some("disconnect"));
+        assert_eq!(o["service"].as_string(), Some("block"));
+        assert_eq!(o["ip"].as_string(), Some("10.0.0.5"));
+        assert_eq!(o["account"].as_u64(), Some(42));
+        assert!(o["lobby"].is_null());
+        assert!(conns.is_empty());
+    }
+}
@@ -56,3 +56,36 @@ impl fmt::Display for CharClass {
         write!(w, "{:?}", self)
     }
 }
+
+/// Every class, in the order the client numbers them.
+pub const CHAR_CLASSES: [CharClass; 12] = [
+    CharClass::HUmar,
+    CharClass::HUnewearl,
+    CharClass::HUcast,
+    CharClass::RAmar,
+    CharClass::RAcast,
+    CharClass::RAcaseal,
+    CharClass::FOmarl,
+    CharClass::FOnewm,
+    CharClass::FOnewearl,
+    CharClass::HUcaseal,
+    CharClass::FOmar,
+    CharClass::RAmarl
+];
+
+impl CharClass {
+    /// The class the client numbers `n`.
+    pub fn from_u8(n: u8) -> Option<CharClass> {
+        CHAR_CLASSES.get(n as usize).map(|c| *c)
+    }
+}
+
+impl FromStr for CharClass {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CharClass, Self::Err> {
+        match CHAR_CLASSES.iter().find(|c| c.to_string() == s) {
+            Some(c) => Ok(*c),
+            None => Err(format!("Unknown class {}", s))
+        }
+    }
+}
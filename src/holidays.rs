@@ -0,0 +1,144 @@
+//! Lobby events that turn on by themselves around common holidays, so they
+//! don't have to be scheduled by hand every year.
+//!
+//! Dates are in the server's local time. A holiday's window includes its first
+//! and last day, and may wrap around the new year.
+
+use time;
+
+/// Lobby event IDs as the client knows them.
+pub const EVENT_CHRISTMAS: u16 = 1;
+pub const EVENT_EASTER: u16 = 4;
+pub const EVENT_HALLOWEEN: u16 = 5;
+pub const EVENT_NEW_YEAR: u16 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolidayDates {
+    /// From one (month, day) through another, every year.
+    Fixed((u8, u8), (u8, u8)),
+    /// The week before Easter Sunday through Easter Monday.
+    Easter
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holiday {
+    pub name: String,
+    pub event: u16,
+    pub dates: HolidayDates
+}
+
+impl Holiday {
+    pub fn new(name: &str, event: u16, dates: HolidayDates) -> Holiday {
+        Holiday {
+            name: name.to_string(),
+            event: event,
+            dates: dates
+        }
+    }
+
+    pub fn is_on(&self, year: i32, month: u8, day: u8) -> bool {
+        match self.dates {
+            HolidayDates::Fixed(start, end) => {
+                let d = (month, day);
+                if start <= end {
+                    start <= d && d <= end
+                } else {
+                    d >= start || d <= end
+                }
+            },
+            HolidayDates::Easter => {
+                let (em, ed) = easter(year);
+                let sunday = day_number(year, em, ed);
+                let d = day_number(year, month, day);
+                d >= sunday - 7 && d <= sunday + 1
+            }
+        }
+    }
+}
+
+/// The built-in calendar.
+pub fn defaults() -> Vec<Holiday> {
+    vec![
+        Holiday::new("halloween", EVENT_HALLOWEEN, HolidayDates::Fixed((10, 24), (10, 31))),
+        Holiday::new("christmas", EVENT_CHRISTMAS, HolidayDates::Fixed((12, 18), (12, 26))),
+        Holiday::new("new_year", EVENT_NEW_YEAR, HolidayDates::Fixed((12, 31), (1, 3))),
+        Holiday::new("easter", EVENT_EASTER, HolidayDates::Easter)
+    ]
+}
+
+/// Parse a "MM-DD" date.
+pub fn parse_month_day(s: &str) -> Option<(u8, u8)> {
+    let mut parts = s.splitn(2, '-');
+    let month = parts.next().and_then(|m| m.parse::<u8>().ok());
+    let day = parts.next().and_then(|d| d.parse::<u8>().ok());
+    match (month, day) {
+        (Some(m), Some(d)) if m >= 1 && m <= 12 && d >= 1 && d <= 31 => Some((m, d)),
+        _ => None
+    }
+}
+
+/// The event of the first holiday on the given date.
+pub fn event_on(holidays: &[Holiday], year: i32, month: u8, day: u8) -> Option<u16> {
+    holidays.iter().find(|h| h.is_on(year, month, day)).map(|h| h.event)
+}
+
+/// The event of the first holiday today, in local time.
+pub fn event_today(holidays: &[Holiday]) -> Option<u16> {
+    let now = time::now();
+    event_on(holidays, now.tm_year + 1900, (now.tm_mon + 1) as u8, now.tm_mday as u8)
+}
+
+/// (month, day) of Easter Sunday in the Gregorian calendar.
+pub fn easter(year: i32) -> (u8, u8) {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    (month as u8, day as u8)
+}
+
+/// Days since 1970-01-01.
+fn day_number(year: i32, month: u8, day: u8) -> i32 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let m = month as i32;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i32 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_easter() {
+        assert_eq!(easter(2016), (3, 27));
+        assert_eq!(easter(2019), (4, 21));
+        assert_eq!(easter(2024), (3, 31));
+    }
+
+    #[test]
+    fn test_event_on() {
+        let h = defaults();
+        assert_eq!(event_on(&h, 2016, 10, 31), Some(EVENT_HALLOWEEN));
+        assert_eq!(event_on(&h, 2016, 11, 1), None);
+        assert_eq!(event_on(&h, 2016, 12, 31), Some(EVENT_NEW_YEAR));
+        assert_eq!(event_on(&h, 2017, 1, 3), Some(EVENT_NEW_YEAR));
+        // The Easter window crosses a month boundary in 2016.
+        assert_eq!(event_on(&h, 2016, 3, 20), Some(EVENT_EASTER));
+        assert_eq!(event_on(&h, 2016, 3, 28), Some(EVENT_EASTER));
+        assert_eq!(event_on(&h, 2016, 3, 29), None);
+    }
+}
@@ -8,10 +8,16 @@ pub struct ClientState {
     pub account_id: u32,
     pub team_id: u32,
     pub bb_guildcard: u32,
-    pub ships: Option<Vec<(SocketAddrV4, String)>>,
+    pub ships: Option<Vec<(SocketAddrV4, String, u32)>>,
     pub options: u32,
     pub key_config: Vec<u8>,
     pub joy_config: Vec<u8>,
     pub shortcuts: Vec<u8>,
-    pub symbol_chats: Vec<u8>
+    pub symbol_chats: Vec<u8>,
+    /// `time::precise_time_ns` by which they must log in. Cleared once they
+    /// have.
+    pub handshake_deadline: Option<u64>,
+    /// They've been shown rules they haven't accepted yet, and can't pick a
+    /// ship until they do.
+    pub rules_pending: bool
 }
@@ -9,10 +9,12 @@ use mio::Sender;
 use psomsg::bb::*;
 
 use psodata::leveltable::LevelTable;
+use psodata::bb_defaults::{DEFAULT_KEYS, DEFAULT_JOY, KEY_CONFIG_LEN, JOY_CONFIG_LEN};
 
 use time;
 
 use ::shipgate::client::callbacks::SgCbMgr;
+use ::shipgate::ships::menu_name;
 use ::shipgate::msg::Message as Sgm;
 use ::shipgate::msg::{
     BbLoginChallenge,
@@ -20,13 +22,22 @@ use ::shipgate::msg::{
     ShipList as SgShipList,
     ShipListAck,
     BbGetCharacter,
-    BbPutCharacter
+    BbCreateCharacter,
+    BbGetRulesVersion,
+    BbSetRulesVersion
 };
 use ::loop_handler::LoopMsg;
+use ::eventlog::EventLog;
+use ::config::{RulesConf, ClassRestrictions};
 
 use super::client::ClientState;
 use super::def_inventory::make_defaults;
 
+const MENU_RULES: u32 = 0x00090000;
+const RULES_ACCEPT: u32 = 1;
+const RULES_SHOW: u32 = 2;
+const RULES_DECLINE: u32 = 3;
+
 pub struct BbLoginHandler {
     sender: Sender<LoopMsg>,
     sg_sender: SgCbMgr<BbLoginHandler>,
@@ -34,11 +45,14 @@ pub struct BbLoginHandler {
     clients: Rc<RefCell<HashMap<usize, ClientState>>>,
     param_files: Arc<(Message, Vec<Message>)>,
     level_table: Arc<LevelTable>,
-    redir_addr: SocketAddrV4
+    redir_addr: SocketAddrV4,
+    event_log: EventLog,
+    rules: Option<Arc<RulesConf>>,
+    class_restrictions: Option<Arc<ClassRestrictions>>
 }
 
 impl BbLoginHandler {
-    pub fn new(sender: Sender<LoopMsg>, redir_addr: SocketAddrV4, sg_sender: SgCbMgr<BbLoginHandler>, client_id: usize, clients: Rc<RefCell<HashMap<usize, ClientState>>>, param_files: Arc<(Message, Vec<Message>)>, level_table: Arc<LevelTable>) -> BbLoginHandler {
+    pub fn new(sender: Sender<LoopMsg>, redir_addr: SocketAddrV4, sg_sender: SgCbMgr<BbLoginHandler>, client_id: usize, clients: Rc<RefCell<HashMap<usize, ClientState>>>, param_files: Arc<(Message, Vec<Message>)>, level_table: Arc<LevelTable>, event_log: EventLog, rules: Option<Arc<RulesConf>>, class_restrictions: Option<Arc<ClassRestrictions>>) -> BbLoginHandler {
         BbLoginHandler {
             sender: sender,
             sg_sender: sg_sender,
@@ -46,7 +60,10 @@ impl BbLoginHandler {
             clients: clients,
             param_files: param_files,
             level_table: level_table,
-            redir_addr: redir_addr
+            redir_addr: redir_addr,
+            event_log: event_log,
+            rules: rules,
+            class_restrictions: class_restrictions
         }
     }
 
@@ -84,6 +101,11 @@ impl BbLoginHandler {
                             return
                         }
 
+                        h.event_log.login(h.client_id, sm.account_id);
+                        if let Some(c) = h.clients.borrow_mut().get_mut(&h.client_id) {
+                            c.handshake_deadline = None;
+                        }
+
                         // If the magic code in the client's security data is 0, we need to redirect to self
                         if sec_data.magic != 0xCAFEB00B {
                             let mut sec_data: BbSecurityData = Default::default();
@@ -154,7 +176,7 @@ impl BbLoginHandler {
                                     msec: 0
                                 });
                                 h.sender.send((h.client_id, r).into()).unwrap();
-                                h.sg_sender.request(h.client_id, SgShipList, move|mut h, m| h.sg_shiplist_ack(m)).unwrap();
+                                h.check_rules(sm.account_id);
                             }
                         }
                     }
@@ -163,9 +185,87 @@ impl BbLoginHandler {
         }).unwrap();
     }
 
+    /// Send the ship list, unless the account has rules to accept first.
+    fn check_rules(&mut self, account_id: u32) {
+        let version = match self.rules {
+            Some(ref r) => r.version,
+            None => return self.request_ship_list()
+        };
+        self.sg_sender.request(self.client_id, BbGetRulesVersion { account_id: account_id }, move |mut h, m| {
+            if let Sgm::BbGetRulesVersionAck(_, a) = m {
+                if a.status != 0 {
+                    error!("Shipgate error getting accepted rules for account {}, status code {}", a.account_id, a.status);
+                    let r = Message::LargeMsg(0, LargeMsg("Internal DB error.".to_string()));
+                    h.sender.send((h.client_id, r).into()).unwrap();
+                    h.sender.send(LoopMsg::DropClient(h.client_id)).unwrap();
+                } else if a.version >= version {
+                    h.request_ship_list();
+                } else {
+                    if let Some(c) = h.clients.borrow_mut().get_mut(&h.client_id) {
+                        c.rules_pending = true;
+                    }
+                    h.send_rules();
+                }
+            }
+        }).unwrap();
+    }
+
+    fn request_ship_list(&mut self) {
+        self.sg_sender.request(self.client_id, SgShipList, move|mut h, m| h.sg_shiplist_ack(m)).unwrap();
+    }
+
+    /// Show the rules and a menu to accept or decline them.
+    fn send_rules(&mut self) {
+        let text = match self.rules {
+            Some(ref r) => r.text.clone(),
+            None => return
+        };
+        let r = Message::LargeMsg(0, LargeMsg(text));
+        self.sender.send((self.client_id, r).into()).unwrap();
+        let items = vec![
+            ShipListItem { menu_id: MENU_RULES, item_id: 0, flags: 0x0004, name: "RULES".to_string() },
+            ShipListItem { menu_id: MENU_RULES, item_id: RULES_ACCEPT, flags: 0x0F04, name: "Accept the rules".to_string() },
+            ShipListItem { menu_id: MENU_RULES, item_id: RULES_SHOW, flags: 0x0F04, name: "Read the rules".to_string() },
+            ShipListItem { menu_id: MENU_RULES, item_id: RULES_DECLINE, flags: 0x0F04, name: "Decline".to_string() }
+        ];
+        let r = Message::ShipList((items.len() - 1) as u32, ShipList(items));
+        self.sender.send((self.client_id, r).into()).unwrap();
+    }
+
+    fn rules_menu_select(&mut self, item: u32) {
+        let (account_id, pending) = match self.clients.borrow().get(&self.client_id) {
+            Some(c) => (c.account_id, c.rules_pending),
+            None => return
+        };
+        if !pending {
+            return
+        }
+        match item {
+            RULES_ACCEPT => {
+                let version = self.rules.as_ref().map(|r| r.version).unwrap_or(0);
+                info!("Account {} accepted version {} of the rules", account_id, version);
+                if let Some(c) = self.clients.borrow_mut().get_mut(&self.client_id) {
+                    c.rules_pending = false;
+                }
+                self.sg_sender.send(BbSetRulesVersion {
+                    account_id: account_id,
+                    version: version
+                }).unwrap();
+                self.request_ship_list();
+            },
+            RULES_DECLINE => {
+                info!("Account {} declined the rules", account_id);
+                let r = Message::LargeMsg(0, LargeMsg("You need to accept the rules to play here.".to_string()));
+                self.sender.send((self.client_id, r).into()).unwrap();
+                self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+            },
+            _ => self.send_rules()
+        }
+    }
+
     pub fn sg_shiplist_ack(&mut self, m: Sgm) {
         if let Sgm::ShipListAck(_, ShipListAck(ships)) = m {
-            let ships: Vec<(SocketAddrV4, String)> = ships;
+            let ships: Vec<(SocketAddrV4, String, u32)> = ships;
             {
                 let mut b = self.clients.borrow_mut();
                 let mut c = b.get_mut(&self.client_id).unwrap();
@@ -179,12 +279,12 @@ impl BbLoginHandler {
                 name: "SHIP/US".to_string()
             });
             let mut i = 1;
-            for (_, name) in ships.into_iter() {
+            for (_, name, players) in ships.into_iter() {
                 shiplist.push(ShipListItem {
                     menu_id: 0,
                     item_id: i,
                     flags: 0x0F04,
-                    name: name.clone()
+                    name: menu_name(&name, players)
                 });
                 i += 1;
             }
@@ -200,12 +300,73 @@ impl BbLoginHandler {
         let clients = self.clients.borrow();
         let client_state = clients.get(&cid).unwrap();
 
-        data.key_config = client_state.key_config.clone();
-        data.joy_config = client_state.joy_config.clone();
+        // Fall back to the defaults if what we have stored is unusable (e.g.
+        // an account that has never saved a config).
+        data.key_config = if client_state.key_config.len() == KEY_CONFIG_LEN {
+            client_state.key_config.clone()
+        } else {
+            DEFAULT_KEYS.to_vec()
+        };
+        data.joy_config = if client_state.joy_config.len() == JOY_CONFIG_LEN {
+            client_state.joy_config.clone()
+        } else {
+            DEFAULT_JOY.to_vec()
+        };
         let r = Message::BbOptionConfig(0, BbOptionConfig(data));
         self.sender.send((self.client_id, r).into()).unwrap();
     }
 
+    pub fn bb_update_keys(&mut self, m: BbUpdateKeys) {
+        use ::shipgate::msg::BbUpdateKeys as SgBbUK;
+        let BbUpdateKeys(keys) = m;
+        info!("{} updated keyboard configuration", self.client_id);
+        let account_id;
+        {
+            let mut clients = self.clients.borrow_mut();
+            let client_state = clients.get_mut(&self.client_id).unwrap();
+            client_state.key_config = keys.clone();
+            account_id = client_state.account_id;
+        }
+        self.sg_sender.send(Sgm::BbUpdateKeys(0, SgBbUK {
+            account_id: account_id,
+            key_config: keys
+        })).unwrap();
+    }
+
+    pub fn bb_update_joy(&mut self, m: BbUpdateJoy) {
+        use ::shipgate::msg::BbUpdateJoy as SgBbJ;
+        let BbUpdateJoy(joy) = m;
+        info!("{} updated joystick configuration", self.client_id);
+        let account_id;
+        {
+            let mut clients = self.clients.borrow_mut();
+            let client_state = clients.get_mut(&self.client_id).unwrap();
+            client_state.joy_config = joy.clone();
+            account_id = client_state.account_id;
+        }
+        self.sg_sender.send(Sgm::BbUpdateJoy(0, SgBbJ {
+            account_id: account_id,
+            joy_config: joy
+        })).unwrap();
+    }
+
+    pub fn bb_update_shortcuts(&mut self, m: BbUpdateShortcuts) {
+        use ::shipgate::msg::BbUpdateShortcuts as SgBbUS;
+        let BbUpdateShortcuts(shortcuts) = m;
+        info!("{} updated chat shortcuts", self.client_id);
+        let account_id;
+        {
+            let mut clients = self.clients.borrow_mut();
+            let client_state = clients.get_mut(&self.client_id).unwrap();
+            client_state.shortcuts = shortcuts.clone();
+            account_id = client_state.account_id;
+        }
+        self.sg_sender.send(Sgm::BbUpdateShortcuts(0, SgBbUS {
+            account_id: account_id,
+            shortcuts: shortcuts
+        })).unwrap();
+    }
+
     pub fn bb_checksum(&mut self, m: BbChecksum) {
         info!("Client {}'s checksum is {:x}", self.client_id, m.0);
         let r = Message::BbChecksumAck(0, BbChecksumAck(true));
@@ -244,25 +405,25 @@ impl BbLoginHandler {
         let BbCharSelect { slot, selecting } = m;
         if selecting {
             // They are selecting an existing character slot.
-            // TODO
-            let mut b = self.clients.borrow_mut();
-            let mut c = b.get_mut(&self.client_id).unwrap();
-            c.sec_data.sel_char = 1;
-            c.sec_data.slot = slot as u8;
-            let r = Message::BbSecurity(0, BbSecurity {
-                err_code: 0,
-                tag: 0x00010000,
-                guildcard: c.bb_guildcard,
-                team_id: 0xFFFFFFFF,
-                security_data: c.sec_data.clone(),
-                caps: 0x00000101
-            });
-            self.sender.send((self.client_id, r).into()).unwrap();
-            let r = Message::BbCharAck(0, BbCharAck {
-                slot: slot,
-                code: 0
-            });
-            self.sender.send((self.client_id, r).into()).unwrap();
+            if self.class_restrictions.as_ref().map(|r| r.existing).unwrap_or(false) {
+                // Look at the character's class before letting them in.
+                let account_id = self.clients.borrow().get(&self.client_id).unwrap().account_id;
+                self.sg_sender.request(self.client_id, Sgm::BbGetCharacter(0, BbGetCharacter { account_id: account_id, slot: slot as u8 }), move |mut h, m| {
+                    if let Sgm::BbGetCharacterAck(_, body) = m {
+                        let class = body.full_char.as_ref().map(|ch| ch.chara.class);
+                        if let Some(class) = class {
+                            if !h.class_allowed(class) {
+                                info!("Client {} selected a character of disallowed class {}", h.client_id, class);
+                                h.refuse_class(slot, 2);
+                                return
+                            }
+                        }
+                        h.select_slot(slot);
+                    }
+                }).unwrap();
+                return
+            }
+            self.select_slot(slot);
         } else {
             // They want information about a character slot.
             let cr = self.clients.clone();
@@ -316,6 +477,42 @@ impl BbLoginHandler {
         }
     }
 
+    /// Let them in with the character in `slot`.
+    fn select_slot(&mut self, slot: u32) {
+        let mut b = self.clients.borrow_mut();
+        let mut c = b.get_mut(&self.client_id).unwrap();
+        c.sec_data.sel_char = 1;
+        c.sec_data.slot = slot as u8;
+        let r = Message::BbSecurity(0, BbSecurity {
+            err_code: 0,
+            tag: 0x00010000,
+            guildcard: c.bb_guildcard,
+            team_id: 0xFFFFFFFF,
+            security_data: c.sec_data.clone(),
+            caps: 0x00000101
+        });
+        self.sender.send((self.client_id, r).into()).unwrap();
+        let r = Message::BbCharAck(0, BbCharAck {
+            slot: slot,
+            code: 0
+        });
+        self.sender.send((self.client_id, r).into()).unwrap();
+    }
+
+    /// Whether the server lets players use class number `class`.
+    fn class_allowed(&self, class: u8) -> bool {
+        self.class_restrictions.as_ref().map(|r| r.allows(class)).unwrap_or(true)
+    }
+
+    /// Tell them their character's class isn't allowed, and refuse the slot
+    /// with `code`.
+    fn refuse_class(&self, slot: u32, code: u32) {
+        let r = Message::LargeMsg(0, LargeMsg("\tEThat class isn't available\non this server.".to_string()));
+        self.sender.send((self.client_id, r).into()).unwrap();
+        let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: code });
+        self.sender.send((self.client_id, r).into()).unwrap();
+    }
+
     pub fn bb_param_hdr_req(&mut self) {
         self.sender.send((self.client_id, self.param_files.0.clone()).into()).unwrap();
     }
@@ -352,6 +549,11 @@ impl BbLoginHandler {
         }
 
         if chardata.guildcard.len() > 0 {
+            if !self.class_allowed(chardata.class) {
+                info!("Client {} tried to create a character of disallowed class {}", self.client_id, chardata.class);
+                self.refuse_class(slot, 1);
+                return
+            }
             info!("Character created: {:?}", chardata);
 
             // Convert BbMiniCharData to BbFullCharData
@@ -384,14 +586,38 @@ impl BbLoginHandler {
             // We don't need to set the account global data here because we aren't
             // going to save it in the shipgate request.
 
-            self.sg_sender.send(BbPutCharacter {
+            let sgm: Sgm = BbCreateCharacter {
                 account_id: account_id,
                 slot: slot as u8,
-                save_acct_data: 0,
                 full_char: fc
+            }.into();
+            self.sg_sender.request(self.client_id, sgm, move |h, m| {
+                if let Sgm::BbCreateCharacterAck(_, a) = m {
+                    match a.status {
+                        0 => h.bb_char_info_ack(slot, &sec_data, bb_guildcard),
+                        4 => {
+                            let r = Message::LargeMsg(0, LargeMsg("\tEThat name is already taken.\nPlease choose another.".to_string()));
+                            h.sender.send((h.client_id, r).into()).unwrap();
+                            let r = Message::BbCharAck(0, BbCharAck { slot: slot, code: 1 });
+                            h.sender.send((h.client_id, r).into()).unwrap();
+                        },
+                        s => {
+                            error!("Shipgate error creating character, status code {}", s);
+                            let r = Message::LargeMsg(0, LargeMsg("Couldn't create your character. Please try again.".to_string()));
+                            h.sender.send((h.client_id, r).into()).unwrap();
+                            h.sender.send(LoopMsg::DropClient(h.client_id)).unwrap();
+                        }
+                    }
+                }
             }).unwrap();
+            return
         }
 
+        self.bb_char_info_ack(slot, &sec_data, bb_guildcard);
+    }
+
+    /// Tell the client its character is ready to play.
+    fn bb_char_info_ack(&self, slot: u32, sec_data: &BbSecurityData, bb_guildcard: u32) {
         let r = Message::BbSecurity(0, BbSecurity {
             err_code: 0,
             tag: 0x00010000,
@@ -416,6 +642,11 @@ impl BbLoginHandler {
                 {
                     let mut b = self.clients.borrow_mut();
                     let c = b.get_mut(&self.client_id).unwrap();
+                    if c.rules_pending {
+                        warn!("Client {} picked a ship without accepting the rules", self.client_id);
+                        self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+                        return
+                    }
                     ships = c.ships.clone();
                 }
 
@@ -434,6 +665,7 @@ impl BbLoginHandler {
                     }
                 }
             },
+            MENU_RULES => self.rules_menu_select(item),
             _ => {
                 let r = Message::LargeMsg(0, LargeMsg("Invalid menu".to_string()));
                 self.sender.send((self.client_id, r).into()).unwrap();
@@ -3,19 +3,18 @@
 //! pointless. IDOLA instead handles both the Login and Character steps inside
 //! the BB Login server.
 
-use ::services::{Service, ServiceMsg};
+use ::services::{Service, ServiceMsg, spawn_ticker, handshake_deadline, check_handshake};
 use ::loop_handler::LoopMsg;
 
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::thread;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::SocketAddrV4;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::HashMap;
 
-use mio::tcp::TcpListener;
 use mio::Sender;
 
 use psomsg::bb::*;
@@ -24,8 +23,13 @@ use psodata::leveltable::LevelTable;
 
 use rand::random;
 
+use time::precise_time_ns;
+
 use ::services::message::NetMsg;
+use ::services::listener::Listener;
+use ::eventlog::EventLog;
 use ::services::ServiceType;
+use ::config::{RulesConf, ClassRestrictions};
 
 use ::shipgate::client::SgSender;
 use ::shipgate::client::callbacks::SgCbMgr;
@@ -44,17 +48,23 @@ pub struct BbLoginService {
     clients: Rc<RefCell<HashMap<usize, ClientState>>>,
     param_files: Arc<(Message, Vec<Message>)>,
     level_table: Arc<LevelTable>,
-    redir_addr: SocketAddrV4
+    event_log: EventLog,
+    redir_addr: SocketAddrV4,
+    handshake_timeout: u32,
+    rules: Option<Arc<RulesConf>>,
+    class_restrictions: Option<Arc<ClassRestrictions>>
 }
 
 impl BbLoginService {
-    pub fn spawn(bind: &SocketAddr, redir_addr: SocketAddrV4, sender: Sender<LoopMsg>, key_table: Arc<Vec<u32>>, sg_sender: &SgSender, param_files: Arc<(Message, Vec<Message>)>, level_table: Arc<LevelTable>) -> Service {
+    pub fn spawn<L: Listener + 'static>(listener: L, redir_addr: SocketAddrV4, sender: Sender<LoopMsg>, key_table: Arc<Vec<u32>>, sg_sender: &SgSender, param_files: Arc<(Message, Vec<Message>)>, level_table: Arc<LevelTable>, event_log: EventLog, handshake_timeout: u32, rules: Option<RulesConf>, class_restrictions: Option<ClassRestrictions>) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
-
         let sg_sender = sg_sender.clone_with(tx.clone());
 
+        if handshake_timeout > 0 {
+            spawn_ticker(tx.clone());
+        }
+
         thread::spawn(move|| {
             let d = BbLoginService {
                 receiver: rx,
@@ -63,7 +73,11 @@ impl BbLoginService {
                 clients: Default::default(),
                 param_files: param_files,
                 level_table: level_table,
-                redir_addr: redir_addr
+                event_log: event_log,
+                redir_addr: redir_addr,
+                handshake_timeout: handshake_timeout,
+                rules: rules.map(Arc::new),
+                class_restrictions: class_restrictions.map(Arc::new)
             };
             d.run()
         });
@@ -79,12 +93,21 @@ impl BbLoginService {
             client_id,
             self.clients.clone(),
             self.param_files.clone(),
-            self.level_table.clone()
+            self.level_table.clone(),
+            self.event_log.clone(),
+            self.rules.clone(),
+            self.class_restrictions.clone()
         )
     }
 
     pub fn run(mut self) {
         info!("Blue burst login service running");
+        if let Some(ref r) = self.rules {
+            info!("Players must accept version {} of the rules", r.version);
+        }
+        if let Some(ref c) = self.class_restrictions {
+            info!("Disallowed classes: {:?}", c.disallowed);
+        }
 
         loop {
             let msg = match self.receiver.recv() {
@@ -101,7 +124,9 @@ impl BbLoginService {
 
                     {
                         let mut b = self.clients.borrow_mut();
-                        b.insert(id, ClientState::default());
+                        let mut c = ClientState::default();
+                        c.handshake_deadline = handshake_deadline(self.handshake_timeout);
+                        b.insert(id, c);
                     }
                 },
                 ServiceMsg::ClientDisconnected(id) => {
@@ -117,6 +142,9 @@ impl BbLoginService {
                     match m {
                         Message::BbLogin(_, m) => { h.bb_login(m) },
                         Message::BbOptionRequest(_, _) => { h.bb_option_request() },
+                        Message::BbUpdateKeys(_, m) => { h.bb_update_keys(m) },
+                        Message::BbUpdateJoy(_, m) => { h.bb_update_joy(m) },
+                        Message::BbUpdateShortcuts(_, m) => { h.bb_update_shortcuts(m) },
                         Message::BbChecksum(_, m) => { h.bb_checksum(m) },
                         Message::BbGuildRequest(_, _) => { h.bb_guildcard_req() },
                         Message::BbGuildCardChunkReq(_, r) => { h.bb_guildcard_chunk_req(r) },
@@ -143,6 +171,12 @@ impl BbLoginService {
                         None => warn!("Got a SG request response for an unexpected request ID {}.", req)
                     }
                 }
+                ServiceMsg::Tick => {
+                    let now = precise_time_ns();
+                    for (id, c) in self.clients.borrow_mut().iter_mut() {
+                        check_handshake(&self.sender, *id, &mut c.handshake_deadline, now, self.handshake_timeout);
+                    }
+                },
                 _ => unreachable!()
             }
         }
@@ -1,9 +1,26 @@
 use mio::{Handler, EventLoop, Token, EventSet};
 use mio::util::Slab;
 
-use ::services::{Service, ServiceMsg};
+use ::services::{Service, ServiceMsg, FIRST_PACKET_TIMEOUT};
 
 use ::services::message::NetMsg;
+use ::util::memory;
+
+/// Timeout value for the periodic stats dump. Any other timeout value is the
+/// token of a client whose throttled send should resume, or one with
+/// `FIRST_PACKET_TIMEOUT` set; client tokens never start at 0.
+const STATS_TIMEOUT: usize = 0;
+const STATS_INTERVAL_MS: u64 = 60000;
+/// Timeout value for giving up on the shipgate at startup. Client tokens
+/// start well above this, at the first service's token times 10000.
+const SHIPGATE_TIMEOUT: usize = 1;
+/// Timeout value for checking memory use against the limit.
+const MEMORY_TIMEOUT: usize = 2;
+const MEMORY_INTERVAL_MS: u64 = 1000;
+/// Timeout value for stopping the loop once services have had time to see
+/// their clients off.
+const SHUTDOWN_TIMEOUT: usize = 3;
+const SHUTDOWN_GRACE_MS: u64 = 3000;
 
 #[derive(Clone)]
 pub enum LoopMsg {
@@ -14,7 +31,14 @@ pub enum LoopMsg {
     Client(usize, NetMsg),
 
     /// Drop a client
-    DropClient(usize)
+    DropClient(usize),
+
+    /// The shipgate client authenticated; services that need it can start
+    /// taking clients.
+    ShipGateReady,
+
+    /// Stop the server, letting services save their clients' work first.
+    Shutdown
 }
 
 impl<I: Into<NetMsg>> From<(usize, I)> for LoopMsg {
@@ -25,11 +49,24 @@ impl<I: Into<NetMsg>> From<(usize, I)> for LoopMsg {
 }
 
 pub struct LoopHandler {
-    services: Slab<Service>
+    services: Slab<Service>,
+    shipgate_ready: bool,
+    startup_failed: bool,
+    /// Resident bytes over which new clients are turned away. 0 for no limit.
+    memory_limit: u64,
+    /// Turning new clients away until memory use drops.
+    busy: bool,
+    shutting_down: bool
 }
 
 impl LoopHandler {
-    pub fn new<H: Handler>(services: Vec<Service>, event_loop: &mut EventLoop<H>) -> LoopHandler {
+    /// Services that need the shipgate start listening once it's ready. If it
+    /// isn't within `shipgate_timeout` seconds the loop shuts down; 0 waits
+    /// forever.
+    ///
+    /// With a `memory_limit_mb`, clients are turned away while the process
+    /// uses more memory than that, until it drops back under 90% of it.
+    pub fn new(services: Vec<Service>, event_loop: &mut EventLoop<LoopHandler>, shipgate_timeout: u32, memory_limit_mb: u32) -> LoopHandler {
         let mut svcs = Slab::new_starting_at(Token(1), 100);
         for mut s in services {
             svcs.insert_with(|token| {
@@ -39,15 +76,68 @@ impl LoopHandler {
         }
 
         let mut r = LoopHandler {
-            services: svcs
+            services: svcs,
+            shipgate_ready: false,
+            startup_failed: false,
+            memory_limit: memory_limit_mb as u64 * 1024 * 1024,
+            busy: false,
+            shutting_down: false
         };
 
+        let mut waiting = 0;
         for s in r.services.iter_mut() {
-            s.register(event_loop).unwrap();
+            if s.needs_shipgate() {
+                waiting += 1;
+            } else {
+                s.register(event_loop).unwrap();
+            }
+        }
+        if waiting > 0 {
+            info!("{} services waiting for the shipgate before taking clients", waiting);
+            if shipgate_timeout > 0 {
+                event_loop.timeout_ms(SHIPGATE_TIMEOUT, shipgate_timeout as u64 * 1000).unwrap();
+            }
+        }
+
+        event_loop.timeout_ms(STATS_TIMEOUT, STATS_INTERVAL_MS).unwrap();
+
+        if r.memory_limit > 0 {
+            if memory::resident_bytes().is_some() {
+                event_loop.timeout_ms(MEMORY_TIMEOUT, MEMORY_INTERVAL_MS).unwrap();
+            } else {
+                warn!("Can't read this process's memory use here; memory_limit_mb has no effect");
+            }
         }
 
         r
     }
+
+    fn check_memory(&mut self) {
+        let used = match memory::resident_bytes() {
+            Some(u) => u,
+            None => return
+        };
+        if !self.busy && used >= self.memory_limit {
+            warn!("Using {} MB of memory, over the {} MB limit; refusing new clients", used / 1048576, self.memory_limit / 1048576);
+            self.busy = true;
+        } else if self.busy && used < self.memory_limit / 10 * 9 {
+            info!("Memory use is down to {} MB; taking new clients again", used / 1048576);
+            self.busy = false;
+        }
+    }
+
+    /// Let services that need to finish up, like the shipgate saving batched
+    /// writes, do so. Call once the loop has stopped.
+    pub fn finish_services(&mut self) {
+        for s in self.services.iter_mut() {
+            s.finish();
+        }
+    }
+
+    /// The loop stopped because a service's dependency never came up.
+    pub fn startup_failed(&self) -> bool {
+        self.startup_failed
+    }
 }
 
 impl Handler for LoopHandler {
@@ -61,7 +151,7 @@ impl Handler for LoopHandler {
                 Some(s) => {
                     // Accept
                     debug!("Listener accept");
-                    match s.accept(event_loop) {
+                    match s.accept(event_loop, self.busy) {
                         Err(_e) => event_loop.shutdown(),
                         Ok(_) => ()
                     }
@@ -88,14 +178,15 @@ impl Handler for LoopHandler {
 
         if events.contains(EventSet::hup()) {
             debug!("Token {} hup", token.0);
-            match self.services.iter_mut().find(|s| s.has_client(token)) {
-                Some(s) => s.drop_client(event_loop, token),
-                None => {
-                    // this is a service hupping, shutdown
-                    warn!("A service listener got hup, shutting down loop.");
-                    event_loop.shutdown();
-                    return
-                }
+            if self.services.contains(token) {
+                // this is a service hupping, shutdown
+                warn!("A service listener got hup, shutting down loop.");
+                event_loop.shutdown();
+                return
+            }
+            // The client may already have been dropped for a bad read above.
+            if let Some(s) = self.services.iter_mut().find(|s| s.has_client(token)) {
+                s.drop_client(event_loop, token);
             }
         }
 
@@ -130,12 +221,72 @@ impl Handler for LoopHandler {
                     .unwrap_or_else(|| {
                         error!("attempted to drop client {} but doesn't exist", t)
                     });
+            },
+            LoopMsg::ShipGateReady => {
+                if self.shipgate_ready {
+                    return
+                }
+                self.shipgate_ready = true;
+                for s in self.services.iter_mut() {
+                    if s.needs_shipgate() {
+                        s.register(event_loop).unwrap();
+                    }
+                }
+                info!("Shipgate is ready; all services are taking clients");
+            },
+            LoopMsg::Shutdown => {
+                if self.shutting_down {
+                    return
+                }
+                info!("Shutting down");
+                self.shutting_down = true;
+                // The loop keeps running a while to carry what services send
+                // their clients and the shipgate as they stop.
+                for s in self.services.iter_mut() {
+                    s.begin_shutdown();
+                }
+                event_loop.timeout_ms(SHUTDOWN_TIMEOUT, SHUTDOWN_GRACE_MS).unwrap();
             }
         }
     }
 
-    fn timeout(&mut self, _event_loop: &mut EventLoop<Self>, _timeout: Self::Timeout) {
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timeout: Self::Timeout) {
         debug!("Timeout triggered");
+        if timeout == STATS_TIMEOUT {
+            for s in self.services.iter_mut() {
+                s.log_stats();
+            }
+            event_loop.timeout_ms(STATS_TIMEOUT, STATS_INTERVAL_MS).unwrap();
+            return
+        }
+        if timeout == MEMORY_TIMEOUT {
+            self.check_memory();
+            event_loop.timeout_ms(MEMORY_TIMEOUT, MEMORY_INTERVAL_MS).unwrap();
+            return
+        }
+        if timeout == SHUTDOWN_TIMEOUT {
+            event_loop.shutdown();
+            return
+        }
+        if timeout == SHIPGATE_TIMEOUT {
+            if !self.shipgate_ready {
+                error!("Couldn't connect and authenticate to the shipgate in time. Check that it's running, and that shipgate_addr and shipgate_password are right.");
+                self.startup_failed = true;
+                event_loop.shutdown();
+            }
+            return
+        }
+        if timeout & FIRST_PACKET_TIMEOUT != 0 {
+            let token = Token(timeout & !FIRST_PACKET_TIMEOUT);
+            if let Some(s) = self.services.iter_mut().find(|s| s.has_client(token)) {
+                s.check_first_packet(event_loop, token);
+            }
+            return
+        }
+        // A throttled client can write again. It may have disconnected since.
+        if let Some(s) = self.services.iter_mut().find(|s| s.has_client(Token(timeout))) {
+            s.resume_send(event_loop, Token(timeout));
+        }
     }
 
     fn interrupted(&mut self, event_loop: &mut EventLoop<Self>) {
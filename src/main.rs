@@ -6,6 +6,7 @@ extern crate psodata;
 extern crate psomsg_common;
 extern crate psodb_common;
 extern crate psodb_sqlite;
+extern crate psodb_mysql;
 
 extern crate rand;
 extern crate byteorder;
@@ -20,11 +21,13 @@ extern crate staticvec;
 extern crate env_logger;
 extern crate toml;
 extern crate time;
+extern crate ctrlc;
 
 pub mod patch;
 pub mod data;
 pub mod game;
 pub mod login;
+pub mod proxy;
 pub mod bb;
 pub mod ship;
 pub mod block;
@@ -36,6 +39,9 @@ pub mod services;
 pub mod config;
 pub mod maps;
 pub mod droptables;
+pub mod eventlog;
+pub mod holidays;
+pub mod stacklimits;
 
 use std::io::Cursor;
 
@@ -44,6 +50,10 @@ use ::args::Args;
 
 use docopt::Docopt;
 
+use env_logger::LogBuilder;
+
+use log::LogLevelFilter;
+
 use mio::EventLoop;
 
 use psodata::battleparam::BattleParamTables;
@@ -52,30 +62,51 @@ use psodata::prs::decompress_prs;
 
 use psoserial::Serial;
 
-use ::loop_handler::LoopHandler;
-use ::patch::PatchService;
+use ::loop_handler::{LoopHandler, LoopMsg};
+use ::patch::{PatchService, Bulletin};
 use ::data::DataService;
+use ::data::manifest::Manifest;
 use ::login::bb::BbLoginService;
+use ::proxy::ProxyService;
 use ::login::paramfiles::load_paramfiles_msgs;
 use ::shipgate::client::ShipGateClient;
 use ::ship::ShipService;
 use ::block::BlockService;
 use ::shipgate::ShipGateService;
 use ::services::Service;
+use ::services::listener::bind_listener;
 use ::config::Config;
 use ::config::ServiceConf;
 use ::droptables::DropTable;
+use ::eventlog::EventLog;
+use ::stacklimits::StackLimits;
+use ::util::clock::check_clock;
 
+use std::env;
 use std::fs::File;
+use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use ::game::Version;
 use ::bb::read_key_table;
 use ::maps::Areas;
 
-fn main() {
-    env_logger::init().expect("env_logger failed to initialize");
+/// Log as `RUST_LOG` says, except for services given their own level.
+fn init_logging(config: &Config) {
+    let mut builder = LogBuilder::new();
+    match env::var("RUST_LOG") {
+        Ok(spec) => { builder.parse(&spec); },
+        // What env_logger does on its own without RUST_LOG.
+        Err(_) => { builder.filter(None, LogLevelFilter::Error); }
+    }
+    for (target, level) in config.log_filters() {
+        builder.filter(Some(&target), level);
+    }
+    builder.init().expect("env_logger failed to initialize");
+}
 
+fn main() {
     let args: Args = Docopt::new(USAGE_STRING)
         .and_then(|o| o.decode())
         .unwrap_or_else(|e| e.exit());
@@ -85,6 +116,11 @@ fn main() {
         return
     }
 
+    if args.flag_generate_config {
+        print!("{}", Config::default_example());
+        return
+    }
+
     let config: Config;
     {
         use std::io::Read;
@@ -93,6 +129,12 @@ fn main() {
         config_file.read_to_string(&mut config_string).expect("Failed to read config file completely");
         config = Config::from_toml_string(&config_string).expect("Failed to parse TOML");
     }
+    init_logging(&config);
+
+    if let Err(e) = check_clock(&config.clock_check) {
+        error!("{}; refusing to start", e);
+        ::std::process::exit(1);
+    }
 
     // Load the bb key table.
     let bb_keytable;
@@ -137,6 +179,24 @@ fn main() {
         .expect("Unable to load drop tables"));
     info!("Loaded BB ItemPT.gsl and ItemRT.gsl drop tables from path: {}/param/", config.data_path);
 
+    // Load the tool stack limits, if there are any
+    let stack_limits = if Path::new(&config.stack_limits_path).exists() {
+        let l = StackLimits::load_from_file(&config.stack_limits_path).expect("Unable to load tool stack limits");
+        info!("Loaded {} tool stack limits from: {}", l.len(), config.stack_limits_path);
+        Arc::new(l)
+    } else {
+        Arc::new(StackLimits::default())
+    };
+
+    let event_log = match config.event_log {
+        Some(ref dest) => {
+            let l = EventLog::spawn(dest).expect(&format!("Failed to open event log {}", dest));
+            info!("Writing connection events to {}", dest);
+            l
+        },
+        None => EventLog::disabled()
+    };
+
     let mut event_loop = EventLoop::new().expect("Could not create event loop");
     info!("Socket event loop created.");
 
@@ -144,73 +204,126 @@ fn main() {
     let mut sg: Option<Service> = None;
     if let Some(c) = config.services.iter().find(|c| if let _e @ &&ServiceConf::ShipGate {..} = c { true } else { false } ) {
         match c {
-            &ServiceConf::ShipGate { ref bind, ref password, ref db } => {
+            &ServiceConf::ShipGate { ref bind, ref password, ref db, storage_quota, shared_bank_slots, unique_names, batch_interval, batch_size, maintenance_hour, maintenance_max_requests, .. } => {
                 let pool = Arc::new(db.make_pool().expect("Couldn't make database pool for ShipGate."));
-                sg = Some(ShipGateService::spawn(bind, event_loop.channel(), password, pool));
+                sg = Some(ShipGateService::spawn(bind_listener(bind), event_loop.channel(), password, pool, storage_quota, shared_bank_slots, unique_names, batch_interval, batch_size, maintenance_hour, maintenance_max_requests));
             },
             _ => unreachable!()
         }
     }
 
     // Spin up the shipgate client.
-    let sg_sender = ShipGateClient::spawn(config.shipgate_addr.clone(), &config.shipgate_password);
+    let sg_sender = ShipGateClient::spawn(config.shipgate_addr.clone(), &config.shipgate_password, event_loop.channel());
+
+    // Blocks show the patch service's MOTD and news too.
+    let bulletin = config.services.iter().filter_map(|s| match s {
+        &ServiceConf::Patch { ref motd, ref news, news_interval, .. } => Some((motd, news, news_interval)),
+        _ => None
+    }).next();
 
     let mut services = Vec::new();
     for s in config.services.iter() {
         match s {
-            &ServiceConf::Patch { ref bind, ref v4_servers, ref motd, random_balance } => {
-                info!("Patch service at {:?}", bind);
+            &ServiceConf::Patch { ref bind, ref v4_servers, ref motd, random_balance, ref versions, ref news, news_interval, .. } => {
+                info!("Patch service at {}", bind);
                 services.push(PatchService::spawn(
-                    bind,
+                    bind_listener(bind),
                     event_loop.channel(),
                     v4_servers.clone(),
                     motd.clone(),
-                    random_balance));
+                    random_balance,
+                    versions.clone(),
+                    news.clone(),
+                    news_interval));
             },
-            &ServiceConf::Data { ref bind, .. } => {
-                info!("Data service at {:?}", bind);
-                services.push(DataService::spawn(bind, event_loop.channel()));
+            &ServiceConf::Data { ref bind, ref files_path, .. } => {
+                info!("Data service at {}", bind);
+                let manifest = match *files_path {
+                    Some(ref p) => {
+                        let path = Path::new(&config.data_path).join(p);
+                        match Manifest::build(&path) {
+                            Ok(m) => {
+                                info!("Data service has {} files, {} bytes in all, from {}", m.files.len(), m.total_size(), path.display());
+                                m
+                            },
+                            Err(e) => {
+                                error!("Data service files_path: {}; refusing to start", e);
+                                ::std::process::exit(1);
+                            }
+                        }
+                    },
+                    None => Manifest::default()
+                };
+                services.push(DataService::spawn(bind_listener(bind), event_loop.channel(), manifest));
             },
-            &ServiceConf::Login { ref bind, version, addr, .. } => {
-                info!("Login service at {:?}", bind);
+            &ServiceConf::Login { ref bind, version, addr, ref rules, ref class_restrictions, .. } => {
+                info!("Login service at {}", bind);
                 match version {
                     Version::BlueBurst => {
                         services.push(BbLoginService::spawn(
-                            bind,
-                            addr,
+                            bind_listener(bind),
+                            ::config::redirect_v4(&addr, "the Blue Burst redirect").expect("Blue Burst login addr is IPv4"),
                             event_loop.channel(),
                             bb_keytable.clone(),
                             &sg_sender,
                             param_files.clone(),
-                            level_table.clone()))
+                            level_table.clone(),
+                            event_log.clone(),
+                            config.handshake_timeout,
+                            rules.clone(),
+                            class_restrictions.clone()))
                     },
                     _ => unimplemented!()
                 }
             },
-            &ServiceConf::Ship { ref bind, ref name, ref blocks, my_ipv4, .. } => {
-                info!("Ship service at {:?}", bind);
-                services.push(ShipService::spawn(bind,
+            &ServiceConf::Ship { ref bind, ref name, ref blocks, addr, .. } => {
+                info!("Ship service at {}", bind);
+                services.push(ShipService::spawn(bind_listener(bind),
                     event_loop.channel(),
                     bb_keytable.clone(),
                     &sg_sender,
                     name,
                     blocks.clone(),
-                    my_ipv4));
+                    ::config::redirect_v4(&addr, "ship registration with the shipgate").expect("ship addr is IPv4"),
+                    config.handshake_timeout));
             },
-            &ServiceConf::Block { ref bind, num, event, .. } => {
-                info!("Block service at {:?}", bind);
+            &ServiceConf::Block { ref bind, num, lobbies, event, event_override, ref ship, ref siblings, random_balance, ref options, .. } => {
+                info!("Block service at {}", bind);
                 services.push(BlockService::spawn(
-                    bind,
+                    bind_listener(bind),
                     event_loop.channel(),
                     &sg_sender,
                     bb_keytable.clone(),
                     num,
+                    lobbies,
                     event,
+                    if event_override { None } else { ship.clone() },
+                    ship.clone(),
+                    config.holidays.clone(),
+                    options.clone(),
+                    match bulletin {
+                        Some((motd, news, news_interval)) => Bulletin::new(motd.clone(), news.clone(), news_interval),
+                        None => Default::default()
+                    },
                     battle_params.clone(),
                     online_maps.clone(),
                     offline_maps.clone(),
                     level_table.clone(),
-                    drop_table.clone()));
+                    drop_table.clone(),
+                    stack_limits.clone(),
+                    event_log.clone(),
+                    config.handshake_timeout,
+                    ::block::plugin::registered(num),
+                    siblings.clone(),
+                    random_balance));
+            },
+            &ServiceConf::Proxy { ref bind, target, .. } => {
+                info!("Proxy service at {}", bind);
+                services.push(ProxyService::spawn(
+                    bind_listener(bind),
+                    ::config::redirect_v4(&target, "the Blue Burst redirect").expect("proxy target is IPv4"),
+                    event_loop.channel(),
+                    bb_keytable.clone()));
             },
             &ServiceConf::ShipGate { .. } => {
                 match sg {
@@ -224,10 +337,35 @@ fn main() {
                 }
             }
         }
+        if let Some(t) = s.throttle() {
+            info!("Throttling outbound client traffic to {} bytes/s", t.rate);
+            services.last_mut().unwrap().set_throttle(Some(t.clone()));
+        }
+        services.last_mut().unwrap().set_key_check(config.reject_key_mismatch);
+        services.last_mut().unwrap().set_first_packet_window(config.first_packet_window_ms);
+        if let Some(m) = s.max_per_ip() {
+            services.last_mut().unwrap().set_max_per_ip(m);
+        }
+        services.last_mut().unwrap().set_event_log(event_log.clone(), s.kind());
     }
     info!("{} total services.", services.len());
 
-    let mut loop_handler = LoopHandler::new(services, &mut event_loop);
+    let shutdown_sender = event_loop.channel();
+    let interrupted = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            // A second Ctrl-C doesn't wait for anything.
+            ::std::process::exit(1);
+        }
+        info!("Interrupted; shutting down. Press Ctrl-C again to stop now.");
+        let _ = shutdown_sender.send(LoopMsg::Shutdown);
+    });
+
+    let mut loop_handler = LoopHandler::new(services, &mut event_loop, config.shipgate_timeout, config.memory_limit_mb);
 
     event_loop.run(&mut loop_handler).unwrap();
+    loop_handler.finish_services();
+    if loop_handler.startup_failed() {
+        ::std::process::exit(1);
+    }
 }
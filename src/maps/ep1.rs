@@ -6,7 +6,7 @@ use std::collections::HashMap;
 use super::VariationData;
 
 /// Container of all areas' maps for Episode 1.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Ep1Areas {
     pub city: VariationData,
     pub forest1: Vec<VariationData>,
@@ -7,7 +7,7 @@ use super::VariationData;
 ///
 /// Fun fact, VR Temple and Spaceship are direct throwbacks to dungeons from
 /// classic Phantasy Star, in particular Phantasy Star 4.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Ep2Areas {
     pub city: VariationData,
     pub ruins1: HashMap<(u32, u32), VariationData>, // temple, not to be confused with ep1's "ancient"
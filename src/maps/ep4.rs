@@ -3,7 +3,7 @@ use std::io;
 use super::VariationData;
 
 /// Container of all areas' maps for Episode 4.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Ep4Areas {
     pub city: VariationData,
     pub wilds1: Vec<VariationData>, // east
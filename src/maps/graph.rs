@@ -0,0 +1,87 @@
+//! Which area changes free exploration allows.
+//!
+//! Pioneer 2 connects to everything: its teleporter reaches each stage, and
+//! a telepipe or Ryuker can bring a player back down to wherever they left.
+//! Anywhere can go back to Pioneer 2. Otherwise a player moves between the
+//! areas of one stage, and from a stage's last area to its boss. Areas that
+//! belong to no stage here (quest and battle maps) are never refused.
+
+use super::{Ep1AreaCode as E1, Ep2AreaCode as E2, Ep4AreaCode as E4};
+
+/// The areas of a stage in order, and its boss if it has one.
+type Stage = (&'static [u8], Option<u8>);
+
+static EP1_STAGES: &'static [Stage] = &[
+    (&[E1::Forest1 as u8, E1::Forest2 as u8], Some(E1::Dragon as u8)),
+    (&[E1::Cave1 as u8, E1::Cave2 as u8, E1::Cave3 as u8], Some(E1::DeRolLe as u8)),
+    (&[E1::Mine1 as u8, E1::Mine2 as u8], Some(E1::VolOpt as u8)),
+    (&[E1::Ruins1 as u8, E1::Ruins2 as u8, E1::Ruins3 as u8], Some(E1::DarkFalz as u8))
+];
+
+static EP2_STAGES: &'static [Stage] = &[
+    (&[E2::TempleAlpha as u8, E2::TempleBeta as u8], Some(E2::BarbaRay as u8)),
+    (&[E2::SpaceshipAlpha as u8, E2::SpaceshipBeta as u8], Some(E2::GolDragon as u8)),
+    (&[E2::CentralControlArea as u8, E2::JungleNorth as u8, E2::JungleEast as u8, E2::Mountain as u8, E2::Seaside as u8], Some(E2::GalGryphon as u8)),
+    (&[E2::SeabedUpper as u8, E2::SeabedLower as u8], Some(E2::OlgaFlow as u8))
+];
+
+static EP4_STAGES: &'static [Stage] = &[
+    (&[E4::CraterEast as u8, E4::CraterWest as u8, E4::CraterSouth as u8, E4::CraterNorth as u8, E4::CraterInterior as u8], None),
+    (&[E4::Desert1 as u8, E4::Desert2 as u8, E4::Desert3 as u8], Some(E4::SaintMilion as u8))
+];
+
+/// Whether a player in a game of `episode` (1, 2, or 3 for Episode 4) can
+/// go from area `from` to area `to`.
+pub fn valid_area_change(episode: u8, from: u8, to: u8) -> bool {
+    let stages = match episode {
+        1 => EP1_STAGES,
+        2 => EP2_STAGES,
+        3 => EP4_STAGES,
+        _ => return true
+    };
+    if from == to || from == 0 || to == 0 {
+        return true
+    }
+    let stage_of = |a: u8| stages.iter().find(|&&(areas, boss)| areas.contains(&a) || boss == Some(a));
+    let (areas, boss) = match (stage_of(from), stage_of(to)) {
+        (Some(f), Some(t)) if f.0 == t.0 => *f,
+        (Some(_), Some(_)) => return false,
+        // One of them isn't a stage area we know.
+        _ => return true
+    };
+    if boss == Some(from) {
+        // Bosses only lead back to Pioneer 2.
+        return false
+    }
+    if boss == Some(to) {
+        return areas.last() == Some(&from)
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::valid_area_change;
+
+    #[test]
+    fn test_ep1() {
+        assert!(valid_area_change(1, 0, 3));
+        assert!(valid_area_change(1, 1, 2));
+        assert!(valid_area_change(1, 2, 1));
+        assert!(valid_area_change(1, 2, 11));
+        assert!(valid_area_change(1, 11, 0));
+        assert!(!valid_area_change(1, 1, 11));
+        assert!(!valid_area_change(1, 11, 3));
+        assert!(!valid_area_change(1, 1, 9));
+        // Battle maps aren't checked.
+        assert!(valid_area_change(1, 16, 17));
+    }
+
+    #[test]
+    fn test_ep4() {
+        assert!(valid_area_change(3, 1, 5));
+        assert!(valid_area_change(3, 8, 9));
+        assert!(!valid_area_change(3, 5, 6));
+        assert!(!valid_area_change(3, 6, 9));
+    }
+}
@@ -9,6 +9,7 @@ use psodata::map::{read_map_enemies, read_map_objects};
 pub mod ep1;
 pub mod ep2;
 pub mod ep4;
+pub mod graph;
 
 pub use self::ep1::Ep1Areas;
 pub use self::ep2::Ep2Areas;
@@ -80,7 +81,7 @@ pub enum Ep4AreaCode {
 }
 
 /// Collection of all areas
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Areas {
     pub ep1: Ep1Areas,
     pub ep2: Ep2Areas,
@@ -131,7 +132,7 @@ pub struct InstanceObject {
 }
 
 /// Map variation data.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct VariationData {
     pub enemies: Vec<MapEnemy>,
     pub objects: Vec<MapObject>
@@ -8,44 +8,170 @@ use std::sync::mpsc::Receiver;
 
 use std::thread;
 
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use mio::tcp::TcpListener;
 use mio::Sender;
 
 use psomsg::patch::*;
 
 use ::services::message::NetMsg;
+use ::services::listener::Listener;
 
 use ::services::ServiceType;
 
 use rand::random;
 
+use time;
+
+use ::config::{PatchVersionConf, NewsItem};
+use ::game::Version;
+
+/// Hands out data servers to connecting clients, either in turn or at
+/// random.
+pub struct Balancer {
+    v4_servers: Vec<SocketAddrV4>,
+    random: bool,
+    next: AtomicUsize
+}
+
+impl Balancer {
+    pub fn new(v4_servers: Vec<SocketAddrV4>, random: bool) -> Balancer {
+        if v4_servers.len() == 0 { panic!("no data redirect servers specified") }
+        Balancer {
+            v4_servers: v4_servers,
+            random: random,
+            next: AtomicUsize::new(0)
+        }
+    }
+
+    /// The data server for the next client.
+    pub fn next(&self) -> SocketAddrV4 {
+        let i = if self.random {
+            random::<usize>()
+        } else {
+            self.next.fetch_add(1, Ordering::SeqCst)
+        };
+        self.v4_servers[i % self.v4_servers.len()]
+    }
+}
+
+/// Overrides for one client version.
+struct VersionOverride {
+    version: Version,
+    motd: Option<String>,
+    nodes: Option<Balancer>
+}
+
 pub struct PatchService {
     receiver: Receiver<ServiceMsg>,
     sender: Sender<LoopMsg>,
-    v4_servers: Vec<SocketAddrV4>,
-    next: usize,
+    nodes: Balancer,
+    bulletin: Bulletin,
+    versions: Vec<VersionOverride>
+}
+
+/// The news rotation. Each item appears as many times as its weight.
+#[derive(Default)]
+struct News {
+    items: Vec<String>,
+    interval: u32,
+    next: usize
+}
+
+impl News {
+    fn new(items: Vec<NewsItem>, interval: u32) -> News {
+        let mut expanded = Vec::new();
+        for i in items {
+            for _ in 0..i.weight {
+                expanded.push(i.text.clone());
+            }
+        }
+        News {
+            items: expanded,
+            interval: interval,
+            next: 0
+        }
+    }
+
+    /// The item to show a client connecting now.
+    fn current(&mut self) -> Option<&str> {
+        if self.items.len() == 0 {
+            return None
+        }
+        let i = if self.interval == 0 {
+            let i = self.next;
+            self.next = (self.next + 1) % self.items.len();
+            i
+        } else {
+            (time::get_time().sec as u64 / self.interval as u64) as usize % self.items.len()
+        };
+        Some(&self.items[i])
+    }
+}
+
+/// The message of the day with the news rotation under it. Blocks show the
+/// patch service's too, so players who skipped it can catch up.
+#[derive(Default)]
+pub struct Bulletin {
     motd: String,
-    random_data: bool
+    news: News
+}
+
+impl Bulletin {
+    pub fn new(motd: String, news: Vec<NewsItem>, news_interval: u32) -> Bulletin {
+        Bulletin {
+            motd: motd,
+            news: News::new(news, news_interval)
+        }
+    }
+
+    /// What to show a client now: the MOTD and the current news item.
+    pub fn current(&mut self) -> String {
+        let motd = self.motd.clone();
+        self.current_with(motd)
+    }
+
+    /// As `current`, with `motd` in place of the configured one.
+    fn current_with(&mut self, mut motd: String) -> String {
+        if let Some(item) = self.news.current() {
+            if motd.len() > 0 {
+                motd.push_str("\n\n");
+            }
+            motd.push_str(item);
+        }
+        motd
+    }
+}
+
+/// Guess the client version from its patch login. Only Blue Burst and PC
+/// clients patch; Blue Burst leaves the username blank and PC sends one.
+fn detect_version(login: &Login) -> Version {
+    if login.username.iter().all(|&b| b == 0) {
+        Version::BlueBurst
+    } else {
+        Version::PC
+    }
 }
 
 impl PatchService {
-    pub fn spawn(bind: &SocketAddr, sender: Sender<LoopMsg>, v4_servers: Vec<SocketAddrV4>, motd: String, random_data: bool) -> Service {
+    pub fn spawn<L: Listener + 'static>(listener: L, sender: Sender<LoopMsg>, v4_servers: Vec<SocketAddrV4>, motd: String, random_data: bool, versions: Vec<PatchVersionConf>, news: Vec<NewsItem>, news_interval: u32) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
-
-        if v4_servers.len() == 0 { panic!("no data redirect servers specified") }
+        let nodes = Balancer::new(v4_servers, random_data);
+        let versions: Vec<VersionOverride> = versions.into_iter().map(|v| VersionOverride {
+            version: v.version,
+            motd: v.motd,
+            nodes: v.v4_servers.map(|s| Balancer::new(s, random_data))
+        }).collect();
 
         thread::spawn(move|| {
             let p = PatchService {
                 receiver: rx,
                 sender: sender,
-                v4_servers: v4_servers,
-                next: 0,
-                motd: motd,
-                random_data: random_data
+                nodes: nodes,
+                bulletin: Bulletin::new(motd, news, news_interval),
+                versions: versions
             };
             p.run()
         });
@@ -72,16 +198,23 @@ impl PatchService {
                         Message::Welcome(None) => {
                             self.sender.send((id, Message::Login(None)).into()).unwrap();
                         },
-                        Message::Login(Some(..)) => {
-                            self.sender.send((id, Message::Motd(Some(Motd { message: self.motd.clone() }))).into()).unwrap();
-                            self.sender.send((id, Message::Redirect(Some(Redirect(self.v4_servers[self.next])))).into()).unwrap();
-
-                            if self.random_data {
-                                self.next = random();
-                            } else {
-                                self.next += 1;
-                            }
-                            self.next %= self.v4_servers.len();
+                        Message::Login(Some(l)) => {
+                            let version = detect_version(&l);
+                            debug!("Patch client {} looks like {:?}", id, version);
+                            let (o_motd, redirect) = {
+                                let o = self.versions.iter().find(|o| o.version == version);
+                                let redirect = match o.and_then(|o| o.nodes.as_ref()) {
+                                    Some(n) => n.next(),
+                                    None => self.nodes.next()
+                                };
+                                (o.and_then(|o| o.motd.clone()), redirect)
+                            };
+                            let motd = match o_motd {
+                                Some(m) => self.bulletin.current_with(m),
+                                None => self.bulletin.current()
+                            };
+                            self.sender.send((id, Message::Motd(Some(Motd { message: motd }))).into()).unwrap();
+                            self.sender.send((id, Message::Redirect(Some(Redirect(redirect)))).into()).unwrap();
                         },
                         u => {
                             warn!("weird patch message sent by client: {:?}", u);
@@ -93,3 +226,54 @@ impl PatchService {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::net::SocketAddrV4;
+
+    use psomsg::patch::Login;
+    use psoserial::Serial;
+
+    use ::game::Version;
+
+    use super::{Balancer, detect_version};
+
+    fn nodes() -> Vec<SocketAddrV4> {
+        vec!["127.0.0.1:11001".parse().unwrap(), "127.0.0.1:11002".parse().unwrap(), "127.0.0.1:11003".parse().unwrap()]
+    }
+
+    #[test]
+    fn test_round_robin_wraps() {
+        let n = nodes();
+        let b = Balancer::new(n.clone(), false);
+        let picked: Vec<_> = (0..7).map(|_| b.next()).collect();
+        assert_eq!(picked, vec![n[0], n[1], n[2], n[0], n[1], n[2], n[0]]);
+    }
+
+    #[test]
+    fn test_random_picks_every_node() {
+        let n = nodes();
+        let b = Balancer::new(n.clone(), true);
+        let mut seen = [false; 3];
+        for _ in 0..1000 {
+            let a = b.next();
+            let i = n.iter().position(|&x| x == a).expect("picked an unknown node");
+            seen[i] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    /// A patch login as it comes off the wire, with `username`.
+    fn login(username: &[u8]) -> Login {
+        let mut data = vec![0u8; 108];
+        data[12..12 + username.len()].copy_from_slice(username);
+        Login::deserialize(&mut Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn test_detect_version() {
+        assert_eq!(detect_version(&login(b"")), Version::BlueBurst);
+        assert_eq!(detect_version(&login(b"player")), Version::PC);
+    }
+}
@@ -0,0 +1,135 @@
+//! A Blue Burst service that only redirects. Clients are welcomed and sent
+//! straight on to the configured target, which is useful in front of servers
+//! on another address or for moving players off one being retired.
+
+use ::services::{Service, ServiceMsg};
+use ::loop_handler::LoopMsg;
+
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::net::SocketAddrV4;
+
+use mio::Sender;
+
+use psomsg::bb::*;
+
+use rand::random;
+
+use ::services::listener::Listener;
+use ::services::ServiceType;
+
+pub struct ProxyService {
+    receiver: Receiver<ServiceMsg>,
+    sender: Sender<LoopMsg>,
+    target: SocketAddrV4
+}
+
+impl ProxyService {
+    pub fn spawn<L: Listener + 'static>(listener: L, target: SocketAddrV4, sender: Sender<LoopMsg>, key_table: Arc<Vec<u32>>) -> Service {
+        let (tx, rx) = channel();
+
+        thread::spawn(move|| {
+            let d = ProxyService {
+                receiver: rx,
+                sender: sender,
+                target: target
+            };
+            d.run()
+        });
+
+        Service::new(listener, tx, ServiceType::Bb(key_table))
+    }
+
+    pub fn run(self) {
+        info!("Proxy service running; redirecting to {}", self.target);
+
+        for msg in self.receiver.iter() {
+            match msg {
+                ServiceMsg::ClientConnected((_addr, id)) => {
+                    info!("Client {} connected to proxy; redirecting to {}", id, self.target);
+                    let sk = vec![random(); 48];
+                    let ck = vec![random(); 48];
+                    self.sender.send((id, Message::BbWelcome(0, BbWelcome(sk, ck))).into()).unwrap();
+                    // The client closes the connection itself once it's
+                    // redirected.
+                    let r = Message::Redirect(0, Redirect {
+                        ip: *self.target.ip(),
+                        port: self.target.port()
+                    });
+                    self.sender.send((id, r).into()).unwrap();
+                },
+                ServiceMsg::ClientDisconnected(id) => {
+                    info!("Client {} disconnected from proxy", id);
+                },
+                ServiceMsg::ClientSaid(id, _) => {
+                    debug!("Client {} said something to the proxy; ignoring it", id);
+                },
+                _ => ()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use mio::{EventLoop, Handler};
+
+    use psomsg::bb::*;
+
+    use ::loop_handler::LoopMsg;
+    use ::services::ServiceMsg;
+    use ::services::message::NetMsg;
+
+    use super::ProxyService;
+
+    /// Collects what the service sends to the loop.
+    struct Collect(Vec<LoopMsg>);
+
+    impl Handler for Collect {
+        type Timeout = ();
+        type Message = LoopMsg;
+
+        fn notify(&mut self, _event_loop: &mut EventLoop<Self>, msg: LoopMsg) {
+            self.0.push(msg);
+        }
+    }
+
+    #[test]
+    fn test_proxy_redirects_on_connect() {
+        let mut event_loop = EventLoop::<Collect>::new().unwrap();
+        let (tx, rx) = channel();
+        let d = ProxyService {
+            receiver: rx,
+            sender: event_loop.channel(),
+            target: "10.0.0.2:12000".parse().unwrap()
+        };
+        let t = thread::spawn(move|| d.run());
+        tx.send(ServiceMsg::ClientConnected(("127.0.0.1:50000".parse().unwrap(), 7))).unwrap();
+        drop(tx);
+        t.join().unwrap();
+
+        let mut h = Collect(Vec::new());
+        for _ in 0..10 {
+            if h.0.len() >= 2 {
+                break
+            }
+            event_loop.run_once(&mut h, Some(100)).unwrap();
+        }
+        assert_eq!(h.0.len(), 2);
+        match h.0[0] {
+            LoopMsg::Client(7, NetMsg::Bb(Message::BbWelcome(..))) => (),
+            _ => panic!("expected a welcome first")
+        }
+        match h.0[1] {
+            LoopMsg::Client(7, NetMsg::Bb(Message::Redirect(_, ref r))) => {
+                assert_eq!(*r, Redirect { ip: "10.0.0.2".parse().unwrap(), port: 12000 });
+            },
+            _ => panic!("expected a redirect to the target")
+        }
+    }
+}
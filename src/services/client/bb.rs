@@ -1,4 +1,3 @@
-use mio::tcp::TcpStream;
 use mio::{EventLoop, EventSet, Handler, PollOpt, Token, TryRead, TryWrite};
 
 use std::io;
@@ -13,8 +12,12 @@ use psomsg::Serial;
 use ::services::ServiceMsg;
 
 use ::services::message::NetMsg;
+use ::services::listener::Stream;
 
-use super::{padded, ClientHandler};
+use super::{padded, ClientHandler, Throttle};
+
+/// Largest first packet we expect from a client logging in.
+const MAX_LOGIN_SIZE: usize = 0x200;
 
 #[derive(Clone, Copy)]
 enum SendState {
@@ -39,7 +42,7 @@ impl Default for ReadState {
 }
 
 pub struct BbClient {
-    pub stream: TcpStream,
+    pub stream: Box<Stream>,
     pub token: Token,
     pub ciphers: Option<(BbCipher, BbCipher)>,
     key_table: Arc<Vec<u32>>,
@@ -50,10 +53,25 @@ pub struct BbClient {
     read_state: ReadState,
     send_buffer: Vec<u8>,
     read_buffer: Vec<u8>,
+    pub throttle: Option<Throttle>,
+    /// Treat a first packet that doesn't decrypt to a login as the client
+    /// using a different key table, and drop it as such.
+    pub key_check: bool,
+    /// Drop the client if its first packet isn't a login, whatever
+    /// `key_check` says.
+    pub strict_first_packet: bool,
+    /// The `time::precise_time_ns` by which the first packet has to have
+    /// arrived, if there's a limit.
+    pub first_packet_deadline: Option<u64>,
+    /// Turned away while the server is busy. What it sends is ignored, and
+    /// it's hung up on once its queued messages are written.
+    pub refused: bool,
+    /// Still waiting on the first packet sent under the ciphers.
+    first_packet: bool
 }
 
 impl BbClient {
-    pub fn new(stream: TcpStream, token: Token, thread_sender: MpscSender<ServiceMsg>, key_table: Arc<Vec<u32>>) -> BbClient {
+    pub fn new(stream: Box<Stream>, token: Token, thread_sender: MpscSender<ServiceMsg>, key_table: Arc<Vec<u32>>) -> BbClient {
         BbClient {
             stream: stream,
             token: token,
@@ -65,9 +83,25 @@ impl BbClient {
             send_state: SendState::WaitingForMsg,
             send_buffer: Vec::new(),
             read_state: Default::default(),
-            read_buffer: vec![0; 4096]
+            read_buffer: vec![0; 4096],
+            throttle: None,
+            key_check: true,
+            strict_first_packet: false,
+            first_packet_deadline: None,
+            refused: false,
+            first_packet: true
         }
     }
+
+    fn key_mismatch(&self, what: &str) -> io::Error {
+        warn!("BB client token {}: key table mismatch, {} under the issued keys; the client's key table differs from ours", self.token.0, what);
+        io::Error::new(io::ErrorKind::InvalidData, "key table mismatch")
+    }
+
+    /// It hasn't sent a whole packet since it was greeted.
+    pub fn awaiting_first_packet(&self) -> bool {
+        self.first_packet
+    }
 }
 impl ClientHandler for BbClient {
     type Msg = Message;
@@ -79,7 +113,7 @@ impl ClientHandler for BbClient {
 
         debug!("Registering BB client token {}", self.token.0);
         event_loop.register(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -90,7 +124,7 @@ impl ClientHandler for BbClient {
         debug!("Reregistering BB client token {}", self.token.0);
         self.interests.insert(EventSet::readable());
         event_loop.reregister(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -99,6 +133,9 @@ impl ClientHandler for BbClient {
 
     fn readable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         use std::io::Cursor;
+        if self.refused {
+            return Ok(())
+        }
         // Do nothing; this is unimplemented
         // At some point, read into a buffer the header, then the body, then
         // send a message on the service sender.
@@ -129,6 +166,18 @@ impl ClientHandler for BbClient {
                                 use byteorder::{LittleEndian as LE, ReadBytesExt};
                                 size = try!(Cursor::new(&self.read_buffer[..]).read_u16::<LE>()) as usize;
                             }
+                            if (self.key_check || self.strict_first_packet) && self.first_packet && self.ciphers.is_some() {
+                                // Every BB client logs in first, so anything
+                                // else means we decrypted it with the wrong
+                                // keys, or it isn't a real client.
+                                let msg_type = self.read_buffer[2] as u16 | (self.read_buffer[3] as u16) << 8;
+                                if msg_type != 0x0093 || size < 8 || size > MAX_LOGIN_SIZE {
+                                    if self.key_check {
+                                        return Err(self.key_mismatch(&format!("first packet header decrypted to type {:#06x}, size {}", msg_type, size)))
+                                    }
+                                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("first packet was type {:#06x}, size {}, not a login", msg_type, size)))
+                                }
+                            }
                             let padded_size = padded(size, 8);
                             let buffer_len = self.read_buffer.len();
                             if buffer_len < padded_size {
@@ -172,7 +221,16 @@ impl ClientHandler for BbClient {
                                 c.decrypt_in_place(&mut self.read_buffer[8..padded_size]).unwrap();
                             }
                             // parse into message
-                            let message = try!(Message::deserialize(&mut Cursor::new(&self.read_buffer[0..padded_size])));
+                            let message = match Message::deserialize(&mut Cursor::new(&self.read_buffer[0..padded_size])) {
+                                Ok(m) => m,
+                                Err(ref e) if self.key_check && self.first_packet && self.ciphers.is_some() => {
+                                    return Err(self.key_mismatch(&format!("login failed to parse ({})", e)))
+                                },
+                                Err(e) => return Err(e)
+                            };
+                            if self.ciphers.is_some() {
+                                self.first_packet = false;
+                            }
                             // send message to service thread
                             match self.sender.send(ServiceMsg::ClientSaid(self.token.0, NetMsg::Bb(message))) {
                                 Ok(_) => (),
@@ -199,7 +257,7 @@ impl ClientHandler for BbClient {
         }}
     }
 
-    fn writable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    fn writable<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         use psomsg::Serial;
         use std::io::Cursor;
         use std::mem::swap;
@@ -239,6 +297,9 @@ impl ClientHandler for BbClient {
                         // we'll loop again to the SendingMsg handler.
                     },
                     None => {
+                        if self.refused {
+                            return Err(io::Error::new(io::ErrorKind::Other, "server busy"))
+                        }
                         debug!("No message in queue");
                         // Nothing in the queue; remove writing from our interests
                         // until we're notified again.
@@ -248,16 +309,44 @@ impl ClientHandler for BbClient {
                 }
             },
             SendState::SendingMsg(start) => {
+                // If we're throttled, only write as much as the bucket allows.
+                let mut end = self.send_buffer.len();
+                let mut wait = false;
+                if let Some(ref mut t) = self.throttle {
+                    let allowed = t.allowed(self.send_buffer.len(), end - start);
+                    if allowed == 0 {
+                        if !t.resume_pending {
+                            t.resume_pending = true;
+                            if event_loop.timeout_ms(self.token.0, t.wait_ms()).is_err() {
+                                warn!("Failed to schedule throttled send for client token {}", self.token.0);
+                            }
+                        }
+                        wait = true;
+                    }
+                    end = start + allowed;
+                }
+                if wait {
+                    // The resume timeout will call back into writable.
+                    self.interests.remove(EventSet::writable());
+                    return self.reregister(event_loop)
+                }
                 // now, try sending the contents of this buffer.
-                match self.stream.try_write(&self.send_buffer[start..]) {
+                match self.stream.try_write(&self.send_buffer[start..end]) {
                     Ok(Some(bytes)) => {
-                        if bytes < self.send_buffer.len() {
+                        if let Some(ref mut t) = self.throttle {
+                            t.consume(bytes);
+                        }
+                        if start + bytes < self.send_buffer.len() {
                             debug!("Message not fully sent, waiting to continue");
                             // Socket was not ready to send whole message,
                             // resume on next writable.
-                            self.interests.insert(EventSet::writable());
                             self.send_state = SendState::SendingMsg(start + bytes);
-                            return self.reregister(event_loop)
+                            if start + bytes < end {
+                                self.interests.insert(EventSet::writable());
+                                return self.reregister(event_loop)
+                            }
+                            // Otherwise we've used the throttle allowance; loop
+                            // back around and wait for more.
                         } else {
                             debug!("Done sending message, checking for another");
                             self.send_state = SendState::WaitingForMsg
@@ -291,6 +380,66 @@ impl ClientHandler for BbClient {
 
     fn drop_client<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         // unregister self; service will remove token and stream from slab
-        event_loop.deregister(&self.stream)
+        event_loop.deregister(&*self.stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use mio::{EventLoop, Token};
+
+    use psocrypto::{BbCipher, Encryptor};
+    use psomsg::Serial;
+    use psomsg::bb::*;
+
+    use ::loop_handler::LoopHandler;
+    use ::services::ServiceMsg;
+    use ::services::message::NetMsg;
+    use ::services::client::ClientHandler;
+    use ::services::listener::{Listener, MemoryListener};
+
+    use super::BbClient;
+
+    #[test]
+    fn test_client_over_memory_listener() {
+        let mut event_loop = EventLoop::<LoopHandler>::new().unwrap();
+        let listener = MemoryListener::new();
+        let mut remote = listener.connect();
+        let (stream, _) = listener.accept().unwrap().unwrap();
+        assert!(listener.accept().unwrap().is_none());
+
+        let table = Arc::new(vec![0x12345678; 1042]);
+        let (tx, rx) = channel();
+        let mut c = BbClient::new(stream, Token(1), tx, table.clone());
+        c.register(&mut event_loop).unwrap();
+
+        // The welcome goes out in the clear and sets up the ciphers.
+        let (sv, cv) = (vec![1; 48], vec![2; 48]);
+        c.send_msg(&mut event_loop, Message::BbWelcome(0, BbWelcome(sv.clone(), cv.clone()))).unwrap();
+        c.writable(&mut event_loop).unwrap();
+        let mut buf = vec![0; 4096];
+        let n = remote.read(&mut buf).unwrap();
+        match Message::deserialize(&mut Cursor::new(&buf[..n])).unwrap() {
+            Message::BbWelcome(_, BbWelcome(a, b)) => assert_eq!((a, b), (sv, cv.clone())),
+            m => panic!("expected the welcome, got {}", m.name())
+        }
+
+        // A login under the client's cipher reaches the service.
+        let login = Message::BbLogin(0, BbLogin { username: "tester".to_string(), ..Default::default() });
+        let mut out = Cursor::new(Vec::new());
+        login.serialize(&mut out).unwrap();
+        let mut out = out.into_inner();
+        BbCipher::new(&cv, &table).encrypt_in_place(&mut out).unwrap();
+        remote.write_all(&out).unwrap();
+        c.readable(&mut event_loop).unwrap();
+        assert!(!c.awaiting_first_packet());
+        match rx.try_recv().unwrap() {
+            ServiceMsg::ClientSaid(1, NetMsg::Bb(Message::BbLogin(_, l))) => assert_eq!(l.username, "tester"),
+            _ => panic!("expected the login")
+        }
     }
 }
@@ -5,15 +5,18 @@
 pub mod patch;
 pub mod bb;
 pub mod shipgate;
+pub mod throttle;
 
 pub use self::patch::PatchClient;
 pub use self::bb::BbClient;
 pub use self::shipgate::ShipGateClient;
+pub use self::throttle::Throttle;
 
 use mio::{EventLoop, Handler};
 use std::io;
 
 use ::services::message::NetMsg;
+use ::config::ThrottleConf;
 
 /// Pads a number to a certain multiple.
 #[inline(always)]
@@ -30,7 +33,7 @@ pub trait ClientHandler {
     fn register<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
     fn reregister<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
     fn readable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
-    fn writable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
+    fn writable<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
     fn send_msg<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, msg: Self::Msg) -> io::Result<()>;
     fn drop_client<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()>;
 }
@@ -41,6 +44,68 @@ pub enum Client {
     ShipGate(ShipGateClient)
 }
 
+impl Client {
+    /// Apply an outbound throttle. The shipgate's clients are never throttled.
+    pub fn set_throttle(&mut self, conf: &ThrottleConf) {
+        match self {
+            &mut Client::Patch(ref mut p) => p.throttle = Some(Throttle::new(conf)),
+            &mut Client::Bb(ref mut b) => b.throttle = Some(Throttle::new(conf)),
+            &mut Client::ShipGate(_) => ()
+        }
+    }
+
+    pub fn set_key_check(&mut self, key_check: bool) {
+        if let &mut Client::Bb(ref mut b) = self {
+            b.key_check = key_check;
+        }
+    }
+
+    pub fn set_strict_first_packet(&mut self, strict: bool) {
+        if let &mut Client::Bb(ref mut b) = self {
+            b.strict_first_packet = strict;
+        }
+    }
+
+    pub fn set_first_packet_deadline(&mut self, deadline: u64) {
+        if let &mut Client::Bb(ref mut b) = self {
+            b.first_packet_deadline = Some(deadline);
+        }
+    }
+
+    /// When a BB client still waiting on its first packet has to have sent
+    /// it by.
+    pub fn first_packet_deadline(&self) -> Option<u64> {
+        match self {
+            &Client::Bb(ref b) if b.awaiting_first_packet() => b.first_packet_deadline,
+            _ => None
+        }
+    }
+
+    /// A BB client that hasn't sent its first packet yet.
+    pub fn awaiting_first_packet(&self) -> bool {
+        match self {
+            &Client::Bb(ref b) => b.awaiting_first_packet(),
+            _ => false
+        }
+    }
+
+    /// Turned away at accept; the service never heard about it.
+    pub fn is_refused(&self) -> bool {
+        match self {
+            &Client::Bb(ref b) => b.refused,
+            _ => false
+        }
+    }
+
+    pub fn throttle_mut(&mut self) -> Option<&mut Throttle> {
+        match self {
+            &mut Client::Patch(ref mut p) => p.throttle.as_mut(),
+            &mut Client::Bb(ref mut b) => b.throttle.as_mut(),
+            &mut Client::ShipGate(_) => None
+        }
+    }
+}
+
 impl ClientHandler for Client {
     type Msg = NetMsg;
     fn register<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
@@ -67,7 +132,7 @@ impl ClientHandler for Client {
         }
     }
 
-    fn writable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    fn writable<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         match self {
             &mut Client::Patch(ref mut p) => p.writable(event_loop),
             &mut Client::Bb(ref mut b) => b.writable(event_loop),
@@ -1,4 +1,3 @@
-use mio::tcp::TcpStream;
 use mio::{EventLoop, EventSet, Handler, PollOpt, Token, TryRead, TryWrite};
 
 use std::io;
@@ -12,8 +11,9 @@ use psomsg::Serial;
 use ::services::ServiceMsg;
 
 use ::services::message::NetMsg;
+use ::services::listener::Stream;
 
-use super::{padded, ClientHandler};
+use super::{padded, ClientHandler, Throttle};
 
 #[derive(Clone, Copy)]
 enum SendState {
@@ -38,7 +38,7 @@ impl Default for ReadState {
 }
 
 pub struct PatchClient {
-    pub stream: TcpStream,
+    pub stream: Box<Stream>,
     pub token: Token,
     pub ciphers: Option<(PcCipher, PcCipher)>,
     interests: EventSet,
@@ -48,10 +48,11 @@ pub struct PatchClient {
     read_state: ReadState,
     send_buffer: Vec<u8>,
     read_buffer: Vec<u8>,
+    pub throttle: Option<Throttle>
 }
 
 impl PatchClient {
-    pub fn new(stream: TcpStream, token: Token, thread_sender: MpscSender<ServiceMsg>) -> PatchClient {
+    pub fn new(stream: Box<Stream>, token: Token, thread_sender: MpscSender<ServiceMsg>) -> PatchClient {
         PatchClient {
             stream: stream,
             token: token,
@@ -62,7 +63,8 @@ impl PatchClient {
             send_state: SendState::WaitingForMsg,
             send_buffer: Vec::new(),
             read_state: Default::default(),
-            read_buffer: vec![0; 4096]
+            read_buffer: vec![0; 4096],
+            throttle: None
         }
     }
 }
@@ -75,7 +77,7 @@ impl ClientHandler for PatchClient {
         self.interests.insert(EventSet::hup());
 
         event_loop.register(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -84,7 +86,7 @@ impl ClientHandler for PatchClient {
 
     fn reregister<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         event_loop.reregister(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -193,7 +195,7 @@ impl ClientHandler for PatchClient {
         }}
     }
 
-    fn writable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    fn writable<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         use psomsg::Serial;
         use std::io::Cursor;
         use std::mem::swap;
@@ -241,15 +243,43 @@ impl ClientHandler for PatchClient {
                 }
             },
             SendState::SendingMsg(start) => {
+                // If we're throttled, only write as much as the bucket allows.
+                let mut end = self.send_buffer.len();
+                let mut wait = false;
+                if let Some(ref mut t) = self.throttle {
+                    let allowed = t.allowed(self.send_buffer.len(), end - start);
+                    if allowed == 0 {
+                        if !t.resume_pending {
+                            t.resume_pending = true;
+                            if event_loop.timeout_ms(self.token.0, t.wait_ms()).is_err() {
+                                warn!("Failed to schedule throttled send for client token {}", self.token.0);
+                            }
+                        }
+                        wait = true;
+                    }
+                    end = start + allowed;
+                }
+                if wait {
+                    // The resume timeout will call back into writable.
+                    self.interests.remove(EventSet::writable());
+                    return self.reregister(event_loop)
+                }
                 // now, try sending the contents of this buffer.
-                match self.stream.try_write(&self.send_buffer[start..]) {
+                match self.stream.try_write(&self.send_buffer[start..end]) {
                     Ok(Some(bytes)) => {
-                        if bytes < self.send_buffer.len() {
+                        if let Some(ref mut t) = self.throttle {
+                            t.consume(bytes);
+                        }
+                        if start + bytes < self.send_buffer.len() {
                             // Socket was not ready to send whole message,
                             // resume on next writable.
-                            self.interests.insert(EventSet::writable());
                             self.send_state = SendState::SendingMsg(start + bytes);
-                            return self.reregister(event_loop)
+                            if start + bytes < end {
+                                self.interests.insert(EventSet::writable());
+                                return self.reregister(event_loop)
+                            }
+                            // Otherwise we've used the throttle allowance; loop
+                            // back around and wait for more.
                         } else {
                             self.send_state = SendState::WaitingForMsg
                             // loop back around... it will return if there's
@@ -282,6 +312,6 @@ impl ClientHandler for PatchClient {
 
     fn drop_client<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         // unregister self; service will remove token and stream from slab
-        event_loop.deregister(&self.stream)
+        event_loop.deregister(&*self.stream)
     }
 }
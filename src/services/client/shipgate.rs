@@ -1,4 +1,3 @@
-use mio::tcp::TcpStream;
 use mio::{EventLoop, EventSet, Handler, PollOpt, Token, TryRead, TryWrite};
 
 use std::io;
@@ -11,6 +10,7 @@ use psomsg::Serial;
 use ::services::ServiceMsg;
 
 use ::services::message::NetMsg;
+use ::services::listener::Stream;
 
 use super::ClientHandler;
 
@@ -37,7 +37,7 @@ impl Default for ReadState {
 }
 
 pub struct ShipGateClient {
-    pub stream: TcpStream,
+    pub stream: Box<Stream>,
     pub token: Token,
     interests: EventSet,
     sender: MpscSender<ServiceMsg>,
@@ -49,7 +49,7 @@ pub struct ShipGateClient {
 }
 
 impl ShipGateClient {
-    pub fn new(stream: TcpStream, token: Token, thread_sender: MpscSender<ServiceMsg>) -> ShipGateClient {
+    pub fn new(stream: Box<Stream>, token: Token, thread_sender: MpscSender<ServiceMsg>) -> ShipGateClient {
         ShipGateClient {
             stream: stream,
             token: token,
@@ -72,7 +72,7 @@ impl ClientHandler for ShipGateClient {
         self.interests.insert(EventSet::hup());
 
         event_loop.register(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -82,7 +82,7 @@ impl ClientHandler for ShipGateClient {
     fn reregister<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         self.interests.insert(EventSet::readable());
         event_loop.reregister(
-            &self.stream,
+            &*self.stream,
             self.token,
             self.interests,
             PollOpt::edge() | PollOpt::oneshot()
@@ -175,7 +175,7 @@ impl ClientHandler for ShipGateClient {
         }}
     }
 
-    fn writable<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    fn writable<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         use psomsg::Serial;
         use std::io::Cursor;
         use std::mem::swap;
@@ -246,6 +246,6 @@ impl ClientHandler for ShipGateClient {
 
     fn drop_client<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         // unregister self; service will remove token and stream from slab
-        event_loop.deregister(&self.stream)
+        event_loop.deregister(&*self.stream)
     }
 }
@@ -0,0 +1,87 @@
+//! Outbound byte-rate limiting for client streams.
+//!
+//! This is a token bucket measured in bytes. Messages smaller than the
+//! configured `min_size` are always written immediately so interactive
+//! traffic doesn't pick up latency; they still draw from the bucket, so bulk
+//! transfers yield to them.
+
+use time::precise_time_ns;
+
+use ::config::ThrottleConf;
+
+pub struct Throttle {
+    rate: u32,
+    min_size: usize,
+    allowance: f64,
+    last: u64,
+    /// A resume timeout has been scheduled on the event loop.
+    pub resume_pending: bool,
+    window_bytes: u64,
+    window_start: u64
+}
+
+impl Throttle {
+    pub fn new(conf: &ThrottleConf) -> Throttle {
+        let now = precise_time_ns();
+        Throttle {
+            rate: conf.rate,
+            min_size: conf.min_size,
+            allowance: conf.rate as f64,
+            last: now,
+            resume_pending: false,
+            window_bytes: 0,
+            window_start: now
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = precise_time_ns();
+        let elapsed = (now - self.last) as f64 / 1_000_000_000f64;
+        self.last = now;
+        self.allowance += elapsed * self.rate as f64;
+        // One second's worth of bytes is the most we'll burst.
+        if self.allowance > self.rate as f64 {
+            self.allowance = self.rate as f64;
+        }
+    }
+
+    /// How many of the `remaining` bytes of a `msg_len` byte message may be
+    /// written right now. Returns 0 if the caller should wait.
+    pub fn allowed(&mut self, msg_len: usize, remaining: usize) -> usize {
+        if msg_len < self.min_size {
+            return remaining
+        }
+        self.refill();
+        if self.allowance < 1f64 {
+            0
+        } else if (self.allowance as usize) < remaining {
+            self.allowance as usize
+        } else {
+            remaining
+        }
+    }
+
+    /// Record bytes that were actually written to the socket.
+    pub fn consume(&mut self, bytes: usize) {
+        self.allowance -= bytes as f64;
+        self.window_bytes += bytes as u64;
+    }
+
+    /// Milliseconds until enough allowance has accrued to write a bit more.
+    pub fn wait_ms(&self) -> u64 {
+        let deficit = 1f64 - self.allowance;
+        let ms = (deficit * 1000f64 / self.rate as f64).ceil() as u64;
+        if ms < 1 { 1 } else { ms }
+    }
+
+    /// Average outbound bytes per second since the last call. Resets the
+    /// measurement window.
+    pub fn take_throughput(&mut self) -> f64 {
+        let now = precise_time_ns();
+        let secs = (now - self.window_start) as f64 / 1_000_000_000f64;
+        let ret = if secs > 0f64 { self.window_bytes as f64 / secs } else { 0f64 };
+        self.window_bytes = 0;
+        self.window_start = now;
+        ret
+    }
+}
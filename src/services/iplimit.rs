@@ -0,0 +1,103 @@
+//! Counting a service's connections by remote address, so one address can't
+//! take all of them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+pub struct IpLimit {
+    /// Most connections from one address. 0 means no limit.
+    max: u32,
+    counts: HashMap<IpAddr, u32>,
+    /// The address of each counted client, by token.
+    clients: HashMap<usize, IpAddr>
+}
+
+impl IpLimit {
+    pub fn new(max: u32) -> IpLimit {
+        IpLimit {
+            max: max,
+            counts: HashMap::new(),
+            clients: HashMap::new()
+        }
+    }
+
+    pub fn set_max(&mut self, max: u32) {
+        self.max = max;
+    }
+
+    /// Whether another connection from `ip` is allowed.
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.max == 0 || self.counts.get(ip).cloned().unwrap_or(0) < self.max
+    }
+
+    /// Count a connected client.
+    pub fn add(&mut self, token: usize, ip: IpAddr) {
+        *self.counts.entry(ip).or_insert(0) += 1;
+        self.clients.insert(token, ip);
+    }
+
+    /// Stop counting a client that's gone. Clients that weren't counted are
+    /// ignored.
+    pub fn remove(&mut self, token: usize) {
+        let ip = match self.clients.remove(&token) {
+            Some(ip) => ip,
+            None => return
+        };
+        let last = match self.counts.get_mut(&ip) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            },
+            None => false
+        };
+        if last {
+            self.counts.remove(&ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::IpLimit;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_over_limit_rejected() {
+        let mut l = IpLimit::new(3);
+        for token in 0..3 {
+            assert!(l.allows(&ip("10.0.0.1")));
+            l.add(token, ip("10.0.0.1"));
+        }
+        assert!(!l.allows(&ip("10.0.0.1")));
+        assert!(l.allows(&ip("10.0.0.2")));
+        assert!(l.allows(&ip("::1")));
+    }
+
+    #[test]
+    fn test_disconnect_frees_slot() {
+        let mut l = IpLimit::new(1);
+        l.add(7, ip("10.0.0.1"));
+        assert!(!l.allows(&ip("10.0.0.1")));
+        l.remove(7);
+        assert!(l.allows(&ip("10.0.0.1")));
+        // Removing again, or a client never counted, changes nothing.
+        l.remove(7);
+        l.remove(8);
+        l.add(9, ip("10.0.0.1"));
+        assert!(!l.allows(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let mut l = IpLimit::new(0);
+        for token in 0..100 {
+            l.add(token, ip("10.0.0.1"));
+        }
+        assert!(l.allows(&ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,221 @@
+//! The listening socket behind a `Service`.
+//!
+//! Services take any `Listener` so a test or another transport can stand in
+//! for a bound socket. Accepted connections are any `Stream`, which the
+//! clients in `services::client` are built on. `MemoryListener` is one with
+//! no socket at all, for tests.
+
+use mio::{Evented, Selector, Token, EventSet, PollOpt};
+use mio::tcp::TcpListener;
+use mio::unix::UnixListener;
+
+#[cfg(test)] use std::cell::RefCell;
+#[cfg(test)] use std::cmp::min;
+#[cfg(test)] use std::collections::VecDeque;
+#[cfg(test)] use std::rc::Rc;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use ::config::ServiceAddr;
+
+/// The peer address reported for connections over a Unix domain socket,
+/// which come from this machine but have no address of their own.
+pub fn unix_peer_addr() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+pub trait Listener: Evented {
+    /// Accept a pending connection. `Ok(None)` means nothing is waiting.
+    fn accept(&self) -> io::Result<Option<(Box<Stream>, SocketAddr)>>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// An accepted connection. Anything that reads and writes without blocking
+/// and can be registered with the event loop will do.
+pub trait Stream: Read + Write + Evented {}
+
+impl<T: Read + Write + Evented> Stream for T {}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Option<(Box<Stream>, SocketAddr)>> {
+        TcpListener::accept(self).map(|o| o.map(|(s, a)| (Box::new(s) as Box<Stream>, a)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> io::Result<Option<(Box<Stream>, SocketAddr)>> {
+        UnixListener::accept(self).map(|o| o.map(|s| (Box::new(s) as Box<Stream>, unix_peer_addr())))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Other, "Unix domain sockets have no socket address"))
+    }
+}
+
+/// A listener bound to either kind of `ServiceAddr`.
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+impl Evented for BoundListener {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match self {
+            &BoundListener::Tcp(ref l) => l.register(selector, token, interest, opts),
+            &BoundListener::Unix(ref l) => l.register(selector, token, interest, opts)
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match self {
+            &BoundListener::Tcp(ref l) => l.reregister(selector, token, interest, opts),
+            &BoundListener::Unix(ref l) => l.reregister(selector, token, interest, opts)
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match self {
+            &BoundListener::Tcp(ref l) => l.deregister(selector),
+            &BoundListener::Unix(ref l) => l.deregister(selector)
+        }
+    }
+}
+
+impl Listener for BoundListener {
+    fn accept(&self) -> io::Result<Option<(Box<Stream>, SocketAddr)>> {
+        match self {
+            &BoundListener::Tcp(ref l) => Listener::accept(l),
+            &BoundListener::Unix(ref l) => Listener::accept(l)
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            &BoundListener::Tcp(ref l) => Listener::local_addr(l),
+            &BoundListener::Unix(ref l) => Listener::local_addr(l)
+        }
+    }
+}
+
+/// Bind a TCP listener for a service, panicking if the address is unusable.
+pub fn bind_tcp(bind: &SocketAddr) -> TcpListener {
+    TcpListener::bind(bind).expect("Couldn't create tcplistener")
+}
+
+/// Bind a Unix domain socket for a service, panicking if the path is
+/// unusable. A socket left behind by an earlier run is replaced.
+pub fn bind_unix(path: &Path) -> UnixListener {
+    if fs::metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        fs::remove_file(path).expect("Couldn't remove old unix socket");
+    }
+    UnixListener::bind(path).expect("Couldn't create unix listener")
+}
+
+/// Bind a listener for a service on either kind of address.
+pub fn bind_listener(bind: &ServiceAddr) -> BoundListener {
+    match bind {
+        &ServiceAddr::Tcp(ref a) => BoundListener::Tcp(bind_tcp(a)),
+        &ServiceAddr::Unix(ref p) => BoundListener::Unix(bind_unix(p))
+    }
+}
+
+/// A listener for tests that connects in memory. Its connections are never
+/// reported ready by the event loop, so a test drives the client itself.
+#[cfg(test)]
+pub struct MemoryListener {
+    pending: RefCell<VecDeque<MemoryStream>>
+}
+
+#[cfg(test)]
+impl MemoryListener {
+    pub fn new() -> MemoryListener {
+        MemoryListener {
+            pending: RefCell::new(VecDeque::new())
+        }
+    }
+
+    /// Open a connection for the next `accept`, returning the remote end.
+    pub fn connect(&self) -> MemoryStream {
+        let (local, remote) = MemoryStream::pair();
+        self.pending.borrow_mut().push_back(local);
+        remote
+    }
+}
+
+#[cfg(test)]
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<Option<(Box<Stream>, SocketAddr)>> {
+        Ok(self.pending.borrow_mut().pop_front().map(|s| (Box::new(s) as Box<Stream>, unix_peer_addr())))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Other, "memory listeners have no socket address"))
+    }
+}
+
+#[cfg(test)]
+impl Evented for MemoryListener {
+    fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> { Ok(()) }
+    fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> { Ok(()) }
+    fn deregister(&self, _: &mut Selector) -> io::Result<()> { Ok(()) }
+}
+
+/// One end of an in-memory connection. Reads take what the other end wrote,
+/// and would block if it hasn't written anything.
+#[cfg(test)]
+pub struct MemoryStream {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<VecDeque<u8>>>
+}
+
+#[cfg(test)]
+impl MemoryStream {
+    pub fn pair() -> (MemoryStream, MemoryStream) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (MemoryStream { incoming: a.clone(), outgoing: b.clone() },
+         MemoryStream { incoming: b, outgoing: a })
+    }
+}
+
+#[cfg(test)]
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing written yet"))
+        }
+        let n = min(buf.len(), incoming.len());
+        for (b, i) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = i;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend(buf.iter().cloned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Evented for MemoryStream {
+    fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> { Ok(()) }
+    fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> { Ok(()) }
+    fn deregister(&self, _: &mut Selector) -> io::Result<()> { Ok(()) }
+}
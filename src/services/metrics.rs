@@ -0,0 +1,115 @@
+//! Counters of what a service has handled, cheap enough to bump on every
+//! message and read from the loop thread for the periodic stats line.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::ServiceMsg;
+
+#[derive(Default)]
+pub struct Metrics {
+    connects: AtomicUsize,
+    disconnects: AtomicUsize,
+    client_msgs: AtomicUsize,
+    shipgate_msgs: AtomicUsize
+}
+
+/// The counters at one moment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub connects: usize,
+    pub disconnects: usize,
+    pub client_msgs: usize,
+    pub shipgate_msgs: usize
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a message the service's thread took off its channel. Ticks and
+    /// shutdown aren't counted.
+    pub fn note(&self, msg: &ServiceMsg) {
+        let counter = match msg {
+            &ServiceMsg::ClientConnected(_) => &self.connects,
+            &ServiceMsg::ClientDisconnected(_) => &self.disconnects,
+            &ServiceMsg::ClientSaid(_, _) => &self.client_msgs,
+            &ServiceMsg::ShipGateMsg(_) => &self.shipgate_msgs,
+            _ => return
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connects: self.connects.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+            client_msgs: self.client_msgs.load(Ordering::Relaxed),
+            shipgate_msgs: self.shipgate_msgs.load(Ordering::Relaxed)
+        }
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} connects, {} disconnects, {} client messages, {} shipgate messages",
+            self.connects, self.disconnects, self.client_msgs, self.shipgate_msgs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use psomsg::bb::{Message, Ping};
+
+    use ::services::ServiceMsg;
+    use ::services::message::NetMsg;
+    use ::shipgate::msg::Message as Sgm;
+
+    use super::{Metrics, MetricsSnapshot};
+
+    #[test]
+    fn test_metrics_count_messages() {
+        let m = Metrics::new();
+        let msgs = vec![
+            ServiceMsg::ClientConnected(("127.0.0.1:1".parse().unwrap(), 1)),
+            ServiceMsg::ClientConnected(("127.0.0.1:2".parse().unwrap(), 2)),
+            ServiceMsg::ClientSaid(1, NetMsg::Bb(Message::Ping(0, Ping))),
+            ServiceMsg::ClientSaid(2, NetMsg::Bb(Message::Ping(0, Ping))),
+            ServiceMsg::ClientSaid(1, NetMsg::Bb(Message::Ping(0, Ping))),
+            ServiceMsg::ShipGateMsg(Sgm::Unknown(0, 0, Vec::new())),
+            ServiceMsg::Tick,
+            ServiceMsg::ClientDisconnected(2),
+            ServiceMsg::Shutdown
+        ];
+        for msg in msgs.iter() {
+            m.note(msg);
+        }
+        assert_eq!(m.snapshot(), MetricsSnapshot {
+            connects: 2,
+            disconnects: 1,
+            client_msgs: 3,
+            shipgate_msgs: 1
+        });
+    }
+
+    #[test]
+    fn test_metrics_shared() {
+        let m = Arc::new(Metrics::new());
+        let threads: Vec<_> = (0..4).map(|_| {
+            let m = m.clone();
+            thread::spawn(move|| {
+                for _ in 0..100 {
+                    m.note(&ServiceMsg::ClientDisconnected(0));
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(m.snapshot().disconnects, 400);
+    }
+}
@@ -1,32 +1,90 @@
 //! Service abstraction for the mio loop handler.
 
-use mio::tcp::TcpListener;
-use mio::{EventLoop, EventSet, Handler, PollOpt, Token};
+use mio::{EventLoop, EventSet, Handler, PollOpt, Token, Sender};
 use mio::util::Slab;
 
 use std::io;
 use std::sync::mpsc::Sender as MpscSender;
 use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
 
 pub mod client;
+pub mod listener;
 pub mod message;
+pub mod metrics;
+mod iplimit;
 
 use self::client::{Client, PatchClient, BbClient, ShipGateClient, ClientHandler};
 
+use self::listener::{Listener, Stream};
 use self::message::NetMsg;
+use self::iplimit::IpLimit;
+use self::metrics::Metrics;
 
 use std::sync::Arc;
 
+use psomsg::bb::{Message as BbMsg, BbWelcome, LargeMsg};
+
+use ::util::gen_seed;
 use ::shipgate::msg::Message as ShipGateMsg;
+use ::config::ThrottleConf;
+use ::eventlog::EventLog;
+use ::loop_handler::LoopMsg;
+
+use time::precise_time_ns;
 
 #[derive(Clone)]
 pub enum ServiceMsg {
     ClientConnected((SocketAddr, usize)),
     ClientSaid(usize, NetMsg),
     ClientDisconnected(usize),
-    ShipGateMsg(ShipGateMsg)
+    ShipGateMsg(ShipGateMsg),
+    /// Sent once a second to services that do periodic work.
+    Tick,
+    /// The server is stopping. Only sent to services with a thread to wait
+    /// for; they should finish what they're doing and return.
+    Shutdown
+}
+
+/// Send `ServiceMsg::Tick` to a service once a second until it hangs up.
+pub fn spawn_ticker(tx: MpscSender<ServiceMsg>) {
+    thread::spawn(move|| {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if tx.send(ServiceMsg::Tick).is_err() {
+                return
+            }
+        }
+    });
 }
 
+/// The `time::precise_time_ns` by which a client connecting now must have
+/// logged in, or `None` if `timeout` is 0.
+pub fn handshake_deadline(timeout: u32) -> Option<u64> {
+    if timeout == 0 {
+        None
+    } else {
+        Some(precise_time_ns() + timeout as u64 * 1_000_000_000)
+    }
+}
+
+/// Drop client `id` if `deadline` has passed without it logging in. The
+/// deadline is cleared so it's only dropped once; `timeout` is for the log.
+pub fn check_handshake(sender: &Sender<LoopMsg>, id: usize, deadline: &mut Option<u64>, now: u64, timeout: u32) {
+    if deadline.map(|d| d <= now).unwrap_or(false) {
+        info!("Client {} didn't log in within {} seconds; dropping", id, timeout);
+        *deadline = None;
+        sender.send(LoopMsg::DropClient(id)).unwrap();
+    }
+}
+
+/// Set on a client's token to make the loop timeout that checks it sent its
+/// first packet in time. Tokens never get this high. The token may have been
+/// reused by the time it fires, so the client's own deadline is what's
+/// checked.
+pub const FIRST_PACKET_TIMEOUT: usize = 1 << 30;
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum ServiceType {
     /// Uses the Patch namespace in `psomsg::patch`
@@ -38,21 +96,110 @@ pub enum ServiceType {
 
 /// A communication handle for a service.
 pub struct Service {
-    pub listener: TcpListener,
+    pub listener: Box<Listener>,
     pub token: Token,
     clients: Slab<Client>,
     pub sender: MpscSender<ServiceMsg>,
-    service_type: ServiceType
+    service_type: ServiceType,
+    throttle: Option<ThrottleConf>,
+    key_check: bool,
+    /// Milliseconds BB clients have to send their first packet. 0 for no
+    /// limit.
+    first_packet_window_ms: u32,
+    /// Connections held by each remote address.
+    ip_limit: IpLimit,
+    event_log: EventLog,
+    /// Service kind named in event log records.
+    kind: &'static str,
+    /// Counters kept by the service's thread, if it keeps any.
+    metrics: Option<Arc<Metrics>>,
+    /// The service's thread, if it has to be waited for at shutdown.
+    thread: Option<thread::JoinHandle<()>>
 }
 
 impl Service {
-    pub fn new(listener: TcpListener, sender: MpscSender<ServiceMsg>, service_type: ServiceType) -> Service {
+    pub fn new<L: Listener + 'static>(listener: L, sender: MpscSender<ServiceMsg>, service_type: ServiceType) -> Service {
         Service {
-            listener: listener,
+            listener: Box::new(listener),
             token: Token(0),
             clients: Slab::new(0),
             sender: sender,
-            service_type: service_type
+            service_type: service_type,
+            throttle: None,
+            key_check: true,
+            first_packet_window_ms: 0,
+            ip_limit: IpLimit::new(0),
+            event_log: EventLog::disabled(),
+            kind: "",
+            metrics: None,
+            thread: None
+        }
+    }
+
+    /// Limit the outbound byte rate of each client accepted from now on.
+    pub fn set_throttle(&mut self, throttle: Option<ThrottleConf>) {
+        self.throttle = throttle;
+    }
+
+    /// Drop BB clients whose first packet doesn't decrypt to a login as
+    /// having the wrong key table.
+    pub fn set_key_check(&mut self, key_check: bool) {
+        self.key_check = key_check;
+    }
+
+    /// Drop BB clients that don't send a login within `ms` milliseconds of
+    /// connecting, or that send anything else first. 0 turns this off.
+    pub fn set_first_packet_window(&mut self, ms: u32) {
+        self.first_packet_window_ms = ms;
+    }
+
+    /// Close connections from an address that already has `max` open. 0
+    /// turns this off.
+    pub fn set_max_per_ip(&mut self, max: u32) {
+        self.ip_limit.set_max(max);
+    }
+
+    /// Record connects and disconnects on this service to an event log.
+    pub fn set_event_log(&mut self, event_log: EventLog, kind: &'static str) {
+        self.event_log = event_log;
+        self.kind = kind;
+    }
+
+    /// Include these counters in the periodic stats line.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Have `finish` wait for this thread to return.
+    pub fn set_thread(&mut self, thread: thread::JoinHandle<()>) {
+        self.thread = Some(thread);
+    }
+
+    /// Tell the thread of a service that talks to the shipgate to stop, while
+    /// the loop still runs to carry its last messages. The shipgate itself
+    /// keeps going until `finish`, to take them.
+    pub fn begin_shutdown(&mut self) {
+        if self.thread.is_some() && self.needs_shipgate() {
+            let _ = self.sender.send(ServiceMsg::Shutdown);
+        }
+    }
+
+    /// Tell the service's thread to stop and wait for it, if it has one. A
+    /// thread that already stopped isn't waited for.
+    pub fn finish(&mut self) {
+        if let Some(t) = self.thread.take() {
+            if self.sender.send(ServiceMsg::Shutdown).is_ok() && t.join().is_err() {
+                error!("Service {} panicked while shutting down", self.token.0);
+            }
+        }
+    }
+
+    /// BB services all talk to the shipgate, so they shouldn't take clients
+    /// until it's reachable.
+    pub fn needs_shipgate(&self) -> bool {
+        match self.service_type {
+            ServiceType::Bb(_) => true,
+            _ => false
         }
     }
 
@@ -60,7 +207,7 @@ impl Service {
         self.clients = Slab::new_starting_at(Token(self.token.0 * 10000), 2000);
 
         event_loop.register(
-            &self.listener,
+            &*self.listener,
             self.token,
             EventSet::readable() | EventSet::hup(),
             PollOpt::edge() | PollOpt::oneshot()
@@ -69,14 +216,16 @@ impl Service {
 
     pub fn reregister<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         event_loop.reregister(
-            &self.listener,
+            &*self.listener,
             self.token,
             EventSet::readable() | EventSet::hup(),
             PollOpt::edge() | PollOpt::oneshot()
         )
     }
 
-    pub fn accept<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    /// Accept a client. While the server is `busy`, clients other than the
+    /// shipgate's are turned away instead.
+    pub fn accept<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>, busy: bool) -> io::Result<()> {
         let (sock, addr) = match self.listener.accept() {
             Ok(Some(s)) => {
                 s
@@ -89,6 +238,18 @@ impl Service {
             }
         };
 
+        if busy && self.service_type != ServiceType::ShipGate {
+            self.refuse(event_loop, sock, addr);
+            return self.reregister(event_loop)
+        }
+
+        if !self.ip_limit.allows(&addr.ip()) {
+            // Dropping the socket closes it.
+            info!("Refusing connection from {}; it has too many open", addr.ip());
+            drop(sock);
+            return self.reregister(event_loop)
+        }
+
         // With the new socket, we now create a client for it and register it.
         let sender_clone = self.sender.clone();
         let st = self.service_type.clone();
@@ -102,9 +263,29 @@ impl Service {
         }) {
             Some(token) => {
                 // inserted successfully
+                if let Some(ref t) = self.throttle {
+                    self.clients.get_mut(token).map(|c| c.set_throttle(t));
+                }
+                let key_check = self.key_check;
+                self.clients.get_mut(token).map(|c| c.set_key_check(key_check));
+                let window = self.first_packet_window_ms;
+                if window > 0 {
+                    let deadline = precise_time_ns() + window as u64 * 1_000_000;
+                    self.clients.get_mut(token).map(|c| {
+                        c.set_strict_first_packet(true);
+                        c.set_first_packet_deadline(deadline);
+                    });
+                }
                 match self.get_client_mut(token).map(|c| c.register(event_loop)) {
                     Some(Ok(_)) => {
+                        self.ip_limit.add(token.0, addr.ip());
                         self.sender.send(ServiceMsg::ClientConnected((addr, token.0))).unwrap();
+                        self.event_log.connect(token.0, self.kind, addr);
+                        if window > 0 && self.clients.get(token).map(|c| c.awaiting_first_packet()).unwrap_or(false) {
+                            if event_loop.timeout_ms(token.0 | FIRST_PACKET_TIMEOUT, window as u64).is_err() {
+                                warn!("Failed to schedule first packet check for client token {}", token.0);
+                            }
+                        }
                     },
                     Some(Err(_e)) => {
                         self.clients.remove(token);
@@ -119,6 +300,36 @@ impl Service {
         self.reregister(event_loop)
     }
 
+    /// Tell a BB client the server is busy and hang up. Patch clients have no
+    /// way to show a message before their handshake, so they're just closed.
+    fn refuse<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, sock: Box<Stream>, addr: SocketAddr) {
+        debug!("Refusing client from {} while busy", addr);
+        let kt = match self.service_type {
+            ServiceType::Bb(ref kt) => kt.clone(),
+            _ => return
+        };
+        let sender_clone = self.sender.clone();
+        let token = match self.clients.insert_with(|token| Client::Bb(BbClient::new(sock, token, sender_clone, kt))) {
+            Some(t) => t,
+            None => return
+        };
+        let welcome = BbMsg::BbWelcome(0, BbWelcome(gen_seed(), gen_seed()));
+        let busy = BbMsg::LargeMsg(0, LargeMsg("\tEThe server is busy.\nPlease try again later.".to_string()));
+        let ok = match self.clients.get_mut(token) {
+            Some(&mut Client::Bb(ref mut b)) => {
+                b.refused = true;
+                b.register(event_loop).is_ok()
+                    && b.send_msg(event_loop, welcome).is_ok()
+                    && b.send_msg(event_loop, busy).is_ok()
+            },
+            _ => false
+        };
+        if !ok {
+            self.clients.get_mut(token).map(|c| c.drop_client(event_loop));
+            self.clients.remove(token);
+        }
+    }
+
     // pub fn get_client(&self, token: Token) -> Option<&Client> {
     //     self.clients.get(token)
     // }
@@ -131,17 +342,78 @@ impl Service {
         self.clients.contains(token)
     }
 
-    pub fn ready<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, token: Token, events: EventSet) {
-        self.clients.get_mut(token).map(|c| {
+    pub fn ready<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>, token: Token, events: EventSet) {
+        let result = self.clients.get_mut(token).map(|c| -> io::Result<()> {
             if events.contains(EventSet::readable()) {
                 debug!("Reading from client token {}", token.0);
-                c.readable(event_loop).unwrap();
+                try!(c.readable(event_loop));
             }
             if events.contains(EventSet::writable()) {
                 debug!("Writing to client token {}", token.0);
-                c.writable(event_loop).unwrap();
+                try!(c.writable(event_loop));
             }
+            Ok(())
         });
+        if let Some(Err(e)) = result {
+            if self.clients.get(token).map(|c| c.is_refused()).unwrap_or(false) {
+                debug!("Hung up on refused client token {}", token.0);
+            } else {
+                warn!("Dropping client token {}: {}", token.0, e);
+            }
+            self.drop_client(event_loop, token);
+        }
+    }
+
+    /// Resume writing to a client whose throttled send was waiting on a timeout.
+    pub fn resume_send<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>, token: Token) {
+        self.clients.get_mut(token).map(|c| {
+            if let Some(t) = c.throttle_mut() {
+                t.resume_pending = false;
+            }
+            c.writable(event_loop).unwrap();
+        });
+    }
+
+    /// Drop a client that still hasn't sent its first packet and is past its
+    /// deadline. One that isn't yet, because the timeout was left over from
+    /// an earlier connection with the same token or fired early, is checked
+    /// again at its own deadline.
+    pub fn check_first_packet<H: Handler<Timeout = usize>>(&mut self, event_loop: &mut EventLoop<H>, token: Token) {
+        let now = precise_time_ns();
+        match self.clients.get(token).and_then(|c| c.first_packet_deadline()) {
+            Some(deadline) if now >= deadline => {
+                info!("Client token {} sent nothing within {} ms of connecting; dropping", token.0, self.first_packet_window_ms);
+                self.drop_client(event_loop, token);
+            },
+            Some(deadline) => {
+                if event_loop.timeout_ms(token.0 | FIRST_PACKET_TIMEOUT, (deadline - now) / 1_000_000 + 1).is_err() {
+                    warn!("Failed to schedule first packet check for client token {}", token.0);
+                }
+            },
+            None => ()
+        }
+    }
+
+    /// Log a summary of this service's clients and their outbound throughput.
+    pub fn log_stats(&mut self) {
+        let mut count = 0;
+        let mut throttled = 0;
+        let mut throughput = 0f64;
+        for c in self.clients.iter_mut() {
+            count += 1;
+            if let Some(t) = c.throttle_mut() {
+                throttled += 1;
+                throughput += t.take_throughput();
+            }
+        }
+        if throttled > 0 {
+            info!("Service {}: {} clients, {} throttled, {:.0} bytes/s effective outbound", self.token.0, count, throttled, throughput);
+        } else {
+            info!("Service {}: {} clients", self.token.0, count);
+        }
+        if let Some(ref m) = self.metrics {
+            info!("Service {}: {}", self.token.0, m.snapshot());
+        }
     }
 
     pub fn notify_svc<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, msg: ServiceMsg) {
@@ -159,10 +431,16 @@ impl Service {
     }
 
     pub fn drop_client<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, token: Token) {
-        self.clients.get_mut(token).map(|c| {
-            c.drop_client(event_loop)
-        });
-        self.sender.send(ServiceMsg::ClientDisconnected(token.0)).unwrap();
+        let refused = self.clients.get_mut(token).map(|c| {
+            // The client may already be gone from the event loop.
+            let _ = c.drop_client(event_loop);
+            c.is_refused()
+        }).unwrap_or(false);
+        if !refused {
+            self.sender.send(ServiceMsg::ClientDisconnected(token.0)).unwrap();
+            self.event_log.disconnect(token.0);
+        }
+        self.ip_limit.remove(token.0);
         self.clients.remove(token);
     }
 }
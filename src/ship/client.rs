@@ -7,5 +7,8 @@ pub struct ClientState {
     pub sec_data: BbSecurityData,
     pub team_id: u32,
     pub bb_guildcard: u32,
-    pub ships: Option<Vec<(SocketAddrV4, String)>>
+    pub ships: Option<Vec<(SocketAddrV4, String, u32)>>,
+    /// `time::precise_time_ns` by which they must log in. Cleared once they
+    /// have.
+    pub handshake_deadline: Option<u64>
 }
@@ -11,9 +11,11 @@ use psomsg::bb::*;
 
 use ::config::BlockConf;
 use ::shipgate::client::callbacks::SgCbMgr;
+use ::shipgate::ships::menu_name;
 use ::shipgate::msg::{BbLoginChallenge,
     //BbLoginChallengeAck,
     BbGetAccountInfo,
+    BbClaimHandoff,
     ShipListAck,
     Message as Sgm};
 
@@ -45,10 +47,8 @@ impl ShipHandler {
         // Security data should be set when connecting to the Ship (sent by Login)
         // Drop if it's invalid.
         info!("Logging in user has security: {:?}", sec_data);
-        if sec_data.magic != 0xCAFEB00B {
-            let m = Message::LargeMsg(0, LargeMsg("Invalid security data".to_string()));
-            self.sender.send((self.client_id, m).into()).unwrap();
-            self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+        if sec_data.magic == 0 {
+            self.refuse_security();
             return
         }
 
@@ -71,63 +71,25 @@ impl ShipHandler {
                     return
                 }
 
-                let sec_data = sec_data.clone();
-
-                let sgm: Sgm = BbGetAccountInfo { account_id: a.account_id }.into();
-                h.sg_sender.request(h.client_id, sgm, move|h, m| {
-                    if let Sgm::BbGetAccountInfoAck(_, a) = m {
-
-                        {
-                            let mut b = h.clients.borrow_mut();
-                            let ref mut c = b.get_mut(&h.client_id).unwrap();
-                            c.sec_data = sec_data.clone();
-                            c.team_id = a.team_id;
-                            c.bb_guildcard = a.guildcard_num;
-                        }
+                let account_id = a.account_id;
+                if sec_data.magic == 0xCAFEB00B {
+                    h.fetch_account(account_id, sec_data.clone());
+                    return
+                }
 
-                        let r = Message::BbSecurity(0, BbSecurity {
-                            err_code: 0,
-                            tag: 0x00010000,
-                            guildcard: a.guildcard_num,
-                            team_id: 0xFFFFFFFF,
-                            security_data: sec_data.clone(),
-                            caps: 0x00000101
-                        });
-                        h.sender.send((h.client_id, r).into()).unwrap();
-
-                        let r = Message::Timestamp(0, Timestamp {
-                            year: 2016,
-                            month: 1,
-                            day: 1,
-                            hour: 0,
-                            minute: 30,
-                            second: 30,
-                            msec: 0
-                        });
-                        h.sender.send((h.client_id, r).into()).unwrap();
-
-                        // send blocklist
-                        info!("Sending blocklist to {}", h.client_id);
-                        let mut blist = Vec::new();
-                        blist.push(ShipListItem {
-                            menu_id: 0x00040000,
-                            item_id: 0,
-                            flags: 0x0000,
-                            name: h.ship_name.clone()
-                        });
-                        let mut i = 1;
-                        for b in h.blocks.iter() {
-                            blist.push(ShipListItem {
-                                menu_id: 0x00040000,
-                                item_id: i,
-                                flags: 0x0000,
-                                name: format!("{:02}:{}", i, b.name)
-                            });
-                            i += 1;
+                // Coming from another ship, they need a token the shipgate
+                // gave for the move. Once it's used up, their session is
+                // the login server's again.
+                let mut sec_data = sec_data.clone();
+                let sgm: Sgm = BbClaimHandoff { account_id: account_id, token: sec_data.magic }.into();
+                h.sg_sender.request(h.client_id, sgm, move|mut h, m| {
+                    if let Sgm::BbClaimHandoffAck(_, ack) = m {
+                        if ack.status == 0 {
+                            sec_data.magic = 0xCAFEB00B;
+                            h.fetch_account(account_id, sec_data.clone());
+                        } else {
+                            h.refuse_security();
                         }
-                        let r = Message::BlockList(blist.len() as u32 - 1, BlockList(blist));
-                        h.sender.send((h.client_id, r).into()).unwrap();
-                        return
                     }
                 }).unwrap();
             } else {
@@ -138,10 +100,78 @@ impl ShipHandler {
         }).unwrap();
     }
 
+    /// Drop a client whose security data we don't trust.
+    fn refuse_security(&mut self) {
+        let m = Message::LargeMsg(0, LargeMsg("Invalid security data".to_string()));
+        self.sender.send((self.client_id, m).into()).unwrap();
+        self.sender.send(LoopMsg::DropClient(self.client_id)).unwrap();
+    }
+
+    /// Greet a logged in client and send them the block list.
+    fn fetch_account(&mut self, account_id: u32, sec_data: BbSecurityData) {
+        let sgm: Sgm = BbGetAccountInfo { account_id: account_id }.into();
+        self.sg_sender.request(self.client_id, sgm, move|h, m| {
+            if let Sgm::BbGetAccountInfoAck(_, a) = m {
+
+                {
+                    let mut b = h.clients.borrow_mut();
+                    let ref mut c = b.get_mut(&h.client_id).unwrap();
+                    c.sec_data = sec_data.clone();
+                    c.team_id = a.team_id;
+                    c.bb_guildcard = a.guildcard_num;
+                    c.handshake_deadline = None;
+                }
+
+                let r = Message::BbSecurity(0, BbSecurity {
+                    err_code: 0,
+                    tag: 0x00010000,
+                    guildcard: a.guildcard_num,
+                    team_id: 0xFFFFFFFF,
+                    security_data: sec_data.clone(),
+                    caps: 0x00000101
+                });
+                h.sender.send((h.client_id, r).into()).unwrap();
+
+                let r = Message::Timestamp(0, Timestamp {
+                    year: 2016,
+                    month: 1,
+                    day: 1,
+                    hour: 0,
+                    minute: 30,
+                    second: 30,
+                    msec: 0
+                });
+                h.sender.send((h.client_id, r).into()).unwrap();
+
+                // send blocklist
+                info!("Sending blocklist to {}", h.client_id);
+                let mut blist = Vec::new();
+                blist.push(ShipListItem {
+                    menu_id: 0x00040000,
+                    item_id: 0,
+                    flags: 0x0000,
+                    name: h.ship_name.clone()
+                });
+                let mut i = 1;
+                for b in h.blocks.iter() {
+                    blist.push(ShipListItem {
+                        menu_id: 0x00040000,
+                        item_id: i,
+                        flags: 0x0000,
+                        name: format!("{:02}:{}", i, b.name)
+                    });
+                    i += 1;
+                }
+                let r = Message::BlockList(blist.len() as u32 - 1, BlockList(blist));
+                h.sender.send((h.client_id, r).into()).unwrap();
+            }
+        }).unwrap();
+    }
+
     pub fn sg_shiplist(&mut self, m: Sgm) {
         info!("Sending ship list to {}", self.client_id);
         if let Sgm::ShipListAck(_, ShipListAck(ships)) = m {
-            let ships: Vec<(SocketAddrV4, String)> = ships;
+            let ships: Vec<(SocketAddrV4, String, u32)> = ships;
             {
                 let mut b = self.clients.borrow_mut();
                 let mut c = b.get_mut(&self.client_id).unwrap();
@@ -155,12 +185,12 @@ impl ShipHandler {
                 name: "".to_string()
             });
             let mut i = 1;
-            for (_, name) in ships.into_iter() {
+            for (_, name, players) in ships.into_iter() {
                 shiplist.push(ShipListItem {
                     menu_id: 0,
                     item_id: i,
                     flags: 0x0F04,
-                    name: name.clone()
+                    name: menu_name(&name, players)
                 });
                 i += 1;
             }
@@ -1,30 +1,33 @@
 //! Ship service runner.
 
-use ::services::{Service, ServiceMsg};
+use ::services::{Service, ServiceMsg, spawn_ticker, handshake_deadline, check_handshake};
 use ::loop_handler::LoopMsg;
 
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::thread;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::SocketAddrV4;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::cell::RefCell;
 
-use mio::tcp::TcpListener;
 use mio::Sender;
 
 use rand::random;
 
+use time::precise_time_ns;
+
 use psomsg::bb::*;
 
 use ::services::message::NetMsg;
+use ::services::listener::Listener;
 use ::services::ServiceType;
 
-use ::shipgate::client::SgSender;
+use ::shipgate::client::{SgSender, ConnectionState};
 use ::shipgate::client::callbacks::SgCbMgr;
 use ::shipgate::msg::RegisterShip;
+use ::shipgate::ships::REPORT_INTERVAL;
 use ::config::BlockConf;
 
 pub mod handler;
@@ -41,25 +44,29 @@ pub struct ShipService {
     clients: Rc<RefCell<HashMap<usize, ClientState>>>,
     name: String,
     blocks: Rc<Vec<BlockConf>>,
-    my_ipv4: SocketAddrV4
+    my_ipv4: SocketAddrV4,
+    handshake_timeout: u32,
+    ticks: u64
 }
 
 impl ShipService {
-    pub fn spawn(bind: &SocketAddr,
+    pub fn spawn<L: Listener + 'static>(listener: L,
                  sender: Sender<LoopMsg>,
                  key_table: Arc<Vec<u32>>,
                  sg_sender: &SgSender,
                  name: &str,
                  blocks: Vec<BlockConf>,
-                 my_ipv4: SocketAddrV4) -> Service {
+                 my_ipv4: SocketAddrV4,
+                 handshake_timeout: u32) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
-
         let sg_sender = sg_sender.clone_with(tx.clone());
 
         let name = name.to_string();
 
+        // Ticks also keep the ship on the shipgate's list.
+        spawn_ticker(tx.clone());
+
         thread::spawn(move|| {
             let d = ShipService {
                 receiver: rx,
@@ -68,7 +75,9 @@ impl ShipService {
                 clients: Default::default(),
                 name: name,
                 blocks: Rc::new(blocks),
-                my_ipv4: my_ipv4
+                my_ipv4: my_ipv4,
+                handshake_timeout: handshake_timeout,
+                ticks: 0
             };
             d.run();
         });
@@ -88,6 +97,21 @@ impl ShipService {
         )
     }
 
+    /// Check the shipgate is answering, and log how quickly.
+    fn send_heartbeat(&mut self, now: u64) {
+        if self.sg_sender.state() != ConnectionState::Connected {
+            return
+        }
+        let name = self.name.clone();
+        let r = self.sg_sender.heartbeat(0, now, move|_, reply| match reply {
+            Ok(r) => debug!("Ship {} heard from the shipgate in {} ms; up {} seconds", name, r.latency_ms, r.uptime),
+            Err(e) => warn!("Ship {} shipgate heartbeat failed: {}", name, e)
+        });
+        if let Err(e) = r {
+            warn!("Couldn't send a shipgate heartbeat: {}", e);
+        }
+    }
+
     pub fn run(mut self) {
         info!("Ship service running.");
 
@@ -107,7 +131,8 @@ impl ShipService {
                     self.sender.send((id, Message::BbWelcome(0, BbWelcome(sk, ck))).into()).unwrap();
 
                     // Add to clients table
-                    let cs = ClientState::default();
+                    let mut cs = ClientState::default();
+                    cs.handshake_deadline = handshake_deadline(self.handshake_timeout);
                     {self.clients.borrow_mut().insert(id, cs);}
                 },
                 ServiceMsg::ClientDisconnected(id) => {
@@ -136,7 +161,22 @@ impl ShipService {
                         Some((client, mut c)) => c(self.make_handler(client), m),
                         None => warn!("Got a SG request response for an unexpected request ID {}.", req)
                     }
-                }
+                },
+                ServiceMsg::Tick => {
+                    self.ticks += 1;
+                    let now = precise_time_ns();
+                    for (client, mut t) in self.sg_sender.expire(now) {
+                        t(self.make_handler(client));
+                    }
+                    if self.ticks % REPORT_INTERVAL == 0 {
+                        // The shipgate drops ships it stops hearing from.
+                        self.sg_sender.send(RegisterShip(self.my_ipv4, self.name.clone())).unwrap();
+                        self.send_heartbeat(now);
+                    }
+                    for (id, c) in self.clients.borrow_mut().iter_mut() {
+                        check_handshake(&self.sender, *id, &mut c.handshake_deadline, now, self.handshake_timeout);
+                    }
+                },
                 _ => unreachable!()
             }
         }
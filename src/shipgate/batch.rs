@@ -0,0 +1,152 @@
+//! Coalescing of frequent small database writes, so they're saved in one
+//! transaction every so often instead of one at a time.
+
+use std::collections::HashMap;
+
+use psodb_common::account::ChatLine;
+
+pub struct WriteBatch {
+    /// Unsaved play time by (account, slot).
+    playtime: HashMap<(u32, u8), u32>,
+    /// Chat waiting for the chat log, oldest first.
+    chat: Vec<ChatLine>,
+    /// Seconds between flushes.
+    interval: u32,
+    /// Pending entries at which to flush early.
+    size: usize,
+    /// Seconds since the last flush.
+    elapsed: u32
+}
+
+impl WriteBatch {
+    pub fn new(interval: u32, size: usize) -> WriteBatch {
+        WriteBatch {
+            playtime: HashMap::new(),
+            chat: Vec::new(),
+            interval: interval,
+            size: size,
+            elapsed: 0
+        }
+    }
+
+    /// Queue play time for a character. Returns whether the batch is full
+    /// and should be flushed now.
+    pub fn add_playtime(&mut self, account_id: u32, slot: u8, seconds: u32) -> bool {
+        let s = self.playtime.entry((account_id, slot)).or_insert(0);
+        *s = s.saturating_add(seconds);
+        self.len() >= self.size
+    }
+
+    /// Queue a line for the chat log. Returns whether the batch is full and
+    /// should be flushed now.
+    pub fn add_chat(&mut self, line: ChatLine) -> bool {
+        self.chat.push(line);
+        self.len() >= self.size
+    }
+
+    /// Drop the play time waiting for a character that's been deleted.
+    pub fn forget(&mut self, account_id: u32, slot: u8) {
+        self.playtime.remove(&(account_id, slot));
+    }
+
+    /// Play time not saved yet, as (whole account, character in the slot).
+    pub fn pending_playtime(&self, account_id: u32, slot: u8) -> (u64, u64) {
+        let mut pending = (0, 0);
+        for (&(a, s), &secs) in self.playtime.iter() {
+            if a == account_id {
+                pending.0 += secs as u64;
+                if s == slot {
+                    pending.1 += secs as u64;
+                }
+            }
+        }
+        pending
+    }
+
+    /// Count a second. Returns whether it's time to flush.
+    pub fn tick(&mut self) -> bool {
+        self.elapsed += 1;
+        self.elapsed >= self.interval && !self.is_empty()
+    }
+
+    /// Take the pending play time, as (account, slot, seconds).
+    pub fn take_playtime(&mut self) -> Vec<(u32, u8, u32)> {
+        self.elapsed = 0;
+        self.playtime.drain().map(|((a, s), secs)| (a, s, secs)).collect()
+    }
+
+    /// Take the chat waiting for the chat log.
+    pub fn take_chat(&mut self) -> Vec<ChatLine> {
+        self.elapsed = 0;
+        self.chat.drain(..).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.playtime.len() + self.chat.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.playtime.is_empty() && self.chat.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use psodb_common::account::ChatLine;
+
+    use super::WriteBatch;
+
+    #[test]
+    fn test_coalesces_playtime() {
+        let mut b = WriteBatch::new(2, 3);
+        assert!(!b.add_playtime(1, 0, 60));
+        assert!(!b.add_playtime(1, 0, 30));
+        assert!(!b.add_playtime(2, 1, 5));
+        assert_eq!(b.len(), 2);
+        assert!(!b.tick());
+        assert!(b.tick());
+        let mut p = b.take_playtime();
+        p.sort();
+        assert_eq!(p, vec![(1, 0, 90), (2, 1, 5)]);
+        assert!(b.is_empty());
+        assert!(!b.tick());
+    }
+
+    #[test]
+    fn test_forget_deleted_character() {
+        let mut b = WriteBatch::new(300, 10);
+        b.add_playtime(1, 0, 60);
+        b.add_playtime(1, 1, 30);
+        b.forget(1, 0);
+        assert_eq!(b.take_playtime(), vec![(1, 1, 30)]);
+    }
+
+    #[test]
+    fn test_pending_playtime() {
+        let mut b = WriteBatch::new(300, 10);
+        b.add_playtime(1, 0, 60);
+        b.add_playtime(1, 1, 30);
+        b.add_playtime(2, 0, 5);
+        assert_eq!(b.pending_playtime(1, 0), (90, 60));
+        assert_eq!(b.pending_playtime(1, 2), (90, 0));
+        assert_eq!(b.pending_playtime(3, 0), (0, 0));
+    }
+
+    #[test]
+    fn test_full_batch_flushes_early() {
+        let mut b = WriteBatch::new(300, 2);
+        assert!(!b.add_playtime(1, 0, 1));
+        assert!(b.add_playtime(2, 0, 1));
+    }
+
+    #[test]
+    fn test_queues_chat() {
+        let mut b = WriteBatch::new(300, 3);
+        let line = ChatLine { guildcard: 1, target: 0, block: 1, lobby: 1, time: 100, text: "hi".to_string() };
+        assert!(!b.add_chat(line.clone()));
+        assert!(!b.add_playtime(1, 0, 1));
+        assert!(b.add_chat(ChatLine { time: 101, ..line.clone() }));
+        assert_eq!(b.take_chat(), vec![line.clone(), ChatLine { time: 101, ..line }]);
+        assert_eq!(b.len(), 1);
+    }
+}
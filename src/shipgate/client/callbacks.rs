@@ -1,24 +1,42 @@
 //! Callback manager for the shipgate client.
 
-use super::SgSender;
+use super::{SgSender, ConnectionState};
 
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::HashMap;
 
-use ::shipgate::msg::Message;
+use time::precise_time_ns;
+
+use ::shipgate::msg::{Message, Heartbeat};
+
+/// Seconds to wait for a heartbeat reply before giving up on it.
+pub const HEARTBEAT_TIMEOUT: u64 = 10;
+
+/// A heartbeat the shipgate answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatReply {
+    /// Round trip time in milliseconds.
+    pub latency_ms: u64,
+    /// Seconds since the shipgate started.
+    pub uptime: u64
+}
 
 /// Ship gate callback manager.
 pub struct SgCbMgr<H> {
     sender: SgSender,
-    callbacks: Rc<RefCell<HashMap<u32, (usize, Box<FnMut(H, Message)>)>>>
+    callbacks: Rc<RefCell<HashMap<u32, (usize, Box<FnMut(H, Message)>)>>>,
+    /// Requests that give up at some point: their deadline in
+    /// `precise_time_ns` terms, and what to call when it passes.
+    timeouts: Rc<RefCell<HashMap<u32, (u64, Box<FnMut(H)>)>>>
 }
 
 impl<H> From<SgSender> for SgCbMgr<H> {
     fn from(val: SgSender) -> SgCbMgr<H> {
         SgCbMgr {
             sender: val,
-            callbacks: Default::default()
+            callbacks: Default::default(),
+            timeouts: Default::default()
         }
     }
 }
@@ -38,21 +56,169 @@ impl<H> SgCbMgr<H> {
         }
     }
 
+    /// Send a request like `request`, but if no response has come by
+    /// `deadline` (in `precise_time_ns` terms), `expire` hands back
+    /// `on_timeout` instead and the response is ignored if it comes later.
+    pub fn request_with_timeout<M: Into<Message>, CB, TO>(&mut self, cid: usize, msg: M, deadline: u64, cb: CB, on_timeout: TO) -> Result<(), String>
+    where CB: FnMut(H, Message) + 'static, TO: FnMut(H) + 'static {
+        let req = try!(self.sender.send(msg.into()));
+        self.callbacks.borrow_mut().insert(req, (cid, Box::new(cb)));
+        self.timeouts.borrow_mut().insert(req, (deadline, Box::new(on_timeout)));
+        debug!("ShipGate request sent with ID {}, timing out at {}", req, deadline);
+        Ok(())
+    }
+
+    /// Check that the shipgate is answering. `cb` gets the round trip time
+    /// and the shipgate's uptime, or an error if there's no reply within
+    /// `HEARTBEAT_TIMEOUT` seconds of `now`.
+    pub fn heartbeat<CB>(&mut self, cid: usize, now: u64, cb: CB) -> Result<(), String>
+    where CB: FnMut(H, Result<HeartbeatReply, String>) + 'static {
+        let cb = Rc::new(RefCell::new(cb));
+        let on_timeout = cb.clone();
+        self.request_with_timeout(cid, Heartbeat, now + HEARTBEAT_TIMEOUT * 1_000_000_000, move|h, m| {
+            let mut cb = cb.borrow_mut();
+            match m {
+                Message::HeartbeatAck(_, ack) => {
+                    let latency_ms = precise_time_ns().saturating_sub(now) / 1_000_000;
+                    (&mut *cb)(h, Ok(HeartbeatReply {
+                        latency_ms: latency_ms,
+                        uptime: ack.uptime
+                    }))
+                },
+                m => (&mut *cb)(h, Err(format!("Unexpected heartbeat reply {:?}", m)))
+            }
+        }, move|h| {
+            let mut cb = on_timeout.borrow_mut();
+            (&mut *cb)(h, Err(format!("No heartbeat reply within {} seconds", HEARTBEAT_TIMEOUT)))
+        })
+    }
+
+    /// Send a request whose responses keep arriving on the same key, and
+    /// return that key. No callback is registered; the service must recognize
+    /// these responses itself.
+    pub fn subscribe<M: Into<Message>>(&mut self, msg: M) -> Result<u32, String> {
+        self.sender.send(msg.into())
+    }
+
     pub fn send<M: Into<Message>>(&mut self, msg: M) -> Result<(), String> {
         self.sender.send_forget(msg.into())
     }
 
+    /// Whether the shipgate client is connected and authenticated.
+    pub fn state(&self) -> ConnectionState {
+        self.sender.state()
+    }
+
     /// Get the callback for the request given
     pub fn cb_for_req(&mut self, req: u32) -> Option<(usize, Box<FnMut(H, Message)>)> {
+        self.timeouts.borrow_mut().remove(&req);
         self.callbacks.borrow_mut().remove(&req)
     }
+
+    /// Forget requests whose deadline passed by `now`, and return their
+    /// timeout callbacks with the client each was for.
+    pub fn expire(&mut self, now: u64) -> Vec<(usize, Box<FnMut(H)>)> {
+        let expired: Vec<u32> = self.timeouts.borrow().iter()
+            .filter(|&(_, &(deadline, _))| deadline <= now)
+            .map(|(&req, _)| req)
+            .collect();
+        let mut ret = Vec::with_capacity(expired.len());
+        for req in expired {
+            let cid = self.callbacks.borrow_mut().remove(&req).map(|(cid, _)| cid);
+            let timeout = self.timeouts.borrow_mut().remove(&req);
+            if let (Some(cid), Some((_, t))) = (cid, timeout) {
+                debug!("ShipGate request {} timed out", req);
+                ret.push((cid, t));
+            }
+        }
+        ret
+    }
 }
 
 impl<H> Clone for SgCbMgr<H> {
     fn clone(&self) -> SgCbMgr<H> {
         SgCbMgr {
             sender: self.sender.clone(),
-            callbacks: self.callbacks.clone()
+            callbacks: self.callbacks.clone(),
+            timeouts: self.timeouts.clone()
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+
+    use time::precise_time_ns;
+
+    use ::shipgate::client::{SgSender, ClientMsg, ConnectionState};
+    use ::shipgate::msg::{Message, HeartbeatAck};
+
+    use super::{SgCbMgr, HeartbeatReply, HEARTBEAT_TIMEOUT};
+
+    /// A manager with no shipgate client behind it. Its requests pile up in
+    /// the receiver, which has to be kept for sending to work.
+    fn cb_mgr() -> (SgCbMgr<()>, Receiver<ClientMsg>) {
+        let (tx, rx) = channel();
+        let (cb_tx, _) = channel();
+        let mgr = SgCbMgr::from(SgSender {
+            tx: tx,
+            cb_sender: Some(cb_tx),
+            req_counter: Arc::new(Mutex::new(1)),
+            state: Arc::new(Mutex::new(ConnectionState::Connected))
+        });
+        (mgr, rx)
+    }
+
+    fn collect(mgr: &mut SgCbMgr<()>, now: u64) -> Rc<RefCell<Vec<Result<HeartbeatReply, String>>>> {
+        let got = Rc::new(RefCell::new(Vec::new()));
+        let g = got.clone();
+        mgr.heartbeat(3, now, move|_, r| g.borrow_mut().push(r)).unwrap();
+        got
+    }
+
+    #[test]
+    fn test_heartbeat_reply() {
+        let (mut mgr, _rx) = cb_mgr();
+        let got = collect(&mut mgr, precise_time_ns());
+
+        // The first request sent gets key 1.
+        let (cid, mut cb) = mgr.cb_for_req(1).unwrap();
+        assert_eq!(cid, 3);
+        cb((), Message::HeartbeatAck(1, HeartbeatAck { uptime: 120 }));
+        {
+            let got = got.borrow();
+            assert_eq!(got.len(), 1);
+            let reply = got[0].clone().unwrap();
+            assert_eq!(reply.uptime, 120);
+            assert!(reply.latency_ms < HEARTBEAT_TIMEOUT * 1000);
+        }
+
+        // Answered requests don't time out.
+        assert!(mgr.expire(precise_time_ns() + HEARTBEAT_TIMEOUT * 2_000_000_000).is_empty());
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let (mut mgr, _rx) = cb_mgr();
+        let now = 1_000_000_000_000;
+        let got = collect(&mut mgr, now);
+
+        assert!(mgr.expire(now + HEARTBEAT_TIMEOUT * 1_000_000_000 - 1).is_empty());
+        let expired = mgr.expire(now + HEARTBEAT_TIMEOUT * 1_000_000_000);
+        assert_eq!(expired.len(), 1);
+        for (cid, mut t) in expired {
+            assert_eq!(cid, 3);
+            t(());
+        }
+        assert_eq!(got.borrow().len(), 1);
+        assert!(got.borrow()[0].is_err());
+
+        // A reply after giving up finds no callback.
+        assert!(mgr.cb_for_req(1).is_none());
+        assert!(mgr.expire(now + HEARTBEAT_TIMEOUT * 2_000_000_000).is_empty());
+    }
+}
@@ -3,31 +3,120 @@
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
 use std::thread;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mio::Sender as MioSender;
 
 use ::shipgate::msg::*;
 use ::services::ServiceMsg;
+use ::loop_handler::LoopMsg;
 use psoserial::Serial;
 
+use ::config::ServiceAddr;
+
 use std::net::TcpStream;
 
 pub mod callbacks;
 
+/// Longest wait between attempts to reach the shipgate, in seconds.
+const MAX_RETRY_DELAY: u64 = 60;
+
+/// Whether the shipgate can be talked to right now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected, or not yet authenticated. Requests are queued.
+    Connecting,
+    Connected
+}
+
 pub struct ShipGateClient {
     receiver: Receiver<ClientMsg>,
-    stream: TcpStream,
+    /// For the reader threads of new connections.
+    tx: Sender<ClientMsg>,
+    addr: ServiceAddr,
+    stream: Option<SgStream>,
+    /// Counts connections, so a reader of an old one can be told apart.
+    connection: u32,
     responders: HashMap<u32, Sender<ServiceMsg>>,
-    password: String
+    /// Requests that haven't been answered, and subscriptions, by response
+    /// key. They're sent again after reconnecting.
+    unanswered: BTreeMap<u32, Message>,
+    /// Messages without a response that a restarted shipgate needs again.
+    standing: Vec<Message>,
+    password: String,
+    state: Arc<Mutex<ConnectionState>>,
+    /// Told once the shipgate accepts our password.
+    ready: Option<MioSender<LoopMsg>>
 }
 
-enum ClientMsg {
+/// What the shipgate client thread is asked to do.
+pub enum ClientMsg {
     /// Send a message to the shipgate
     Send(Sender<ServiceMsg>, Message),
     SendForget(Message),
     // Respond to the shipgate.
-    Recv(Message)
+    Recv(Message),
+    /// The reader of this connection lost it.
+    Disconnected(u32)
+}
+
+/// A connection to the shipgate, over TCP or a Unix domain socket.
+enum SgStream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl SgStream {
+    fn connect(addr: &ServiceAddr) -> io::Result<SgStream> {
+        match addr {
+            &ServiceAddr::Tcp(a) => TcpStream::connect(a).map(SgStream::Tcp),
+            &ServiceAddr::Unix(ref p) => UnixStream::connect(p).map(SgStream::Unix)
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<SgStream> {
+        match self {
+            &SgStream::Tcp(ref s) => s.try_clone().map(SgStream::Tcp),
+            &SgStream::Unix(ref s) => s.try_clone().map(SgStream::Unix)
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            &SgStream::Tcp(ref s) => s.shutdown(how),
+            &SgStream::Unix(ref s) => s.shutdown(how)
+        }
+    }
+}
+
+impl Read for SgStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut SgStream::Tcp(ref mut s) => s.read(buf),
+            &mut SgStream::Unix(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for SgStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut SgStream::Tcp(ref mut s) => s.write(buf),
+            &mut SgStream::Unix(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut SgStream::Tcp(ref mut s) => s.flush(),
+            &mut SgStream::Unix(ref mut s) => s.flush()
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -35,7 +124,8 @@ enum ClientMsg {
 pub struct SgSender {
     tx: Sender<ClientMsg>,
     cb_sender: Option<Sender<ServiceMsg>>,
-    req_counter: Arc<Mutex<u32>>
+    req_counter: Arc<Mutex<u32>>,
+    state: Arc<Mutex<ConnectionState>>
 }
 
 impl SgSender {
@@ -65,10 +155,33 @@ impl SgSender {
         SgSender {
             tx: self.tx.clone(),
             cb_sender: Some(cb_sender),
-            req_counter: self.req_counter.clone()
+            req_counter: self.req_counter.clone(),
+            state: self.state.clone()
+        }
+    }
+
+    /// Whether the shipgate client is connected and authenticated.
+    pub fn state(&self) -> ConnectionState {
+        match self.state.lock() {
+            Ok(g) => *g,
+            Err(_) => ConnectionState::Connecting
         }
     }
 
+    /// A sender with no shipgate client behind it, for tests. What's sent
+    /// waits in the receiver, which has to be kept for sending to work.
+    #[cfg(test)]
+    pub fn detached() -> (SgSender, Receiver<ClientMsg>) {
+        let (tx, rx) = channel();
+        let sender = SgSender {
+            tx: tx,
+            cb_sender: None,
+            req_counter: Arc::new(Mutex::new(1)),
+            state: Arc::new(Mutex::new(ConnectionState::Connected))
+        };
+        (sender, rx)
+    }
+
     fn get_req_key(&mut self) -> Result<u32, String> {
         match self.req_counter.lock() {
             Ok(mut g) => {
@@ -81,67 +194,267 @@ impl SgSender {
     }
 }
 
+/// Messages that set something up on the shipgate rather than ask for
+/// something, so they're sent again to a restarted shipgate.
+fn is_standing(m: &Message) -> bool {
+    match m {
+        &Message::RegisterShip(..) | &Message::ShipEventSubscribe(..) => true,
+        _ => false
+    }
+}
+
+/// Seconds to wait after the `attempt`th failure to connect, doubling up to
+/// `MAX_RETRY_DELAY`.
+fn retry_delay(attempt: u32) -> u64 {
+    if attempt >= 6 {
+        MAX_RETRY_DELAY
+    } else {
+        ::std::cmp::min(1 << attempt, MAX_RETRY_DELAY)
+    }
+}
+
 impl ShipGateClient {
-    pub fn spawn(addr: SocketAddr, password: &str) -> SgSender {
+    /// Connect to the shipgate in the background, retrying until it's up,
+    /// and again whenever the connection drops. Requests sent meanwhile are
+    /// queued. `ready` gets `LoopMsg::ShipGateReady` once we're first
+    /// authenticated.
+    pub fn spawn(addr: ServiceAddr, password: &str, ready: MioSender<LoopMsg>) -> SgSender {
         let (tx, rx) = channel();
+        let state = Arc::new(Mutex::new(ConnectionState::Connecting));
 
-        let stream = TcpStream::connect(addr).unwrap();
-        let s_c = stream.try_clone().unwrap();
-        let pw = password.to_owned();
-        thread::spawn(move|| {
-            let c = ShipGateClient {
-                receiver: rx,
-                stream: s_c,
-                responders: Default::default(),
-                password: pw
-            };
-            c.run()
-        });
-
-        let tx_c = tx.clone();
-        let mut s_c = stream.try_clone().unwrap();
-        thread::spawn(move|| {
-            loop {
-                match Message::deserialize(&mut s_c) {
-                    Ok(m) => {
-                        if let Err(_) = tx_c.send(ClientMsg::Recv(m)) {
+        let c = ShipGateClient {
+            receiver: rx,
+            tx: tx.clone(),
+            addr: addr,
+            stream: None,
+            connection: 0,
+            responders: Default::default(),
+            unanswered: BTreeMap::new(),
+            standing: Vec::new(),
+            password: password.to_owned(),
+            state: state.clone(),
+            ready: Some(ready)
+        };
+        thread::spawn(move|| c.run());
+
+        SgSender {
+            tx: tx,
+            req_counter: Arc::new(Mutex::new(1)),
+            cb_sender: None,
+            state: state
+        }
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        if let Ok(mut g) = self.state.lock() {
+            *g = state;
+        }
+    }
+
+    /// Connect, retrying with backoff until it works.
+    fn open(addr: &ServiceAddr) -> SgStream {
+        let mut attempt = 0;
+        loop {
+            match SgStream::connect(addr) {
+                Ok(s) => {
+                    info!("Connected to shipgate at {}", addr);
+                    return s
+                },
+                Err(e) => {
+                    let delay = retry_delay(attempt);
+                    warn!("Couldn't connect to shipgate at {} ({}); retrying in {} seconds", addr, e, delay);
+                    attempt += 1;
+                    thread::sleep(Duration::from_secs(delay));
+                }
+            }
+        }
+    }
+
+    /// (Re)connect and authenticate, then send again everything the shipgate
+    /// hasn't answered or needs to know again. Tries until that all goes out.
+    fn connect(&mut self) {
+        self.set_state(ConnectionState::Connecting);
+        if let Some(old) = self.stream.take() {
+            // Stops the old reader, if it hasn't already.
+            let _ = old.shutdown(Shutdown::Both);
+        }
+        loop {
+            let mut stream = ShipGateClient::open(&self.addr);
+            self.connection += 1;
+            let connection = self.connection;
+            let mut s_c = stream.try_clone().unwrap();
+            let tx_c = self.tx.clone();
+            thread::spawn(move|| {
+                loop {
+                    match Message::deserialize(&mut s_c) {
+                        Ok(m) => {
+                            if let Err(_) = tx_c.send(ClientMsg::Recv(m)) {
+                                return
+                            }
+                        },
+                        Err(_) => {
+                            let _ = tx_c.send(ClientMsg::Disconnected(connection));
                             return
                         }
-                    },
-                    Err(_) => return
+                    }
+                }
+            });
+            match self.introduce(&mut stream) {
+                Ok(()) => {
+                    self.stream = Some(stream);
+                    return
+                },
+                Err(e) => {
+                    warn!("Lost the shipgate connection while authenticating ({}); reconnecting", e);
+                    let _ = stream.shutdown(Shutdown::Both);
                 }
             }
-        });
+        }
+    }
 
-        SgSender {
-            tx: tx,
-            req_counter: Arc::new(Mutex::new(1)),
-            cb_sender: None
+    fn introduce(&self, stream: &mut SgStream) -> io::Result<()> {
+        try!(Message::Auth(0, Auth(0, self.password.clone())).serialize(stream));
+        for m in self.standing.iter() {
+            try!(m.serialize(stream));
+        }
+        if !self.unanswered.is_empty() {
+            info!("Resending {} requests to the shipgate", self.unanswered.len());
+        }
+        for m in self.unanswered.values() {
+            try!(m.serialize(stream));
+        }
+        Ok(())
+    }
+
+    /// Send a message, reconnecting until it goes out.
+    fn send(&mut self, m: &Message) {
+        loop {
+            let r = match self.stream {
+                Some(ref mut s) => m.serialize(s),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"))
+            };
+            match r {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!("Lost the shipgate connection ({}); reconnecting", e);
+                    self.connect();
+                }
+            }
         }
     }
 
     pub fn run(mut self) {
-        // Authenticate
-        let m = Message::Auth(0, Auth(0, self.password.clone()));
-        m.serialize(&mut self.stream).unwrap();
+        self.connect();
 
-        for msg in self.receiver.iter() {
+        while let Ok(msg) = self.receiver.recv() {
             match msg {
                 ClientMsg::Send(callback, m) => {
-                    self.responders.insert(m.get_response_key(), callback);
-                    m.serialize(&mut self.stream).unwrap();
+                    let k = m.get_response_key();
+                    self.responders.insert(k, callback);
+                    self.send(&m);
+                    // Only now, or a reconnect in `send` would send it twice.
+                    self.unanswered.insert(k, m);
                 },
                 ClientMsg::SendForget(m) => {
-                    m.serialize(&mut self.stream).unwrap();
-                }
+                    self.send(&m);
+                    if is_standing(&m) {
+                        // Ships register again every so often; only the
+                        // latest registration needs sending again.
+                        if let Message::RegisterShip(_, RegisterShip(_, ref name)) = m {
+                            self.standing.retain(|s| match s {
+                                &Message::RegisterShip(_, RegisterShip(_, ref n)) => n != name,
+                                _ => true
+                            });
+                        }
+                        self.standing.push(m);
+                    }
+                },
+                ClientMsg::Recv(Message::AuthAck(..)) => {
+                    self.set_state(ConnectionState::Connected);
+                    if let Some(r) = self.ready.take() {
+                        info!("Authenticated with shipgate");
+                        if r.send(LoopMsg::ShipGateReady).is_err() {
+                            error!("Couldn't tell the event loop the shipgate is ready");
+                        }
+                    } else {
+                        info!("Authenticated with shipgate again");
+                    }
+                },
                 ClientMsg::Recv(m) => {
                     let rk = m.get_response_key();
+                    let answered = self.unanswered.get(&rk).map(|u| !is_standing(u)).unwrap_or(false);
+                    if answered {
+                        self.unanswered.remove(&rk);
+                    }
                     self.responders.get(&rk).map(|r| {
                         debug!("Shipgate request had response callback: {:?}", m);
                         r.send(ServiceMsg::ShipGateMsg(m))
                     });
+                },
+                ClientMsg::Disconnected(c) => {
+                    if c == self.connection {
+                        warn!("Lost the shipgate connection; reconnecting");
+                        self.connect();
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use mio::EventLoop;
+
+    use psoserial::Serial;
+
+    use ::config::ServiceAddr;
+    use ::loop_handler::LoopHandler;
+    use ::shipgate::msg::{Message, ShipList};
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), 1);
+        assert_eq!(retry_delay(3), 8);
+        assert_eq!(retry_delay(5), 32);
+        assert_eq!(retry_delay(6), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_request_sent_after_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let event_loop = EventLoop::<LoopHandler>::new().unwrap();
+        let (svc_tx, _svc_rx) = channel();
+        let mut sender = ShipGateClient::spawn(ServiceAddr::Tcp(addr), "pw", event_loop.channel()).clone_with(svc_tx);
+
+        // The shipgate goes away right after the client authenticates.
+        {
+            let (mut s, _) = listener.accept().unwrap();
+            match Message::deserialize(&mut s).unwrap() {
+                Message::Auth(..) => (),
+                m => panic!("expected Auth, got {:?}", m)
+            }
+        }
+        let key = sender.send(Message::ShipList(0, ShipList)).unwrap();
+        assert_eq!(sender.state(), ConnectionState::Connecting);
+
+        let (mut s, _) = listener.accept().unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        match Message::deserialize(&mut s).unwrap() {
+            Message::Auth(..) => (),
+            m => panic!("expected Auth, got {:?}", m)
+        }
+        match Message::deserialize(&mut s).unwrap() {
+            Message::ShipList(k, _) => assert_eq!(k, key),
+            m => panic!("expected ShipList, got {:?}", m)
+        }
+    }
+}
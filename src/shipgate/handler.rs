@@ -1,9 +1,14 @@
 use std::sync::Arc;
 
+use time;
+
 use psodb_common::pool::Pool;
+use psodb_common::error::Error as DbError;
 use psodb_common::account::Account;
 use psodb_common::account::BbAccountInfo;
-use psodata::chara::BbFullCharData;
+use psodb_common::account::ChatLine;
+use psodata::chara::{BbFullCharData, ItemBank};
+use psodata::bb_defaults::{KEY_CONFIG_LEN, JOY_CONFIG_LEN, SHORTCUTS_LEN};
 
 use ::shipgate::msg::*;
 use super::ClientCtx;
@@ -25,22 +30,7 @@ impl<'a> MsgHandler<'a> {
     pub fn handle_login_challenge(&mut self, m: BbLoginChallenge) -> Message {
         let BbLoginChallenge { username, password } = m;
 
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error getting pool connection: {:?}", e);
-                return BbLoginChallengeAck { status: 1, account_id: 0 }.into() // unknown error occurred
-            }
-        };
-
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbLoginChallengeAck { status: 1, account_id: 0 }.into()
-            }
-        };
-        let account: Account = match handle.get_account_by_username(&username) {
+        let mut account: Account = match self.pool.run(|db| db.get_account_by_username(&username)) {
             Ok(Some(a)) => a,
             Ok(None) => return BbLoginChallengeAck { status: 8, account_id: 0 }.into(), // no user exists
             Err(e) => {
@@ -58,29 +48,23 @@ impl<'a> MsgHandler<'a> {
             return BbLoginChallengeAck { status: 6, account_id: 0 }.into()
         }
 
+        // Now that we know the password, move a legacy hash over to bcrypt.
+        if account.needs_rehash() {
+            account.set_password(password);
+            let r = self.pool.run(|db| db.put_account(&mut account));
+            match r {
+                Ok(_) => info!("Rehashed legacy password for {}", username),
+                Err(e) => warn!("Couldn't rehash legacy password for {}: {:?}", username, e)
+            }
+        }
+
         BbLoginChallengeAck { status: 0, account_id: account.id().unwrap() }.into()
     }
 
     pub fn handle_get_bb_account_info(&mut self, m: BbGetAccountInfo) -> Message {
         let BbGetAccountInfo { account_id } = m;
 
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetAccountInfoAck::default().into()
-            }
-        };
-
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetAccountInfoAck::default().into()
-            }
-        };
-
-        let info: BbAccountInfo = match handle.fetch_bb_account_info(account_id) {
+        let info: BbAccountInfo = match self.pool.run(|db| db.fetch_bb_account_info(account_id)) {
             Ok(Some(a)) => a,
             Ok(None) => {
                 error!("Account doesn't exist");
@@ -106,21 +90,7 @@ impl<'a> MsgHandler<'a> {
     }
 
     pub fn handle_bb_update_options(&mut self, m: BbUpdateOptions) {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let mut info: BbAccountInfo = match handle.fetch_bb_account_info(m.account_id) {
+        let mut info: BbAccountInfo = match self.pool.run(|db| db.fetch_bb_account_info(m.account_id)) {
             Ok(Some(a)) => a,
             Ok(None) => {
                 error!("Account doesn't exist; not updating account options");
@@ -133,31 +103,17 @@ impl<'a> MsgHandler<'a> {
         };
 
         info.options = m.options;
-        match handle.put_bb_account_info(&info) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Couldn't update account options: {:?}", e);
-                return
-            }
+        if let Err(e) = self.pool.run(|db| db.put_bb_account_info(&info)) {
+            error!("Couldn't update account options: {:?}", e);
         }
     }
 
     pub fn handle_bb_update_keys(&mut self, m: BbUpdateKeys) {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let mut info: BbAccountInfo = match handle.fetch_bb_account_info(m.account_id) {
+        if m.key_config.len() != KEY_CONFIG_LEN {
+            warn!("Key config for account {} is {} bytes, expected {}; not saving", m.account_id, m.key_config.len(), KEY_CONFIG_LEN);
+            return
+        }
+        let mut info: BbAccountInfo = match self.pool.run(|db| db.fetch_bb_account_info(m.account_id)) {
             Ok(Some(a)) => a,
             Ok(None) => {
                 error!("Account doesn't exist; not updating account key config");
@@ -170,31 +126,17 @@ impl<'a> MsgHandler<'a> {
         };
 
         info.key_config = m.key_config;
-        match handle.put_bb_account_info(&info) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Couldn't update account options: {:?}", e);
-                return
-            }
+        if let Err(e) = self.pool.run(|db| db.put_bb_account_info(&info)) {
+            error!("Couldn't update account options: {:?}", e);
         }
     }
 
     pub fn handle_bb_update_joy(&mut self, m: BbUpdateJoy) {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let mut info: BbAccountInfo = match handle.fetch_bb_account_info(m.account_id) {
+        if m.joy_config.len() != JOY_CONFIG_LEN {
+            warn!("Joystick config for account {} is {} bytes, expected {}; not saving", m.account_id, m.joy_config.len(), JOY_CONFIG_LEN);
+            return
+        }
+        let mut info: BbAccountInfo = match self.pool.run(|db| db.fetch_bb_account_info(m.account_id)) {
             Ok(Some(a)) => a,
             Ok(None) => {
                 error!("Account doesn't exist; not updating account joystick config");
@@ -207,42 +149,37 @@ impl<'a> MsgHandler<'a> {
         };
 
         info.joy_config = m.joy_config;
-        match handle.put_bb_account_info(&info) {
-            Ok(_) => (),
+        if let Err(e) = self.pool.run(|db| db.put_bb_account_info(&info)) {
+            error!("Couldn't update account options: {:?}", e);
+        }
+    }
+
+    pub fn handle_bb_update_shortcuts(&mut self, m: BbUpdateShortcuts) {
+        if m.shortcuts.len() != SHORTCUTS_LEN {
+            warn!("Chat shortcuts for account {} are {} bytes, expected {}; not saving", m.account_id, m.shortcuts.len(), SHORTCUTS_LEN);
+            return
+        }
+        let mut info: BbAccountInfo = match self.pool.run(|db| db.fetch_bb_account_info(m.account_id)) {
+            Ok(Some(a)) => a,
+            Ok(None) => {
+                error!("Account doesn't exist; not updating account chat shortcuts");
+                return
+            },
             Err(e) => {
-                error!("Couldn't update account options: {:?}", e);
+                error!("Database error getting Bb account info: {:?}", e);
                 return
             }
+        };
+
+        info.shortcuts = m.shortcuts;
+        if let Err(e) = self.pool.run(|db| db.put_bb_account_info(&info)) {
+            error!("Couldn't update account chat shortcuts: {:?}", e);
         }
     }
 
     pub fn handle_bb_get_character(&mut self, m: BbGetCharacter) -> Message {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetCharacterAck {
-                    status: 1,
-                    account_id: 0,
-                    slot: 0,
-                    full_char: None
-                }.into()
-            }
-        };
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetCharacterAck {
-                    status: 2,
-                    account_id: 0,
-                    slot: 0,
-                    full_char: None
-                }.into()
-            }
-        };
         info!("Fetching character {} for account {} from database", m.slot, m.account_id);
-        let chara: Option<BbFullCharData> = match handle.fetch_bb_character(m.account_id, m.slot) {
+        let chara: Option<BbFullCharData> = match self.pool.run(|db| db.fetch_bb_character(m.account_id, m.slot)) {
             Ok(a) => a,
             Err(e) => {
                 error!("Database error getting character: {:?}", e);
@@ -264,78 +201,210 @@ impl<'a> MsgHandler<'a> {
     }
 
     pub fn handle_bb_put_character(&mut self, m: BbPutCharacter) {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
-        let handle = match a.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
-            }
-        };
         let BbPutCharacter { account_id, slot, full_char, save_acct_data } = m;
-        match handle.put_bb_character(account_id, slot, full_char, save_acct_data > 0) {
+        match self.pool.run(|db| db.put_bb_character(account_id, slot, full_char.clone(), save_acct_data > 0)) {
             Ok(_) => (),
+            Err(DbError::NotFound(what)) => {
+                warn!("Not saving character slot {} for account {}: {} not found", slot, account_id, what);
+            },
             Err(e) => {
                 error!("Database error putting character slot {} for account {}: {}", slot, account_id, e);
-                return
             }
         }
     }
 
-    pub fn handle_bb_set_login_flags(&mut self, m: BbSetLoginFlags) {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
+    /// `slots` is the cached per-slot usage for the account; any slot not
+    /// known yet is read from the database and filled in.
+    pub fn handle_bb_get_storage_usage(&mut self, m: BbGetStorageUsage, slots: &mut [Option<u32>; 4], quota: u32) -> Message {
+        let account_id = m.account_id;
+        let fail = |status: u32| -> Message {
+            BbGetStorageUsageAck {
+                status: status,
+                account_id: account_id,
+                used_elsewhere: 0,
+                quota: quota
+            }.into()
+        };
+        for (i, s) in slots.iter_mut().enumerate() {
+            if s.is_some() {
+                continue
+            }
+            match self.pool.run(|db| db.fetch_bb_character(account_id, i as u8)) {
+                Ok(c) => *s = Some(c.map(|c| c.stored_items()).unwrap_or(0)),
+                Err(e) => {
+                    error!("Database error counting stored items for account {}: {:?}", account_id, e);
+                    return fail(3)
+                }
+            }
+        }
+        let used_elsewhere = slots.iter().enumerate()
+            .filter(|&(i, _)| i != m.slot as usize)
+            .map(|(_, s)| s.unwrap_or(0))
+            .fold(0, |a, b| a + b);
+        BbGetStorageUsageAck {
+            status: 0,
+            account_id: m.account_id,
+            used_elsewhere: used_elsewhere,
+            quota: quota
+        }.into()
+    }
+
+    pub fn handle_bb_get_shared_bank(&mut self, m: BbGetSharedBank, capacity: u32) -> Message {
+        let account_id = m.account_id;
+        match self.pool.run(|db| db.fetch_bb_shared_bank(account_id)) {
+            Ok(bank) => BbGetSharedBankAck {
+                status: 0,
+                account_id: account_id,
+                capacity: capacity,
+                bank: bank
+            }.into(),
             Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
+                error!("Database error getting shared bank for account {}: {}", account_id, e);
+                BbGetSharedBankAck {
+                    status: 3,
+                    account_id: account_id,
+                    capacity: capacity,
+                    bank: ItemBank::default()
+                }.into()
             }
+        }
+    }
+
+    /// Nothing is written unless the bank is within `capacity`, and then the
+    /// character and bank are written together.
+    pub fn handle_bb_shared_bank_transfer(&mut self, m: BbSharedBankTransfer, capacity: u32) -> Message {
+        let BbSharedBankTransfer { account_id, slot, full_char, bank } = m;
+        let ack = |status: u32| -> Message {
+            BbSharedBankTransferAck {
+                status: status,
+                account_id: account_id
+            }.into()
         };
-        let handle = match a.lock() {
-            Ok(h) => h,
+        if bank.item_count > capacity {
+            warn!("Refusing shared bank for account {} with {} items, over capacity {}", account_id, bank.item_count, capacity);
+            return ack(4)
+        }
+        match self.pool.run(|db| db.put_bb_character_and_shared_bank(account_id, slot, full_char.clone(), &bank)) {
+            Ok(_) => ack(0),
             Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return
+                error!("Database error saving shared bank transfer for account {} slot {}: {}", account_id, slot, e);
+                ack(3)
             }
+        }
+    }
+
+    /// With `unique_names`, the character is only saved if its name could be
+    /// claimed.
+    pub fn handle_bb_create_character(&mut self, m: BbCreateCharacter, unique_names: bool) -> Message {
+        let BbCreateCharacter { account_id, slot, full_char } = m;
+        let ack = |status: u32| -> Message {
+            BbCreateCharacterAck {
+                status: status,
+                account_id: account_id,
+                slot: slot
+            }.into()
         };
-        match handle.set_bb_login_flags(m.account_id, m.flags) {
-            Ok(_) => (),
+        if unique_names {
+            let name = name_key(&full_char.chara.name);
+            match self.pool.run(|db| db.claim_bb_character_name(account_id, slot, &name)) {
+                Ok(true) => (),
+                Ok(false) => {
+                    info!("Account {} tried to make a character named {}, which is taken", account_id, name);
+                    return ack(4)
+                },
+                Err(e) => {
+                    error!("Database error claiming character name for account {} slot {}: {}", account_id, slot, e);
+                    return ack(3)
+                }
+            }
+        }
+        match self.pool.run(|db| db.put_bb_character(account_id, slot, full_char.clone(), false)) {
+            Ok(_) => ack(0),
             Err(e) => {
-                error!("Database error setting login flags: {:?}", e);
-                return
+                error!("Database error putting character slot {} for account {}: {}", slot, account_id, e);
+                ack(3)
             }
         }
     }
 
-    pub fn handle_bb_get_login_flags(&mut self, m: BbGetLoginFlags) -> Message {
-        let a = match self.pool.get_connection() {
-            Ok(h) => h,
+    pub fn handle_bb_get_rules_version(&mut self, m: BbGetRulesVersion) -> Message {
+        let account_id = m.account_id;
+        let (status, version) = match self.pool.run(|db| db.fetch_bb_rules_version(account_id)) {
+            Ok(v) => (0, v),
             Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetLoginFlagsAck {
-                    status: 1,
-                    account_id: 0,
-                    flags: 0
-                }.into()
+                error!("Database error getting accepted rules for account {}: {}", account_id, e);
+                (3, 0)
             }
         };
-        let handle = match a.lock() {
-            Ok(h) => h,
+        BbGetRulesVersionAck {
+            status: status,
+            account_id: account_id,
+            version: version
+        }.into()
+    }
+
+    pub fn handle_bb_set_rules_version(&mut self, m: BbSetRulesVersion) {
+        if let Err(e) = self.pool.run(|db| db.put_bb_rules_version(m.account_id, m.version)) {
+            error!("Database error saving accepted rules for account {}: {}", m.account_id, e);
+        }
+    }
+
+    pub fn handle_bb_add_playtime(&mut self, m: BbAddPlaytime) {
+        // Not retried, since the first try might have been added.
+        if let Err(e) = self.pool.run_once(|db| db.add_bb_playtime(m.account_id, m.slot, m.seconds)) {
+            error!("Database error adding playtime for account {} slot {}: {}", m.account_id, m.slot, e);
+        }
+    }
+
+    pub fn handle_bb_chat_log(&mut self, m: BbChatLog) {
+        save_bb_chat_logs(&self.pool, &[chat_line(m)]);
+    }
+
+    pub fn handle_bb_get_playtime(&mut self, m: BbGetPlaytime) -> Message {
+        let account_id = m.account_id;
+        let (status, times) = match self.pool.run(|db| db.fetch_bb_playtime(account_id, m.slot)) {
+            Ok(t) => (0, t),
             Err(e) => {
-                error!("Database error locking connection handle: {:?}", e);
-                return BbGetLoginFlagsAck {
-                    status: 2,
-                    account_id: 0,
-                    flags: 0
-                }.into()
+                error!("Database error getting playtime for account {}: {}", account_id, e);
+                (3, (0, 0))
+            }
+        };
+        BbGetPlaytimeAck {
+            status: status,
+            account_id: account_id,
+            account_seconds: times.0,
+            character_seconds: times.1
+        }.into()
+    }
+
+    pub fn handle_bb_set_login_flags(&mut self, m: BbSetLoginFlags) {
+        if let Err(e) = self.pool.run(|db| db.set_bb_login_flags(m.account_id, m.flags)) {
+            error!("Database error setting login flags: {:?}", e);
+        }
+    }
+
+    pub fn handle_bb_ban_account(&mut self, m: BbBanAccount) {
+        let mut account = match self.pool.run(|db| db.get_account_by_id(m.account_id)) {
+            Ok(Some(a)) => a,
+            Ok(None) => {
+                warn!("Asked to ban account {}, which doesn't exist", m.account_id);
+                return
+            },
+            Err(e) => {
+                error!("Database error fetching account {} to ban: {:?}", m.account_id, e);
+                return
             }
         };
-        match handle.get_bb_login_flags(m.account_id) {
+        account.banned = true;
+        let r = self.pool.run(|db| db.put_account(&mut account));
+        match r {
+            Ok(_) => info!("Banned account {} ({})", m.account_id, account.username),
+            Err(e) => error!("Database error banning account {}: {:?}", m.account_id, e)
+        }
+    }
+
+    pub fn handle_bb_get_login_flags(&mut self, m: BbGetLoginFlags) -> Message {
+        match self.pool.run(|db| db.get_bb_login_flags(m.account_id)) {
             Ok(flags) => BbGetLoginFlagsAck {
                 status: 0,
                 account_id: m.account_id,
@@ -351,4 +420,104 @@ impl<'a> MsgHandler<'a> {
             }
         }
     }
+
+    pub fn handle_bb_check_ban(&mut self, m: BbCheckBan) -> Message {
+        match self.pool.run(|db| db.fetch_bb_ban(m.guildcard)) {
+            Ok(Some(ban)) => BbCheckBanAck {
+                status: 0,
+                banned: true,
+                expires: ban.expires.unwrap_or(0),
+                reason: ban.reason
+            }.into(),
+            Ok(None) => BbCheckBanAck::default().into(),
+            Err(e) => {
+                error!("Database error checking guild card {} for a ban: {:?}", m.guildcard, e);
+                BbCheckBanAck { status: 3, ..Default::default() }.into()
+            }
+        }
+    }
+
+    pub fn handle_bb_block_transfer(&mut self, m: BbBlockTransfer) -> Message {
+        let BbBlockTransfer { account_id, slot, full_char } = m;
+        let status = match self.pool.run(|db| db.put_bb_character(account_id, slot, full_char.clone(), false)) {
+            Ok(_) => 0,
+            Err(e) => {
+                error!("Database error saving character slot {} for account {} before a block transfer: {}", slot, account_id, e);
+                3
+            }
+        };
+        BbBlockTransferAck {
+            status: status,
+            account_id: account_id
+        }.into()
+    }
+
+    pub fn handle_bb_delete_character(&mut self, m: BbDeleteCharacter) -> Message {
+        let BbDeleteCharacter { account_id, slot } = m;
+        let (status, deleted) = match self.pool.run(|db| db.delete_bb_character(account_id, slot)) {
+            Ok(deleted) => {
+                if deleted {
+                    info!("Deleted character slot {} for account {}", slot, account_id);
+                }
+                (0, deleted)
+            },
+            Err(e) => {
+                error!("Database error deleting character slot {} for account {}: {}", slot, account_id, e);
+                (3, false)
+            }
+        };
+        BbDeleteCharacterAck {
+            status: status,
+            account_id: account_id,
+            slot: slot,
+            deleted: if deleted { 1 } else { 0 }
+        }.into()
+    }
+}
+
+/// A character name as compared for uniqueness: without the client's
+/// language marker or padding.
+fn name_key(name: &str) -> String {
+    let n = if name.starts_with("\t") { name.chars().skip(2).collect() } else { name.to_string() };
+    n.trim_right_matches('\0').trim().to_string()
+}
+
+/// Save batched play time, as (account, slot, seconds). Not retried, since
+/// the first try might have been added.
+pub fn save_bb_playtimes(pool: &Pool, entries: &[(u32, u8, u32)]) {
+    if let Err(e) = pool.run_once(|db| db.add_bb_playtimes(entries)) {
+        error!("Database error saving {} batched playtime updates: {}", entries.len(), e);
+    }
+}
+
+/// The database's record of a logged chat message.
+pub fn chat_line(m: BbChatLog) -> ChatLine {
+    ChatLine {
+        guildcard: m.guildcard,
+        target: m.target,
+        block: m.block,
+        lobby: m.lobby,
+        time: m.time,
+        text: m.text
+    }
+}
+
+/// Save chat for the chat log. Not retried, so a line isn't logged twice.
+pub fn save_bb_chat_logs(pool: &Pool, lines: &[ChatLine]) {
+    if let Err(e) = pool.run_once(|db| db.put_bb_chat_logs(lines)) {
+        error!("Database error logging {} chat messages: {}", lines.len(), e);
+    }
+}
+
+/// Compact the database, logging how long it took and how much it freed.
+pub fn run_maintenance(pool: &Pool) {
+    info!("Starting database maintenance");
+    let start = time::precise_time_ns();
+    match pool.run_once(|db| db.maintain()) {
+        Ok((before, after)) => {
+            let ms = (time::precise_time_ns() - start) / 1_000_000;
+            info!("Database maintenance took {} ms and reclaimed {} bytes ({} -> {})", ms, before.saturating_sub(after), before, after);
+        },
+        Err(e) => error!("Database error during maintenance: {}", e)
+    }
 }
@@ -0,0 +1,91 @@
+//! One-time tokens for players moving from a block to another ship. The
+//! block leaving puts the token in the player's security data, and the ship
+//! they arrive at claims it before letting them in.
+
+use std::collections::HashMap;
+
+use rand::random;
+
+/// Seconds a player has to reach the other ship.
+pub const HANDOFF_TTL: u64 = 60;
+
+/// The security data magic the login server gives, which a token is never.
+pub const LOGIN_MAGIC: u32 = 0xCAFEB00B;
+
+struct Handoff {
+    token: u32,
+    expires: u64
+}
+
+#[derive(Default)]
+pub struct Handoffs {
+    /// The outstanding token for each account moving ships.
+    accounts: HashMap<u32, Handoff>
+}
+
+impl Handoffs {
+    pub fn new() -> Handoffs {
+        Handoffs::default()
+    }
+
+    /// A fresh token for the account, replacing any it had.
+    pub fn issue(&mut self, account_id: u32, now: u64) -> u32 {
+        let mut token = random::<u32>();
+        while token == 0 || token == LOGIN_MAGIC {
+            token = random::<u32>();
+        }
+        self.accounts.insert(account_id, Handoff {
+            token: token,
+            expires: now + HANDOFF_TTL
+        });
+        token
+    }
+
+    /// Use up the account's token if it's `token` and hasn't expired.
+    pub fn claim(&mut self, account_id: u32, token: u32, now: u64) -> bool {
+        let ok = match self.accounts.get(&account_id) {
+            Some(h) => h.token == token && now < h.expires,
+            None => false
+        };
+        if ok {
+            self.accounts.remove(&account_id);
+        }
+        ok
+    }
+
+    /// Forget tokens nobody came to claim.
+    pub fn expire(&mut self, now: u64) {
+        self.accounts.retain(|_, h| now < h.expires);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Handoffs, HANDOFF_TTL, LOGIN_MAGIC};
+
+    #[test]
+    fn test_handoff_claimed_once() {
+        let mut h = Handoffs::new();
+        let token = h.issue(1, 100);
+        assert!(token != LOGIN_MAGIC);
+        assert!(!h.claim(2, token, 100));
+        assert!(!h.claim(1, token.wrapping_add(1), 100));
+        assert!(h.claim(1, token, 100));
+        assert!(!h.claim(1, token, 100));
+    }
+
+    #[test]
+    fn test_handoff_expires() {
+        let mut h = Handoffs::new();
+        let old = h.issue(1, 100);
+        let token = h.issue(1, 100);
+        if old != token {
+            assert!(!h.claim(1, old, 100));
+        }
+        assert!(!h.claim(1, token, 100 + HANDOFF_TTL));
+
+        h.issue(2, 100);
+        h.expire(100 + HANDOFF_TTL);
+        assert!(h.accounts.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! Scheduling for the daily database maintenance. It runs once a day during
+//! the configured hour, local time, as soon as the shipgate is quiet enough.
+
+/// What the shipgate should do about maintenance after a tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Due {
+    Nothing,
+    Run,
+    /// It's the hour, but there were this many requests in the last minute.
+    Deferred(u32),
+    /// The hour passed without a quiet enough minute.
+    Skipped
+}
+
+pub struct MaintenanceSchedule {
+    hour: u8,
+    /// Most requests a minute at which maintenance may still start.
+    max_requests: u32,
+    /// Requests so far this minute.
+    requests: u32,
+    /// Requests in the last full minute.
+    last_minute: u32,
+    /// Seconds into the current minute.
+    seconds: u32,
+    /// (year, day of year) maintenance last ran or was given up on.
+    done_on: Option<(i32, i32)>,
+    /// (year, day of year) maintenance was last put off.
+    deferred_on: Option<(i32, i32)>
+}
+
+impl MaintenanceSchedule {
+    pub fn new(hour: u8, max_requests: u32) -> MaintenanceSchedule {
+        MaintenanceSchedule {
+            hour: hour,
+            max_requests: max_requests,
+            requests: 0,
+            last_minute: 0,
+            seconds: 0,
+            done_on: None,
+            deferred_on: None
+        }
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    /// Count a request from a block.
+    pub fn note_request(&mut self) {
+        self.requests = self.requests.saturating_add(1);
+    }
+
+    /// Count a second, at `day` (year, day of year) and `hour` local time.
+    /// Only once a minute does it say anything other than `Nothing`, and it
+    /// reports a deferral only the first time each day.
+    pub fn tick(&mut self, day: (i32, i32), hour: u8) -> Due {
+        self.seconds += 1;
+        if self.seconds < 60 {
+            return Due::Nothing
+        }
+        self.seconds = 0;
+        self.last_minute = self.requests;
+        self.requests = 0;
+
+        if hour != self.hour {
+            if self.deferred_on.is_some() && self.deferred_on != self.done_on {
+                self.done_on = self.deferred_on;
+                return Due::Skipped
+            }
+            return Due::Nothing
+        }
+        if self.done_on == Some(day) {
+            return Due::Nothing
+        }
+        if self.last_minute <= self.max_requests {
+            self.done_on = Some(day);
+            return Due::Run
+        }
+        if self.deferred_on == Some(day) {
+            return Due::Nothing
+        }
+        self.deferred_on = Some(day);
+        Due::Deferred(self.last_minute)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MaintenanceSchedule, Due};
+
+    fn minute(m: &mut MaintenanceSchedule, day: (i32, i32), hour: u8, requests: u32) -> Due {
+        for _ in 0..requests {
+            m.note_request();
+        }
+        let mut due = Due::Nothing;
+        for _ in 0..60 {
+            due = m.tick(day, hour);
+        }
+        due
+    }
+
+    #[test]
+    fn test_runs_once_a_day() {
+        let mut m = MaintenanceSchedule::new(4, 10);
+        assert_eq!(minute(&mut m, (2016, 1), 3, 0), Due::Nothing);
+        assert_eq!(minute(&mut m, (2016, 1), 4, 5), Due::Run);
+        assert_eq!(minute(&mut m, (2016, 1), 4, 0), Due::Nothing);
+        assert_eq!(minute(&mut m, (2016, 2), 4, 0), Due::Run);
+    }
+
+    #[test]
+    fn test_defers_while_busy() {
+        let mut m = MaintenanceSchedule::new(4, 10);
+        assert_eq!(minute(&mut m, (2016, 1), 4, 11), Due::Deferred(11));
+        assert_eq!(minute(&mut m, (2016, 1), 4, 20), Due::Nothing);
+        assert_eq!(minute(&mut m, (2016, 1), 4, 3), Due::Run);
+
+        assert_eq!(minute(&mut m, (2016, 2), 4, 50), Due::Deferred(50));
+        assert_eq!(minute(&mut m, (2016, 2), 5, 50), Due::Skipped);
+        assert_eq!(minute(&mut m, (2016, 2), 5, 0), Due::Nothing);
+    }
+}
@@ -7,17 +7,18 @@
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::thread;
-use std::net::{SocketAddr, SocketAddrV4};
-use std::collections::{HashMap, BTreeMap};
+use std::net::SocketAddr;
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use mio::tcp::TcpListener;
 use mio::Sender;
+use time;
 
 use psodb_common::pool::Pool;
 
 use ::services::message::NetMsg;
-use ::services::{ServiceType, Service, ServiceMsg};
+use ::services::listener::Listener;
+use ::services::{ServiceType, Service, ServiceMsg, spawn_ticker};
 use ::loop_handler::LoopMsg;
 
 use ::shipgate::msg::*;
@@ -25,8 +26,18 @@ use ::shipgate::msg::*;
 pub mod msg;
 pub mod client;
 mod handler;
+mod batch;
+mod maintenance;
+mod storage;
+mod handoff;
+pub mod ships;
 
-use self::handler::MsgHandler;
+use self::handler::{MsgHandler, save_bb_playtimes, save_bb_chat_logs, chat_line, run_maintenance};
+use self::batch::WriteBatch;
+use self::maintenance::{MaintenanceSchedule, Due};
+use self::ships::ShipRegistry;
+use self::storage::StorageCache;
+use self::handoff::Handoffs;
 
 pub struct ShipGateService {
     receiver: Receiver<ServiceMsg>,
@@ -34,7 +45,23 @@ pub struct ShipGateService {
     password: String,
     clients: HashMap<usize, ClientCtx>,
     pool: Arc<Pool>,
-    ships: BTreeMap<usize, (SocketAddrV4, String)>
+    ships: ShipRegistry,
+    /// Blocks waiting on ship event changes: (client, response key, ship name)
+    event_subs: Vec<(usize, u32, String)>,
+    storage_quota: u32,
+    /// Items stored per character slot, for accounts whose usage has been
+    /// asked for lately. Kept current as characters are saved.
+    storage: StorageCache,
+    /// Tokens for players on their way to another ship.
+    handoffs: Handoffs,
+    shared_bank_slots: u32,
+    unique_names: bool,
+    /// Frequent writes waiting to be saved together, if batching is on.
+    batch: Option<WriteBatch>,
+    /// When to compact the database, if scheduled.
+    maintenance: Option<MaintenanceSchedule>,
+    /// When the service started, in seconds, for heartbeat replies.
+    started: i64
 }
 
 
@@ -46,34 +73,93 @@ pub struct ClientCtx {
 }
 
 impl ShipGateService {
-    pub fn spawn(bind: &SocketAddr, sender: Sender<LoopMsg>, password: &str, pool: Arc<Pool>) -> Service {
+    /// With a `batch_interval`, play time and chat log lines are saved
+    /// every that many seconds, or once `batch_size` are waiting, rather
+    /// than as they come in. With a `maintenance_hour`, the database is
+    /// compacted once a day during that hour, in the first minute with no
+    /// more than `maintenance_max_requests` requests.
+    pub fn spawn<L: Listener + 'static>(listener: L, sender: Sender<LoopMsg>, password: &str, pool: Arc<Pool>, storage_quota: u32, shared_bank_slots: u32, unique_names: bool, batch_interval: u32, batch_size: u32, maintenance_hour: Option<u8>, maintenance_max_requests: u32) -> Service {
         let (tx, rx) = channel();
 
-        let listener = TcpListener::bind(bind).expect("Couldn't create tcplistener");
+        let batch = if batch_interval > 0 {
+            Some(WriteBatch::new(batch_interval, batch_size as usize))
+        } else {
+            None
+        };
+        let maintenance = maintenance_hour.map(|h| MaintenanceSchedule::new(h, maintenance_max_requests));
+        if batch.is_some() || maintenance.is_some() {
+            spawn_ticker(tx.clone());
+        }
 
         let pw = password.to_owned();
-        thread::spawn(move|| {
+        let thread = thread::spawn(move|| {
             let p = ShipGateService {
                 receiver: rx,
                 sender: sender,
                 password: pw,
                 clients: Default::default(),
                 pool: pool,
-                ships: Default::default()
+                ships: ShipRegistry::new(),
+                event_subs: Vec::new(),
+                storage_quota: storage_quota,
+                storage: StorageCache::new(),
+                handoffs: Handoffs::new(),
+                shared_bank_slots: shared_bank_slots,
+                unique_names: unique_names,
+                batch: batch,
+                maintenance: maintenance,
+                started: time::get_time().sec
             };
             p.run()
         });
 
-        Service::new(listener, tx, ServiceType::ShipGate)
+        let mut s = Service::new(listener, tx, ServiceType::ShipGate);
+        s.set_thread(thread);
+        s
+    }
+
+    /// Save everything waiting in the write batch.
+    fn flush_batch(&mut self) {
+        flush_batch(&self.pool, &mut self.batch);
+    }
+
+    fn tick_maintenance(&mut self) {
+        let due = match self.maintenance {
+            Some(ref mut m) => {
+                let now = time::now();
+                m.tick((now.tm_year, now.tm_yday), now.tm_hour as u8)
+            },
+            None => return
+        };
+        match due {
+            Due::Run => {
+                // Anything batched goes in before the file is rewritten.
+                self.flush_batch();
+                run_maintenance(&self.pool);
+            },
+            Due::Deferred(n) => info!("Deferring database maintenance; {} requests in the last minute", n),
+            Due::Skipped => warn!("Skipped database maintenance today; the shipgate was never quiet enough"),
+            Due::Nothing => ()
+        }
     }
 
     pub fn run(mut self) {
         info!("ShipGate service running");
+        if self.unique_names {
+            info!("Character names must be unique");
+        }
+        if let Some(h) = self.maintenance.as_ref().map(|m| m.hour()) {
+            info!("Database maintenance scheduled for {:02}:00", h);
+        }
 
         loop {
             let msg = match self.receiver.recv() {
                 Ok(m) => m,
-                Err(_) => return // receiver closed; we can exit service
+                Err(_) => {
+                    // receiver closed; we can exit service
+                    self.flush_batch();
+                    return
+                }
             };
             match msg {
                 ServiceMsg::ClientConnected((addr, id)) => {
@@ -89,6 +175,8 @@ impl ShipGateService {
                 ServiceMsg::ClientDisconnected(id) => {
                     info!("Client {} disconnected from shipgate.", id);
                     self.clients.remove(&id);
+                    self.event_subs.retain(|&(c, _, _)| c != id);
+                    self.ships.remove_client(id);
                 },
                 ServiceMsg::ClientSaid(id, NetMsg::ShipGate(m)) => {
                     let mut c = match self.clients.get_mut(&id) {
@@ -97,6 +185,10 @@ impl ShipGateService {
                     };
 
                     if c.authenticated {
+                        if let Some(ref mut m) = self.maintenance {
+                            m.note_request();
+                        }
+                        let mut flush = false;
                         let mut handler = MsgHandler::new(self.pool.clone(), c);
                         let response: Option<(u32, Message)> = match m {
                             Message::BbLoginChallenge(req, body) => {
@@ -106,15 +198,20 @@ impl ShipGateService {
                                 Some((req, handler.handle_get_bb_account_info(body).into()))
                             },
                             Message::RegisterShip(req, body) => {
-                                // Register the ship.
-                                info!("Ship {} at {:?} registered", body.1, body.0);
-                                self.ships.insert(id, (body.0, body.1));
+                                // Register the ship, or keep it listed.
+                                debug!("Ship {} at {:?} registered", body.1, body.0);
+                                self.ships.register(id, body.0, &body.1, time::get_time().sec as u64);
                                 Some((req, RegisterShipAck.into()))
                             },
+                            Message::ShipPopulation(_, body) => {
+                                self.ships.report_block(&body.ship, body.block, body.players, time::get_time().sec as u64);
+                                None
+                            },
+                            Message::Heartbeat(req, _) => {
+                                Some((req, heartbeat_ack(self.started).into()))
+                            },
                             Message::ShipList(req, _) => {
-                                let ships: Vec<_> = self.ships.values().enumerate().map(|v| {
-                                    ((v.1).0, format!("{:02}:{}", v.0 + 1, (v.1).1))
-                                }).collect();
+                                let ships = self.ships.list(time::get_time().sec as u64);
                                 Some((req, ShipListAck(ships).into()))
                             },
                             Message::BbUpdateOptions(_, body) => {
@@ -129,19 +226,135 @@ impl ShipGateService {
                                 handler.handle_bb_update_joy(body);
                                 None
                             },
+                            Message::BbUpdateShortcuts(_, body) => {
+                                handler.handle_bb_update_shortcuts(body);
+                                None
+                            },
                             Message::BbGetCharacter(req, body) => {
                                 Some((req, handler.handle_bb_get_character(body)))
                             },
                             Message::BbPutCharacter(_, body) => {
+                                self.storage.set(body.account_id, body.slot, body.full_char.stored_items(), time::get_time().sec as u64);
                                 handler.handle_bb_put_character(body);
                                 None
                             },
+                            Message::BbCreateCharacter(req, body) => {
+                                let (account_id, slot, stored) = (body.account_id, body.slot, body.full_char.stored_items());
+                                let ack = handler.handle_bb_create_character(body, self.unique_names);
+                                if let Message::BbCreateCharacterAck(_, BbCreateCharacterAck { status: 0, .. }) = ack {
+                                    self.storage.set(account_id, slot, stored, time::get_time().sec as u64);
+                                }
+                                Some((req, ack))
+                            },
+                            Message::BbDeleteCharacter(req, body) => {
+                                let (account_id, slot) = (body.account_id, body.slot);
+                                let ack = handler.handle_bb_delete_character(body);
+                                if let Message::BbDeleteCharacterAck(_, BbDeleteCharacterAck { status: 0, .. }) = ack {
+                                    self.storage.set(account_id, slot, 0, time::get_time().sec as u64);
+                                    // Or it would be saved against whatever is made in the slot next.
+                                    if let Some(ref mut b) = self.batch {
+                                        b.forget(account_id, slot);
+                                    }
+                                }
+                                Some((req, ack))
+                            },
+                            Message::BbGetStorageUsage(req, body) => {
+                                let slots = self.storage.slots(body.account_id, time::get_time().sec as u64);
+                                Some((req, handler.handle_bb_get_storage_usage(body, slots, self.storage_quota)))
+                            },
+                            Message::BbGetSharedBank(req, body) => {
+                                Some((req, handler.handle_bb_get_shared_bank(body, self.shared_bank_slots)))
+                            },
+                            Message::BbSharedBankTransfer(req, body) => {
+                                let (account_id, slot, stored) = (body.account_id, body.slot, body.full_char.stored_items());
+                                let ack = handler.handle_bb_shared_bank_transfer(body, self.shared_bank_slots);
+                                if let Message::BbSharedBankTransferAck(_, BbSharedBankTransferAck { status: 0, .. }) = ack {
+                                    self.storage.set(account_id, slot, stored, time::get_time().sec as u64);
+                                }
+                                Some((req, ack))
+                            },
+                            Message::BbGetRulesVersion(req, body) => {
+                                Some((req, handler.handle_bb_get_rules_version(body)))
+                            },
+                            Message::BbSetRulesVersion(_, body) => {
+                                handler.handle_bb_set_rules_version(body);
+                                None
+                            },
+                            Message::BbAddPlaytime(_, body) => {
+                                match self.batch {
+                                    Some(ref mut b) => flush = b.add_playtime(body.account_id, body.slot, body.seconds),
+                                    None => handler.handle_bb_add_playtime(body)
+                                }
+                                None
+                            },
+                            Message::BbChatLog(_, body) => {
+                                match self.batch {
+                                    Some(ref mut b) => flush = b.add_chat(chat_line(body)),
+                                    None => handler.handle_bb_chat_log(body)
+                                }
+                                None
+                            },
+                            Message::BbGetPlaytime(req, body) => {
+                                let (account_id, slot) = (body.account_id, body.slot);
+                                let mut ack = handler.handle_bb_get_playtime(body);
+                                // Time still in the batch hasn't reached the database.
+                                if let Some(ref b) = self.batch {
+                                    if let Message::BbGetPlaytimeAck(_, BbGetPlaytimeAck { status: 0, ref mut account_seconds, ref mut character_seconds, .. }) = ack {
+                                        let (account, character) = b.pending_playtime(account_id, slot);
+                                        *account_seconds += account;
+                                        *character_seconds += character;
+                                    }
+                                }
+                                Some((req, ack))
+                            },
                             Message::BbSetLoginFlags(_, body) => {
                                 handler.handle_bb_set_login_flags(body);
                                 None
                             },
+                            Message::BbBanAccount(_, body) => {
+                                handler.handle_bb_ban_account(body);
+                                None
+                            },
                             Message::BbGetLoginFlags(req, body) => {
                                 Some((req, handler.handle_bb_get_login_flags(body)))
+                            },
+                            Message::BbCheckBan(req, body) => {
+                                Some((req, handler.handle_bb_check_ban(body)))
+                            },
+                            Message::BbBlockTransfer(req, body) => {
+                                let (account_id, slot, stored) = (body.account_id, body.slot, body.full_char.stored_items());
+                                let ack = handler.handle_bb_block_transfer(body);
+                                if let Message::BbBlockTransferAck(_, BbBlockTransferAck { status: 0, .. }) = ack {
+                                    self.storage.set(account_id, slot, stored, time::get_time().sec as u64);
+                                }
+                                Some((req, ack))
+                            },
+                            Message::BbIssueHandoff(req, body) => {
+                                let now = time::get_time().sec as u64;
+                                self.handoffs.expire(now);
+                                let token = self.handoffs.issue(body.account_id, now);
+                                Some((req, BbIssueHandoffAck { account_id: body.account_id, token: token }.into()))
+                            },
+                            Message::BbClaimHandoff(req, body) => {
+                                let ok = self.handoffs.claim(body.account_id, body.token, time::get_time().sec as u64);
+                                if !ok {
+                                    warn!("Account {} arrived at a ship with a bad or expired token", body.account_id);
+                                }
+                                Some((req, BbClaimHandoffAck { status: if ok { 0 } else { 1 } }.into()))
+                            },
+                            Message::ShipEventSubscribe(req, ShipEventSubscribe(ship)) => {
+                                debug!("Client {} subscribed to events for ship {}", id, ship);
+                                self.event_subs.push((id, req, ship));
+                                None
+                            },
+                            Message::SetShipEvent(_, body) => {
+                                info!("Setting event {} for ship {}", body.event, body.ship);
+                                for &(c, key, ref ship) in self.event_subs.iter() {
+                                    if *ship == body.ship {
+                                        self.sender.send((c, Message::SetShipEvent(key, body.clone())).into()).unwrap();
+                                    }
+                                }
+                                None
                             }
                             _ => unimplemented!()
                         };
@@ -150,6 +363,9 @@ impl ShipGateService {
                             response.set_response_key(req);
                             self.sender.send((id, response).into()).unwrap();
                         }
+                        if flush {
+                            flush_batch(&self.pool, &mut self.batch);
+                        }
                     } else {
                         if let Message::Auth(res, Auth(version, pw)) = m {
                             if version == 0 && pw == self.password {
@@ -162,6 +378,11 @@ impl ShipGateService {
                                 self.sender.send(LoopMsg::DropClient(id)).unwrap();
                                 continue
                             }
+                        } else if let Message::Heartbeat(res, _) = m {
+                            // Monitoring tools can check on us without the
+                            // password.
+                            self.sender.send((id, Message::HeartbeatAck(res, heartbeat_ack(self.started))).into()).unwrap();
+                            continue
                         } else {
                             // Client must auth first. Drop immediately.
                             warn!("Shipgate client {} tried to do something other than Auth first", id);
@@ -170,8 +391,52 @@ impl ShipGateService {
                         }
                     }
                 },
+                ServiceMsg::Tick => {
+                    if self.batch.as_mut().map(|b| b.tick()).unwrap_or(false) {
+                        self.flush_batch();
+                    }
+                    self.tick_maintenance();
+                    self.storage.expire(time::get_time().sec as u64);
+                },
+                ServiceMsg::Shutdown => {
+                    self.flush_batch();
+                    return
+                },
                 _ => unreachable!()
             }
         }
     }
 }
+
+fn heartbeat_ack(started: i64) -> HeartbeatAck {
+    HeartbeatAck {
+        uptime: uptime(started, time::get_time().sec)
+    }
+}
+
+/// Whole seconds between `started` and `now`, never negative if the clock
+/// was set back.
+fn uptime(started: i64, now: i64) -> u64 {
+    if now > started {
+        (now - started) as u64
+    } else {
+        0
+    }
+}
+
+fn flush_batch(pool: &Pool, batch: &mut Option<WriteBatch>) {
+    if let Some(ref mut b) = *batch {
+        if !b.is_empty() {
+            let playtime = b.take_playtime();
+            if !playtime.is_empty() {
+                debug!("Saving {} batched playtime updates", playtime.len());
+                save_bb_playtimes(pool, &playtime);
+            }
+            let chat = b.take_chat();
+            if !chat.is_empty() {
+                debug!("Saving {} batched chat messages", chat.len());
+                save_bb_chat_logs(pool, &chat);
+            }
+        }
+    }
+}
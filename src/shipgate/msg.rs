@@ -7,7 +7,7 @@ use std::net::{SocketAddrV4, Ipv4Addr};
 use psoserial::Serial;
 use psoserial::util::*;
 
-use psodata::chara::BbFullCharData;
+use psodata::chara::{BbFullCharData, ItemBank};
 
 use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
 
@@ -156,7 +156,39 @@ impl_shipgate_message_enum! {
     15 => BbPutCharacter,
     16 => BbSetLoginFlags,
     17 => BbGetLoginFlags,
-    18 => BbGetLoginFlagsAck
+    18 => BbGetLoginFlagsAck,
+    19 => ShipEventSubscribe,
+    20 => SetShipEvent,
+    21 => BbGetStorageUsage,
+    22 => BbGetStorageUsageAck,
+    23 => BbGetSharedBank,
+    24 => BbGetSharedBankAck,
+    25 => BbSharedBankTransfer,
+    26 => BbSharedBankTransferAck,
+    27 => BbCreateCharacter,
+    28 => BbCreateCharacterAck,
+    29 => BbGetRulesVersion,
+    30 => BbGetRulesVersionAck,
+    31 => BbSetRulesVersion,
+    32 => BbAddPlaytime,
+    33 => BbGetPlaytime,
+    34 => BbGetPlaytimeAck,
+    35 => BbBanAccount,
+    36 => BbUpdateShortcuts,
+    37 => BbCheckBan,
+    38 => BbCheckBanAck,
+    39 => BbBlockTransfer,
+    40 => BbBlockTransferAck,
+    41 => ShipPopulation,
+    42 => Heartbeat,
+    43 => HeartbeatAck,
+    44 => BbDeleteCharacter,
+    45 => BbDeleteCharacterAck,
+    46 => BbChatLog,
+    47 => BbIssueHandoff,
+    48 => BbIssueHandoffAck,
+    49 => BbClaimHandoff,
+    50 => BbClaimHandoffAck
 }
 
 #[derive(Clone, Debug)]
@@ -309,17 +341,19 @@ derive_serial!(RegisterShipAck);
 
 derive_serial!(ShipList);
 
+/// Each ship's address, menu name and players online.
 #[derive(Clone, Debug)]
-pub struct ShipListAck(pub Vec<(SocketAddrV4, String)>);
+pub struct ShipListAck(pub Vec<(SocketAddrV4, String, u32)>);
 impl Serial for ShipListAck {
     fn serialize(&self, dst: &mut Write) -> io::Result<()> {
         try!((self.0.len() as u32).serialize(dst));
-        for &(ref s, ref n) in self.0.iter() {
+        for &(ref s, ref n, players) in self.0.iter() {
             let ip = s.ip().octets();
             let port = s.port();
             try!(write_array(&ip, 4, dst));
             try!(port.serialize(dst));
             try!(write_utf16(n, dst));
+            try!(players.serialize(dst));
         }
         Ok(())
     }
@@ -332,7 +366,8 @@ impl Serial for ShipListAck {
             let port = try!(u16::deserialize(src));
             let socketaddr = SocketAddrV4::new(Ipv4Addr::new(ip_octets[0], ip_octets[1], ip_octets[2], ip_octets[3]), port);
             let name = try!(read_utf16(src));
-            ships.push((socketaddr, name));
+            let players = try!(u32::deserialize(src));
+            ships.push((socketaddr, name, players));
         }
         Ok(ShipListAck(ships))
     }
@@ -369,6 +404,30 @@ impl Serial for BbUpdateKeys {
     }
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct BbUpdateShortcuts {
+    pub account_id: u32,
+    pub shortcuts: Vec<u8>
+}
+impl Serial for BbUpdateShortcuts {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(self.account_id.serialize(dst));
+        try!((self.shortcuts.len() as u32).serialize(dst));
+        try!(write_array(&self.shortcuts, self.shortcuts.len() as u32, dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        let account_id = try!(Serial::deserialize(src));
+        let len = try!(u32::deserialize(src));
+        let shortcuts = try!(read_array(len, src));
+        Ok(BbUpdateShortcuts {
+            account_id: account_id,
+            shortcuts: shortcuts
+        })
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct BbUpdateJoy {
     pub account_id: u32,
@@ -471,3 +530,354 @@ derive_serial_default! {
         pub flags: u32
     }
 }
+
+/// Sent by a block to be told when its ship's event changes. The shipgate
+/// answers with `SetShipEvent` on this request's response key every time the
+/// named ship's event is set.
+#[derive(Clone, Debug, Default)]
+pub struct ShipEventSubscribe(pub String);
+impl Serial for ShipEventSubscribe {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(write_utf16(&self.0, dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        Ok(ShipEventSubscribe(try!(read_utf16(src))))
+    }
+}
+
+/// Set the seasonal event for every block on a ship that doesn't override it.
+#[derive(Clone, Debug, Default)]
+pub struct SetShipEvent {
+    pub ship: String,
+    pub event: u16
+}
+impl Serial for SetShipEvent {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(write_utf16(&self.ship, dst));
+        try!(self.event.serialize(dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        let ship = try!(read_utf16(src));
+        let event = try!(Serial::deserialize(src));
+        Ok(SetShipEvent {
+            ship: ship,
+            event: event
+        })
+    }
+}
+
+// Ask how much of an account's storage quota is used by characters other
+// than the one in `slot`.
+derive_serial_default! {
+    BbGetStorageUsage {
+        pub account_id: u32,
+        pub slot: u8
+    }
+}
+
+derive_serial_default! {
+    BbGetStorageUsageAck {
+        pub status: u32,
+        pub account_id: u32,
+        // Items stored by the account's other characters.
+        pub used_elsewhere: u32,
+        // 0 means there is no quota.
+        pub quota: u32
+    }
+}
+
+derive_serial_default! {
+    BbGetSharedBank {
+        pub account_id: u32
+    }
+}
+
+derive_serial_default! {
+    BbGetSharedBankAck {
+        pub status: u32,
+        pub account_id: u32,
+        // Most items the shared bank may hold.
+        pub capacity: u32,
+        pub bank: ItemBank
+    }
+}
+
+// Save a character and the account's shared bank in one transaction, after
+// items or meseta moved between them.
+derive_serial_default! {
+    BbSharedBankTransfer {
+        pub account_id: u32,
+        pub slot: u8,
+        pub full_char: BbFullCharData,
+        pub bank: ItemBank
+    }
+}
+
+derive_serial_default! {
+    BbSharedBankTransferAck {
+        pub status: u32,
+        pub account_id: u32
+    }
+}
+
+// Save a newly made character. If the shipgate enforces unique character
+// names, the name is claimed first and the character isn't saved if it's
+// taken.
+derive_serial_default! {
+    BbCreateCharacter {
+        pub account_id: u32,
+        pub slot: u8,
+        pub full_char: BbFullCharData
+    }
+}
+
+derive_serial_default! {
+    BbCreateCharacterAck {
+        // 4 if the name is taken
+        pub status: u32,
+        pub account_id: u32,
+        pub slot: u8
+    }
+}
+
+// The version of the server rules an account last accepted.
+derive_serial_default! {
+    BbGetRulesVersion {
+        pub account_id: u32
+    }
+}
+
+derive_serial_default! {
+    BbGetRulesVersionAck {
+        pub status: u32,
+        pub account_id: u32,
+        // 0 if they never have
+        pub version: u32
+    }
+}
+
+derive_serial_default! {
+    BbSetRulesVersion {
+        pub account_id: u32,
+        pub version: u32
+    }
+}
+
+// Time played on a character since it was last credited.
+derive_serial_default! {
+    BbAddPlaytime {
+        pub account_id: u32,
+        pub slot: u8,
+        pub seconds: u32
+    }
+}
+
+derive_serial_default! {
+    BbGetPlaytime {
+        pub account_id: u32,
+        pub slot: u8
+    }
+}
+
+derive_serial_default! {
+    BbGetPlaytimeAck {
+        pub status: u32,
+        pub account_id: u32,
+        // seconds on the whole account
+        pub account_seconds: u64,
+        // seconds on the character in the slot
+        pub character_seconds: u64
+    }
+}
+
+derive_serial_default! {
+    BbBanAccount {
+        pub account_id: u32
+    }
+}
+
+derive_serial_default! {
+    BbCheckBan {
+        pub guildcard: u32
+    }
+}
+
+/// The ban on a guild card, if any. The block decides whether it has
+/// expired.
+#[derive(Clone, Debug, Default)]
+pub struct BbCheckBanAck {
+    pub status: u32,
+    pub banned: bool,
+    /// Unix time at which the ban lifts; 0 if it doesn't.
+    pub expires: u64,
+    pub reason: String
+}
+impl Serial for BbCheckBanAck {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(self.status.serialize(dst));
+        try!((self.banned as u8).serialize(dst));
+        try!(self.expires.serialize(dst));
+        try!(write_utf16(&self.reason, dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        let status = try!(Serial::deserialize(src));
+        let banned: u8 = try!(Serial::deserialize(src));
+        let expires = try!(Serial::deserialize(src));
+        let reason = try!(read_utf16(src));
+        Ok(BbCheckBanAck {
+            status: status,
+            banned: banned != 0,
+            expires: expires,
+            reason: reason
+        })
+    }
+}
+
+// Save the character of a player moving to another block. The block only
+// sends them on once this is acknowledged, so the next block loads what
+// this one had.
+derive_serial_default! {
+    BbBlockTransfer {
+        pub account_id: u32,
+        pub slot: u8,
+        pub full_char: BbFullCharData
+    }
+}
+
+derive_serial_default! {
+    BbBlockTransferAck {
+        pub status: u32,
+        pub account_id: u32
+    }
+}
+
+/// A block's population, sent every `ships::REPORT_INTERVAL` seconds for the
+/// ship list.
+#[derive(Clone, Debug, Default)]
+pub struct ShipPopulation {
+    pub ship: String,
+    pub block: u16,
+    pub players: u32
+}
+impl Serial for ShipPopulation {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(write_utf16(&self.ship, dst));
+        try!(self.block.serialize(dst));
+        try!(self.players.serialize(dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        Ok(ShipPopulation {
+            ship: try!(read_utf16(src)),
+            block: try!(Serial::deserialize(src)),
+            players: try!(Serial::deserialize(src))
+        })
+    }
+}
+
+// Asks the shipgate whether it's alive. Answered even before `Auth`, so a
+// monitoring tool needn't know the password.
+derive_serial!(Heartbeat);
+
+// `uptime` is in seconds since the shipgate started.
+derive_serial_default! {
+    HeartbeatAck {
+        pub uptime: u64
+    }
+}
+
+derive_serial_default! {
+    BbDeleteCharacter {
+        pub account_id: u32,
+        pub slot: u8
+    }
+}
+
+// `deleted` is 0 if the slot was already empty.
+derive_serial_default! {
+    BbDeleteCharacterAck {
+        pub status: u32,
+        pub account_id: u32,
+        pub slot: u8,
+        pub deleted: u8
+    }
+}
+
+/// A chat message for the database's chat log. Nothing answers it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BbChatLog {
+    pub guildcard: u32,
+    /// The guild card whispered to, or 0 for lobby chat.
+    pub target: u32,
+    pub block: u16,
+    /// The sender's lobby, from 1; 0 if they weren't in one.
+    pub lobby: u8,
+    /// Unix time it was said.
+    pub time: u64,
+    pub text: String
+}
+impl Serial for BbChatLog {
+    fn serialize(&self, dst: &mut Write) -> io::Result<()> {
+        try!(self.guildcard.serialize(dst));
+        try!(self.target.serialize(dst));
+        try!(self.block.serialize(dst));
+        try!(self.lobby.serialize(dst));
+        try!(self.time.serialize(dst));
+        try!(write_utf16(&self.text, dst));
+        Ok(())
+    }
+
+    fn deserialize(src: &mut Read) -> io::Result<Self> {
+        let guildcard = try!(Serial::deserialize(src));
+        let target = try!(Serial::deserialize(src));
+        let block = try!(Serial::deserialize(src));
+        let lobby = try!(Serial::deserialize(src));
+        let time = try!(Serial::deserialize(src));
+        let text = try!(read_utf16(src));
+        Ok(BbChatLog {
+            guildcard: guildcard,
+            target: target,
+            block: block,
+            lobby: lobby,
+            time: time,
+            text: text
+        })
+    }
+}
+
+// A one-time token for a player leaving for another ship, for the block to
+// put in their security data.
+derive_serial_default! {
+    BbIssueHandoff {
+        pub account_id: u32
+    }
+}
+
+derive_serial_default! {
+    BbIssueHandoffAck {
+        pub account_id: u32,
+        pub token: u32
+    }
+}
+
+// Sent by the ship a player arrives at with a token instead of the login
+// server's magic. `status` is 0 if the token was theirs and is now used up.
+derive_serial_default! {
+    BbClaimHandoff {
+        pub account_id: u32,
+        pub token: u32
+    }
+}
+
+derive_serial_default! {
+    BbClaimHandoffAck {
+        pub status: u32
+    }
+}
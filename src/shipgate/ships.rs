@@ -0,0 +1,159 @@
+//! The ships connected to the shipgate and how many players each has, as
+//! shown on the ship select menu.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+
+/// Seconds between a ship registering itself again and its blocks reporting
+/// their population.
+pub const REPORT_INTERVAL: u64 = 30;
+/// Seconds after its last report that a ship or block is dropped from the
+/// list, having missed a few.
+pub const REPORT_TIMEOUT: u64 = 3 * REPORT_INTERVAL;
+
+struct Ship {
+    /// The shipgate client it registered from.
+    client: usize,
+    addr: SocketAddrV4,
+    last_seen: u64,
+    /// Players on each block and when the block last reported.
+    blocks: BTreeMap<u16, (u32, u64)>
+}
+
+#[derive(Default)]
+pub struct ShipRegistry {
+    ships: BTreeMap<String, Ship>
+}
+
+impl ShipRegistry {
+    pub fn new() -> ShipRegistry {
+        ShipRegistry::default()
+    }
+
+    /// Add a ship, or note that one already listed is still there.
+    pub fn register(&mut self, client: usize, addr: SocketAddrV4, name: &str, now: u64) {
+        let ship = self.ships.entry(name.to_string()).or_insert_with(|| Ship {
+            client: client,
+            addr: addr,
+            last_seen: now,
+            blocks: BTreeMap::new()
+        });
+        ship.client = client;
+        ship.addr = addr;
+        ship.last_seen = now;
+    }
+
+    /// Record a block's population. Reports for ships that haven't
+    /// registered are ignored.
+    pub fn report_block(&mut self, name: &str, block: u16, players: u32, now: u64) {
+        if let Some(ship) = self.ships.get_mut(name) {
+            ship.blocks.insert(block, (players, now));
+        }
+    }
+
+    /// Drop the ships registered from a shipgate client that went away.
+    pub fn remove_client(&mut self, client: usize) {
+        let gone: Vec<String> = self.ships.iter()
+            .filter(|&(_, s)| s.client == client)
+            .map(|(n, _)| n.clone())
+            .collect();
+        for n in gone {
+            info!("Ship {} went offline", n);
+            self.ships.remove(&n);
+        }
+    }
+
+    /// Drop ships and blocks that haven't reported within `REPORT_TIMEOUT`.
+    pub fn expire(&mut self, now: u64) {
+        let gone: Vec<String> = self.ships.iter()
+            .filter(|&(_, s)| now.saturating_sub(s.last_seen) > REPORT_TIMEOUT)
+            .map(|(n, _)| n.clone())
+            .collect();
+        for n in gone {
+            info!("Ship {} stopped reporting; dropping it from the list", n);
+            self.ships.remove(&n);
+        }
+        for ship in self.ships.values_mut() {
+            let stale: Vec<u16> = ship.blocks.iter()
+                .filter(|&(_, &(_, t))| now.saturating_sub(t) > REPORT_TIMEOUT)
+                .map(|(&b, _)| b)
+                .collect();
+            for b in stale {
+                ship.blocks.remove(&b);
+            }
+        }
+    }
+
+    /// The ship list after expiring stale entries: address, menu name and
+    /// players on the ship.
+    pub fn list(&mut self, now: u64) -> Vec<(SocketAddrV4, String, u32)> {
+        self.expire(now);
+        self.ships.iter().enumerate().map(|(i, (name, ship))| {
+            let players = ship.blocks.values().fold(0u32, |a, &(p, _)| a.saturating_add(p));
+            (ship.addr, format!("{:02}:{}", i + 1, name), players)
+        }).collect()
+    }
+}
+
+/// A ship's name on the ship select menu, with how many are playing there.
+pub fn menu_name(name: &str, players: u32) -> String {
+    format!("{} ({})", name, players)
+}
+
+/// A ship's name as given in the ship list, without the number before it.
+pub fn listed_name(listed: &str) -> &str {
+    match listed.find(':') {
+        Some(i) => &listed[i + 1..],
+        None => listed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddrV4;
+
+    use super::{ShipRegistry, REPORT_TIMEOUT, listed_name};
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_ship_list_expires() {
+        let mut r = ShipRegistry::new();
+        r.register(1, addr(12000), "Alpha", 100);
+        r.register(1, addr(13000), "Beta", 100);
+        r.report_block("Alpha", 1, 5, 100);
+        r.report_block("Alpha", 2, 3, 100);
+        r.report_block("Beta", 1, 2, 100);
+        r.report_block("Gamma", 1, 9, 100);
+        assert_eq!(r.list(100), vec![
+            (addr(12000), "01:Alpha".to_string(), 8),
+            (addr(13000), "02:Beta".to_string(), 2)
+        ]);
+
+        // Alpha keeps reporting; Beta stops.
+        let later = 100 + REPORT_TIMEOUT + 1;
+        r.register(1, addr(12000), "Alpha", later);
+        r.report_block("Alpha", 1, 6, later);
+        assert_eq!(r.list(later), vec![(addr(12000), "01:Alpha".to_string(), 6)]);
+    }
+
+    #[test]
+    fn test_ship_list_client_gone() {
+        let mut r = ShipRegistry::new();
+        r.register(1, addr(12000), "Alpha", 0);
+        r.register(2, addr(13000), "Beta", 0);
+        r.remove_client(1);
+        assert_eq!(r.list(0), vec![(addr(13000), "01:Beta".to_string(), 0)]);
+    }
+
+    #[test]
+    fn test_listed_name() {
+        let mut r = ShipRegistry::new();
+        r.register(1, addr(12000), "Alpha:2", 0);
+        let ships = r.list(0);
+        assert_eq!(listed_name(&ships[0].1), "Alpha:2");
+        assert_eq!(listed_name("Beta"), "Beta");
+    }
+}
@@ -0,0 +1,80 @@
+//! How many items each account's characters store, for the storage quota.
+//! Accounts are dropped once nothing has asked about or saved them for a
+//! while, so the cache only holds the ones being played.
+
+use std::collections::HashMap;
+
+/// Seconds an account stays cached after its usage was last asked for or
+/// changed.
+pub const STORAGE_CACHE_TTL: u64 = 60 * 60;
+
+struct Usage {
+    /// Items stored per character slot, None until read from the database.
+    slots: [Option<u32>; 4],
+    last_used: u64
+}
+
+#[derive(Default)]
+pub struct StorageCache {
+    accounts: HashMap<u32, Usage>
+}
+
+impl StorageCache {
+    pub fn new() -> StorageCache {
+        StorageCache::default()
+    }
+
+    /// The account's per-slot usage, for the slots not known yet to be
+    /// filled in from the database.
+    pub fn slots(&mut self, account_id: u32, now: u64) -> &mut [Option<u32>; 4] {
+        let usage = self.accounts.entry(account_id).or_insert_with(|| Usage {
+            slots: [None; 4],
+            last_used: now
+        });
+        usage.last_used = now;
+        &mut usage.slots
+    }
+
+    /// Note what a slot stores now. Accounts that aren't cached are left
+    /// for the next time their usage is asked for.
+    pub fn set(&mut self, account_id: u32, slot: u8, stored: u32, now: u64) {
+        if let Some(usage) = self.accounts.get_mut(&account_id) {
+            if let Some(s) = usage.slots.get_mut(slot as usize) {
+                *s = Some(stored);
+                usage.last_used = now;
+            }
+        }
+    }
+
+    /// Forget accounts unused for `STORAGE_CACHE_TTL` seconds.
+    pub fn expire(&mut self, now: u64) {
+        self.accounts.retain(|_, u| now.saturating_sub(u.last_used) < STORAGE_CACHE_TTL);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StorageCache, STORAGE_CACHE_TTL};
+
+    #[test]
+    fn test_storage_cache_expires() {
+        let mut c = StorageCache::new();
+        c.slots(1, 100)[0] = Some(30);
+        c.slots(2, 100)[1] = Some(40);
+        // Saving keeps an account around; accounts not cached aren't added.
+        c.set(1, 0, 35, 100 + STORAGE_CACHE_TTL - 1);
+        c.set(3, 0, 10, 100 + STORAGE_CACHE_TTL - 1);
+        assert_eq!(c.len(), 2);
+
+        c.expire(100 + STORAGE_CACHE_TTL);
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.slots(1, 100 + STORAGE_CACHE_TTL)[0], Some(35));
+        // Account 2 starts over, to be read from the database again.
+        assert_eq!(c.slots(2, 100 + STORAGE_CACHE_TTL)[1], None);
+    }
+}
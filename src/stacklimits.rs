@@ -0,0 +1,132 @@
+//! Per-item caps on how many tools fit in one stack, in the inventory or the
+//! bank. Tools the table doesn't list use the block's `tool_stack_limit`.
+//!
+//! The table is a TOML file of `"code" = limit` pairs. A code is the item's
+//! first bytes in hex: `"03SSII"` for one tool, or `"03SS"` for every tool of
+//! that subtype. An exact code wins over its subtype.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use toml::{Parser, Value};
+
+use psodata::chara::ItemData;
+
+/// The most the client will show in one stack.
+pub const MAX_STACK_LIMIT: u8 = 99;
+
+#[derive(Debug, Clone, Default)]
+pub struct StackLimits {
+    items: HashMap<(u8, u8), u8>,
+    subtypes: HashMap<u8, u8>
+}
+
+impl StackLimits {
+    pub fn load_from_file(path: &str) -> Result<StackLimits, String> {
+        let mut s = String::new();
+        let mut f = try!(File::open(path).map_err(|e| format!("{}: {}", path, e)));
+        try!(f.read_to_string(&mut s).map_err(|e| format!("{}: {}", path, e)));
+        StackLimits::from_toml_string(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn from_toml_string(s: &str) -> Result<StackLimits, String> {
+        let mut parser = Parser::new(s);
+        let t = match parser.parse() {
+            Some(t) => t,
+            None => {
+                let errors: Vec<String> = parser.errors.into_iter().map(|e| format!("{}", e)).collect();
+                return Err(format!("{:?}", errors))
+            }
+        };
+        let mut limits = StackLimits::default();
+        for (code, v) in t.iter() {
+            let limit = match v {
+                &Value::Integer(n) if n >= 1 && n <= MAX_STACK_LIMIT as i64 => n as u8,
+                _ => return Err(format!("stack limit for {} must be between 1 and {}", code, MAX_STACK_LIMIT))
+            };
+            let bytes = match parse_code(code) {
+                Some(b) => b,
+                None => return Err(format!("{} is not a tool code like \"03SS\" or \"03SSII\"", code))
+            };
+            if bytes[0] != 3 || bytes[1] == 2 {
+                return Err(format!("{} is not a stackable tool", code))
+            }
+            if bytes.len() == 3 {
+                limits.items.insert((bytes[1], bytes[2]), limit);
+            } else {
+                limits.subtypes.insert(bytes[1], limit);
+            }
+        }
+        Ok(limits)
+    }
+
+    /// The stack cap for `item`, or `default` if the table doesn't list it.
+    pub fn limit_for(&self, item: &ItemData, default: u8) -> u8 {
+        if !item.is_stackable() {
+            return default
+        }
+        let (subtype, index) = (item.data[1], item.data[2]);
+        self.items.get(&(subtype, index))
+            .or_else(|| self.subtypes.get(&subtype))
+            .map(|l| *l)
+            .unwrap_or(default)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len() + self.subtypes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Hex "TTSS" or "TTSSII" to bytes.
+pub fn parse_code(code: &str) -> Option<Vec<u8>> {
+    if (code.len() != 4 && code.len() != 6) || !code.chars().all(|c| c.is_digit(16)) {
+        return None
+    }
+    let bytes = (0..code.len() / 2)
+        .map(|i| u8::from_str_radix(&code[i * 2..i * 2 + 2], 16).unwrap())
+        .collect();
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use psodata::chara::ItemData;
+
+    fn tool(subtype: u8, index: u8) -> ItemData {
+        let mut d = ItemData::default();
+        d.data[0] = 3;
+        d.data[1] = subtype;
+        d.data[2] = index;
+        d
+    }
+
+    #[test]
+    fn test_limit_for() {
+        let l = StackLimits::from_toml_string("\"0310\" = 99\n\"031002\" = 20\n").unwrap();
+        assert_eq!(l.limit_for(&tool(0x10, 0), 10), 99);
+        assert_eq!(l.limit_for(&tool(0x10, 2), 10), 20);
+        assert_eq!(l.limit_for(&tool(0x00, 0), 10), 10);
+        // Weapons aren't tools, whatever their bytes look like.
+        let mut w = tool(0x10, 0);
+        w.data[0] = 0;
+        assert_eq!(l.limit_for(&w, 10), 10);
+    }
+
+    #[test]
+    fn test_rejects_bad_tables() {
+        assert!(StackLimits::from_toml_string("\"0310\" = 0").is_err());
+        assert!(StackLimits::from_toml_string("\"0310\" = 100").is_err());
+        assert!(StackLimits::from_toml_string("\"0310\" = \"99\"").is_err());
+        assert!(StackLimits::from_toml_string("\"0010\" = 5").is_err());
+        assert!(StackLimits::from_toml_string("\"0302\" = 5").is_err());
+        assert!(StackLimits::from_toml_string("\"03zz\" = 5").is_err());
+        assert!(StackLimits::from_toml_string("\"03100\" = 5").is_err());
+        assert!(StackLimits::from_toml_string("").unwrap().is_empty());
+    }
+}
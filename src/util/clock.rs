@@ -0,0 +1,106 @@
+//! Checking the system clock at startup. Ban expiries, daily maintenance and
+//! saved timestamps all go by it, so a clock that's far off quietly breaks
+//! them. Intervals at runtime use `time::precise_time_ns`, which is
+//! monotonic, so only wall clock times need this.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use time;
+
+use ::config::ClockCheckConf;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+/// 2016-01-01. A clock showing anything earlier is certainly wrong.
+const EARLIEST_PLAUSIBLE: i64 = 1_451_606_400;
+/// How long to wait for the NTP server to answer.
+const NTP_TIMEOUT_MS: u64 = 3000;
+
+/// Ask an NTP server for the time, in seconds since the Unix epoch.
+pub fn ntp_time(server: &str) -> io::Result<i64> {
+    let addr = if server.contains(':') { server.to_string() } else { format!("{}:123", server) };
+    let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+    try!(socket.set_read_timeout(Some(Duration::from_millis(NTP_TIMEOUT_MS))));
+    // Version 3, client mode; the rest of the request can be zero.
+    let mut req = [0u8; 48];
+    req[0] = 0x1B;
+    try!(socket.send_to(&req, &addr[..]));
+    let mut buf = [0u8; 48];
+    let (len, _) = try!(socket.recv_from(&mut buf));
+    parse_ntp_reply(&buf[..len])
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "malformed NTP reply"))
+}
+
+/// The transmit time in an NTP server's reply, in seconds since the Unix
+/// epoch.
+fn parse_ntp_reply(buf: &[u8]) -> Option<i64> {
+    if buf.len() < 48 || buf[0] & 0x07 != 4 {
+        return None
+    }
+    let secs = (buf[40] as u32) << 24 | (buf[41] as u32) << 16 | (buf[42] as u32) << 8 | buf[43] as u32;
+    if secs == 0 {
+        return None
+    }
+    Some(secs as i64 - NTP_UNIX_OFFSET)
+}
+
+/// Check the system clock is plausible and, if a server is configured, close
+/// enough to NTP time. Problems are logged, or returned if they're
+/// configured to stop the server. Not reaching the NTP server is only ever
+/// a warning.
+pub fn check_clock(conf: &ClockCheckConf) -> Result<(), String> {
+    let now = time::get_time().sec;
+    let problem = if now < EARLIEST_PLAUSIBLE {
+        Some(format!("System clock reads {}, which can't be right", time::now_utc().rfc3339()))
+    } else {
+        match conf.ntp_server {
+            Some(ref server) => match ntp_time(server) {
+                Ok(t) => {
+                    let skew = now - t;
+                    if skew.abs() > conf.max_skew as i64 {
+                        Some(format!("System clock is {} seconds {} {}", skew.abs(), if skew > 0 { "ahead of" } else { "behind" }, server))
+                    } else {
+                        info!("System clock is within {} seconds of {}", skew.abs(), server);
+                        None
+                    }
+                },
+                Err(e) => {
+                    warn!("Couldn't check the system clock against {}: {}", server, e);
+                    None
+                }
+            },
+            None => None
+        }
+    };
+    match problem {
+        Some(p) => if conf.fatal {
+            Err(p)
+        } else {
+            warn!("{}; ban expiries, maintenance and save times will be off", p);
+            Ok(())
+        },
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_ntp_reply;
+
+    #[test]
+    fn test_parse_ntp_reply() {
+        let mut buf = [0u8; 48];
+        buf[0] = 0x1C;
+        // 2016-01-01 in NTP time.
+        buf[40] = 0xDA;
+        buf[41] = 0x30;
+        buf[42] = 0x40;
+        buf[43] = 0x00;
+        assert_eq!(parse_ntp_reply(&buf), Some(1_451_606_400));
+        buf[0] = 0x1B;
+        assert_eq!(parse_ntp_reply(&buf), None);
+        assert_eq!(parse_ntp_reply(&buf[..40]), None);
+    }
+}
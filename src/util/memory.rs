@@ -0,0 +1,39 @@
+//! How much memory the server is using, for turning clients away before the
+//! kernel kills the process.
+
+use std::fs::File;
+use std::io::Read;
+
+/// Size of a page in `/proc/self/statm`. Every platform the server runs on
+/// uses 4 KiB pages.
+const PAGE_SIZE: u64 = 4096;
+
+/// The process's resident set size in bytes, or `None` where there's no
+/// `/proc` to read it from.
+pub fn resident_bytes() -> Option<u64> {
+    let mut s = String::new();
+    match File::open("/proc/self/statm").and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => parse_statm(&s),
+        Err(_) => None
+    }
+}
+
+/// The resident size in `statm`'s second field, in bytes.
+fn parse_statm(s: &str) -> Option<u64> {
+    s.split_whitespace()
+        .nth(1)
+        .and_then(|p| p.parse::<u64>().ok())
+        .map(|p| p * PAGE_SIZE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_statm;
+
+    #[test]
+    fn test_parse_statm() {
+        assert_eq!(parse_statm("12345 2048 300 10 0 900 0\n"), Some(2048 * 4096));
+        assert_eq!(parse_statm("12345"), None);
+        assert_eq!(parse_statm(""), None);
+    }
+}
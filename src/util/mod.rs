@@ -16,4 +16,6 @@ pub fn gen_seed() -> Vec<u8> {
     ret
 }
 
+pub mod clock;
+pub mod memory;
 pub mod nsc;